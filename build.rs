@@ -1,23 +1,37 @@
 use anyhow::*;
-use fs_extra::copy_items;
-use fs_extra::dir::CopyOptions;
 use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+// Every file under `directory`, with its path relative to `root`
+fn files(root: &Path, directory: &Path, found: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files(root, &path, found)?;
+        } else {
+            let name = path.strip_prefix(root)?.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+            found.push((name, path));
+        }
+    }
+    Ok(())
+}
 
 fn main() -> Result<()> {
     // This tells cargo to rerun this script if something in /res/ changes.
-    println!("cargo:rerun-if-changed=res/*");
+    println!("cargo:rerun-if-changed=res");
 
-    let out_dir = env::var("OUT_DIR")?;
-    let mut copy_options = CopyOptions::new();
-    copy_options.overwrite = true;
-    copy_options.copy_inside = true;
-    let mut paths_to_copy = Vec::new();
-    paths_to_copy.push("res/");
-    copy_items(&paths_to_copy, out_dir, &copy_options)?;
+    // The resources get baked into the binary, it falls back on those when it can't find them on disk
+    let root = Path::new(&env::var("CARGO_MANIFEST_DIR")?).join("res");
+    let mut found = Vec::new();
+    files(&root, &root, &mut found)?;
+    found.sort();
+    let mut embedded = String::from("pub static EMBEDDED: &[(&str, &[u8])] = &[\n");
+    for (name, path) in found {
+        writeln!(embedded, "    ({:?}, include_bytes!({:?})),", name, path)?;
+    }
+    embedded.push_str("];\n");
+    std::fs::write(Path::new(&env::var("OUT_DIR")?).join("embedded_assets.rs"), embedded)?;
 
     Ok(())
 }
-
- 
-
- 
\ No newline at end of file
@@ -0,0 +1,33 @@
+// Alpha tested depth passes, put in front of the shadow and depth prepass shaders. Masked
+// materials bind their material at group 1 and the cut out texels get discarded.
+
+struct MaskedOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+// Start of the material uniform, the colours after it aren't needed here
+struct MaskMaterial {
+    use_texture: i32,
+    dissolve: f32,
+    alpha_mode: u32,
+    alpha_cutoff: f32,
+}
+
+@group(1) @binding(0)
+var t_mask_diffuse: texture_2d<f32>;
+@group(1) @binding(1)
+var s_mask_diffuse: sampler;
+@group(1) @binding(4)
+var<uniform> mask_material: MaskMaterial;
+
+@fragment
+fn fs_masked(in: MaskedOutput) {
+    var alpha = mask_material.dissolve;
+    if (mask_material.use_texture == 1) {
+        alpha = alpha * textureSample(t_mask_diffuse, s_mask_diffuse, in.tex_coords).a;
+    }
+    if (alpha < mask_material.alpha_cutoff) {
+        discard;
+    }
+}
@@ -0,0 +1,111 @@
+// Physically based bloom, the hdr image gets downsampled into a chain of smaller targets
+// and then upsampled back up again, adding every level onto the next bigger one.
+
+struct PostSettings {
+    surface_is_srgb: u32,
+    bloom_filter_radius: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    lut_strength: f32,
+    fxaa_edge_threshold: f32,
+    fxaa_edge_threshold_min: f32,
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+    _padding3: u32,
+};
+
+@group(0) @binding(0)
+var source_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+@group(0) @binding(2)
+var<uniform> post: PostSettings;
+
+fn sample_source(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(source_texture, source_sampler, uv, 0.0).rgb;
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+struct Downsample {
+    center: vec3<f32>,
+    // The 13 taps split into the five overlapping boxes they are made of
+    top_left: vec3<f32>,
+    top_right: vec3<f32>,
+    bottom_left: vec3<f32>,
+    bottom_right: vec3<f32>,
+};
+
+// 13 tap downsample from Call of Duty: Advanced Warfare
+fn downsample_boxes(uv: vec2<f32>) -> Downsample {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source_texture));
+    let a = sample_source(uv + texel * vec2<f32>(-2.0, -2.0));
+    let b = sample_source(uv + texel * vec2<f32>(0.0, -2.0));
+    let c = sample_source(uv + texel * vec2<f32>(2.0, -2.0));
+    let d = sample_source(uv + texel * vec2<f32>(-2.0, 0.0));
+    let e = sample_source(uv);
+    let f = sample_source(uv + texel * vec2<f32>(2.0, 0.0));
+    let g = sample_source(uv + texel * vec2<f32>(-2.0, 2.0));
+    let h = sample_source(uv + texel * vec2<f32>(0.0, 2.0));
+    let i = sample_source(uv + texel * vec2<f32>(2.0, 2.0));
+    let j = sample_source(uv + texel * vec2<f32>(-1.0, -1.0));
+    let k = sample_source(uv + texel * vec2<f32>(1.0, -1.0));
+    let l = sample_source(uv + texel * vec2<f32>(-1.0, 1.0));
+    let m = sample_source(uv + texel * vec2<f32>(1.0, 1.0));
+
+    var boxes: Downsample;
+    boxes.center = (j + k + l + m) * 0.25;
+    boxes.top_left = (a + b + d + e) * 0.25;
+    boxes.top_right = (b + c + e + f) * 0.25;
+    boxes.bottom_left = (d + e + g + h) * 0.25;
+    boxes.bottom_right = (e + f + h + i) * 0.25;
+    return boxes;
+}
+
+// The first downsample weights every box by its brightness (Karis average), so single
+// very bright pixels don't turn into flickering blobs
+@fragment
+fn fs_downsample_first(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let boxes = downsample_boxes(in.uv);
+    let w_center = 0.5 / (1.0 + luminance(boxes.center));
+    let w_top_left = 0.125 / (1.0 + luminance(boxes.top_left));
+    let w_top_right = 0.125 / (1.0 + luminance(boxes.top_right));
+    let w_bottom_left = 0.125 / (1.0 + luminance(boxes.bottom_left));
+    let w_bottom_right = 0.125 / (1.0 + luminance(boxes.bottom_right));
+    let color = boxes.center * w_center
+        + boxes.top_left * w_top_left
+        + boxes.top_right * w_top_right
+        + boxes.bottom_left * w_bottom_left
+        + boxes.bottom_right * w_bottom_right;
+    let total = w_center + w_top_left + w_top_right + w_bottom_left + w_bottom_right;
+    return vec4<f32>(max(color / total, vec3<f32>(0.0)), 1.0);
+}
+
+@fragment
+fn fs_downsample(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let boxes = downsample_boxes(in.uv);
+    let color = boxes.center * 0.5
+        + (boxes.top_left + boxes.top_right + boxes.bottom_left + boxes.bottom_right) * 0.125;
+    return vec4<f32>(color, 1.0);
+}
+
+// 3x3 tent filter, blended additively onto the next bigger level
+@fragment
+fn fs_upsample(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let r = post.bloom_filter_radius;
+    let a = sample_source(in.uv + vec2<f32>(-r, -r));
+    let b = sample_source(in.uv + vec2<f32>(0.0, -r));
+    let c = sample_source(in.uv + vec2<f32>(r, -r));
+    let d = sample_source(in.uv + vec2<f32>(-r, 0.0));
+    let e = sample_source(in.uv);
+    let f = sample_source(in.uv + vec2<f32>(r, 0.0));
+    let g = sample_source(in.uv + vec2<f32>(-r, r));
+    let h = sample_source(in.uv + vec2<f32>(0.0, r));
+    let i = sample_source(in.uv + vec2<f32>(r, r));
+    let color = (e * 4.0 + (b + d + f + h) * 2.0 + (a + c + g + i)) / 16.0;
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,51 @@
+// Depth only pass for the screen space effects. The scene's own depth buffer can be
+// multisampled and is still being written to when the ambient term needs it.
+
+struct Camera {
+    view_proj: mat4x4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> @builtin(position) vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    return camera.view_proj * model_matrix * vec4(model.position, 1.0);
+}
+
+@vertex
+fn vs_main_masked(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> MaskedOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: MaskedOutput;
+    out.clip_position = camera.view_proj * model_matrix * vec4(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
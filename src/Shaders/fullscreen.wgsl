@@ -0,0 +1,17 @@
+// Vertex stage shared by every fullscreen pass, it gets put in front of the pass' own shader
+
+struct FullscreenOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// A single triangle that covers the whole screen
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> FullscreenOutput {
+    var out: FullscreenOutput;
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    // Texture space has its origin at the top left
+    out.uv = vec2<f32>(uv.x, 1.0 - uv.y);
+    return out;
+}
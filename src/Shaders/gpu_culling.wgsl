@@ -0,0 +1,195 @@
+// Instance culling on the GPU. Visible instances get copied into a compacted buffer, grouped
+// by their level of detail, and the indirect draw of every mesh and level gets their amount. Occlusion is tested against a max depth
+// pyramid built from the previous frame's depth prepass. Like the IBL shader, every entry
+// point uses its own bindings.
+
+// Level 0 of the depth pyramid, straight from the depth prepass
+@group(0) @binding(0)
+var depth_texture: texture_depth_2d;
+@group(0) @binding(1)
+var pyramid_out: texture_storage_2d<r32float, write>;
+
+// Every next level keeps the farthest depth of the texels it covers
+@group(0) @binding(2)
+var pyramid_source: texture_2d<f32>;
+
+struct Cull {
+    planes: array<vec4<f32>, 6>, // Inwards facing, xyz is the normal
+    previous_view_proj: mat4x4<f32>,
+    bounds_min: vec3<f32>,       // Of the model, in model space
+    instance_count: u32,
+    bounds_max: vec3<f32>,
+    occlusion: u32,              // 1 when the pyramid can be used
+    pyramid_size: vec2<f32>,
+    pyramid_levels: u32,
+    mesh_count: u32,
+    capacity: u32,               // Instances every level has room for in the compacted buffer
+};
+
+// One per level of detail
+struct Counter {
+    counts: array<atomic<u32>, 3>,
+};
+
+// Laid out like wgpu's DrawIndexedIndirect
+struct DrawCommand {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+};
+
+// The level of detail of every instance, picked on the CPU
+@group(0) @binding(3)
+var<storage, read> lods: array<u32>;
+@group(0) @binding(4)
+var<uniform> cull: Cull;
+// InstanceRaw, a model matrix and a normal matrix
+@group(0) @binding(5)
+var<storage, read> instances: array<f32>;
+@group(0) @binding(6)
+var<storage, read_write> visible: array<f32>;
+@group(0) @binding(7)
+var<storage, read_write> counter: Counter;
+@group(0) @binding(8)
+var depth_pyramid: texture_2d<f32>;
+@group(0) @binding(9)
+var<storage, read_write> commands: array<DrawCommand>;
+
+let INSTANCE_FLOATS: u32 = 25u;
+let MAX_LODS: u32 = 3u;
+
+@compute @workgroup_size(8, 8)
+fn cs_copy_depth(@builtin(global_invocation_id) id: vec3<u32>) {
+    let texel = vec2<i32>(id.xy);
+    let size = textureDimensions(pyramid_out);
+    if (texel.x >= size.x || texel.y >= size.y) {
+        return;
+    }
+    let depth = textureLoad(depth_texture, texel, 0);
+    textureStore(pyramid_out, texel, vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
+
+@compute @workgroup_size(8, 8)
+fn cs_downsample(@builtin(global_invocation_id) id: vec3<u32>) {
+    let texel = vec2<i32>(id.xy);
+    let size = textureDimensions(pyramid_out);
+    if (texel.x >= size.x || texel.y >= size.y) {
+        return;
+    }
+    // Odd sizes fold their last row and column into the texels next to them
+    let source_size = textureDimensions(pyramid_source);
+    var last = texel * 2 + 1;
+    if (texel.x == size.x - 1) {
+        last.x = source_size.x - 1;
+    }
+    if (texel.y == size.y - 1) {
+        last.y = source_size.y - 1;
+    }
+    var depth = 0.0;
+    for (var y = texel.y * 2; y <= last.y; y = y + 1) {
+        for (var x = texel.x * 2; x <= last.x; x = x + 1) {
+            depth = max(depth, textureLoad(pyramid_source, vec2<i32>(x, y), 0).r);
+        }
+    }
+    textureStore(pyramid_out, texel, vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
+
+// True when the box was completely behind the depth of the previous frame
+fn occluded(center: vec3<f32>, extents: vec3<f32>) -> bool {
+    var uv_min = vec2<f32>(1.0);
+    var uv_max = vec2<f32>(0.0);
+    var nearest = 1.0;
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let corner = center + extents * vec3<f32>(
+            select(-1.0, 1.0, (i & 1u) != 0u),
+            select(-1.0, 1.0, (i & 2u) != 0u),
+            select(-1.0, 1.0, (i & 4u) != 0u),
+        );
+        let clip = cull.previous_view_proj * vec4<f32>(corner, 1.0);
+        if (clip.w <= 0.0) {
+            // Reaches behind the camera, the projection can't be trusted
+            return false;
+        }
+        let ndc = clip.xyz / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        uv_min = min(uv_min, uv);
+        uv_max = max(uv_max, uv);
+        nearest = min(nearest, ndc.z);
+    }
+    uv_min = clamp(uv_min, vec2<f32>(0.0), vec2<f32>(1.0));
+    uv_max = clamp(uv_max, vec2<f32>(0.0), vec2<f32>(1.0));
+    if (uv_min.x >= uv_max.x || uv_min.y >= uv_max.y) {
+        // Off screen last frame, there's no depth to test against
+        return false;
+    }
+
+    // Pick the level where the box covers at most 2x2 texels
+    let size = (uv_max - uv_min) * cull.pyramid_size;
+    let level = i32(clamp(ceil(log2(max(max(size.x, size.y), 1.0))), 0.0, f32(cull.pyramid_levels - 1u)));
+    let level_size = textureDimensions(depth_pyramid, level);
+    let min_texel = clamp(vec2<i32>(uv_min * vec2<f32>(level_size)), vec2<i32>(0), level_size - 1);
+    let max_texel = clamp(vec2<i32>(uv_max * vec2<f32>(level_size)), vec2<i32>(0), level_size - 1);
+    let farthest = max(
+        max(
+            textureLoad(depth_pyramid, min_texel, level).r,
+            textureLoad(depth_pyramid, vec2<i32>(max_texel.x, min_texel.y), level).r,
+        ),
+        max(
+            textureLoad(depth_pyramid, vec2<i32>(min_texel.x, max_texel.y), level).r,
+            textureLoad(depth_pyramid, max_texel, level).r,
+        ),
+    );
+    return nearest > farthest;
+}
+
+@compute @workgroup_size(64)
+fn cs_cull(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if (index >= cull.instance_count) {
+        return;
+    }
+    let base = index * INSTANCE_FLOATS;
+    let model = mat4x4<f32>(
+        vec4<f32>(instances[base], instances[base + 1u], instances[base + 2u], instances[base + 3u]),
+        vec4<f32>(instances[base + 4u], instances[base + 5u], instances[base + 6u], instances[base + 7u]),
+        vec4<f32>(instances[base + 8u], instances[base + 9u], instances[base + 10u], instances[base + 11u]),
+        vec4<f32>(instances[base + 12u], instances[base + 13u], instances[base + 14u], instances[base + 15u]),
+    );
+
+    // World space box around the transformed model bounds
+    let local_center = (cull.bounds_min + cull.bounds_max) * 0.5;
+    let local_extents = (cull.bounds_max - cull.bounds_min) * 0.5;
+    let center = (model * vec4<f32>(local_center, 1.0)).xyz;
+    let extents = abs(model[0].xyz) * local_extents.x
+        + abs(model[1].xyz) * local_extents.y
+        + abs(model[2].xyz) * local_extents.z;
+
+    for (var i = 0; i < 6; i = i + 1) {
+        let plane = cull.planes[i];
+        // The corner furthest along the normal still being outside means all of it is
+        if (dot(plane.xyz, center) + dot(abs(plane.xyz), extents) + plane.w < 0.0) {
+            return;
+        }
+    }
+    if (cull.occlusion == 1u && occluded(center, extents)) {
+        return;
+    }
+
+    let lod = min(lods[index], MAX_LODS - 1u);
+    let slot = (lod * cull.capacity + atomicAdd(&counter.counts[lod], 1u)) * INSTANCE_FLOATS;
+    for (var i = 0u; i < INSTANCE_FLOATS; i = i + 1u) {
+        visible[slot + i] = instances[base + i];
+    }
+}
+
+// Runs after the culling, every mesh draws all of the visible instances at each level. The
+// commands are ordered by level, then by mesh.
+@compute @workgroup_size(64)
+fn cs_commands(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= cull.mesh_count * MAX_LODS) {
+        return;
+    }
+    commands[id.x].instance_count = atomicLoad(&counter.counts[id.x / cull.mesh_count]);
+}
@@ -0,0 +1,240 @@
+// Load time compute passes that turn an equirectangular hdr image into everything the
+// image based lighting needs. Every entry point uses its own bindings, the pipelines
+// derive their layouts from them.
+
+let PI: f32 = 3.14159265359;
+
+// Converting the equirectangular image into the environment cube
+@group(0) @binding(0)
+var equirect_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var environment_out: texture_storage_2d_array<rgba16float, write>;
+
+// Downsampling the environment cube into its mips
+@group(0) @binding(2)
+var mip_source: texture_2d_array<f32>;
+@group(0) @binding(3)
+var mip_out: texture_storage_2d_array<rgba16float, write>;
+
+// Convolutions of the environment cube
+@group(0) @binding(4)
+var environment_texture: texture_cube<f32>;
+@group(0) @binding(5)
+var environment_sampler: sampler;
+@group(0) @binding(6)
+var irradiance_out: texture_storage_2d_array<rgba16float, write>;
+@group(0) @binding(7)
+var prefiltered_out: texture_storage_2d_array<rgba16float, write>;
+
+struct Prefilter {
+    roughness: f32,
+    environment_size: f32,
+    _padding0: u32,
+    _padding1: u32,
+};
+@group(0) @binding(8)
+var<uniform> prefilter: Prefilter;
+
+// The split sum BRDF lookup table
+@group(0) @binding(9)
+var brdf_out: texture_storage_2d<rgba16float, write>;
+
+// Direction through the center of a texel of a cube face, in the usual face order
+fn cube_direction(face: u32, texel: vec2<u32>, size: u32) -> vec3<f32> {
+    let uv = (vec2<f32>(texel) + 0.5) / f32(size) * 2.0 - 1.0;
+    var dir: vec3<f32>;
+    switch (face) {
+        case 0u: { dir = vec3<f32>(1.0, -uv.y, -uv.x); }
+        case 1u: { dir = vec3<f32>(-1.0, -uv.y, uv.x); }
+        case 2u: { dir = vec3<f32>(uv.x, 1.0, uv.y); }
+        case 3u: { dir = vec3<f32>(uv.x, -1.0, -uv.y); }
+        case 4u: { dir = vec3<f32>(uv.x, -uv.y, 1.0); }
+        default: { dir = vec3<f32>(-uv.x, -uv.y, -1.0); }
+    }
+    return normalize(dir);
+}
+
+// Float textures can't be filtered everywhere, so the bilinear filtering is done by hand
+fn sample_equirect(dir: vec3<f32>) -> vec3<f32> {
+    let size = vec2<f32>(textureDimensions(equirect_texture));
+    let uv = vec2<f32>(atan2(dir.z, dir.x) / (2.0 * PI) + 0.5, acos(clamp(dir.y, -1.0, 1.0)) / PI);
+    let position = uv * size - 0.5;
+    let base = floor(position);
+    let f = position - base;
+    let max_texel = vec2<i32>(size) - 1;
+
+    var corners: array<vec3<f32>, 4>;
+    for (var i = 0; i < 4; i = i + 1) {
+        var texel = vec2<i32>(base) + vec2<i32>(i % 2, i / 2);
+        // Wraps around horizontally, clamps at the poles
+        texel.x = (texel.x + max_texel.x + 1) % (max_texel.x + 1);
+        texel.y = clamp(texel.y, 0, max_texel.y);
+        corners[i] = textureLoad(equirect_texture, texel, 0).rgb;
+    }
+    return mix(mix(corners[0], corners[1], f.x), mix(corners[2], corners[3], f.x), f.y);
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn equirect_to_cube(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = u32(textureDimensions(environment_out).x);
+    if (id.x >= size || id.y >= size) {
+        return;
+    }
+    let color = sample_equirect(cube_direction(id.z, id.xy, size));
+    textureStore(environment_out, vec2<i32>(id.xy), i32(id.z), vec4<f32>(color, 1.0));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn downsample_cube(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = u32(textureDimensions(mip_out).x);
+    if (id.x >= size || id.y >= size) {
+        return;
+    }
+    let source = vec2<i32>(id.xy) * 2;
+    let face = i32(id.z);
+    let color = textureLoad(mip_source, source, face, 0)
+        + textureLoad(mip_source, source + vec2<i32>(1, 0), face, 0)
+        + textureLoad(mip_source, source + vec2<i32>(0, 1), face, 0)
+        + textureLoad(mip_source, source + vec2<i32>(1, 1), face, 0);
+    textureStore(mip_out, vec2<i32>(id.xy), face, color * 0.25);
+}
+
+// Cosine weighted integral of the incoming light over the hemisphere around every normal
+@compute @workgroup_size(8, 8, 1)
+fn irradiance(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = u32(textureDimensions(irradiance_out).x);
+    if (id.x >= size || id.y >= size) {
+        return;
+    }
+    let normal = cube_direction(id.z, id.xy, size);
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(normal.y) > 0.999) {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let right = normalize(cross(up, normal));
+    up = cross(normal, right);
+
+    let sample_delta = 0.05;
+    var sum = vec3<f32>(0.0);
+    var samples = 0.0;
+    for (var phi = 0.0; phi < 2.0 * PI; phi = phi + sample_delta) {
+        for (var theta = 0.0; theta < 0.5 * PI; theta = theta + sample_delta) {
+            let tangent = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let dir = tangent.x * right + tangent.y * up + tangent.z * normal;
+            // A blurrier mip keeps small bright spots like the sun from aliasing
+            sum = sum + textureSampleLevel(environment_texture, environment_sampler, dir, 3.0).rgb * cos(theta) * sin(theta);
+            samples = samples + 1.0;
+        }
+    }
+    textureStore(irradiance_out, vec2<i32>(id.xy), i32(id.z), vec4<f32>(PI * sum / samples, 1.0));
+}
+
+fn radical_inverse(index: u32) -> f32 {
+    var bits = index;
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return f32(bits) * 2.3283064365386963e-10;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), radical_inverse(i));
+}
+
+fn importance_sample_ggx(xi: vec2<f32>, normal: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    var up = vec3<f32>(0.0, 0.0, 1.0);
+    if (abs(normal.z) > 0.999) {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return normalize(tangent * h.x + bitangent * h.y + normal * h.z);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+// Convolves the environment with the GGX lobe of the roughness this mip stands for.
+// The normal, view and reflection directions are assumed to be the same.
+@compute @workgroup_size(8, 8, 1)
+fn prefilter_specular(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = u32(textureDimensions(prefiltered_out).x);
+    if (id.x >= size || id.y >= size) {
+        return;
+    }
+    let normal = cube_direction(id.z, id.xy, size);
+    let sample_count = 512u;
+    // Solid angle of a texel of the base environment
+    let texel_solid_angle = 4.0 * PI / (6.0 * prefilter.environment_size * prefilter.environment_size);
+
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < sample_count; i = i + 1u) {
+        let h = importance_sample_ggx(hammersley(i, sample_count), normal, prefilter.roughness);
+        let l = normalize(2.0 * dot(normal, h) * h - normal);
+        let n_dot_l = dot(normal, l);
+        if (n_dot_l > 0.0) {
+            // Pick the mip that matches the area the sample covers, so it doesn't sparkle
+            let n_dot_h = max(dot(normal, h), 0.0);
+            let pdf = distribution_ggx(n_dot_h, prefilter.roughness) * 0.25 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(sample_count) * pdf + 0.0001);
+            var lod = 0.0;
+            if (prefilter.roughness > 0.0) {
+                lod = 0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0;
+            }
+            color = color + textureSampleLevel(environment_texture, environment_sampler, l, lod).rgb * n_dot_l;
+            weight = weight + n_dot_l;
+        }
+    }
+    textureStore(prefiltered_out, vec2<i32>(id.xy), i32(id.z), vec4<f32>(color / max(weight, 0.0001), 1.0));
+}
+
+fn geometry_schlick_ggx(n_dot_v: f32, roughness: f32) -> f32 {
+    // The k for image based lighting, not the one for analytic lights
+    let k = roughness * roughness / 2.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+// Scale and bias to F0 of the specular response, for every view angle and roughness
+@compute @workgroup_size(8, 8, 1)
+fn brdf_lut(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(brdf_out));
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    let n_dot_v = (f32(id.x) + 0.5) / f32(size.x);
+    let roughness = (f32(id.y) + 0.5) / f32(size.y);
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    let normal = vec3<f32>(0.0, 0.0, 1.0);
+
+    let sample_count = 1024u;
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < sample_count; i = i + 1u) {
+        let h = importance_sample_ggx(hammersley(i, sample_count), normal, roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = max(l.z, 0.0);
+        let n_dot_h = max(h.z, 0.0);
+        let v_dot_h = max(dot(v, h), 0.0);
+        if (n_dot_l > 0.0) {
+            let g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fresnel = pow(1.0 - v_dot_h, 5.0);
+            scale = scale + (1.0 - fresnel) * g_vis;
+            bias = bias + fresnel * g_vis;
+        }
+    }
+    textureStore(brdf_out, vec2<i32>(id.xy), vec4<f32>(scale, bias, 0.0, 1.0) / vec4<f32>(f32(sample_count), f32(sample_count), 1.0, 1.0));
+}
@@ -11,12 +11,17 @@ var<uniform> camera: Camera;
 struct Light {
     proj: mat4x4<f32>,
     position: vec3<f32>,
+    shadow_index: u32,
     color: vec3<f32>,
     intensity: f32,
     radius: f32,
     is_spotlight: i32,
     limitcos_inner: f32,
     limitcos_outer: f32,
+    limitdir: vec3<f32>,
+    normal_offset: f32,
+    shadow_rect: vec4<f32>,
+    slope_bias: f32,
 }
 @group(1) @binding(0)
 var<storage> lights: array<Light>;
@@ -33,11 +38,13 @@ struct VertexOutput {
 @vertex
 fn vs_main(
     model: VertexInput,
+    @builtin(instance_index) light_index: u32,
 ) -> VertexOutput {
     let scale = 0.25;
+    let light = lights[light_index];
     var out: VertexOutput;
-    out.clip_position = camera.view_proj * vec4<f32>(model.position * scale + lights[0].position, 1.0);
-    out.color = lights[0].color;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position * scale + light.position, 1.0);
+    out.color = light.color;
     return out;
 }
 
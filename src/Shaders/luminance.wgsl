@@ -0,0 +1,78 @@
+// Builds a log luminance histogram of the hdr target and turns it into the average
+// scene luminance the tonemapper exposes with.
+
+struct LuminanceParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    time_coeff: f32,    // How far the average moves towards the new value this frame
+    pixel_count: u32,
+};
+
+@group(0) @binding(0)
+var hdr_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var<storage, read_write> histogram: array<atomic<u32>, 256>;
+@group(0) @binding(2)
+var<storage, read_write> average_luminance: f32;
+@group(0) @binding(3)
+var<uniform> params: LuminanceParams;
+
+let EPSILON: f32 = 0.0001;
+
+var<workgroup> shared_bins: array<atomic<u32>, 256>;
+var<workgroup> weighted_bins: array<f32, 256>;
+
+// Bin 0 is reserved for (almost) black pixels so they don't drag the average down
+fn luminance_bin(color: vec3<f32>) -> u32 {
+    let luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    if (luminance < EPSILON) {
+        return 0u;
+    }
+    let log_luminance = clamp((log2(luminance) - params.min_log_luminance) / params.log_luminance_range, 0.0, 1.0);
+    return u32(log_luminance * 254.0 + 1.0);
+}
+
+@compute @workgroup_size(16, 16)
+fn build_histogram(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    atomicStore(&shared_bins[local_index], 0u);
+    workgroupBarrier();
+
+    let dimensions = textureDimensions(hdr_texture);
+    if (global_id.x < u32(dimensions.x) && global_id.y < u32(dimensions.y)) {
+        let color = textureLoad(hdr_texture, vec2<i32>(global_id.xy), 0).rgb;
+        atomicAdd(&shared_bins[luminance_bin(color)], 1u);
+    }
+    workgroupBarrier();
+
+    atomicAdd(&histogram[local_index], atomicLoad(&shared_bins[local_index]));
+}
+
+@compute @workgroup_size(256)
+fn average_histogram(@builtin(local_invocation_index) local_index: u32) {
+    let count = atomicLoad(&histogram[local_index]);
+    weighted_bins[local_index] = f32(count) * f32(local_index);
+    // Clear it right away for the next frame
+    atomicStore(&histogram[local_index], 0u);
+    workgroupBarrier();
+
+    for (var stride = 128u; stride > 0u; stride = stride >> 1u) {
+        if (local_index < stride) {
+            weighted_bins[local_index] = weighted_bins[local_index] + weighted_bins[local_index + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if (local_index == 0u) {
+        // The first thread holds bin 0, the black pixels
+        let lit_pixels = f32(params.pixel_count) - f32(count);
+        if (lit_pixels < 1.0) {
+            return;
+        }
+        let average_bin = weighted_bins[0] / lit_pixels - 1.0;
+        let luminance = exp2(average_bin / 254.0 * params.log_luminance_range + params.min_log_luminance);
+        average_luminance = average_luminance + (luminance - average_luminance) * params.time_coeff;
+    }
+}
@@ -0,0 +1,125 @@
+// Effects that run after tonemapping, every one of them reads the previous result
+// and writes a new one, so they can be put in any order.
+
+struct PostSettings {
+    surface_is_srgb: u32,
+    bloom_filter_radius: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    lut_strength: f32,
+    fxaa_edge_threshold: f32,
+    fxaa_edge_threshold_min: f32,
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+    _padding3: u32,
+};
+
+@group(0) @binding(0)
+var source_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+@group(0) @binding(2)
+var<uniform> post: PostSettings;
+@group(0) @binding(3)
+var lut_texture: texture_3d<f32>;
+
+let FXAA_SPAN_MAX: f32 = 8.0;
+let FXAA_REDUCE_MUL: f32 = 0.125;
+let FXAA_REDUCE_MIN: f32 = 0.0078125;
+
+fn sample_source(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(source_texture, source_sampler, uv, 0.0).rgb;
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3<f32>(0.0031308));
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
+// Srgb surfaces hand us linear colours, everything else is display encoded already
+fn to_display(color: vec3<f32>) -> vec3<f32> {
+    if (post.surface_is_srgb != 0u) {
+        return linear_to_srgb(clamp(color, vec3<f32>(0.0), vec3<f32>(1.0)));
+    }
+    return color;
+}
+
+fn from_display(color: vec3<f32>) -> vec3<f32> {
+    if (post.surface_is_srgb != 0u) {
+        return srgb_to_linear(color);
+    }
+    return color;
+}
+
+fn luma(color: vec3<f32>) -> f32 {
+    return dot(to_display(color), vec3<f32>(0.299, 0.587, 0.114));
+}
+
+// FXAA, finds the direction of the edge from the corner lumas and blurs along it
+@fragment
+fn fs_fxaa(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source_texture));
+    let color = sample_source(in.uv);
+    let luma_m = luma(color);
+    let luma_nw = luma(sample_source(in.uv + texel * vec2<f32>(-1.0, -1.0)));
+    let luma_ne = luma(sample_source(in.uv + texel * vec2<f32>(1.0, -1.0)));
+    let luma_sw = luma(sample_source(in.uv + texel * vec2<f32>(-1.0, 1.0)));
+    let luma_se = luma(sample_source(in.uv + texel * vec2<f32>(1.0, 1.0)));
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+    if (luma_max - luma_min < max(post.fxaa_edge_threshold_min, luma_max * post.fxaa_edge_threshold)) {
+        return vec4<f32>(color, 1.0);
+    }
+
+    var dir = vec2<f32>(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    let rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2<f32>(-FXAA_SPAN_MAX), vec2<f32>(FXAA_SPAN_MAX)) * texel;
+
+    let rgb_a = 0.5 * (
+        sample_source(in.uv + dir * (1.0 / 3.0 - 0.5)) +
+        sample_source(in.uv + dir * (2.0 / 3.0 - 0.5))
+    );
+    let rgb_b = rgb_a * 0.5 + 0.25 * (
+        sample_source(in.uv + dir * -0.5) +
+        sample_source(in.uv + dir * 0.5)
+    );
+    let luma_b = luma(rgb_b);
+    if (luma_b < luma_min || luma_b > luma_max) {
+        return vec4<f32>(rgb_a, 1.0);
+    }
+    return vec4<f32>(rgb_b, 1.0);
+}
+
+@fragment
+fn fs_vignette(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let color = sample_source(in.uv);
+    // 0 in the center of the screen, 1 in the corners
+    let distance = length(in.uv - 0.5) * sqrt(2.0);
+    let falloff = 1.0 - smoothstep(post.vignette_radius - post.vignette_smoothness, post.vignette_radius, distance);
+    return vec4<f32>(color * mix(1.0, falloff, post.vignette_intensity), 1.0);
+}
+
+@fragment
+fn fs_color_grading(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let color = clamp(to_display(sample_source(in.uv)), vec3<f32>(0.0), vec3<f32>(1.0));
+    // Sample in between the outer texel centers, so 0 and 1 hit the first and last entry
+    let lut_size = f32(textureDimensions(lut_texture).x);
+    let scale = (lut_size - 1.0) / lut_size;
+    let offset = 0.5 / lut_size;
+    let graded = textureSampleLevel(lut_texture, source_sampler, color * scale + offset, 0.0).rgb;
+    return vec4<f32>(from_display(mix(color, graded, post.lut_strength)), 1.0);
+}
@@ -1,8 +1,18 @@
 // Vertex shader
 
+struct Fog {
+    color: vec3<f32>,
+    density: f32,
+    start: f32,
+    height: f32,
+    height_falloff: f32,
+    flags: u32, // 1 distance, 2 height, 4 colour from the skybox
+}
+
 struct Camera {
     view_pos: vec4<f32>,
     view_proj: mat4x4<f32>,
+    fog: Fog,
 }
 @group(1) @binding(0)
 var<uniform> camera: Camera;
@@ -10,6 +20,7 @@ var<uniform> camera: Camera;
 struct Light {
     proj: mat4x4<f32>,
     position: vec3<f32>,
+    shadow_index: u32,
     color: vec3<f32>,
     intensity: f32,
     radius: f32,
@@ -17,6 +28,9 @@ struct Light {
     limitcos_inner: f32,
     limitcos_outer: f32,
     limitdir: vec3<f32>,
+    normal_offset: f32,
+    shadow_rect: vec4<f32>,
+    slope_bias: f32,
 }
 @group(2) @binding(0)
 var<storage> lights: array<Light>;
@@ -28,6 +42,8 @@ struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
     @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
 }
 
 struct InstanceInput {
@@ -46,6 +62,8 @@ struct VertexOutput {
     @location(1) world_normal: vec3<f32>,
     @location(2) world_position: vec3<f32>,
     @location(3) full_world_pos: vec4<f32>,
+    @location(4) world_tangent: vec3<f32>,
+    @location(5) world_bitangent: vec3<f32>,
 }
 
 @vertex
@@ -67,6 +85,14 @@ fn vs_main(
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
     out.world_normal = normal_matrix * model.normal;
+    // Tangents follow the surface, so they go through the model matrix itself
+    let tangent_matrix = mat3x3<f32>(
+        instance.model_matrix_0.xyz,
+        instance.model_matrix_1.xyz,
+        instance.model_matrix_2.xyz,
+    );
+    out.world_tangent = tangent_matrix * model.tangent;
+    out.world_bitangent = tangent_matrix * model.bitangent;
     var world_position: vec4<f32> = model_matrix * vec4<f32>(model.position, 1.0);
     out.world_position = world_position.xyz;
     out.full_world_pos = world_position;
@@ -89,6 +115,9 @@ var depth_texture: texture_2d<f32>;
 
 struct MaterialUniform {
     @location(0) use_texture: i32,
+    dissolve: f32,
+    alpha_mode: u32, // 0 opaque, 1 alpha tested, 2 blended
+    alpha_cutoff: f32,
     @location(1) u_ambient: vec3<f32>,
     @location(2) u_diffuse: vec3<f32>,
     @location(3) u_specular: vec3<f32>,
@@ -103,41 +132,391 @@ var t_shadow: texture_depth_cube_array;
 @group(3)
 @binding(1)
 var sampler_shadow: sampler_comparison;
+@group(3)
+@binding(2)
+var t_spot_shadow: texture_depth_2d;
+
+struct ShadowSettings {
+    filter_mode: u32, // 0 hardware 2x2, 1 poisson pcf, 2 pcss
+    pcf_samples: u32,
+    pcf_radius: f32,
+    blocker_samples: u32,
+    light_size: f32,
+    cube_resolution: f32,
+    atlas_resolution: f32,
+    _padding: u32,
+}
+@group(3)
+@binding(3)
+var<uniform> shadow_settings: ShadowSettings;
+@group(3)
+@binding(4)
+var sampler_shadow_depth: sampler;
+
+// Same as SHADOW_NEAR and SHADOW_FAR in light.rs
+let SHADOW_NEAR: f32 = 0.1;
+let SHADOW_FAR: f32 = 100.0;
 
 @group(4) @binding(0)
 var<uniform> render_target: i32;
-
+// Ambient occlusion at full resolution, read per pixel
 @group(4) @binding(1)
-var t_depth: texture_depth_2d;
+var t_ambient_occlusion: texture_2d<f32>;
 @group(4) @binding(2)
-var s_depth: sampler_comparison;
+var t_raw_ambient_occlusion: texture_2d<f32>;
+
+struct Sun {
+    direction: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    cascade_count: u32,
+    camera_forward: vec3<f32>,
+    blend_width: f32,
+    splits: vec4<f32>,
+    texel_size: f32,
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+    view_proj: array<mat4x4<f32>, 4>,
+}
+
+@group(5) @binding(0)
+var<uniform> sun: Sun;
+@group(5) @binding(1)
+var t_cascades: texture_depth_2d_array;
+@group(5) @binding(2)
+var s_cascades: sampler_comparison;
+
+struct Ibl {
+    intensity: f32,
+    prefiltered_mips: f32,
+    sky_intensity: f32,
+    _padding0: u32,
+}
+
+@group(6) @binding(0)
+var irradiance_map: texture_cube<f32>;
+@group(6) @binding(1)
+var prefiltered_map: texture_cube<f32>;
+@group(6) @binding(2)
+var brdf_lut: texture_2d<f32>;
+@group(6) @binding(3)
+var ibl_sampler: sampler;
+@group(6) @binding(4)
+var<uniform> ibl: Ibl;
+
+// Roughly what the specular exponent of 32 the lights use comes down to
+let MATERIAL_ROUGHNESS: f32 = 0.25;
+let DIELECTRIC_F0: f32 = 0.04;
+
+// Light coming in from the environment, diffuse from the irradiance map and specular
+// from the prefiltered map with the split sum approximation
+fn ambient_light(normal: vec3<f32>, view_dir: vec3<f32>, albedo: vec3<f32>) -> vec3<f32> {
+    let n_dot_v = max(dot(normal, view_dir), 0.0);
+    // Schlick with roughness, so rough surfaces don't get a bright rim
+    let fresnel = DIELECTRIC_F0 + (max(1.0 - MATERIAL_ROUGHNESS, DIELECTRIC_F0) - DIELECTRIC_F0) * pow(1.0 - n_dot_v, 5.0);
+
+    let irradiance = textureSampleLevel(irradiance_map, ibl_sampler, normal, 0.0).rgb;
+    let diffuse = irradiance * albedo * (1.0 - fresnel);
+
+    let reflected = reflect(-view_dir, normal);
+    let prefiltered = textureSampleLevel(prefiltered_map, ibl_sampler, reflected, MATERIAL_ROUGHNESS * (ibl.prefiltered_mips - 1.0)).rgb;
+    let brdf = textureSampleLevel(brdf_lut, ibl_sampler, vec2<f32>(n_dot_v, MATERIAL_ROUGHNESS), 0.0).rg;
+    let specular = prefiltered * (DIELECTRIC_F0 * brdf.x + brdf.y);
+
+    return (diffuse + specular) * ibl.intensity;
+}
 
-fn fetch_shadow(light_id: u32, homogeneous_coords: vec4<f32>) -> f32 {
-    if (homogeneous_coords.w <= 0.0) {
+// Exponential fog along the view ray, the height fog thins out exponentially above fog.height
+fn apply_fog(color: vec3<f32>, world_position: vec3<f32>) -> vec3<f32> {
+    let fog = camera.fog;
+    let to_surface = world_position - camera.view_pos.xyz;
+    let distance = length(to_surface);
+    let ray_dir = to_surface / max(distance, 0.0001);
+    let fog_distance = max(distance - fog.start, 0.0);
+
+    var optical_depth = 0.0;
+    if ((fog.flags & 1u) != 0u) {
+        optical_depth = optical_depth + fog.density * fog_distance;
+    }
+    if ((fog.flags & 2u) != 0u) {
+        // Density integrated along the ray, starting where the fog starts
+        let start_height = camera.view_pos.y + ray_dir.y * fog.start - fog.height;
+        let falloff = fog.height_falloff * ray_dir.y * fog_distance;
+        var along_ray = fog_distance;
+        if (abs(falloff) > 0.0001) {
+            along_ray = fog_distance * (1.0 - exp(-falloff)) / falloff;
+        }
+        optical_depth = optical_depth + fog.density * exp(-fog.height_falloff * start_height) * along_ray;
+    }
+
+    var fog_color = fog.color;
+    if ((fog.flags & 4u) != 0u) {
+        // The blurriest prefiltered mip, so the fog doesn't show every detail of the sky
+        fog_color = textureSampleLevel(prefiltered_map, ibl_sampler, ray_dir, ibl.prefiltered_mips - 1.0).rgb * ibl.sky_intensity;
+    }
+    return mix(fog_color, color, exp(-optical_depth));
+}
+
+// 3x3 PCF over a single cascade, outside of the cascade counts as lit
+fn fetch_cascade_shadow(cascade: u32, world_pos: vec4<f32>) -> f32 {
+    let light_coords = sun.view_proj[cascade] * world_pos;
+    let ndc = light_coords.xyz / light_coords.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5, 0.5);
+    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0) {
         return 1.0;
     }
-    
-    let flip_correction = vec3<f32>(0.5, -0.5, -0.5);
 
-    let proj_correction = 1.0 / homogeneous_coords.w;
+    var visibility = 0.0;
+    for (var x: i32 = -1; x <= 1; x = x + 1) {
+        for (var y: i32 = -1; y <= 1; y = y + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * sun.texel_size;
+            visibility = visibility + textureSampleCompareLevel(t_cascades, s_cascades, uv + offset, i32(cascade), ndc.z);
+        }
+    }
+    return visibility / 9.0;
+}
+
+fn cascade_index(view_depth: f32) -> u32 {
+    var cascade = sun.cascade_count - 1u;
+    for (var i: u32 = 0u; i < sun.cascade_count; i = i + 1u) {
+        if (view_depth < sun.splits[i]) {
+            cascade = i;
+            break;
+        }
+    }
+    return cascade;
+}
+
+// Shadow of the sun, fading into the next cascade near the end of the current one
+fn fetch_sun_shadow(world_pos: vec4<f32>) -> f32 {
+    let view_depth = dot(world_pos.xyz - camera.view_pos.xyz, sun.camera_forward);
+    let cascade = cascade_index(view_depth);
+    var shadow = fetch_cascade_shadow(cascade, world_pos);
+
+    if (cascade + 1u < sun.cascade_count) {
+        var cascade_start = 0.0;
+        if (cascade > 0u) {
+            cascade_start = sun.splits[cascade - 1u];
+        }
+        let cascade_end = sun.splits[cascade];
+        let blend_start = cascade_end - (cascade_end - cascade_start) * sun.blend_width;
+        if (view_depth > blend_start) {
+            let next_shadow = fetch_cascade_shadow(cascade + 1u, world_pos);
+            shadow = mix(shadow, next_shadow, smoothstep(blend_start, cascade_end, view_depth));
+        }
+    }
+    return shadow;
+}
+
+fn poisson_disk(i: u32) -> vec2<f32> {
+    var disk = array<vec2<f32>, 16>(
+        vec2<f32>(-0.94201624, -0.39906216),
+        vec2<f32>(0.94558609, -0.76890725),
+        vec2<f32>(-0.09418410, -0.92938870),
+        vec2<f32>(0.34495938, 0.29387760),
+        vec2<f32>(-0.91588581, 0.45771432),
+        vec2<f32>(-0.81544232, -0.87912464),
+        vec2<f32>(-0.38277543, 0.27676845),
+        vec2<f32>(0.97484398, 0.75648379),
+        vec2<f32>(0.44323325, -0.97511554),
+        vec2<f32>(0.53742981, -0.47373420),
+        vec2<f32>(-0.26496911, -0.41893023),
+        vec2<f32>(0.79197514, 0.19090188),
+        vec2<f32>(-0.24188840, 0.99706507),
+        vec2<f32>(-0.81409955, 0.91437590),
+        vec2<f32>(0.19984126, 0.78641367),
+        vec2<f32>(0.14383161, -0.14100790),
+    );
+    return disk[i];
+}
+
+// Both the cube faces and the spot lights use the same perspective depth range
+fn depth_to_distance(depth: f32) -> f32 {
+    return SHADOW_FAR * SHADOW_NEAR / (SHADOW_FAR - depth * (SHADOW_FAR - SHADOW_NEAR));
+}
+
+fn distance_to_depth(distance: f32) -> f32 {
+    return SHADOW_FAR / (SHADOW_FAR - SHADOW_NEAR)
+        - SHADOW_FAR * SHADOW_NEAR / ((SHADOW_FAR - SHADOW_NEAR) * distance);
+}
+
+// Where the shadow map lookup happens for a light. For spot lights xy is the uv inside
+// the light's tile, for point lights xyz is the direction from the light.
+struct ShadowCoords {
+    coords: vec3<f32>,
+    distance: f32,    // Distance along the light's view axis
+    texel_world: f32, // Size of a single shadow map texel at that distance
+    in_map: bool,
+}
+
+fn spot_tan_half_fov(light: Light) -> f32 {
+    // Mirrors the clamping in Light::spot_view_projection
+    let cone_cos = clamp(min(light.limitcos_inner, light.limitcos_outer), 0.0871557, 0.9999619);
+    return sqrt(1.0 - cone_cos * cone_cos) / cone_cos;
+}
+
+fn shadow_coords(light: Light, world_pos: vec3<f32>) -> ShadowCoords {
+    var out: ShadowCoords;
+    if (light.is_spotlight == 1) {
+        let homogeneous_coords = light.proj * vec4<f32>(world_pos, 1.0);
+        let tile_resolution = shadow_settings.atlas_resolution * light.shadow_rect.z;
+        out.distance = homogeneous_coords.w;
+        out.texel_world = 2.0 * out.distance * spot_tan_half_fov(light) / tile_resolution;
+        if (homogeneous_coords.w <= 0.0) {
+            out.in_map = false;
+            return out;
+        }
+        let ndc = homogeneous_coords.xyz / homogeneous_coords.w;
+        let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5, 0.5);
+        out.coords = vec3<f32>(uv, 0.0);
+        out.in_map = all(uv >= vec2<f32>(0.0)) && all(uv <= vec2<f32>(1.0)) && ndc.z <= 1.0;
+        return out;
+    }
+
+    // The cube face is picked by the major axis, which is also the depth it was rendered at
+    let light_to_frag = world_pos - light.position;
+    let abs_dir = abs(light_to_frag);
+    out.coords = light_to_frag;
+    out.distance = max(abs_dir.x, max(abs_dir.y, abs_dir.z));
+    out.texel_world = 2.0 * out.distance / shadow_settings.cube_resolution;
+    out.in_map = true;
+    return out;
+}
+
+fn spot_atlas_uv(light: Light, uv: vec2<f32>, offset: vec2<f32>) -> vec2<f32> {
+    // Kept half a texel inside the tile, so filtering never reads the neighbouring light
+    let tile_resolution = shadow_settings.atlas_resolution * light.shadow_rect.z;
+    let half_texel = 0.5 / tile_resolution;
+    let tile_uv = clamp(uv + offset / tile_resolution, vec2<f32>(half_texel), vec2<f32>(1.0 - half_texel));
+    return light.shadow_rect.xy + tile_uv * light.shadow_rect.zw;
+}
 
-    return textureSampleCompareLevel(t_shadow, sampler_shadow, homogeneous_coords.xyz, i32(light_id), homogeneous_coords.z * proj_correction);
+fn cube_offset_dir(dir: vec3<f32>, offset: vec2<f32>, texel_world: f32) -> vec3<f32> {
+    let n = normalize(dir);
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(n.y) > 0.99) {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let t = normalize(cross(up, n));
+    let b = cross(n, t);
+    return dir + (t * offset.x + b * offset.y) * texel_world;
+}
+
+// A single comparison tap, offset in texels
+fn shadow_compare(light: Light, sc: ShadowCoords, offset: vec2<f32>, depth: f32) -> f32 {
+    if (light.is_spotlight == 1) {
+        return textureSampleCompareLevel(t_spot_shadow, sampler_shadow, spot_atlas_uv(light, sc.coords.xy, offset), depth);
+    }
+    return textureSampleCompareLevel(t_shadow, sampler_shadow, cube_offset_dir(sc.coords, offset, sc.texel_world), i32(light.shadow_index), depth);
+}
+
+// The raw stored depths of the 2x2 texels around a tap, for the blocker search
+fn shadow_depths(light: Light, sc: ShadowCoords, offset: vec2<f32>) -> vec4<f32> {
+    if (light.is_spotlight == 1) {
+        return textureGather(t_spot_shadow, sampler_shadow_depth, spot_atlas_uv(light, sc.coords.xy, offset));
+    }
+    return textureGather(t_shadow, sampler_shadow_depth, cube_offset_dir(sc.coords, offset, sc.texel_world), i32(light.shadow_index));
+}
+
+fn poisson_pcf(light: Light, sc: ShadowCoords, radius: f32, depth: f32) -> f32 {
+    var visibility = 0.0;
+    let samples = clamp(shadow_settings.pcf_samples, 1u, 16u);
+    for (var i: u32 = 0u; i < samples; i = i + 1u) {
+        visibility = visibility + shadow_compare(light, sc, poisson_disk(i) * radius, depth);
+    }
+    return visibility / f32(samples);
+}
+
+fn pcss(light: Light, sc: ShadowCoords, depth: f32) -> f32 {
+    // The closer the receiver is to the light, the less of the map the light covers
+    let search_radius = shadow_settings.light_size * (sc.distance - SHADOW_NEAR) / sc.distance;
+    var blocker_sum = 0.0;
+    var blocker_count = 0.0;
+    let samples = clamp(shadow_settings.blocker_samples, 1u, 16u);
+    for (var i: u32 = 0u; i < samples; i = i + 1u) {
+        let blocker_depths = shadow_depths(light, sc, poisson_disk(i) * search_radius);
+        for (var j: i32 = 0; j < 4; j = j + 1) {
+            if (blocker_depths[j] < depth) {
+                blocker_sum = blocker_sum + depth_to_distance(blocker_depths[j]);
+                blocker_count = blocker_count + 1.0;
+            }
+        }
+    }
+    if (blocker_count == 0.0) {
+        return 1.0;
+    }
+
+    let blocker_distance = blocker_sum / blocker_count;
+    let penumbra = (sc.distance - blocker_distance) * shadow_settings.light_size / blocker_distance;
+    return poisson_pcf(light, sc, clamp(penumbra, 1.0, shadow_settings.light_size * 4.0), depth);
+}
+
+fn fetch_shadow(light_id: u32, world_pos: vec4<f32>, normal: vec3<f32>) -> f32 {
+    let light = lights[light_id];
+
+    // Normal offset first, it needs the texel size at the original position
+    let unbiased = shadow_coords(light, world_pos.xyz);
+    let offset_pos = world_pos.xyz + normal * light.normal_offset * unbiased.texel_world;
+    let sc = shadow_coords(light, offset_pos);
+    if (!sc.in_map) {
+        return 1.0;
+    }
+
+    // Surfaces at a grazing angle cover more depth per texel, so they need more bias
+    let n_dot_l = clamp(dot(normal, normalize(light.position - world_pos.xyz)), 0.0, 1.0);
+    let slope = min(sqrt(1.0 - n_dot_l * n_dot_l) / max(n_dot_l, 0.05), 10.0);
+    let depth = distance_to_depth(sc.distance - light.slope_bias * slope * sc.texel_world);
+
+    if (shadow_settings.filter_mode == 1u) {
+        return poisson_pcf(light, sc, shadow_settings.pcf_radius, depth);
+    } else if (shadow_settings.filter_mode == 2u) {
+        return pcss(light, sc, depth);
+    }
+    return shadow_compare(light, sc, vec2<f32>(0.0), depth);
 }
 
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     var object_color: vec4<f32> = vec4(0.0,0.0,0.0,0.0);
-    var object_normal: vec4<f32> = vec4(0.0,0.0,0.0,0.0);
+    // Materials without a normal map get a flat one, so this is always valid
+    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
     if (materialUniform.use_texture == 1) {
         object_color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-        object_normal = textureSample(t_normal, s_normal, in.tex_coords);
     } else {
         object_color = vec4(materialUniform.u_diffuse, 1.0);
-        object_normal = vec4(0.0,0.0,0.0,0.0);
     }
+    object_color.a = object_color.a * materialUniform.dissolve;
+    if (materialUniform.alpha_mode == 0u) {
+        object_color.a = 1.0;
+    } else if (materialUniform.alpha_mode == 1u) {
+        if (object_color.a < materialUniform.alpha_cutoff) {
+            discard;
+        }
+        object_color.a = 1.0;
+    }
+
+    // Tangent space -> world space, re-orthogonalized since interpolation skews it
+    let vertex_normal = normalize(in.world_normal);
+    var world_tangent = in.world_tangent - vertex_normal * dot(vertex_normal, in.world_tangent);
+    if (dot(world_tangent, world_tangent) < 0.000001) {
+        world_tangent = in.world_bitangent;
+    }
+    world_tangent = normalize(world_tangent);
+    var world_bitangent = cross(vertex_normal, world_tangent);
+    if (dot(world_bitangent, in.world_bitangent) < 0.0) {
+        world_bitangent = -world_bitangent;
+    }
+    let tbn = mat3x3<f32>(world_tangent, world_bitangent, vertex_normal);
+    // Two channel (BC5) normal maps leave z out, so it's always rebuilt from x and y
+    let normal_xy = object_normal.xy * 2.0 - 1.0;
+    let tangent_normal = vec3(normal_xy, sqrt(max(1.0 - dot(normal_xy, normal_xy), 0.0)));
+    let normal = normalize(tbn * tangent_normal);
     
-    var result = vec3(0.0,0.0,0.0);
+    let surface_to_eye = normalize(camera.view_pos.xyz - in.world_position);
+    let ambient_occlusion = textureLoad(t_ambient_occlusion, vec2<i32>(in.clip_position.xy), 0).r;
+    var result = ambient_light(normal, surface_to_eye, object_color.xyz) * ambient_occlusion;
     
     //result = object_color.xyz; // Static Colors no Shadow
         
@@ -162,12 +541,8 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
 
         l_radius = max(l_radius, 0.00001);
         
-        var shadow = fetch_shadow(u32(i), lights[i].proj * in.full_world_pos);
+        var shadow = fetch_shadow(u32(i), in.full_world_pos, normal);
 
-        var ambient_color = l_color * l_radius / max(l_radius, distance(l_position, in.world_position));
-        ambient_color = ambient_color * in_light;
-        
-        var normal = normalize(in.world_normal);
         var light_dir = normalize(l_position - in.world_position);
 
         var diffuse_strength = max(dot(normal, light_dir), 0.0);
@@ -179,7 +554,9 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         var specular_strength = pow(max(dot(normal, half_dir), 0.0), 32.0);
         var specular_color = specular_strength * in_light * l_color;
         
-        var lig = l_intensity * (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+        // Full strength up to the radius, falling off with distance after it
+        var falloff = l_radius / max(l_radius, distance(l_position, in.world_position));
+        var lig = l_intensity * falloff * (diffuse_color + specular_color) * object_color.xyz;
         if (render_target == 3) {
             result = result + lig;
         } else {
@@ -187,12 +564,40 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         }
         
     }
+
+    // The sun, no falloff and no ambient term of its own
+    let sun_dir = -sun.direction;
+    let sun_diffuse = max(dot(normal, sun_dir), 0.0);
+    let sun_half_dir = normalize(normalize(camera.view_pos.xyz - in.world_position) + sun_dir);
+    let sun_specular = pow(max(dot(normal, sun_half_dir), 0.0), 32.0);
+    var sun_light = sun.intensity * sun.color * (sun_diffuse + sun_specular) * object_color.xyz;
+    if (render_target != 3) {
+        sun_light = sun_light * fetch_sun_shadow(in.full_world_pos);
+    }
+    result = result + sun_light;
+
+    result = apply_fog(result, in.world_position);
+
     var final_result = vec4<f32>(result, object_color.a);
     
     if (render_target == 1) {
-        final_result = vec4(textureSampleCompare(t_depth, s_depth, in.tex_coords, 0.0));
+        // The depth buffer is still being written to, but the fragment's own depth is what
+        // ends up in it for everything that's visible
+        final_result = vec4(vec3(in.clip_position.z), 1.0);
     } else if (render_target == 2) {
-        final_result = vec4(fetch_shadow(u32(0), lights[0].proj * in.full_world_pos));
+        final_result = vec4(fetch_shadow(0u, in.full_world_pos, normal));
+    } else if (render_target == 5) {
+        final_result = vec4(vec3(textureLoad(t_raw_ambient_occlusion, vec2<i32>(in.clip_position.xy), 0).r), 1.0);
+    } else if (render_target == 4) {
+        let view_depth = dot(in.world_position - camera.view_pos.xyz, sun.camera_forward);
+        var cascade_colors = array<vec3<f32>, 4>(
+            vec3<f32>(1.0, 0.2, 0.2),
+            vec3<f32>(0.2, 1.0, 0.2),
+            vec3<f32>(0.2, 0.2, 1.0),
+            vec3<f32>(1.0, 1.0, 0.2),
+        );
+        let tint = cascade_colors[cascade_index(view_depth)];
+        final_result = vec4(mix(result, tint, 0.5) * fetch_sun_shadow(in.full_world_pos), object_color.a);
     }
     
     return final_result;
@@ -32,4 +32,22 @@ fn vs_bake(
     );
     let instance_space = model_matrix * vec4(model.position, 1.0);
     return view_proj[view_index] * instance_space;
-}
\ No newline at end of file
+}
+
+@vertex
+fn vs_bake_masked(
+    model: VertexInput,
+    instance: InstanceInput,
+    @builtin(view_index) view_index: i32
+) -> MaskedOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: MaskedOutput;
+    out.clip_position = view_proj[view_index] * model_matrix * vec4(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
@@ -0,0 +1,26 @@
+// Draws the environment behind everything, the fullscreen triangle gets turned back
+// into view directions with the inverse of the camera's rotation and projection.
+
+struct Skybox {
+    inv_view_proj: mat4x4<f32>,
+    intensity: f32,
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> skybox: Skybox;
+@group(0) @binding(1)
+var environment_texture: texture_cube<f32>;
+@group(0) @binding(2)
+var environment_sampler: sampler;
+
+@fragment
+fn fs_main(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let ndc = vec2<f32>(in.uv.x * 2.0 - 1.0, 1.0 - in.uv.y * 2.0);
+    let far = skybox.inv_view_proj * vec4<f32>(ndc, 1.0, 1.0);
+    let dir = normalize(far.xyz / far.w);
+    let color = textureSampleLevel(environment_texture, environment_sampler, dir, 0.0).rgb;
+    return vec4<f32>(color * skybox.intensity, 1.0);
+}
@@ -0,0 +1,120 @@
+// Screen space ambient occlusion from the depth prepass. Normals are reconstructed from
+// the depth buffer, so nothing besides depth has to be rendered up front.
+
+struct Ssao {
+    view_proj: mat4x4<f32>, // Used by the depth prepass
+    proj: mat4x4<f32>,
+    inv_proj: mat4x4<f32>,
+    radius: f32,      // In world units
+    intensity: f32,   // Exponent applied to the result
+    sample_count: u32,
+    bias: f32,
+};
+
+@group(0) @binding(0)
+var depth_texture: texture_depth_2d;
+@group(0) @binding(1)
+var<uniform> ssao: Ssao;
+// Only the blur reads this one
+@group(0) @binding(2)
+var ao_texture: texture_2d<f32>;
+
+let PI: f32 = 3.14159265359;
+
+fn view_position(texel: vec2<i32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(depth_texture));
+    let clamped = clamp(texel, vec2<i32>(0), size - 1);
+    let depth = textureLoad(depth_texture, clamped, 0);
+    let uv = (vec2<f32>(clamped) + 0.5) / vec2<f32>(size);
+    let view = ssao.inv_proj * vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return view.xyz / view.w;
+}
+
+// Takes the neighbour on the side with the smaller depth step, so edges don't bend the normal
+fn view_normal(texel: vec2<i32>, center: vec3<f32>) -> vec3<f32> {
+    let left = view_position(texel - vec2<i32>(1, 0));
+    let right = view_position(texel + vec2<i32>(1, 0));
+    let up = view_position(texel - vec2<i32>(0, 1));
+    let down = view_position(texel + vec2<i32>(0, 1));
+
+    var dx = right - center;
+    if (abs(center.z - left.z) < abs(right.z - center.z)) {
+        dx = center - left;
+    }
+    var dy = down - center;
+    if (abs(center.z - up.z) < abs(down.z - center.z)) {
+        dy = center - up;
+    }
+    // Texel rows go down while view space y goes up
+    return normalize(cross(dy, dx));
+}
+
+@fragment
+fn fs_ssao(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(in.clip_position.xy);
+    if (textureLoad(depth_texture, texel, 0) >= 1.0) {
+        // Nothing there to occlude
+        return vec4<f32>(1.0);
+    }
+    let size = vec2<f32>(textureDimensions(depth_texture));
+    let position = view_position(texel);
+    let normal = view_normal(texel, position);
+
+    // The pattern repeats every 4x4 texels, which is exactly what the blur averages out
+    let tile = vec2<u32>(texel % 4);
+    let rotation = f32(tile.x + tile.y * 4u) / 16.0;
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(normal.y) > 0.999) {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+
+    let count = max(ssao.sample_count, 1u);
+    var occlusion = 0.0;
+    for (var i = 0u; i < count; i = i + 1u) {
+        // Cosine weighted directions on a golden angle spiral, the length favours close samples
+        let height = (f32(i) + 0.5) / f32(count);
+        let phi = 2.0 * PI * fract(f32(i) * 0.618034 + rotation);
+        let r = sqrt(height);
+        let direction = vec3<f32>(r * cos(phi), r * sin(phi), sqrt(1.0 - height));
+        let scale = fract(f32(i) * 0.5698403 + 0.5 * rotation);
+        let offset = (tangent * direction.x + bitangent * direction.y + normal * direction.z) * ssao.radius * mix(0.1, 1.0, scale * scale);
+        let sample_position = position + offset;
+
+        let clip = ssao.proj * vec4<f32>(sample_position, 1.0);
+        let ndc = clip.xy / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        let scene = view_position(vec2<i32>(uv * size));
+
+        // Geometry way in front of the sample doesn't count, that would leave halos
+        let range = smoothstep(0.0, 1.0, ssao.radius / abs(position.z - scene.z));
+        if (scene.z >= sample_position.z + ssao.bias) {
+            occlusion = occlusion + range;
+        }
+    }
+    let ao = 1.0 - occlusion / f32(count);
+    return vec4<f32>(pow(ao, ssao.intensity), 0.0, 0.0, 1.0);
+}
+
+// Averages the 4x4 noise tile, skipping texels that are too far away in depth
+@fragment
+fn fs_blur(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(in.clip_position.xy);
+    let size = vec2<i32>(textureDimensions(ao_texture));
+    let center = view_position(texel).z;
+
+    var sum = 0.0;
+    var weight = 0.0;
+    for (var y = -2; y < 2; y = y + 1) {
+        for (var x = -2; x < 2; x = x + 1) {
+            let sample_texel = clamp(texel + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            let difference = abs(view_position(sample_texel).z - center);
+            let w = max(1.0 - difference / ssao.radius, 0.0);
+            sum = sum + textureLoad(ao_texture, sample_texel, 0).r * w;
+            weight = weight + w;
+        }
+    }
+    // The center texel always counts, so the weight can't be zero
+    return vec4<f32>(sum / weight, 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,115 @@
+// Fullscreen pass that exposes the hdr target and maps it into the displayable range
+
+struct Tonemap {
+    tonemapper: u32, // 0 aces, 1 reinhard, 2 agx
+    auto_exposure: u32,
+    exposure: f32,   // Linear multiplier, only used for manual exposure
+    exposure_compensation: f32,
+    apply_srgb: u32, // Set when the surface isn't an srgb format
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+};
+
+@group(0) @binding(0)
+var hdr_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> tonemap: Tonemap;
+@group(0) @binding(2)
+var<storage, read> average_luminance: f32;
+
+// Stephen Hill's fit of the ACES reference transform
+fn rrt_and_odt_fit(v: vec3<f32>) -> vec3<f32> {
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return a / b;
+}
+
+fn tonemap_aces(color: vec3<f32>) -> vec3<f32> {
+    let aces_input = mat3x3<f32>(
+        vec3<f32>(0.59719, 0.07600, 0.02840),
+        vec3<f32>(0.35458, 0.90834, 0.13383),
+        vec3<f32>(0.04823, 0.01566, 0.83777),
+    );
+    let aces_output = mat3x3<f32>(
+        vec3<f32>(1.60475, -0.10208, -0.00327),
+        vec3<f32>(-0.53108, 1.10813, -0.07276),
+        vec3<f32>(-0.07367, -0.00605, 1.07602),
+    );
+    return clamp(aces_output * rrt_and_odt_fit(aces_input * color), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn tonemap_reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (1.0 + color);
+}
+
+// Polynomial approximation of the AgX base contrast curve
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2
+        - 40.14 * x4 * x
+        + 31.96 * x4
+        - 6.868 * x2 * x
+        + 0.4298 * x2
+        + 0.1191 * x
+        - 0.00232;
+}
+
+fn tonemap_agx(color: vec3<f32>) -> vec3<f32> {
+    let agx_input = mat3x3<f32>(
+        vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+        vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+    );
+    let agx_output = mat3x3<f32>(
+        vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    var value = agx_input * color;
+    value = clamp(log2(max(value, vec3<f32>(1e-10))), vec3<f32>(min_ev), vec3<f32>(max_ev));
+    value = (value - min_ev) / (max_ev - min_ev);
+    value = agx_output * agx_contrast(value);
+    // The curve ends up in display encoding, the surface wants it linear again
+    return pow(max(value, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3<f32>(0.0031308));
+}
+
+@fragment
+fn fs_main(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let hdr = textureLoad(hdr_texture, vec2<i32>(in.clip_position.xy), 0).rgb;
+
+    var exposure = tonemap.exposure;
+    if (tonemap.auto_exposure != 0u) {
+        // Expose so the average luminance ends up as middle grey
+        exposure = 0.18 / max(average_luminance, 0.0001);
+    }
+    let color = hdr * exposure * tonemap.exposure_compensation;
+
+    var mapped: vec3<f32>;
+    switch (tonemap.tonemapper) {
+        case 1u: {
+            mapped = tonemap_reinhard(color);
+        }
+        case 2u: {
+            mapped = tonemap_agx(color);
+        }
+        default: {
+            mapped = tonemap_aces(color);
+        }
+    }
+
+    if (tonemap.apply_srgb != 0u) {
+        mapped = linear_to_srgb(mapped);
+    }
+    return vec4<f32>(mapped, 1.0);
+}
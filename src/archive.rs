@@ -0,0 +1,324 @@
+// Packed resources, so a map's content can ship as one data file. The layout, little endian:
+//
+//   "MPAK", version: u32, entry count: u32
+//   per entry: name length: u16, name, offset: u64, stored size: u64, size: u64,
+//              compression: u8, crc32 of the unpacked content: u32
+//   the stored files, offsets count from the end of the index
+//
+// The content hashes let the files get checked when they're read, and the server compare
+// what its clients have. Nothing in the index is trusted further than the file's length, so
+// a broken or hostile archive gives an error instead of a huge allocation.
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+
+const MAGIC: &[u8; 4] = b"MPAK";
+const VERSION: u32 = 1;
+// Magic, version and entry count
+const HEADER_SIZE: u64 = 12;
+// An entry in the index with an empty name
+const MIN_ENTRY_SIZE: u64 = 2 + 8 + 8 + 8 + 1 + 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    Stored = 0,
+    Deflate = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    offset: u64,
+    stored_size: u64,
+    pub size: u64,
+    pub compression: Compression,
+    pub hash: u32,
+}
+
+pub struct Archive {
+    path: PathBuf,
+    // Sorted by name
+    entries: Vec<Entry>,
+    data_start: u64,
+}
+
+impl Archive {
+    // Only reads the index, the files get read when they're asked for
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let read_index = || -> anyhow::Result<(Vec<Entry>, u64)> {
+            let file = File::open(path)?;
+            let length = file.metadata()?.len();
+            let mut reader = BufReader::new(file);
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic)?;
+            ensure!(&magic == MAGIC, "Not an archive");
+            let version = read_u32(&mut reader)?;
+            ensure!(version == VERSION, "Version {} isn't supported", version);
+            let count = read_u32(&mut reader)?;
+            ensure!(
+                count as u64 * MIN_ENTRY_SIZE <= length.saturating_sub(HEADER_SIZE),
+                "{} entries don't fit in {} bytes",
+                count,
+                length
+            );
+            let mut entries = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let mut name = vec![0; read_u16(&mut reader)? as usize];
+                reader.read_exact(&mut name)?;
+                let name = String::from_utf8(name)?;
+                let offset = read_u64(&mut reader)?;
+                let stored_size = read_u64(&mut reader)?;
+                let size = read_u64(&mut reader)?;
+                let mut compression = [0];
+                reader.read_exact(&mut compression)?;
+                let compression = match compression[0] {
+                    0 => Compression::Stored,
+                    1 => Compression::Deflate,
+                    other => bail!("{} has unknown compression {}", name, other),
+                };
+                ensure!(
+                    compression == Compression::Deflate || size == stored_size,
+                    "{} is stored in {} bytes but {} long",
+                    name,
+                    stored_size,
+                    size
+                );
+                let hash = read_u32(&mut reader)?;
+                entries.push(Entry { name, offset, stored_size, size, compression, hash });
+            }
+            let data_start = reader.stream_position()?;
+            for entry in &entries {
+                let end = entry.offset.checked_add(entry.stored_size).and_then(|end| end.checked_add(data_start));
+                ensure!(end.is_some_and(|end| end <= length), "{} goes past the end of the file", entry.name);
+            }
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            // Which one a lookup found would depend on the sort
+            if let Some(pair) = entries.windows(2).find(|pair| pair[0].name == pair[1].name) {
+                bail!("{} is in it more than once", pair[0].name);
+            }
+            Ok((entries, data_start))
+        };
+        let (entries, data_start) = read_index().with_context(|| format!("Couldn't open archive {}", path.display()))?;
+        Ok(Self { path: path.to_path_buf(), entries, data_start })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&Entry> {
+        self.entries.binary_search_by(|entry| entry.name.as_str().cmp(name)).ok().map(|i| &self.entries[i])
+    }
+
+    // None when the archive doesn't have the file
+    pub fn read(&self, name: &str) -> Option<anyhow::Result<Vec<u8>>> {
+        let entry = self.entry(name)?;
+        let read = || -> anyhow::Result<Vec<u8>> {
+            let mut file = File::open(&self.path)?;
+            file.seek(SeekFrom::Start(self.data_start + entry.offset))?;
+            // The file could have changed since it was opened, so the sizes only limit what
+            // gets read and the buffers grow with what's actually there
+            let mut stored = Vec::new();
+            file.take(entry.stored_size).read_to_end(&mut stored)?;
+            ensure!(stored.len() as u64 == entry.stored_size, "The archive ends in the middle of it");
+            let data = match entry.compression {
+                Compression::Stored => stored,
+                Compression::Deflate => {
+                    // One byte more than it should have is enough to tell it's too long
+                    let mut data = Vec::new();
+                    flate2::read::DeflateDecoder::new(stored.as_slice()).take(entry.size.saturating_add(1)).read_to_end(&mut data)?;
+                    data
+                }
+            };
+            ensure!(data.len() as u64 == entry.size, "It's {} bytes instead of {}", data.len(), entry.size);
+            ensure!(crc32fast::hash(&data) == entry.hash, "Its content doesn't match the hash");
+            Ok(data)
+        };
+        Some(read().with_context(|| format!("Couldn't read {} from {}", name, self.path.display())))
+    }
+
+    // One hash over the names and content hashes of every file, equal for archives with
+    // the same content
+    pub fn content_hash(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for entry in &self.entries {
+            hasher.update(entry.name.as_bytes());
+            hasher.update(&entry.hash.to_le_bytes());
+        }
+        hasher.finalize()
+    }
+}
+
+// Packs the files, by the names they get read with. Files are deflated when `compress` is
+// set and that makes them smaller, images that are compressed already get stored as they are.
+pub fn write(path: &Path, files: &[(String, Vec<u8>)], compress: bool) -> anyhow::Result<()> {
+    let mut index = Vec::new();
+    let mut blob = Vec::new();
+    index.extend_from_slice(MAGIC);
+    index.extend_from_slice(&VERSION.to_le_bytes());
+    index.extend_from_slice(&(files.len() as u32).to_le_bytes());
+    for (name, data) in files {
+        let deflated = if compress {
+            let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(data)?;
+            Some(encoder.finish()?).filter(|deflated| deflated.len() < data.len())
+        } else {
+            None
+        };
+        let (compression, stored) = match &deflated {
+            Some(deflated) => (Compression::Deflate, deflated),
+            None => (Compression::Stored, data),
+        };
+        ensure!(name.len() <= u16::MAX as usize, "The name {} is too long", name);
+        index.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        index.extend_from_slice(name.as_bytes());
+        index.extend_from_slice(&(blob.len() as u64).to_le_bytes());
+        index.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+        index.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        index.push(compression as u8);
+        index.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        blob.extend_from_slice(stored);
+    }
+    let mut file = File::create(path).with_context(|| format!("Couldn't create archive {}", path.display()))?;
+    file.write_all(&index)
+        .and_then(|_| file.write_all(&blob))
+        .with_context(|| format!("Couldn't write archive {}", path.display()))
+}
+
+fn read_u16(reader: &mut impl Read) -> std::io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let files = vec![
+            ("maps/default.json".to_string(), include_bytes!("../res/maps/default.json").to_vec()),
+            ("assets/default_texture.png".to_string(), include_bytes!("../res/assets/default_texture.png").to_vec()),
+            ("empty".to_string(), Vec::new()),
+        ];
+        let path = std::env::temp_dir().join(format!("archive_round_trip_{}.pak", std::process::id()));
+        write(&path, &files, true).unwrap();
+        let archive = Archive::open(&path).unwrap();
+        let read: Vec<_> = files.iter().map(|(name, _)| archive.read(name).unwrap().unwrap()).collect();
+        let missing = archive.read("missing").is_none();
+        let compression = archive.entry("maps/default.json").unwrap().compression;
+        std::fs::remove_file(&path).unwrap();
+        for ((_, data), read) in files.iter().zip(read) {
+            assert_eq!(&read, data);
+        }
+        assert!(missing);
+        assert_eq!(compression, Compression::Deflate);
+    }
+
+    #[test]
+    fn corrupted_content_fails_the_hash() {
+        let files = vec![("file".to_string(), vec![7; 64])];
+        let path = std::env::temp_dir().join(format!("archive_corrupted_{}.pak", std::process::id()));
+        write(&path, &files, false).unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        *data.last_mut().unwrap() = 8;
+        std::fs::write(&path, data).unwrap();
+        let read = Archive::open(&path).unwrap().read("file").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+
+    #[test]
+    fn content_hash_follows_the_content() {
+        let hash = |files: &[(String, Vec<u8>)]| {
+            let path = std::env::temp_dir().join(format!("archive_hash_{}.pak", std::process::id()));
+            write(&path, files, true).unwrap();
+            let hash = Archive::open(&path).unwrap().content_hash();
+            std::fs::remove_file(&path).unwrap();
+            hash
+        };
+        let a = vec![("a".to_string(), b"one".to_vec()), ("b".to_string(), b"two".to_vec())];
+        let reordered = vec![a[1].clone(), a[0].clone()];
+        let changed = vec![a[0].clone(), ("b".to_string(), b"three".to_vec())];
+        assert_eq!(hash(&a), hash(&reordered));
+        assert_ne!(hash(&a), hash(&changed));
+    }
+
+    // One file called "file", with its index patched. The fields start at: count 8,
+    // offset 18, stored size 26, size 34
+    fn patched(test: &str, compress: bool, patch: impl FnOnce(&mut Vec<u8>)) -> anyhow::Result<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!("archive_{}_{}.pak", test, std::process::id()));
+        write(&path, &[("file".to_string(), vec![7; 64])], compress).unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        patch(&mut data);
+        std::fs::write(&path, data).unwrap();
+        let read = Archive::open(&path).and_then(|archive| archive.read("file").unwrap());
+        std::fs::remove_file(&path).unwrap();
+        read
+    }
+
+    fn set_u64(data: &mut [u8], at: usize, value: u64) {
+        data[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn index_cant_point_past_the_file() {
+        assert_eq!(patched("intact", true, |_| ()).unwrap(), vec![7; 64]);
+        let error = patched("count", true, |data| data[8..12].copy_from_slice(&u32::MAX.to_le_bytes())).unwrap_err();
+        assert!(format!("{:#}", error).contains("don't fit"));
+        let error = patched("stored_size", false, |data| {
+            set_u64(data, 26, u64::MAX);
+            set_u64(data, 34, u64::MAX);
+        })
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("past the end"));
+        let error = patched("offset", true, |data| set_u64(data, 18, u64::MAX - 4)).unwrap_err();
+        assert!(format!("{:#}", error).contains("past the end"));
+        // Stored files can't claim to unpack to something else
+        let error = patched("stored_mismatch", false, |data| set_u64(data, 34, u64::MAX)).unwrap_err();
+        assert!(format!("{:#}", error).contains("stored in"));
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let path = std::env::temp_dir().join(format!("archive_duplicates_{}.pak", std::process::id()));
+        write(&path, &[("a".to_string(), b"one".to_vec()), ("b".to_string(), b"two".to_vec())], false).unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        // The second entry's name, after the header and the first entry
+        data[12 + 2 + 1 + 29 + 2] = b'a';
+        std::fs::write(&path, data).unwrap();
+        let opened = Archive::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{:#}", opened.err().unwrap()).contains("a is in it more than once"));
+    }
+
+    #[test]
+    fn unpacked_size_has_to_match() {
+        // A huge size only gets as far as what the content unpacks to
+        let error = patched("huge", true, |data| set_u64(data, 34, u64::MAX)).unwrap_err();
+        assert!(format!("{:#}", error).contains("instead of"));
+        let error = patched("short", true, |data| set_u64(data, 34, 10)).unwrap_err();
+        assert!(format!("{:#}", error).contains("instead of"));
+    }
+}
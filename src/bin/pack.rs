@@ -0,0 +1,86 @@
+// Packs resources into an archive the game can mount with --archive, or by leaving it next
+// to the executable. Everything under the source directory goes in unless some of it is named.
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use multiplayer_client_rust::archive;
+
+struct Args {
+    output: PathBuf,
+    source: PathBuf,
+    // Files or directories under the source, all of it when empty
+    include: Vec<String>,
+    compress: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut output = None;
+        let mut source = PathBuf::from("res");
+        let mut include = Vec::new();
+        let mut compress = true;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--source" => source = args.next().ok_or("--source needs a directory")?.into(),
+                "--store" => compress = false,
+                _ if arg.starts_with("--") => return Err(format!("Unknown argument {}", arg)),
+                _ if output.is_none() => output = Some(arg.into()),
+                _ => include.push(arg),
+            }
+        }
+        let output = output.ok_or("No archive to write")?;
+        Ok(Self { output, source, include, compress })
+    }
+}
+
+// Every file under `path`, by its name relative to `root`
+fn collect(root: &Path, path: &Path, files: &mut Vec<(String, Vec<u8>)>) -> anyhow::Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path).with_context(|| format!("Couldn't read {}", path.display()))? {
+            collect(root, &entry?.path(), files)?;
+        }
+    } else {
+        let name = path.strip_prefix(root)?.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        let data = std::fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+        files.push((name, data));
+    }
+    Ok(())
+}
+
+fn pack(args: &Args) -> anyhow::Result<()> {
+    let mut files = Vec::new();
+    if args.include.is_empty() {
+        collect(&args.source, &args.source, &mut files)?;
+    }
+    for name in &args.include {
+        let path = args.source.join(name);
+        anyhow::ensure!(path.exists(), "{} doesn't exist", path.display());
+        collect(&args.source, &path, &mut files)?;
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    files.dedup_by(|a, b| a.0 == b.0);
+    archive::write(&args.output, &files, args.compress)?;
+
+    let archive = archive::Archive::open(&args.output)?;
+    for entry in archive.entries() {
+        println!("{:08x} {:>10} {:?} {}", entry.hash, entry.size, entry.compression, entry.name);
+    }
+    let size = std::fs::metadata(&args.output)?.len();
+    println!("Packed {} files into {} ({} bytes), content hash {:08x}", files.len(), args.output.display(), size, archive.content_hash());
+    Ok(())
+}
+
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\nUsage: pack <archive> [--source <directory>] [--store] [<file or directory>...]", e);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = pack(&args) {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+}
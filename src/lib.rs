@@ -0,0 +1,6 @@
+// The game, and what its tools and a headless server share with it
+pub mod archive;
+mod window;
+pub mod world;
+
+pub use window::run;
@@ -1,5 +1,39 @@
-mod window;
+use std::path::PathBuf;
+
+// What can be passed on the command line
+#[derive(Default)]
+struct Args {
+    // Map file to load instead of the default one
+    map: Option<PathBuf>,
+    // Resource directory to look in before the default ones
+    assets: Option<PathBuf>,
+    // Packed resources, looked in after the directory
+    archives: Vec<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--map" => parsed.map = Some(args.next().ok_or("--map needs a file")?.into()),
+                "--assets" => parsed.assets = Some(args.next().ok_or("--assets needs a directory")?.into()),
+                "--archive" => parsed.archives.push(args.next().ok_or("--archive needs a file")?.into()),
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        Ok(parsed)
+    }
+}
 
 fn main() {
-    pollster::block_on(window::run());
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\nUsage: multiplayer_client_rust [--map <file>] [--assets <directory>] [--archive <file>...]", e);
+            std::process::exit(2);
+        }
+    };
+    pollster::block_on(multiplayer_client_rust::run(args.map, args.assets, args.archives));
 }
@@ -0,0 +1,186 @@
+// Assets that get shared, by the name they were loaded with. Asking for a name that's loaded
+// already hands out another handle to the same asset instead of loading it again. Handles
+// count references, and unloading drops whatever only the cache still refers to.
+use std::{collections::HashMap, ops::Deref, sync::Arc};
+
+use super::{model, texture};
+
+pub struct Handle<T>(Arc<T>);
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Deref for Handle<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Handle<T> {
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        Arc::ptr_eq(&a.0, &b.0)
+    }
+}
+
+// A material texture, with the alpha mode its alpha channel asks for. Opaque for normal maps.
+pub struct TextureAsset {
+    pub texture: texture::Texture,
+    pub alpha_mode: model::AlphaMode,
+}
+
+struct Entry<T> {
+    handle: Handle<T>,
+    // On the GPU, 0 where that isn't known
+    bytes: u64,
+}
+
+// One loaded asset, for the UI
+pub struct AssetInfo {
+    pub name: String,
+    // Handles outside of the cache
+    pub references: usize,
+    pub bytes: u64,
+}
+
+pub struct Cache<T> {
+    entries: HashMap<String, Entry<T>>,
+}
+
+impl<T> Default for Cache<T> {
+    fn default() -> Self {
+        Self { entries: HashMap::new() }
+    }
+}
+
+impl<T> Cache<T> {
+    pub fn get(&self, name: &str) -> Option<Handle<T>> {
+        self.entries.get(name).map(|entry| entry.handle.clone())
+    }
+
+    // Replaces whatever had the name, handles to that keep it around until they're dropped
+    pub fn insert(&mut self, name: &str, asset: T, bytes: u64) -> Handle<T> {
+        let handle = Handle(Arc::new(asset));
+        self.entries.insert(name.to_string(), Entry { handle: handle.clone(), bytes });
+        handle
+    }
+
+    // The closure returns the asset along with how many bytes it takes up
+    pub fn get_or_insert_with(&mut self, name: &str, create: impl FnOnce() -> (T, u64)) -> Handle<T> {
+        match self.get(name) {
+            Some(handle) => handle,
+            None => {
+                let (asset, bytes) = create();
+                self.insert(name, asset, bytes)
+            }
+        }
+    }
+
+    // The next time the name is asked for it gets loaded again
+    pub fn remove(&mut self, name: &str) {
+        self.entries.remove(name);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Drops everything no handle outside the cache refers to, and returns how many that was
+    pub fn unload_unused(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| Arc::strong_count(&entry.handle.0) > 1);
+        before - self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.entries.values().map(|entry| entry.bytes).sum()
+    }
+
+    // By name
+    pub fn infos(&self) -> Vec<AssetInfo> {
+        let mut infos: Vec<AssetInfo> = self
+            .entries
+            .iter()
+            .map(|(name, entry)| AssetInfo {
+                name: name.clone(),
+                references: Arc::strong_count(&entry.handle.0) - 1,
+                bytes: entry.bytes,
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+}
+
+#[derive(Default)]
+pub struct AssetManager {
+    // By texture_key
+    pub textures: Cache<TextureAsset>,
+    // The meshes of a model file, every model loaded from it draws these
+    pub meshes: Cache<Vec<model::Mesh>>,
+    // By shader file
+    pub shaders: Cache<wgpu::ShaderModule>,
+}
+
+impl AssetManager {
+    pub fn unload_unused(&mut self) -> usize {
+        self.textures.unload_unused() + self.meshes.unload_unused() + self.shaders.unload_unused()
+    }
+}
+
+// Normal maps are linear and colour textures sRGB, so a file used as both is two textures
+pub fn texture_key(file: &str, is_normal_map: bool) -> String {
+    if is_normal_map {
+        format!("{} (normal map)", file)
+    } else {
+        file.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loaded_names_are_shared() {
+        let mut cache = Cache::default();
+        let a = cache.insert("a", 1, 4);
+        let again = cache.get_or_insert_with("a", || panic!("a got loaded twice"));
+        assert!(Handle::ptr_eq(&a, &again));
+        assert_eq!(*again, 1);
+        assert_eq!(cache.infos()[0].references, 2);
+    }
+
+    #[test]
+    fn unloading_keeps_what_is_used() {
+        let mut cache = Cache::default();
+        let used = cache.insert("used", 1, 4);
+        drop(cache.insert("dropped", 2, 8));
+        assert_eq!(cache.bytes(), 12);
+        assert_eq!(cache.unload_unused(), 1);
+        assert!(cache.get("dropped").is_none());
+        assert_eq!(cache.bytes(), 4);
+        drop(used);
+        assert_eq!(cache.unload_unused(), 1);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn replaced_assets_live_on_in_their_handles() {
+        let mut cache = Cache::default();
+        let old = cache.insert("a", 1, 0);
+        cache.remove("a");
+        let new = cache.get_or_insert_with("a", || (2, 8));
+        assert_eq!((*old, *new), (1, 2));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.bytes(), 8);
+    }
+}
@@ -0,0 +1,341 @@
+// Where the resources get read from while the game runs. The asset root is a list of mounts
+// searched in order, the first one that has a file wins, so a directory can override single
+// files of the ones after it.
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::Context;
+
+use crate::archive;
+
+use super::shaders;
+
+// A directory of resources to look in before the default ones
+pub const ENV_VAR: &str = "MULTIPLAYER_ASSETS";
+// Archives next to the executable with this get mounted
+const ARCHIVE_EXTENSION: &str = "pak";
+
+include!(concat!(env!("OUT_DIR"), "/embedded_assets.rs"));
+
+// Something files can be read from, by the names the loaders take
+pub trait Mount: Send + Sync {
+    // None when it doesn't have the file, so the mounts after it get looked in
+    fn read(&self, name: &str) -> Option<anyhow::Result<Vec<u8>>>;
+    fn files(&self) -> Vec<String>;
+    // Shows up in errors and the log
+    fn describe(&self) -> String;
+    // The directory on disk it reads from and what the names in it start with, for the hot
+    // reloading to watch
+    fn directory(&self) -> Option<(&Path, &str)> {
+        None
+    }
+}
+
+// Loose files under a directory
+pub struct Directory {
+    root: PathBuf,
+    // What the names of the files in it start with, empty or ending in a slash
+    prefix: String,
+}
+
+impl Directory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::with_prefix(root, "")
+    }
+
+    // The files in it get read as `prefix/name`
+    pub fn with_prefix(root: impl Into<PathBuf>, prefix: &str) -> Self {
+        let prefix = if prefix.is_empty() { String::new() } else { format!("{}/", prefix.trim_end_matches('/')) };
+        Self { root: root.into(), prefix }
+    }
+}
+
+impl Mount for Directory {
+    fn read(&self, name: &str) -> Option<anyhow::Result<Vec<u8>>> {
+        let name = name.strip_prefix(&self.prefix)?;
+        // Nothing outside the root can be read, whatever the name says
+        let path = match normalize_name(name) {
+            Ok(name) => self.root.join(name),
+            Err(e) => return Some(Err(e)),
+        };
+        if !path.is_file() {
+            return None;
+        }
+        Some(std::fs::read(&path).with_context(|| format!("Couldn't read {}", path.display())))
+    }
+
+    fn files(&self) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut directories = vec![self.root.clone()];
+        while let Some(directory) = directories.pop() {
+            let Ok(entries) = std::fs::read_dir(&directory) else { continue };
+            for path in entries.flatten().map(|entry| entry.path()) {
+                if path.is_dir() {
+                    directories.push(path);
+                } else if let Some(name) = relative_name(&self.root, &path) {
+                    files.push(format!("{}{}", self.prefix, name));
+                }
+            }
+        }
+        files
+    }
+
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn directory(&self) -> Option<(&Path, &str)> {
+        Some((&self.root, &self.prefix))
+    }
+}
+
+// The res directory as it was when the game got built, baked into the binary
+pub struct Embedded;
+
+impl Mount for Embedded {
+    fn read(&self, name: &str) -> Option<anyhow::Result<Vec<u8>>> {
+        EMBEDDED.iter().find(|(file, _)| *file == name).map(|(_, data)| Ok(data.to_vec()))
+    }
+
+    fn files(&self) -> Vec<String> {
+        EMBEDDED.iter().map(|(file, _)| file.to_string()).collect()
+    }
+
+    fn describe(&self) -> String {
+        "the embedded resources".into()
+    }
+}
+
+impl Mount for archive::Archive {
+    fn read(&self, name: &str) -> Option<anyhow::Result<Vec<u8>>> {
+        archive::Archive::read(self, name)
+    }
+
+    fn files(&self) -> Vec<String> {
+        self.entries().iter().map(|entry| entry.name.clone()).collect()
+    }
+
+    // With the content hash, so it's in the log when the content doesn't match a server's
+    fn describe(&self) -> String {
+        format!("{} (content hash {:08x})", self.path().display(), self.content_hash())
+    }
+}
+
+#[derive(Default)]
+pub struct AssetRoot {
+    mounts: Vec<Box<dyn Mount>>,
+}
+
+impl AssetRoot {
+    // Looked in after everything mounted before it
+    pub fn mount(&mut self, mount: impl Mount + 'static) {
+        log::info!("Mounted {}", mount.describe());
+        self.mounts.push(Box::new(mount));
+    }
+
+    // The directory given on the command line, then the one in ENV_VAR, the archives given on
+    // the command line, res and the archives next to the executable, and the embedded
+    // resources and shaders. Debug builds look in the source tree before the embedded ones,
+    // so changes to it get picked up without a rebuild. Archives next to the executable that
+    // can't be opened get skipped, the ones given on the command line have to open.
+    pub fn search(directory: Option<PathBuf>, archives: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut root = Self::default();
+        let given = [
+            directory.map(|directory| (directory, "--assets")),
+            std::env::var_os(ENV_VAR).map(|directory| (directory.into(), ENV_VAR)),
+        ];
+        for (directory, source) in given.into_iter().flatten() {
+            anyhow::ensure!(directory.is_dir(), "The asset directory {} from {} doesn't exist", directory.display(), source);
+            root.mount(Directory::new(directory));
+        }
+        for path in archives {
+            root.mount(archive::Archive::open(path)?);
+        }
+        if let Some(executable_dir) = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf)) {
+            if executable_dir.join("res").is_dir() {
+                root.mount(Directory::new(executable_dir.join("res")));
+            }
+            let mut packed: Vec<PathBuf> = std::fs::read_dir(&executable_dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|e| e == ARCHIVE_EXTENSION))
+                .collect();
+            packed.sort();
+            for path in packed {
+                match archive::Archive::open(&path) {
+                    Ok(archive) => root.mount(archive),
+                    Err(e) => log::error!("Skipping {}: {:#}", path.display(), e),
+                }
+            }
+        }
+        if cfg!(debug_assertions) {
+            let source = Path::new(env!("CARGO_MANIFEST_DIR"));
+            for (directory, prefix) in [(source.join("res"), ""), (source.join("src").join("Shaders"), shaders::PREFIX)] {
+                if directory.is_dir() {
+                    log::info!("Debug build, reading the sources at {} before the embedded copies", directory.display());
+                    root.mount(Directory::with_prefix(directory, prefix));
+                }
+            }
+        }
+        root.mount(Embedded);
+        root.mount(shaders::Baked);
+        Ok(root)
+    }
+
+    pub fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        self.find(name).unwrap_or_else(|| {
+            let searched = self.mounts.iter().map(|mount| mount.describe()).collect::<Vec<_>>().join(", ");
+            Err(anyhow::anyhow!("Couldn't find {} in {}", name, searched))
+        })
+    }
+
+    // Like `read`, but None when none of the mounts has the file, for the ones that are optional
+    pub fn find(&self, name: &str) -> Option<anyhow::Result<Vec<u8>>> {
+        let name = match normalize_name(name) {
+            Ok(name) => name,
+            Err(e) => return Some(Err(e)),
+        };
+        self.mounts.iter().find_map(|mount| mount.read(&name))
+    }
+
+    // Every file in any of the mounts, once each
+    pub fn files(&self) -> Vec<String> {
+        let mut files: Vec<String> = self.mounts.iter().flat_map(|mount| mount.files()).collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    // The loose file directories, with the prefix of the names in them
+    pub fn directories(&self) -> Vec<(PathBuf, String)> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| mount.directory())
+            .map(|(directory, prefix)| (directory.to_path_buf(), prefix.to_string()))
+            .collect()
+    }
+}
+
+static ROOT: RwLock<Option<AssetRoot>> = RwLock::new(None);
+
+// Replaces the root the loaders read from
+pub fn set_root(root: AssetRoot) {
+    *ROOT.write().unwrap() = Some(root);
+}
+
+// The root set last, or the default search path when none was
+pub fn with_root<T>(use_root: impl FnOnce(&AssetRoot) -> T) -> T {
+    if let Some(root) = ROOT.read().unwrap().as_ref() {
+        return use_root(root);
+    }
+    let mut root = ROOT.write().unwrap();
+    use_root(root.get_or_insert_with(|| AssetRoot::search(None, &[]).unwrap_or_default()))
+}
+
+// Names are relative and use forward slashes. Empty and `.` parts get dropped, anything that
+// would lead out of the root is an error.
+pub fn normalize_name(name: &str) -> anyhow::Result<String> {
+    let escapes = || anyhow::anyhow!("{} points outside the resources", name);
+    if name.starts_with(['/', '\\']) {
+        return Err(escapes());
+    }
+    let mut parts = Vec::new();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => return Err(escapes()),
+            // Drive letters and the like
+            part if part.contains(':') => return Err(escapes()),
+            part => parts.push(part),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+// The name the loaders take for a file somewhere under `root`
+pub fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    Some(relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    // A directory under the temp dir with the files written into it, removed again on drop
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let root = std::env::temp_dir().join(format!("assets_{}_{}", name, std::process::id()));
+            for (file, text) in files {
+                let path = root.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, text).unwrap();
+            }
+            Self(root)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn normalizes_names() {
+        assert_eq!(normalize_name("models/cube.obj").unwrap(), "models/cube.obj");
+        assert_eq!(normalize_name("./models//cube.obj").unwrap(), "models/cube.obj");
+        assert_eq!(normalize_name("models\\cube.obj").unwrap(), "models/cube.obj");
+        for name in ["../secret", "models/../../secret", "/etc/passwd", "\\windows", "C:/windows", "models/.."] {
+            assert!(normalize_name(name).is_err(), "{} got through", name);
+        }
+    }
+
+    #[test]
+    fn directories_stay_inside_their_root() {
+        let outside = TempDir::new("outside", &[("secret.txt", "secret")]);
+        let inside = TempDir::new("inside", &[("a/b.txt", "b")]);
+        let directory = Directory::new(&inside.0);
+        assert_eq!(directory.read("a/b.txt").unwrap().unwrap(), b"b");
+        assert_eq!(directory.read("./a//b.txt").unwrap().unwrap(), b"b");
+        assert!(directory.read("missing.txt").is_none());
+        let escape = format!("../{}/secret.txt", outside.0.file_name().unwrap().to_string_lossy());
+        assert!(directory.read(&escape).unwrap().is_err());
+        assert!(directory.read(&outside.0.join("secret.txt").to_string_lossy()).unwrap().is_err());
+        assert_eq!(directory.files(), ["a/b.txt"]);
+    }
+
+    #[test]
+    fn prefixed_directories() {
+        let shaders = TempDir::new("prefixed", &[("test.wgsl", "shader")]);
+        let directory = Directory::with_prefix(&shaders.0, "shaders");
+        assert_eq!(directory.read("shaders/test.wgsl").unwrap().unwrap(), b"shader");
+        assert!(directory.read("test.wgsl").is_none());
+        assert_eq!(directory.files(), ["shaders/test.wgsl"]);
+    }
+
+    #[test]
+    fn earlier_mounts_win() {
+        let first = TempDir::new("first", &[("a.txt", "first")]);
+        let second = TempDir::new("second", &[("a.txt", "second"), ("b.txt", "second")]);
+        let mut root = AssetRoot::default();
+        root.mount(Directory::new(&first.0));
+        root.mount(Directory::new(&second.0));
+        root.mount(shaders::Baked);
+        assert_eq!(root.read("a.txt").unwrap(), b"first");
+        assert_eq!(root.read("b.txt").unwrap(), b"second");
+        assert!(root.read("c.txt").unwrap_err().to_string().contains("Couldn't find c.txt"));
+        assert!(root.find("c.txt").is_none());
+        assert!(root.find("../a.txt").unwrap().is_err());
+        assert!(root.read("../a.txt").is_err());
+        assert!(!root.read("shaders/shader.wgsl").unwrap().is_empty());
+        assert_eq!(root.directories(), [(first.0.clone(), String::new()), (second.0.clone(), String::new())]);
+        assert!(root.files().contains(&"shaders/tonemap.wgsl".to_string()));
+    }
+}
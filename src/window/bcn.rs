@@ -0,0 +1,794 @@
+// CPU decoders for block compressed textures, used when the adapter can't sample them.
+// BC1 to BC5 and BC7 decode to four bytes per texel, the snorm ones as signed bytes. BC6H
+// decodes to four half floats per texel.
+use anyhow::*;
+
+// Format the decoded texels get uploaded as
+pub fn decoded_format(format: wgpu::TextureFormat) -> wgpu::TextureFormat {
+    use wgpu::TextureFormat::*;
+    match format {
+        Bc4RSnorm | Bc5RgSnorm => Rgba8Snorm,
+        Bc6hRgbUfloat | Bc6hRgbSfloat => Rgba16Float,
+        _ if format.describe().srgb => Rgba8UnormSrgb,
+        _ => Rgba8Unorm,
+    }
+}
+
+pub fn decode(format: wgpu::TextureFormat, data: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    use wgpu::TextureFormat::*;
+    let block_size = format.describe().block_size as usize;
+    let (blocks_x, blocks_y) = ((width as usize).div_ceil(4), (height as usize).div_ceil(4));
+    if data.len() < blocks_x * blocks_y * block_size {
+        bail!("{:?} data for {}x{} is only {} bytes", format, width, height, data.len());
+    }
+    let data = &data[..blocks_x * blocks_y * block_size];
+    let decode_block: fn(&[u8], &mut [[u8; 4]; 16]) = match format {
+        Bc1RgbaUnorm | Bc1RgbaUnormSrgb => bc1_block,
+        Bc2RgbaUnorm | Bc2RgbaUnormSrgb => bc2_block,
+        Bc3RgbaUnorm | Bc3RgbaUnormSrgb => bc3_block,
+        Bc4RUnorm => bc4_unorm_block,
+        Bc4RSnorm => bc4_snorm_block,
+        Bc5RgUnorm => bc5_unorm_block,
+        Bc5RgSnorm => bc5_snorm_block,
+        Bc7RgbaUnorm | Bc7RgbaUnormSrgb => bc7_block,
+        Bc6hRgbUfloat => return Ok(decode_blocks(data, width, height, |block, texels| bc6h_block(block, texels, false))),
+        Bc6hRgbSfloat => return Ok(decode_blocks(data, width, height, |block, texels| bc6h_block(block, texels, true))),
+        _ => bail!("{:?} can't be decoded on the CPU", format),
+    };
+    Ok(decode_blocks(data, width, height, decode_block))
+}
+
+// Lays the decoded blocks out in rows, `data` has to hold exactly the blocks of the image
+fn decode_blocks<const TEXEL: usize>(data: &[u8], width: u32, height: u32, decode_block: impl Fn(&[u8], &mut [[u8; TEXEL]; 16])) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let blocks_x = width.div_ceil(4);
+    let block_size = data.len() / (blocks_x * height.div_ceil(4));
+    let mut decoded = vec![0u8; width * height * TEXEL];
+    let mut texels = [[0u8; TEXEL]; 16];
+    for (i, block) in data.chunks_exact(block_size).enumerate() {
+        decode_block(block, &mut texels);
+        let (bx, by) = (i % blocks_x * 4, i / blocks_x * 4);
+        // Blocks on the edge of odd sized mips hang over the side
+        for (t, texel) in texels.iter().enumerate() {
+            let (x, y) = (bx + t % 4, by + t / 4);
+            if x < width && y < height {
+                let start = (y * width + x) * TEXEL;
+                decoded[start..start + TEXEL].copy_from_slice(texel);
+            }
+        }
+    }
+    decoded
+}
+
+fn rgb565(c: u16) -> [u8; 3] {
+    let (r, g, b) = ((c >> 11) as u8 & 31, (c >> 5) as u8 & 63, c as u8 & 31);
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+// BC2 and BC3 always use the four colour mode, only BC1 has the punch through alpha
+fn color_block(block: &[u8], texels: &mut [[u8; 4]; 16], allow_alpha: bool) {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let (e0, e1) = (rgb565(c0), rgb565(c1));
+    let mut palette = [[e0[0], e0[1], e0[2], 255], [e1[0], e1[1], e1[2], 255], [0; 4], [0; 4]];
+    for c in 0..3 {
+        let (a, b) = (e0[c] as u32, e1[c] as u32);
+        if c0 > c1 || !allow_alpha {
+            palette[2][c] = ((2 * a + b) / 3) as u8;
+            palette[3][c] = ((a + 2 * b) / 3) as u8;
+        } else {
+            palette[2][c] = ((a + b) / 2) as u8;
+        }
+    }
+    palette[2][3] = 255;
+    if c0 > c1 || !allow_alpha {
+        palette[3][3] = 255;
+    }
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    for (i, texel) in texels.iter_mut().enumerate() {
+        *texel = palette[(indices >> (i * 2)) as usize & 3];
+    }
+}
+
+// Eight endpoints interpolated from two, or six plus the extremes when the first is smaller
+fn alpha_palette(e0: i32, e1: i32, min: i32, max: i32) -> [i32; 8] {
+    let mut palette = [e0, e1, 0, 0, 0, 0, min, max];
+    if e0 > e1 {
+        for i in 1..7 {
+            palette[i + 1] = ((7 - i as i32) * e0 + i as i32 * e1) / 7;
+        }
+    } else {
+        for i in 1..5 {
+            palette[i + 1] = ((5 - i as i32) * e0 + i as i32 * e1) / 5;
+        }
+    }
+    palette
+}
+
+fn alpha_indices(block: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes[..6].copy_from_slice(&block[2..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn unorm_channel(block: &[u8], texels: &mut [[u8; 4]; 16], channel: usize) {
+    let palette = alpha_palette(block[0] as i32, block[1] as i32, 0, 255);
+    let indices = alpha_indices(block);
+    for (i, texel) in texels.iter_mut().enumerate() {
+        texel[channel] = palette[(indices >> (i * 3)) as usize & 7] as u8;
+    }
+}
+
+fn snorm_channel(block: &[u8], texels: &mut [[u8; 4]; 16], channel: usize) {
+    // -128 and -127 both mean -1
+    let endpoint = |b: u8| (b as i8).max(-127) as i32;
+    let palette = alpha_palette(endpoint(block[0]), endpoint(block[1]), -127, 127);
+    let indices = alpha_indices(block);
+    for (i, texel) in texels.iter_mut().enumerate() {
+        texel[channel] = palette[(indices >> (i * 3)) as usize & 7] as i8 as u8;
+    }
+}
+
+fn bc1_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    color_block(block, texels, true);
+}
+
+fn bc2_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    color_block(&block[8..], texels, false);
+    let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+    for (i, texel) in texels.iter_mut().enumerate() {
+        texel[3] = ((alpha >> (i * 4)) as u8 & 15) * 17;
+    }
+}
+
+fn bc3_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    color_block(&block[8..], texels, false);
+    unorm_channel(block, texels, 3);
+}
+
+fn bc4_unorm_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    unorm_channel(block, texels, 0);
+    for texel in texels.iter_mut() {
+        texel[1] = 0;
+        texel[2] = 0;
+        texel[3] = 255;
+    }
+}
+
+fn bc4_snorm_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    snorm_channel(block, texels, 0);
+    for texel in texels.iter_mut() {
+        texel[1] = 0;
+        texel[2] = 0;
+        texel[3] = 127;
+    }
+}
+
+fn bc5_unorm_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    unorm_channel(block, texels, 0);
+    unorm_channel(&block[8..], texels, 1);
+    for texel in texels.iter_mut() {
+        texel[2] = 0;
+        texel[3] = 255;
+    }
+}
+
+fn bc5_snorm_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    snorm_channel(block, texels, 0);
+    snorm_channel(&block[8..], texels, 1);
+    for texel in texels.iter_mut() {
+        texel[2] = 0;
+        texel[3] = 127;
+    }
+}
+
+// Per mode: subsets, partition bits, rotation bits, index selection bits, colour bits,
+// alpha bits, endpoint p-bits, shared p-bits, index bits, secondary index bits
+struct Bc7Mode {
+    subsets: usize,
+    partition_bits: u32,
+    rotation_bits: u32,
+    index_selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    endpoint_pbits: bool,
+    shared_pbits: bool,
+    index_bits: u32,
+    index2_bits: u32,
+}
+
+const fn bc7_mode(m: [u32; 10]) -> Bc7Mode {
+    Bc7Mode {
+        subsets: m[0] as usize,
+        partition_bits: m[1],
+        rotation_bits: m[2],
+        index_selection_bits: m[3],
+        color_bits: m[4],
+        alpha_bits: m[5],
+        endpoint_pbits: m[6] == 1,
+        shared_pbits: m[7] == 1,
+        index_bits: m[8],
+        index2_bits: m[9],
+    }
+}
+
+const BC7_MODES: [Bc7Mode; 8] = [
+    bc7_mode([3, 4, 0, 0, 4, 0, 1, 0, 3, 0]),
+    bc7_mode([2, 6, 0, 0, 6, 0, 0, 1, 3, 0]),
+    bc7_mode([3, 6, 0, 0, 5, 0, 0, 0, 2, 0]),
+    bc7_mode([2, 6, 0, 0, 7, 0, 1, 0, 2, 0]),
+    bc7_mode([1, 0, 2, 1, 5, 6, 0, 0, 2, 3]),
+    bc7_mode([1, 0, 2, 0, 7, 8, 0, 0, 2, 2]),
+    bc7_mode([1, 0, 0, 0, 7, 7, 1, 0, 4, 0]),
+    bc7_mode([2, 6, 0, 0, 5, 5, 1, 0, 2, 0]),
+];
+
+// Bit i set means texel i belongs to the second subset
+const BC7_PARTITIONS_2: [u16; 64] = [
+    0xcccc, 0x8888, 0xeeee, 0xecc8, 0xc880, 0xfeec, 0xfec8, 0xec80,
+    0xc800, 0xffec, 0xfe80, 0xe800, 0xffe8, 0xff00, 0xfff0, 0xf000,
+    0xf710, 0x008e, 0x7100, 0x08ce, 0x008c, 0x7310, 0x3100, 0x8cce,
+    0x088c, 0x3110, 0x6666, 0x366c, 0x17e8, 0x0ff0, 0x718e, 0x399c,
+    0xaaaa, 0xf0f0, 0x5a5a, 0x33cc, 0x3c3c, 0x55aa, 0x9696, 0xa55a,
+    0x73ce, 0x13c8, 0x324c, 0x3bdc, 0x6996, 0xc33c, 0x9966, 0x0660,
+    0x0272, 0x04e4, 0x4e40, 0x2720, 0xc936, 0x936c, 0x39c6, 0x639c,
+    0x9336, 0x9cc6, 0x817e, 0xe718, 0xccf0, 0x0fcc, 0x7744, 0xee22,
+];
+
+// Two bits per texel, texel 0 in the lowest bits
+const BC7_PARTITIONS_3: [u32; 64] = [
+    0xaa685050, 0x6a5a5040, 0x5a5a4200, 0x5450a0a8, 0xa5a50000, 0xa0a05050, 0x5555a0a0, 0x5a5a5050,
+    0xaa550000, 0xaa555500, 0xaaaa5500, 0x90909090, 0x94949494, 0xa4a4a4a4, 0xa9a59450, 0x2a0a4250,
+    0xa5945040, 0x0a425054, 0xa5a5a500, 0x55a0a0a0, 0xa8a85454, 0x6a6a4040, 0xa4a45000, 0x1a1a0500,
+    0x0050a4a4, 0xaaa59090, 0x14696914, 0x69691400, 0xa08585a0, 0xaa821414, 0x50a4a450, 0x6a5a0200,
+    0xa9a58000, 0x5090a0a8, 0xa8a09050, 0x24242424, 0x00aa5500, 0x24924924, 0x24499224, 0x50a50a50,
+    0x500aa550, 0xaaaa4444, 0x66660000, 0xa5a0a5a0, 0x50a050a0, 0x69286928, 0x44aaaa44, 0x66666600,
+    0xaa444444, 0x54a854a8, 0x95809580, 0x96969600, 0xa85454a8, 0x80959580, 0xaa141414, 0x96960000,
+    0xaaaa1414, 0xa05050a0, 0xa0a5a5a0, 0x96000000, 0x40804080, 0xa9a8a9a8, 0xaaaaaa44, 0x2a4a5254,
+];
+
+// Texels whose index is one bit shorter, besides texel 0
+const BC7_ANCHORS_2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+    15, 2, 8, 2, 2, 8, 8, 15, 2, 8, 2, 2, 8, 8, 2, 2,
+    15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6,
+    6, 2, 6, 8, 15, 15, 2, 2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+
+const BC7_ANCHORS_3_SECOND: [u8; 64] = [
+    3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3,
+    3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6, 8, 5, 15, 15,
+    8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15,
+    3, 15, 5, 5, 5, 8, 5, 10, 5, 10, 8, 13, 15, 12, 3, 3,
+];
+
+const BC7_ANCHORS_3_THIRD: [u8; 64] = [
+    15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8,
+    15, 8, 15, 3, 15, 8, 15, 8, 3, 15, 6, 10, 15, 15, 10, 8,
+    15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8,
+    15, 3, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+];
+
+const BC7_WEIGHTS_2: [u32; 4] = [0, 21, 43, 64];
+const BC7_WEIGHTS_3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC7_WEIGHTS_4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+struct Bits {
+    value: u128,
+    position: u32,
+}
+
+impl Bits {
+    fn read(&mut self, count: u32) -> u32 {
+        let bits = (self.value >> self.position) as u32 & ((1u64 << count) - 1) as u32;
+        self.position += count;
+        bits
+    }
+}
+
+fn bc7_interpolate(e0: u8, e1: u8, index: u32, bits: u32) -> u8 {
+    let weight = match bits {
+        2 => BC7_WEIGHTS_2[index as usize],
+        3 => BC7_WEIGHTS_3[index as usize],
+        _ => BC7_WEIGHTS_4[index as usize],
+    };
+    (((64 - weight) * e0 as u32 + weight * e1 as u32 + 32) >> 6) as u8
+}
+
+fn bc7_block(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    let mut bits = Bits { value: u128::from_le_bytes(block.try_into().unwrap()), position: 0 };
+    let mode_index = bits.value.trailing_zeros();
+    if mode_index >= 8 {
+        // Reserved, decoders have to return transparent black
+        *texels = [[0; 4]; 16];
+        return;
+    }
+    bits.position = mode_index + 1;
+    let mode = &BC7_MODES[mode_index as usize];
+
+    let partition = bits.read(mode.partition_bits) as usize;
+    let rotation = bits.read(mode.rotation_bits);
+    let index_selection = bits.read(mode.index_selection_bits);
+
+    // Channel values for both endpoints of every subset
+    let mut endpoints = [[[0u32; 4]; 2]; 3];
+    for channel in 0..3 {
+        for subset in endpoints.iter_mut().take(mode.subsets) {
+            for endpoint in subset.iter_mut() {
+                endpoint[channel] = bits.read(mode.color_bits);
+            }
+        }
+    }
+    for subset in endpoints.iter_mut().take(mode.subsets) {
+        for endpoint in subset.iter_mut() {
+            endpoint[3] = if mode.alpha_bits > 0 { bits.read(mode.alpha_bits) } else { 255 };
+        }
+    }
+
+    let (mut color_bits, mut alpha_bits) = (mode.color_bits, mode.alpha_bits);
+    if mode.endpoint_pbits || mode.shared_pbits {
+        let mut pbits = [[0u32; 2]; 3];
+        for subset in pbits.iter_mut().take(mode.subsets) {
+            if mode.endpoint_pbits {
+                subset[0] = bits.read(1);
+                subset[1] = bits.read(1);
+            } else {
+                let shared = bits.read(1);
+                *subset = [shared, shared];
+            }
+        }
+        for (subset, pbits) in endpoints.iter_mut().zip(pbits) {
+            for (endpoint, pbit) in subset.iter_mut().zip(pbits) {
+                for value in endpoint.iter_mut().take(if mode.alpha_bits > 0 { 4 } else { 3 }) {
+                    *value = *value << 1 | pbit;
+                }
+            }
+        }
+        color_bits += 1;
+        if alpha_bits > 0 {
+            alpha_bits += 1;
+        }
+    }
+    // Widen to 8 bits by repeating the top bits in the bottom
+    let expand = |value: u32, precision: u32| {
+        let value = value << (8 - precision);
+        (value | value >> precision) as u8
+    };
+    let mut colors = [[[0u8; 4]; 2]; 3];
+    for (subset, expanded) in endpoints.iter().zip(colors.iter_mut()) {
+        for (endpoint, expanded) in subset.iter().zip(expanded.iter_mut()) {
+            for channel in 0..3 {
+                expanded[channel] = expand(endpoint[channel], color_bits);
+            }
+            expanded[3] = if alpha_bits > 0 { expand(endpoint[3], alpha_bits) } else { 255 };
+        }
+    }
+
+    let subset_of = |texel: usize| -> usize {
+        match mode.subsets {
+            2 => (BC7_PARTITIONS_2[partition] >> texel) as usize & 1,
+            3 => (BC7_PARTITIONS_3[partition] >> (texel * 2)) as usize & 3,
+            _ => 0,
+        }
+    };
+    let is_anchor = |texel: usize| -> bool {
+        texel == 0
+            || match mode.subsets {
+                2 => texel == BC7_ANCHORS_2[partition] as usize,
+                3 => texel == BC7_ANCHORS_3_SECOND[partition] as usize || texel == BC7_ANCHORS_3_THIRD[partition] as usize,
+                _ => false,
+            }
+    };
+
+    let mut indices = [0u32; 16];
+    for (texel, index) in indices.iter_mut().enumerate() {
+        *index = bits.read(if is_anchor(texel) { mode.index_bits - 1 } else { mode.index_bits });
+    }
+    let mut indices2 = [0u32; 16];
+    if mode.index2_bits > 0 {
+        for (texel, index) in indices2.iter_mut().enumerate() {
+            *index = bits.read(if texel == 0 { mode.index2_bits - 1 } else { mode.index2_bits });
+        }
+    }
+
+    for (texel, out) in texels.iter_mut().enumerate() {
+        let [e0, e1] = colors[subset_of(texel)];
+        // Modes 4 and 5 keep alpha on its own set of indices, mode 4 can swap the two sets
+        let (color_index, color_index_bits, alpha_index, alpha_index_bits) = if mode.index2_bits == 0 {
+            (indices[texel], mode.index_bits, indices[texel], mode.index_bits)
+        } else if index_selection == 0 {
+            (indices[texel], mode.index_bits, indices2[texel], mode.index2_bits)
+        } else {
+            (indices2[texel], mode.index2_bits, indices[texel], mode.index_bits)
+        };
+        for channel in 0..3 {
+            out[channel] = bc7_interpolate(e0[channel], e1[channel], color_index, color_index_bits);
+        }
+        out[3] = bc7_interpolate(e0[3], e1[3], alpha_index, alpha_index_bits);
+        match rotation {
+            1 => out.swap(0, 3),
+            2 => out.swap(1, 3),
+            3 => out.swap(2, 3),
+            _ => {}
+        }
+    }
+}
+
+// Endpoint fields of the BC6H bit layouts, w and x are the endpoints of the first region,
+// y and z the ones of the second
+const RW: u8 = 0;
+const GW: u8 = 1;
+const BW: u8 = 2;
+const RX: u8 = 3;
+const GX: u8 = 4;
+const BX: u8 = 5;
+const RY: u8 = 6;
+const GY: u8 = 7;
+const BY: u8 = 8;
+const RZ: u8 = 9;
+const GZ: u8 = 10;
+const BZ: u8 = 11;
+
+struct Bc6hMode {
+    // The mode bits, two for the first two modes and five for the others
+    id: u32,
+    subsets: usize,
+    // The other endpoints are stored as deltas from w
+    transformed: bool,
+    endpoint_bits: u32,
+    delta_bits: [u32; 3],
+    // (field, a, b) reads bits b to a of the field, in that order, like the spec's f[a:b]
+    layout: &'static [(u8, u8, u8)],
+}
+
+const BC6H_MODES: [Bc6hMode; 14] = [
+    Bc6hMode { id: 0, subsets: 2, transformed: true, endpoint_bits: 10, delta_bits: [5, 5, 5], layout: &[
+        (GY, 4, 4), (BY, 4, 4), (BZ, 4, 4), (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 4, 0), (GZ, 4, 4), (GY, 3, 0),
+        (GX, 4, 0), (BZ, 0, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3),
+    ] },
+    Bc6hMode { id: 1, subsets: 2, transformed: true, endpoint_bits: 7, delta_bits: [6, 6, 6], layout: &[
+        (GY, 5, 5), (GZ, 4, 4), (GZ, 5, 5), (RW, 6, 0), (BZ, 0, 0), (BZ, 1, 1), (BY, 4, 4), (GW, 6, 0), (BY, 5, 5),
+        (BZ, 2, 2), (GY, 4, 4), (BW, 6, 0), (BZ, 3, 3), (BZ, 5, 5), (BZ, 4, 4), (RX, 5, 0), (GY, 3, 0), (GX, 5, 0),
+        (GZ, 3, 0), (BX, 5, 0), (BY, 3, 0), (RY, 5, 0), (RZ, 5, 0),
+    ] },
+    Bc6hMode { id: 2, subsets: 2, transformed: true, endpoint_bits: 11, delta_bits: [5, 4, 4], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 4, 0), (RW, 10, 10), (GY, 3, 0), (GX, 3, 0), (GW, 10, 10), (BZ, 0, 0),
+        (GZ, 3, 0), (BX, 3, 0), (BW, 10, 10), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3),
+    ] },
+    Bc6hMode { id: 6, subsets: 2, transformed: true, endpoint_bits: 11, delta_bits: [4, 5, 4], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 3, 0), (RW, 10, 10), (GZ, 4, 4), (GY, 3, 0), (GX, 4, 0), (GW, 10, 10),
+        (GZ, 3, 0), (BX, 3, 0), (BW, 10, 10), (BZ, 1, 1), (BY, 3, 0), (RY, 3, 0), (BZ, 0, 0), (BZ, 2, 2), (RZ, 3, 0),
+        (GY, 4, 4), (BZ, 3, 3),
+    ] },
+    Bc6hMode { id: 10, subsets: 2, transformed: true, endpoint_bits: 11, delta_bits: [4, 4, 5], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 3, 0), (RW, 10, 10), (BY, 4, 4), (GY, 3, 0), (GX, 3, 0), (GW, 10, 10),
+        (BZ, 0, 0), (GZ, 3, 0), (BX, 4, 0), (BW, 10, 10), (BY, 3, 0), (RY, 3, 0), (BZ, 1, 1), (BZ, 2, 2), (RZ, 3, 0),
+        (BZ, 4, 4), (BZ, 3, 3),
+    ] },
+    Bc6hMode { id: 14, subsets: 2, transformed: true, endpoint_bits: 9, delta_bits: [5, 5, 5], layout: &[
+        (RW, 8, 0), (BY, 4, 4), (GW, 8, 0), (GY, 4, 4), (BW, 8, 0), (BZ, 4, 4), (RX, 4, 0), (GZ, 4, 4), (GY, 3, 0),
+        (GX, 4, 0), (BZ, 0, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3),
+    ] },
+    Bc6hMode { id: 18, subsets: 2, transformed: true, endpoint_bits: 8, delta_bits: [6, 5, 5], layout: &[
+        (RW, 7, 0), (GZ, 4, 4), (BY, 4, 4), (GW, 7, 0), (BZ, 2, 2), (GY, 4, 4), (BW, 7, 0), (BZ, 3, 3), (BZ, 4, 4),
+        (RX, 5, 0), (GY, 3, 0), (GX, 4, 0), (BZ, 0, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 5, 0), (RZ, 5, 0),
+    ] },
+    Bc6hMode { id: 22, subsets: 2, transformed: true, endpoint_bits: 8, delta_bits: [5, 6, 5], layout: &[
+        (RW, 7, 0), (BZ, 0, 0), (BY, 4, 4), (GW, 7, 0), (GY, 5, 5), (GY, 4, 4), (BW, 7, 0), (GZ, 5, 5), (BZ, 4, 4),
+        (RX, 4, 0), (GZ, 4, 4), (GY, 3, 0), (GX, 5, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0),
+        (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3),
+    ] },
+    Bc6hMode { id: 26, subsets: 2, transformed: true, endpoint_bits: 8, delta_bits: [5, 5, 6], layout: &[
+        (RW, 7, 0), (BZ, 1, 1), (BY, 4, 4), (GW, 7, 0), (BY, 5, 5), (GY, 4, 4), (BW, 7, 0), (BZ, 5, 5), (BZ, 4, 4),
+        (RX, 4, 0), (GZ, 4, 4), (GY, 3, 0), (GX, 4, 0), (BZ, 0, 0), (GZ, 3, 0), (BX, 5, 0), (BY, 3, 0), (RY, 4, 0),
+        (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3),
+    ] },
+    Bc6hMode { id: 30, subsets: 2, transformed: false, endpoint_bits: 6, delta_bits: [6, 6, 6], layout: &[
+        (RW, 5, 0), (GZ, 4, 4), (BZ, 0, 0), (BZ, 1, 1), (BY, 4, 4), (GW, 5, 0), (GY, 5, 5), (BY, 5, 5), (BZ, 2, 2),
+        (GY, 4, 4), (BW, 5, 0), (GZ, 5, 5), (BZ, 3, 3), (BZ, 5, 5), (BZ, 4, 4), (RX, 5, 0), (GY, 3, 0), (GX, 5, 0),
+        (GZ, 3, 0), (BX, 5, 0), (BY, 3, 0), (RY, 5, 0), (RZ, 5, 0),
+    ] },
+    Bc6hMode { id: 3, subsets: 1, transformed: false, endpoint_bits: 10, delta_bits: [10, 10, 10], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 9, 0), (GX, 9, 0), (BX, 9, 0),
+    ] },
+    Bc6hMode { id: 7, subsets: 1, transformed: true, endpoint_bits: 11, delta_bits: [9, 9, 9], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 8, 0), (RW, 10, 10), (GX, 8, 0), (GW, 10, 10), (BX, 8, 0), (BW, 10, 10),
+    ] },
+    Bc6hMode { id: 11, subsets: 1, transformed: true, endpoint_bits: 12, delta_bits: [8, 8, 8], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 7, 0), (RW, 10, 11), (GX, 7, 0), (GW, 10, 11), (BX, 7, 0), (BW, 10, 11),
+    ] },
+    Bc6hMode { id: 15, subsets: 1, transformed: true, endpoint_bits: 16, delta_bits: [4, 4, 4], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 3, 0), (RW, 10, 15), (GX, 3, 0), (GW, 10, 15), (BX, 3, 0), (BW, 10, 15),
+    ] },
+];
+
+// Half float 1.0, BC6H has no alpha
+const HALF_ONE: u16 = 0x3c00;
+
+fn sign_extend(value: i32, bits: u32) -> i32 {
+    (value << (32 - bits)) >> (32 - bits)
+}
+
+// Stretches an endpoint over the full 16 bits (or 15 and the sign) before interpolating
+fn bc6h_unquantize(value: i32, bits: u32, signed: bool) -> i32 {
+    if !signed {
+        if bits >= 15 {
+            value
+        } else if value == 0 {
+            0
+        } else if value == (1 << bits) - 1 {
+            0xffff
+        } else {
+            ((value << 16) + 0x8000) >> bits
+        }
+    } else {
+        if bits >= 16 {
+            return value;
+        }
+        let magnitude = value.abs();
+        let unquantized = if magnitude == 0 {
+            0
+        } else if magnitude >= (1 << (bits - 1)) - 1 {
+            0x7fff
+        } else {
+            ((magnitude << 15) + 0x4000) >> (bits - 1)
+        };
+        if value < 0 { -unquantized } else { unquantized }
+    }
+}
+
+// Scales an interpolated value to the bits of a half float
+fn bc6h_finish(value: i32, signed: bool) -> u16 {
+    if !signed {
+        ((value * 31) >> 6) as u16
+    } else {
+        let magnitude = ((value.abs() * 31) >> 5) as u16;
+        if value < 0 { 0x8000 | magnitude } else { magnitude }
+    }
+}
+
+fn bc6h_block(block: &[u8], texels: &mut [[u8; 8]; 16], signed: bool) {
+    let texel = |rgb: [u16; 3]| {
+        let mut bytes = [0u8; 8];
+        for (channel, value) in rgb.into_iter().chain([HALF_ONE]).enumerate() {
+            bytes[channel * 2..channel * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    };
+    let mut bits = Bits { value: u128::from_le_bytes(block.try_into().unwrap()), position: 0 };
+    let mut id = bits.read(2);
+    if id > 1 {
+        id |= bits.read(3) << 2;
+    }
+    let Some(mode) = BC6H_MODES.iter().find(|mode| mode.id == id) else {
+        // Reserved, decoders have to return black
+        *texels = [texel([0; 3]); 16];
+        return;
+    };
+
+    // w, x, y and z, red green and blue each
+    let mut endpoints = [[0i32; 3]; 4];
+    for &(field, a, b) in mode.layout {
+        let (endpoint, channel) = (field as usize / 3, field as usize % 3);
+        let mut bit = b as i32;
+        let step = if a >= b { 1 } else { -1 };
+        loop {
+            endpoints[endpoint][channel] |= (bits.read(1) as i32) << bit;
+            if bit == a as i32 {
+                break;
+            }
+            bit += step;
+        }
+    }
+    let endpoint_count = mode.subsets * 2;
+    if signed {
+        for value in endpoints[0].iter_mut() {
+            *value = sign_extend(*value, mode.endpoint_bits);
+        }
+    }
+    let base = endpoints[0];
+    for endpoint in endpoints.iter_mut().take(endpoint_count).skip(1) {
+        for (channel, value) in endpoint.iter_mut().enumerate() {
+            if mode.transformed {
+                let delta = sign_extend(*value, mode.delta_bits[channel]);
+                *value = (base[channel] + delta) & ((1 << mode.endpoint_bits) - 1);
+            }
+            if signed {
+                *value = sign_extend(*value, mode.endpoint_bits);
+            }
+        }
+    }
+    for endpoint in endpoints.iter_mut().take(endpoint_count) {
+        for value in endpoint.iter_mut() {
+            *value = bc6h_unquantize(*value, mode.endpoint_bits, signed);
+        }
+    }
+
+    let partition = if mode.subsets == 2 { bits.read(5) as usize } else { 0 };
+    let (index_bits, weights): (u32, &[u32]) = if mode.subsets == 2 { (3, &BC7_WEIGHTS_3) } else { (4, &BC7_WEIGHTS_4) };
+    for (i, out) in texels.iter_mut().enumerate() {
+        let is_anchor = i == 0 || (mode.subsets == 2 && i == BC7_ANCHORS_2[partition] as usize);
+        let weight = weights[bits.read(if is_anchor { index_bits - 1 } else { index_bits }) as usize] as i32;
+        let subset = if mode.subsets == 2 { (BC7_PARTITIONS_2[partition] >> i) as usize & 1 } else { 0 };
+        let (e0, e1) = (endpoints[subset * 2], endpoints[subset * 2 + 1]);
+        let mut rgb = [0u16; 3];
+        for (channel, value) in rgb.iter_mut().enumerate() {
+            *value = bc6h_finish(((64 - weight) * e0[channel] + weight * e1[channel] + 32) >> 6, signed);
+        }
+        *out = texel(rgb);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wgpu::TextureFormat::*;
+
+    // Packs fields from the lowest bit up, the way the BC6H and BC7 blocks read them
+    #[derive(Default)]
+    struct Writer {
+        value: u128,
+        position: u32,
+    }
+
+    impl Writer {
+        fn write(mut self, value: u32, count: u32) -> Self {
+            self.value |= (value as u128) << self.position;
+            self.position += count;
+            self
+        }
+
+        fn block(self) -> [u8; 16] {
+            assert!(self.position <= 128);
+            self.value.to_le_bytes()
+        }
+    }
+
+    fn texels(format: wgpu::TextureFormat, block: &[u8]) -> Vec<[u8; 4]> {
+        decode(format, block, 4, 4).unwrap().chunks_exact(4).map(|t| t.try_into().unwrap()).collect()
+    }
+
+    fn half_texels(format: wgpu::TextureFormat, block: &[u8]) -> Vec<[u16; 4]> {
+        decode(format, block, 4, 4)
+            .unwrap()
+            .chunks_exact(8)
+            .map(|t| [0, 1, 2, 3].map(|c| u16::from_le_bytes([t[c * 2], t[c * 2 + 1]])))
+            .collect()
+    }
+
+    #[test]
+    fn bc1_four_and_three_colours() {
+        // Red to blue, texels 0 to 3 use indices 0 to 3
+        let t = texels(Bc1RgbaUnorm, &[0x00, 0xf8, 0x1f, 0x00, 0xe4, 0, 0, 0]);
+        assert_eq!(&t[..4], &[[255, 0, 0, 255], [0, 0, 255, 255], [170, 0, 85, 255], [85, 0, 170, 255]]);
+        // With the endpoints swapped the fourth colour is transparent
+        let t = texels(Bc1RgbaUnorm, &[0x1f, 0x00, 0x00, 0xf8, 0xe4, 0, 0, 0]);
+        assert_eq!(&t[..4], &[[0, 0, 255, 255], [255, 0, 0, 255], [127, 0, 127, 255], [0, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn bc2_explicit_alpha() {
+        let mut block = [0u8; 16];
+        block[0] = 0x8f;
+        block[8..12].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        let t = texels(Bc2RgbaUnorm, &block);
+        assert_eq!(&t[..3], &[[255, 255, 255, 255], [255, 255, 255, 136], [255, 255, 255, 0]]);
+    }
+
+    #[test]
+    fn bc3_interpolated_alpha() {
+        // Indices 0, 1, 2 and 7 between 255 and 0
+        let mut block = [0u8; 16];
+        block[..4].copy_from_slice(&[255, 0, 0x88, 0x0e]);
+        let alpha: Vec<u8> = texels(Bc3RgbaUnorm, &block).iter().map(|t| t[3]).collect();
+        assert_eq!(&alpha[..4], &[255, 0, 218, 36]);
+        // With the first endpoint smaller, indices 6 and 7 are the extremes
+        block[..4].copy_from_slice(&[10, 20, 0x88, 0x7c]);
+        let alpha: Vec<u8> = texels(Bc3RgbaUnorm, &block).iter().map(|t| t[3]).collect();
+        assert_eq!(&alpha[..5], &[10, 20, 12, 0, 255]);
+    }
+
+    #[test]
+    fn bc4_and_bc5_channels() {
+        let t = texels(Bc4RUnorm, &[255, 0, 0x88, 0x0e, 0, 0, 0, 0]);
+        assert_eq!(&t[..4], &[[255, 0, 0, 255], [0, 0, 0, 255], [218, 0, 0, 255], [36, 0, 0, 255]]);
+        // -128 reads as -127
+        let t = texels(Bc4RSnorm, &[0x7f, 0x80, 0x88, 0x0e, 0, 0, 0, 0]);
+        assert_eq!(t[..3].iter().map(|t| t[0] as i8).collect::<Vec<_>>(), [127, -127, 90]);
+        assert_eq!(t[0][3], 127);
+        let t = texels(Bc5RgUnorm, &[255, 0, 0x88, 0x0e, 0, 0, 0, 0, 0, 255, 0x88, 0x0e, 0, 0, 0, 0]);
+        // The second channel goes up, so it uses the six value palette
+        assert_eq!(&t[..3], &[[255, 0, 0, 255], [0, 255, 0, 255], [218, 51, 0, 255]]);
+    }
+
+    #[test]
+    fn bc7_mode_6() {
+        let mut writer = Writer::default().write(1 << 6, 7);
+        for value in [0x7f, 0, 0, 0x7f, 0x40, 0x40, 0x7f, 0x7f] {
+            writer = writer.write(value, 7);
+        }
+        // P-bits, then indices 0, 15 and 8
+        let block = writer.write(1, 1).write(0, 1).write(0, 3).write(15, 4).write(8, 4).block();
+        let t = texels(Bc7RgbaUnorm, &block);
+        assert_eq!(&t[..3], &[[255, 1, 129, 255], [0, 254, 128, 254], [120, 135, 128, 254]]);
+    }
+
+    #[test]
+    fn bc7_mode_5_rotation() {
+        let mut writer = Writer::default().write(1 << 5, 6).write(1, 2);
+        for value in [0x7f, 0x7f, 0, 0, 0, 0] {
+            writer = writer.write(value, 7);
+        }
+        let block = writer.write(0x10, 8).write(0x10, 8).block();
+        // Red and alpha swap places
+        assert!(texels(Bc7RgbaUnorm, &block).iter().all(|t| *t == [16, 0, 0, 255]));
+    }
+
+    #[test]
+    fn bc7_mode_1_partitions() {
+        // Partition 0 puts the right two columns in the second subset
+        let mut writer = Writer::default().write(0b10, 2).write(0, 6);
+        for _ in 0..3 {
+            writer = writer.write(0, 6).write(0, 6).write(63, 6).write(63, 6);
+        }
+        let block = writer.write(0, 1).write(1, 1).block();
+        let t = texels(Bc7RgbaUnorm, &block);
+        assert_eq!(&t[..4], &[[0, 0, 0, 255], [0, 0, 0, 255], [255; 4], [255; 4]]);
+    }
+
+    #[test]
+    fn bc7_reserved_mode() {
+        assert!(texels(Bc7RgbaUnorm, &[0; 16]).iter().all(|t| *t == [0; 4]));
+    }
+
+    #[test]
+    fn bc6h_mode_11_explicit_endpoints() {
+        let block = Writer::default()
+            .write(3, 5)
+            .write(0x3ff, 10).write(0x200, 10).write(0, 10)
+            .write(0, 10).write(0, 10).write(0x3ff, 10)
+            .write(0, 3).write(15, 4)
+            .block();
+        let t = half_texels(Bc6hRgbUfloat, &block);
+        assert_eq!(&t[..2], &[[0x7bff, 0x3e0f, 0, 0x3c00], [0, 0, 0x7bff, 0x3c00]]);
+        assert_eq!(decode(Bc6hRgbUfloat, &block, 4, 4).unwrap().len(), 16 * 8);
+    }
+
+    #[test]
+    fn bc6h_signed_endpoints() {
+        // -1 and the largest positive value in 10 bits
+        let block = Writer::default().write(3, 5).write(0x3ff, 10).write(0x1ff, 10).write(0, 10).block();
+        let t = half_texels(Bc6hRgbSfloat, &block);
+        assert_eq!(t[0], [0x805d, 0x7bff, 0, 0x3c00]);
+    }
+
+    #[test]
+    fn bc6h_mode_1_deltas_and_partitions() {
+        // w is 256 red, x is one more and y one less, z the same as w
+        let block = Writer::default()
+            .write(0, 2)
+            .write(0, 3)
+            .write(0x100, 10).write(0, 10).write(0, 10)
+            .write(1, 5).write(0, 1).write(0, 4).write(0, 5).write(0, 1).write(0, 4).write(0, 5)
+            .write(0, 1).write(0, 4).write(0x1f, 5).write(0, 1).write(0, 5).write(0, 1)
+            .write(0, 5)
+            .write(0, 2).write(7, 3)
+            .block();
+        let t = half_texels(Bc6hRgbUfloat, &block);
+        // Texels 0 and 1 are in the first region, 2 in the second
+        assert_eq!(&t[..3], &[[0x1f0f, 0, 0, 0x3c00], [0x1f2e, 0, 0, 0x3c00], [0x1ef0, 0, 0, 0x3c00]]);
+    }
+
+    #[test]
+    fn bc6h_reserved_mode() {
+        let block = Writer::default().write(19, 5).block();
+        assert!(half_texels(Bc6hRgbUfloat, &block).iter().all(|t| *t == [0, 0, 0, 0x3c00]));
+    }
+
+    #[test]
+    fn edge_blocks_are_clipped() {
+        let rgba = decode(Bc1RgbaUnorm, &[0x00, 0xf8, 0x00, 0xf8, 0, 0, 0, 0], 2, 2).unwrap();
+        assert_eq!(rgba, [255, 0, 0, 255].repeat(4));
+        assert!(decode(Bc1RgbaUnorm, &[0; 7], 2, 2).is_err());
+    }
+}
@@ -1,19 +1,10 @@
-use std::time::Instant;
-
-use cgmath::InnerSpace;
+use cgmath::{InnerSpace, SquareMatrix, Transform};
 use wgpu::util::DeviceExt;
 use winit::{event::{WindowEvent, ElementState, VirtualKeyCode, KeyboardInput, DeviceEvent}, window::Window};
 
-pub struct Camera {
-    pub eye: cgmath::Point3<f32>,
-    pub target: cgmath::Point3<f32>,
-    pub rotation: cgmath::Vector2<f32>, // x for horizontal rotation, y for vertical rotation
-    pub up: cgmath::Vector3<f32>,
-    pub aspect: f32,
-    pub fovy: f32,
-    pub znear: f32,
-    pub zfar: f32,
-}
+use super::{culling, fog};
+pub use crate::world::Camera;
+use crate::world::PlayerInput;
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -24,47 +15,41 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
 );
 
 
+// The projection and GPU side of the world's camera
 impl Camera {
-    pub fn new(pos: cgmath::Vector3<f32>, rotation: f32, aspect: f32, fovy: f32) -> Self {
-        let mut target = pos;
-        let rotation = rotation.to_radians();
-        target.x -= rotation.sin();
-        target.z -= rotation.cos();
-        Self {
-            aspect: aspect,
-            fovy: 45.0,
-            zfar: 100.0,
-            znear: 0.1,
-            eye: cgmath::Point3::new(pos.x,pos.y,pos.z),
-            rotation: cgmath::Vector2::new(rotation, 0.0),
-            target: cgmath::Point3::new(target.x,target.y,target.z),
-            up: cgmath::Vector3::unit_y(),
-        }
-    }
-
     pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
 
         let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
 
-        return OPENGL_TO_WGPU_MATRIX * proj * view
+        OPENGL_TO_WGPU_MATRIX * proj * view
     }
 
-    pub fn resize(&mut self, width: u32, height: u32) {
-        self.aspect = width as f32 / height as f32;
+    // The ray through a pixel, from the near plane outwards. Pixels count from the top left.
+    pub fn ray(&self, pixel: [f32; 2], size: [f32; 2]) -> culling::Ray {
+        let inverse = self.build_view_projection_matrix().invert().unwrap_or_else(cgmath::Matrix4::identity);
+        let x = pixel[0] / size[0] * 2.0 - 1.0;
+        let y = 1.0 - pixel[1] / size[1] * 2.0;
+        let near = inverse.transform_point(cgmath::point3(x, y, 0.0));
+        let far = inverse.transform_point(cgmath::point3(x, y, 1.0));
+        culling::Ray { origin: near, direction: (far - near).normalize() }
     }
 
-    pub fn update_target(&mut self) {
-        // (cos(pitch)cos(yaw), cos(pitch)sin(yaw), sin(pitch))
-        let pitch = self.rotation.x.to_radians();
-        let yaw = self.rotation.y.to_radians();
-        let target: cgmath::Point3<f32> = cgmath::point3(pitch.cos()*yaw.cos(), pitch.cos()*yaw.sin(), pitch.sin());
-        self.target = cgmath::point3(self.eye.x+target.x, self.eye.y+target.y, self.eye.z+target.z);
+    // Where the point ends up on screen in pixels, None when it's behind the camera
+    pub fn project(&self, point: cgmath::Point3<f32>, size: [f32; 2]) -> Option<[f32; 2]> {
+        let clip = self.build_view_projection_matrix() * point.to_homogeneous();
+        if clip.w <= self.znear {
+            return None;
+        }
+        Some([
+            (clip.x / clip.w + 1.0) / 2.0 * size[0],
+            (1.0 - clip.y / clip.w) / 2.0 * size[1],
+        ])
     }
 
     pub fn create_camera_buffers_and_uniform(&self, device: &wgpu::Device) -> (CameraUniform, wgpu::Buffer, wgpu::BindGroupLayout, wgpu::BindGroup) {
         let mut camera_uniform = CameraUniform::new();
-        camera_uniform.update_view_proj(&self);
+        camera_uniform.update_view_proj(self);
         
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Camera Buffer"),
@@ -98,7 +83,7 @@ impl Camera {
             ],
             label: Some("camera_bind_group"),
         });
-        return (camera_uniform, camera_buffer, camera_bind_group_layout, camera_bind_group);
+        (camera_uniform, camera_buffer, camera_bind_group_layout, camera_bind_group)
     }
 }
 
@@ -107,6 +92,7 @@ impl Camera {
 pub struct CameraUniform {
     pub view_position: [f32; 4],
     pub view_proj: [[f32; 4]; 4],
+    pub fog: fog::FogUniform,
 }
 
 impl CameraUniform {
@@ -115,6 +101,7 @@ impl CameraUniform {
         Self {
             view_position: [0.0; 4],
             view_proj: cgmath::Matrix4::identity().into(),
+            fog: fog::FogUniform::new(&fog::FogSettings::default()),
         }
     }
 
@@ -122,122 +109,56 @@ impl CameraUniform {
         self.view_position = camera.eye.to_homogeneous().into();
         self.view_proj = (OPENGL_TO_WGPU_MATRIX * camera.build_view_projection_matrix()).into();
     }
-}
 
-pub struct CameraController {
-    speed: f32,
-    mouse_speed: f32,
-    is_forward_pressed: bool,
-    is_backwards_pressed: bool,
-    is_left_pressed: bool,
-    is_right_pressed: bool,
-    mouse_delta: (f64, f64),
-    mouse_event: bool,
-    last_frame: Instant,
-} 
-impl CameraController {
-    pub fn new(speed: f32, mouse_speed: f32) -> Self {
-
-        Self { 
-            speed: speed,
-            mouse_speed: mouse_speed,
-            is_forward_pressed: false,
-            is_backwards_pressed: false,
-            is_left_pressed: false,
-            is_right_pressed: false,
-            mouse_delta: (0.0,0.0),
-            mouse_event: false,
-            last_frame: Instant::now(),
-        }
+    pub fn update_fog(&mut self, settings: &fog::FogSettings) {
+        self.fog = fog::FogUniform::new(settings);
     }
+}
 
-    pub fn process_event(&mut self, event: &WindowEvent, window: &Window) -> bool {
-        match event {
-            WindowEvent::KeyboardInput {
-                input: KeyboardInput {
-                    state,
-                    virtual_keycode: Some(keycode),
-                    ..
-                },
+// Fills in the player's input from the window's keyboard events
+pub fn process_event(input: &mut PlayerInput, event: &WindowEvent, window: &Window) -> bool {
+    match event {
+        WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+                state,
+                virtual_keycode: Some(keycode),
                 ..
-            } => {
-                let is_pressed = *state == ElementState::Pressed;
-                match keycode {
-                    VirtualKeyCode::W | VirtualKeyCode::Up => {
-                        self.is_forward_pressed = is_pressed;
-                        true
-                    }
-                    VirtualKeyCode::A | VirtualKeyCode::Left => {
-                        self.is_left_pressed = is_pressed;
-                        true
-                    }
-                    VirtualKeyCode::S | VirtualKeyCode::Down => {
-                        self.is_backwards_pressed = is_pressed;
-                        true
-                    }
-                    VirtualKeyCode::D | VirtualKeyCode::Right => {
-                        self.is_right_pressed = is_pressed;
-                        true
-                    }
-                    VirtualKeyCode::F10 => {
-                        window.set_cursor_grab(true);
-                        true
-                    }
-                    VirtualKeyCode::F11 => {
-                        window.set_fullscreen(Some(winit::window::Fullscreen::Borderless(None)));
-                        true
+            },
+            ..
+        } => {
+            let is_pressed = *state == ElementState::Pressed;
+            match keycode {
+                VirtualKeyCode::W | VirtualKeyCode::Up => {
+                    input.forward = is_pressed;
+                    true
+                }
+                VirtualKeyCode::S | VirtualKeyCode::Down => {
+                    input.backwards = is_pressed;
+                    true
+                }
+                VirtualKeyCode::F10 => {
+                    if let Err(e) = window.set_cursor_grab(true) {
+                        log::warn!("Couldn't grab the cursor: {}", e);
                     }
-                    _ => false,
+                    true
+                }
+                VirtualKeyCode::F11 => {
+                    window.set_fullscreen(Some(winit::window::Fullscreen::Borderless(None)));
+                    true
                 }
+                _ => false,
             }
-            _ => false,
-        }
-    }
-
-    pub fn process_mouse_event(&mut self, event: &DeviceEvent) -> bool {
-        match event {
-            DeviceEvent::MouseMotion { delta } => {
-                self.mouse_delta = *delta;
-                self.mouse_event = true;
-                true
-            },
-            _ => {false}
         }
+        _ => false,
     }
+}
 
-    pub fn update_camera(&mut self, camera: &mut Camera) {
-        let delta_s = self.last_frame.elapsed();
-        self.last_frame = Instant::now();
-        
-        if self.mouse_event {
-            self.mouse_event = false;
-            let mut delta: cgmath::Vector2<f64> = self.mouse_delta.into();
-            delta.y *= -1.0;
-            delta *= self.mouse_speed as f64;
-            let delta: cgmath::Vector2<f32> = cgmath::Vector2 { x: delta.x as f32, y: delta.y as f32 };
-            camera.rotation.x += delta.x;
-            camera.rotation.y -= delta.y;
-            camera.rotation.y = camera.rotation.y.clamp(-80., 80.);
-            camera.rotation.x = camera.rotation.x % 720.;
-            camera.update_target();
-        }
-        
-        let forward = camera.eye - camera.target;
-        let forward_norm = forward.normalize();
-
-        if self.is_forward_pressed {
-            camera.eye -= forward_norm * self.speed;
-            camera.target -= forward_norm * self.speed;
-        }
-        if self.is_backwards_pressed {
-            camera.eye += forward_norm * self.speed;
-            camera.target += forward_norm * self.speed;
-        }
-        if self.is_left_pressed {
-            
-        }
-        if self.is_right_pressed {
-            
-        }
+pub fn process_mouse_event(input: &mut PlayerInput, event: &DeviceEvent) -> bool {
+    match event {
+        DeviceEvent::MouseMotion { delta } => {
+            input.mouse_delta = Some(*delta);
+            true
+        },
+        _ => {false}
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,405 @@
+use super::{camera, culling, light, instances, lod, model, render_pipeline::DepthPipelines};
+use cgmath::{InnerSpace, SquareMatrix};
+use std::{mem, num::NonZeroU32};
+
+pub const MAX_CASCADES: usize = 4;
+
+// Cascaded shadow maps for the sun, every cascade covers a slice of the camera frustum
+pub struct CascadedShadow {
+    pipelines: DepthPipelines,
+    cascade_targets: Vec<CascadeTarget>,
+    sun_buffer: wgpu::Buffer,
+    cascade_size: u32,
+    instance_buffer: instances::InstanceBuffer,
+
+    pub cascade_count: usize,
+    pub shadow_distance: f32,
+    pub split_lambda: f32, // 0 is uniform splits, 1 is fully logarithmic
+    pub blend_width: f32,  // Fraction of a cascade that blends into the next one
+
+    pub ext_bind_group: wgpu::BindGroup,
+    pub ext_bind_group_layout: wgpu::BindGroupLayout,
+}
+
+struct CascadeTarget {
+    view: wgpu::TextureView,
+    uniform_buf: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    // From the last update, for culling
+    frustum: culling::Frustum,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SunUniform {
+    pub direction: [f32; 3],
+    pub intensity: f32,
+    pub color: [f32; 3],
+    pub cascade_count: u32,
+    pub camera_forward: [f32; 3],
+    pub blend_width: f32,
+    pub splits: [f32; MAX_CASCADES],
+    pub texel_size: f32,
+    pub _padding: [u32; 3],
+    pub view_proj: [[[f32; 4]; 4]; MAX_CASCADES],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CascadeUniform {
+    view_proj: [[f32; 4]; 4],
+}
+
+impl CascadedShadow {
+    pub fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        vertex_layouts: &[wgpu::VertexBufferLayout],
+        material_layout: &wgpu::BindGroupLayout,
+        cascade_count: usize,
+        cascade_size: u32,
+    ) -> Self {
+        let cascade_count = cascade_count.clamp(1, MAX_CASCADES);
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Internal Cascade Bind Group"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+
+        let pipelines = DepthPipelines::new(
+            device,
+            "cascade shadow",
+            &bind_group_layout,
+            material_layout,
+            shader,
+            "vs_bake",
+            vertex_layouts,
+            wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                // Casters in front of the near plane get flattened onto it instead of clipped
+                unclipped_depth: device
+                    .features()
+                    .contains(wgpu::Features::DEPTH_CLIP_CONTROL),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                ..Default::default()
+            },
+            wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            },
+            None,
+        );
+
+        let cascade_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: cascade_size,
+                height: cascade_size,
+                depth_or_array_layers: MAX_CASCADES as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Depth32Float,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: Some("Cascade Shadow Texture"),
+        });
+        let cascade_view = cascade_texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Cascade Shadow View"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let cascade_targets = (0..MAX_CASCADES)
+            .map(|i| {
+                let view = cascade_texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("Cascade View"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    aspect: wgpu::TextureAspect::DepthOnly,
+                    base_mip_level: 0,
+                    mip_level_count: None,
+                    base_array_layer: i as u32,
+                    array_layer_count: NonZeroU32::new(1),
+                    format: None,
+                });
+                let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Cascade Projection Buffer"),
+                    size: mem::size_of::<CascadeUniform>() as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buf.as_entire_binding(),
+                    }],
+                    label: None,
+                });
+                CascadeTarget { view, uniform_buf, bind_group, frustum: culling::Frustum::from_matrix(cgmath::Matrix4::identity()) }
+            })
+            .collect::<Vec<_>>();
+
+        let sun_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sun Buffer"),
+            size: mem::size_of::<SunUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let cascade_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Cascade Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let pub_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("External Cascade Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+            ],
+        });
+
+        let pub_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("External Cascade Bind Group"),
+            layout: &pub_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: sun_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&cascade_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&cascade_sampler),
+                },
+            ],
+        });
+
+        Self {
+            pipelines,
+            cascade_targets,
+            sun_buffer,
+            cascade_size,
+            instance_buffer: instances::InstanceBuffer::with_capacity(device, 1),
+            cascade_count,
+            shadow_distance: 60.0,
+            split_lambda: 0.75,
+            blend_width: 0.1,
+            ext_bind_group: pub_bind_group,
+            ext_bind_group_layout: pub_bind_group_layout,
+        }
+    }
+
+    // Far distance of every cascade, blends the uniform and logarithmic split schemes
+    pub fn calculate_splits(&self, near: f32, far: f32) -> [f32; MAX_CASCADES] {
+        let mut splits = [far; MAX_CASCADES];
+        for (i, split) in splits.iter_mut().enumerate().take(self.cascade_count) {
+            let p = (i + 1) as f32 / self.cascade_count as f32;
+            let log = near * (far / near).powf(p);
+            let uniform = near + (far - near) * p;
+            *split = self.split_lambda * log + (1.0 - self.split_lambda) * uniform;
+        }
+        splits
+    }
+
+    // Fits an orthographic projection around the part of the camera frustum between near
+    // and far. It's fitted to a bounding sphere so the size doesn't change when the camera
+    // rotates, and the origin is snapped to whole texels so the edges don't shimmer when
+    // it moves.
+    pub fn calculate_cascade(
+        &self,
+        camera: &camera::Camera,
+        direction: cgmath::Vector3<f32>,
+        near: f32,
+        far: f32,
+    ) -> cgmath::Matrix4<f32> {
+        let forward = (camera.target - camera.eye).normalize();
+        let right = forward.cross(camera.up).normalize();
+        let up = right.cross(forward);
+        let tan_half_fovy = (camera.fovy.to_radians() * 0.5).tan();
+
+        let mut corners = Vec::with_capacity(8);
+        for distance in [near, far] {
+            let half_height = distance * tan_half_fovy;
+            let half_width = half_height * camera.aspect;
+            let center = camera.eye + forward * distance;
+            for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                corners.push(center + right * half_width * x + up * half_height * y);
+            }
+        }
+
+        let center = corners
+            .iter()
+            .fold(cgmath::Vector3::new(0.0, 0.0, 0.0), |acc, c| acc + cgmath::vec3(c.x, c.y, c.z))
+            / corners.len() as f32;
+        let center = cgmath::point3(center.x, center.y, center.z);
+        let radius = corners
+            .iter()
+            .map(|c| (c - center).magnitude())
+            .fold(0.0f32, f32::max);
+        // Round the radius up a bit, so float noise doesn't change the size every frame
+        let radius = (radius * 16.0).ceil() / 16.0;
+
+        let light_up = if direction.y.abs() > 0.99 {
+            cgmath::Vector3::unit_z()
+        } else {
+            cgmath::Vector3::unit_y()
+        };
+        // A fixed light space around the origin, only the projection follows the camera
+        let light_view = cgmath::Matrix4::look_to_rh(cgmath::point3(0.0, 0.0, 0.0), direction, light_up);
+        let light_center = light_view * center.to_homogeneous();
+
+        let texel_size = 2.0 * radius / self.cascade_size as f32;
+        let snapped_x = (light_center.x / texel_size).floor() * texel_size;
+        let snapped_y = (light_center.y / texel_size).floor() * texel_size;
+
+        // Shadow casters behind the camera frustum still need to end up in the map
+        let caster_margin = self.shadow_distance;
+        let projection = cgmath::ortho(
+            snapped_x - radius,
+            snapped_x + radius,
+            snapped_y - radius,
+            snapped_y + radius,
+            -light_center.z - radius - caster_margin,
+            -light_center.z + radius,
+        );
+
+        camera::OPENGL_TO_WGPU_MATRIX * projection * light_view
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &camera::Camera, sun: &light::DirectionalLight) {
+        let near = camera.znear;
+        let far = camera.zfar.min(self.shadow_distance);
+        let splits = self.calculate_splits(near, far);
+
+        let mut view_proj = [cgmath::Matrix4::identity().into(); MAX_CASCADES];
+        let mut cascade_near = near;
+        for i in 0..self.cascade_count {
+            let matrix = self.calculate_cascade(camera, sun.direction, cascade_near, splits[i]);
+            view_proj[i] = matrix.into();
+            self.cascade_targets[i].frustum = culling::Frustum::from_matrix(matrix);
+            queue.write_buffer(&self.cascade_targets[i].uniform_buf, 0, bytemuck::cast_slice(&[CascadeUniform {
+                view_proj: view_proj[i],
+            }]));
+            cascade_near = splits[i];
+        }
+
+        let forward = (camera.target - camera.eye).normalize();
+        queue.write_buffer(&self.sun_buffer, 0, bytemuck::cast_slice(&[SunUniform {
+            direction: sun.direction.into(),
+            intensity: sun.intensity,
+            color: sun.color.into(),
+            cascade_count: self.cascade_count as u32,
+            camera_forward: forward.into(),
+            blend_width: self.blend_width,
+            splits,
+            texel_size: 1.0 / self.cascade_size as f32,
+            _padding: [0; 3],
+            view_proj,
+        }]));
+    }
+
+    pub fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &[model::ModelInstances],
+    ) -> culling::CullStats {
+        let mut stats = culling::CullStats::default();
+        let mut raws = Vec::new();
+        let mut ranges = Vec::with_capacity(self.cascade_count);
+        for target in self.cascade_targets.iter().take(self.cascade_count) {
+            ranges.push(
+                scene
+                    .iter()
+                    .map(|instances| {
+                        let mut visible = culling::visible_instances(&[target.frustum], &instances.model.bounds, instances.transforms);
+                        stats.add(visible.len(), instances.transforms.len());
+                        lod::append_by_level(&mut raws, &mut visible, instances.transforms, instances.lods)
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+        self.instance_buffer.write(device, queue, &raws);
+
+        use model::DrawDepth;
+        encoder.push_debug_group("cascade shadow passes");
+        for (i, (target, ranges)) in self.cascade_targets.iter().zip(ranges).enumerate() {
+            encoder.push_debug_group(&format!("cascade pass {}", i));
+            {
+                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[],
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: &target.view,
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(1.0),
+                            store: true,
+                        }),
+                        stencil_ops: None,
+                    }),
+                });
+
+                pass.set_bind_group(0, &target.bind_group, &[]);
+                for (instances, ranges) in scene.iter().zip(&ranges) {
+                    pass.draw_model_depth(instances.model, &lod::direct_batches(&self.instance_buffer.buffer, ranges), &self.pipelines, true);
+                }
+            }
+            encoder.pop_debug_group();
+        }
+        encoder.pop_debug_group();
+        stats
+    }
+}
@@ -0,0 +1,276 @@
+// Visibility tests on the CPU. Every pass keeps only the instances whose bounds touch one
+// of its frusta and draws those from a compacted instance buffer.
+use cgmath::{EuclideanSpace, InnerSpace, Transform};
+
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: cgmath::Point3<f32>,
+    pub max: cgmath::Point3<f32>,
+}
+
+impl Aabb {
+    pub fn from_points(points: impl IntoIterator<Item = [f32; 3]>) -> Self {
+        let mut min = cgmath::Point3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = cgmath::Point3::new(f32::MIN, f32::MIN, f32::MIN);
+        for point in points {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(point[axis]);
+                max[axis] = max[axis].max(point[axis]);
+            }
+        }
+        if min.x > max.x {
+            // No points at all, keep it a valid box
+            min = cgmath::Point3::origin();
+            max = min;
+        }
+        Self { min, max }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: cgmath::Point3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: cgmath::Point3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    pub fn center(&self) -> cgmath::Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn extents(&self) -> cgmath::Vector3<f32> {
+        (self.max - self.min) / 2.0
+    }
+
+    // Box around the transformed box, the extents are projected on every world axis
+    pub fn transformed(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        let center = matrix.transform_point(self.center());
+        let extents = self.extents();
+        let mut world_extents = cgmath::Vector3::new(0.0, 0.0, 0.0);
+        for axis in 0..3 {
+            world_extents[axis] = matrix.x[axis].abs() * extents.x
+                + matrix.y[axis].abs() * extents.y
+                + matrix.z[axis].abs() * extents.z;
+        }
+        Self { min: center - world_extents, max: center + world_extents }
+    }
+    // How far along the ray it enters the box, zero when it starts inside. Slab test, one
+    // axis at a time.
+    pub fn ray_distance(&self, ray: &Ray) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = f32::MAX;
+        for axis in 0..3 {
+            let (origin, direction) = (ray.origin[axis], ray.direction[axis]);
+            if direction == 0.0 {
+                // Parallel to this slab, it either runs inside of it or misses the box
+                if origin < self.min[axis] || origin > self.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (self.min[axis] - origin) / direction;
+            let t1 = (self.max[axis] - origin) / direction;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+}
+
+// Half line, `origin + direction * t` for t >= 0
+#[derive(Debug, Copy, Clone)]
+pub struct Ray {
+    pub origin: cgmath::Point3<f32>,
+    pub direction: cgmath::Vector3<f32>,
+}
+
+impl Ray {
+    // The direction doesn't get normalized again, so a t along the transformed ray is the
+    // same point as that t along this one
+    pub fn transformed(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        Self {
+            origin: matrix.transform_point(self.origin),
+            direction: matrix.transform_vector(self.direction),
+        }
+    }
+}
+
+// Planes pointing inwards, as (normal, distance) so inside means dot(normal, p) + distance >= 0
+#[derive(Debug, Copy, Clone)]
+pub struct Frustum {
+    planes: [cgmath::Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // Works for any wgpu view projection, depth going from 0 to 1
+    pub fn from_matrix(matrix: cgmath::Matrix4<f32>) -> Self {
+        use cgmath::Matrix;
+        let (r0, r1, r2, r3) = (matrix.row(0), matrix.row(1), matrix.row(2), matrix.row(3));
+        let mut planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2];
+        for plane in &mut planes {
+            let length = plane.truncate().magnitude();
+            if length > f32::EPSILON {
+                *plane /= length;
+            }
+        }
+        Self { planes }
+    }
+
+    pub fn planes(&self) -> [cgmath::Vector4<f32>; 6] {
+        self.planes
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane normal
+            let corner = cgmath::Vector3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CullStats {
+    pub drawn: u32,
+    pub culled: u32,
+}
+
+impl CullStats {
+    pub fn add(&mut self, drawn: usize, total: usize) {
+        self.drawn += drawn as u32;
+        self.culled += (total - drawn) as u32;
+    }
+}
+
+impl std::ops::AddAssign for CullStats {
+    fn add_assign(&mut self, other: Self) {
+        self.drawn += other.drawn;
+        self.culled += other.culled;
+    }
+}
+
+// Indices of the instances, given by their world matrices, that end up in at least one of the
+// frusta. A model's bounds are tested as a whole, so a visible instance always draws every mesh.
+pub fn visible_instances(frusta: &[Frustum], bounds: &Aabb, transforms: &[cgmath::Matrix4<f32>]) -> Vec<usize> {
+    transforms
+        .iter()
+        .enumerate()
+        .filter(|(_, transform)| {
+            let world_bounds = bounds.transformed(transform);
+            frusta.iter().any(|frustum| frustum.intersects_aabb(&world_bounds))
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aabb(min: [f32; 3], max: [f32; 3]) -> Aabb {
+        Aabb { min: min.into(), max: max.into() }
+    }
+
+    fn assert_close(a: cgmath::Point3<f32>, b: [f32; 3]) {
+        assert!((0..3).all(|axis| (a[axis] - b[axis]).abs() < 1e-5), "{:?} isn't {:?}", a, b);
+    }
+
+    // Looking down -z with a 90 degree field of view, near 1 and far 100, so the side planes
+    // go through x = ±z and y = ±z
+    fn frustum() -> Frustum {
+        let view = cgmath::Matrix4::look_at_rh(cgmath::point3(0.0, 0.0, 0.0), cgmath::point3(0.0, 0.0, -1.0), cgmath::Vector3::unit_y());
+        let projection = cgmath::perspective(cgmath::Deg(90.0), 1.0, 1.0, 100.0);
+        Frustum::from_matrix(crate::window::camera::OPENGL_TO_WGPU_MATRIX * projection * view)
+    }
+
+    #[test]
+    fn frustum_inside_and_outside() {
+        let frustum = frustum();
+        assert!(frustum.intersects_aabb(&aabb([-1.0, -1.0, -11.0], [1.0, 1.0, -9.0])));
+        // Behind the camera, past the far plane, and off to the left
+        assert!(!frustum.intersects_aabb(&aabb([-1.0, -1.0, 9.0], [1.0, 1.0, 11.0])));
+        assert!(!frustum.intersects_aabb(&aabb([-1.0, -1.0, -201.0], [1.0, 1.0, -199.0])));
+        assert!(!frustum.intersects_aabb(&aabb([-21.0, -1.0, -11.0], [-19.0, 1.0, -9.0])));
+        // Above the top plane
+        assert!(!frustum.intersects_aabb(&aabb([-1.0, 12.0, -11.0], [1.0, 14.0, -9.0])));
+    }
+
+    #[test]
+    fn frustum_straddling() {
+        let frustum = frustum();
+        // Across the left plane, the near plane and the far plane
+        assert!(frustum.intersects_aabb(&aabb([-11.0, -1.0, -11.0], [-9.5, 1.0, -9.0])));
+        assert!(frustum.intersects_aabb(&aabb([-0.1, -0.1, -2.0], [0.1, 0.1, -0.5])));
+        assert!(frustum.intersects_aabb(&aabb([-1.0, -1.0, -101.0], [1.0, 1.0, -99.0])));
+        // Around the whole frustum
+        assert!(frustum.intersects_aabb(&aabb([-500.0; 3], [500.0; 3])));
+    }
+
+    #[test]
+    fn aabb_transformed() {
+        let bounds = aabb([0.0, 0.0, 0.0], [2.0, 1.0, 1.0]);
+        let rotated = bounds.transformed(&cgmath::Matrix4::from_angle_y(cgmath::Deg(90.0)));
+        assert_close(rotated.min, [0.0, 0.0, -2.0]);
+        assert_close(rotated.max, [1.0, 1.0, 0.0]);
+
+        let matrix = cgmath::Matrix4::from_translation(cgmath::vec3(10.0, 0.0, 0.0)) * cgmath::Matrix4::from_scale(2.0);
+        let moved = bounds.transformed(&matrix);
+        assert_close(moved.min, [10.0, 0.0, 0.0]);
+        assert_close(moved.max, [14.0, 2.0, 2.0]);
+
+        // Rotated by 45 degrees the box has to grow to still hold the corners
+        let grown = aabb([-1.0; 3], [1.0; 3]).transformed(&cgmath::Matrix4::from_angle_z(cgmath::Deg(45.0)));
+        let half_diagonal = 2.0f32.sqrt();
+        assert_close(grown.max, [half_diagonal, half_diagonal, 1.0]);
+    }
+
+    #[test]
+    fn aabb_from_points_and_union() {
+        let bounds = Aabb::from_points([[1.0, -2.0, 3.0], [-1.0, 2.0, 0.0]]);
+        assert_close(bounds.min, [-1.0, -2.0, 0.0]);
+        assert_close(bounds.max, [1.0, 2.0, 3.0]);
+        let empty = Aabb::from_points([]);
+        assert_close(empty.min, [0.0; 3]);
+        assert_close(empty.max, [0.0; 3]);
+        let union = bounds.union(&aabb([5.0; 3], [6.0; 3]));
+        assert_close(union.max, [6.0; 3]);
+        assert_close(union.center(), [2.5, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn visible_instances_per_transform() {
+        let bounds = aabb([-1.0; 3], [1.0; 3]);
+        let transforms = [
+            cgmath::Matrix4::from_translation(cgmath::vec3(0.0, 0.0, -10.0)),
+            cgmath::Matrix4::from_translation(cgmath::vec3(0.0, 0.0, 10.0)),
+            cgmath::Matrix4::from_translation(cgmath::vec3(-30.0, 0.0, -10.0)),
+        ];
+        assert_eq!(visible_instances(&[frustum()], &bounds, &transforms), [0]);
+        assert!(visible_instances(&[], &bounds, &transforms).is_empty());
+    }
+
+    #[test]
+    fn ray_distance_hits_and_misses() {
+        let bounds = aabb([-1.0; 3], [1.0; 3]);
+        let ray = |origin: [f32; 3], direction: [f32; 3]| Ray { origin: origin.into(), direction: direction.into() };
+        assert_eq!(bounds.ray_distance(&ray([0.0, 0.0, 5.0], [0.0, 0.0, -1.0])), Some(4.0));
+        // Starting inside
+        assert_eq!(bounds.ray_distance(&ray([0.5, 0.0, 0.0], [1.0, 0.0, 0.0])), Some(0.0));
+        // Pointing away, passing beside it, and parallel to a slab outside of it
+        assert_eq!(bounds.ray_distance(&ray([0.0, 0.0, 5.0], [0.0, 0.0, 1.0])), None);
+        assert_eq!(bounds.ray_distance(&ray([0.0, 3.0, 5.0], [0.0, 0.1, -1.0])), None);
+        assert_eq!(bounds.ray_distance(&ray([2.0, 0.0, 5.0], [0.0, 0.0, -1.0])), None);
+        // Diagonal through a corner region
+        let hit = bounds.ray_distance(&ray([3.0, 3.0, 0.0], [-1.0, -1.0, 0.0])).unwrap();
+        assert!((hit - 2.0).abs() < 1e-5);
+        // Transformed rays keep their distances
+        let moved = ray([0.0, 0.0, 5.0], [0.0, 0.0, -1.0]).transformed(&cgmath::Matrix4::from_translation(cgmath::vec3(0.0, 0.0, 1.0)));
+        assert_eq!(bounds.ray_distance(&moved), Some(5.0));
+    }
+}
@@ -0,0 +1,859 @@
+// The level editor, toggled with F1. Entities get picked with the mouse, moved around with
+// the gizmos and edited in the inspector. Every change goes through the history so it can
+// be undone, and the world gets saved back as a map.
+use std::collections::HashMap;
+
+use cgmath::{EuclideanSpace, InnerSpace, Rotation3, SquareMatrix};
+use winit::event::{ElementState, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent};
+
+use crate::world::{self, material::{MaterialKey, MaterialSettings}, scene, Entity, ModelId, World};
+use super::{camera, culling, gizmo, instances, light, model, resources};
+
+// Half the size of the cube lights get drawn as
+const LIGHT_PICK_SIZE: f32 = 0.25;
+// Changes that can be undone, the oldest get dropped
+const MAX_HISTORY: usize = 100;
+// How far in front of the camera spawned models show up
+const SPAWN_DISTANCE: f32 = 5.0;
+
+// What the editor works on, borrowed from the State for as long as an edit takes. The models
+// are only looked at, material changes go into the world and the State applies them.
+pub struct EditorScene<'a> {
+    pub world: &'a mut World,
+    pub models: &'a [model::Model],
+}
+
+// Changes made in the editor's windows, applied by the State after drawing
+pub enum EditorCommand {
+    Select(Option<Entity>),
+    SetTransform(Entity, instances::Instance),
+    SetLight(Entity, light::Light),
+    SetMaterial(MaterialKey, MaterialSettings),
+    Delete(Entity),
+    Undo,
+    Redo,
+    // These need the State, the models get loaded and the map saved there
+    SpawnModel(String),
+    Save(std::path::PathBuf),
+}
+
+// Everything needed to bring a deleted entity back
+#[derive(Clone)]
+struct Snapshot {
+    entity: Entity,
+    name: String,
+    // The local transform and the parent
+    transform: Option<(instances::Instance, Option<Entity>)>,
+    model: Option<ModelId>,
+    light: Option<light::Light>,
+    // What was parented to it, with the transforms they had under it
+    children: Vec<(Entity, instances::Instance)>,
+}
+
+impl Snapshot {
+    fn take(world: &World, entity: Entity) -> Self {
+        let node = world.transforms.get(entity).and_then(|transform| world.scene.node(transform.node));
+        let entity_of = entity_of_node(world);
+        Self {
+            entity,
+            name: node.map(|node| node.name.clone()).unwrap_or_default(),
+            transform: node.map(|node| (*node.local(), node.parent().and_then(|parent| entity_of.get(&parent).copied()))),
+            model: world.mesh_renderers.get(entity).map(|renderer| renderer.model),
+            light: world.lights.get(entity).copied(),
+            children: node
+                .map(|node| node.children())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|child| Some((*entity_of.get(child)?, *world.scene.node(*child)?.local())))
+                .collect(),
+        }
+    }
+
+    // Spawns it again, the entity comes back under a new handle
+    fn restore(&self, world: &mut World) -> Entity {
+        let entity = world.spawn();
+        if let Some((local, parent)) = self.transform {
+            world.set_transform(entity, &self.name, local, parent);
+        }
+        if let Some(model) = self.model {
+            world.mesh_renderers.insert(entity, world::MeshRenderer { model });
+        }
+        if let Some(light) = self.light {
+            world.lights.insert(entity, light);
+        }
+        for (child, local) in &self.children {
+            if world.is_alive(*child) {
+                world.set_transform(*child, "", *local, Some(entity));
+            }
+        }
+        entity
+    }
+
+    fn remap(&mut self, old: Entity, new: Entity) {
+        remap(&mut self.entity, old, new);
+        if let Some((_, Some(parent))) = &mut self.transform {
+            remap(parent, old, new);
+        }
+        for (child, _) in &mut self.children {
+            remap(child, old, new);
+        }
+    }
+}
+
+fn remap(entity: &mut Entity, old: Entity, new: Entity) {
+    if *entity == old {
+        *entity = new;
+    }
+}
+
+// One change that can be undone
+enum Edit {
+    Transform { entity: Entity, before: instances::Instance, after: instances::Instance },
+    Light { entity: Entity, before: light::Light, after: light::Light },
+    // None is the material as its file has it
+    Material { key: MaterialKey, before: Option<MaterialSettings>, after: Option<MaterialSettings> },
+    Spawn(Snapshot),
+    Delete(Snapshot),
+}
+
+impl Edit {
+    // Puts the world the way it is after the edit, or before it when undoing. Returns the
+    // old and new handle of an entity that had to be spawned again.
+    fn apply(&self, scene: &mut EditorScene, undo: bool) -> Option<(Entity, Entity)> {
+        match self {
+            Edit::Transform { entity, before, after } => {
+                if let Some(local) = scene.world.local_mut(*entity) {
+                    *local = if undo { *before } else { *after };
+                }
+                None
+            }
+            Edit::Light { entity, before, after } => {
+                if let Some(light) = scene.world.lights.get_mut(*entity) {
+                    *light = if undo { *before } else { *after };
+                }
+                None
+            }
+            Edit::Material { key, before, after } => {
+                match if undo { before } else { after } {
+                    Some(settings) => scene.world.materials.insert(key.clone(), *settings),
+                    None => scene.world.materials.remove(key),
+                };
+                None
+            }
+            Edit::Spawn(snapshot) => {
+                if undo {
+                    scene.world.despawn(snapshot.entity);
+                    None
+                } else {
+                    Some((snapshot.entity, snapshot.restore(scene.world)))
+                }
+            }
+            Edit::Delete(snapshot) => {
+                if undo {
+                    Some((snapshot.entity, snapshot.restore(scene.world)))
+                } else {
+                    scene.world.despawn(snapshot.entity);
+                    None
+                }
+            }
+        }
+    }
+
+    // Folds a later edit of the same thing into this one, so dragging a value is one edit
+    fn merge(&mut self, later: &Edit) -> bool {
+        match (self, later) {
+            (Edit::Transform { entity, after, .. }, Edit::Transform { entity: other, after: later, .. }) if entity == other => *after = *later,
+            (Edit::Light { entity, after, .. }, Edit::Light { entity: other, after: later, .. }) if entity == other => *after = *later,
+            (Edit::Material { key, after, .. }, Edit::Material { key: other, after: later, .. }) if key == other => *after = *later,
+            _ => return false,
+        }
+        true
+    }
+
+    fn remap(&mut self, old: Entity, new: Entity) {
+        match self {
+            Edit::Transform { entity, .. } | Edit::Light { entity, .. } => remap(entity, old, new),
+            Edit::Material { .. } => {}
+            Edit::Spawn(snapshot) | Edit::Delete(snapshot) => snapshot.remap(old, new),
+        }
+    }
+}
+
+// The selection's state from before a gizmo drag
+#[derive(Copy, Clone)]
+enum DragStart {
+    Transform(instances::Instance),
+    // Lights without a transform only have their own position
+    Light(light::Light),
+}
+
+struct ActiveDrag {
+    drag: gizmo::Drag,
+    entity: Entity,
+    start: DragStart,
+    // How long the parent's axes are, the local position moves that much slower
+    parent_scale: cgmath::Vector3<f32>,
+}
+
+pub struct Editor {
+    pub enabled: bool,
+    pub mode: gizmo::GizmoMode,
+    selected: Option<Entity>,
+    drag: Option<ActiveDrag>,
+    // The camera only turns with the mouse while the right button is held
+    pub looking: bool,
+    // In physical pixels
+    cursor: [f32; 2],
+    modifiers: ModifiersState,
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+    // A widget is being dragged and already was last frame, its changes join the last edit
+    continuing: bool,
+    item_active: bool,
+    model_files: Vec<String>,
+    spawn_file: usize,
+    save_path: String,
+}
+
+impl Editor {
+    pub fn new(save_path: &std::path::Path) -> Self {
+        Self {
+            enabled: false,
+            mode: gizmo::GizmoMode::Translate,
+            selected: None,
+            drag: None,
+            looking: false,
+            cursor: [0.0; 2],
+            modifiers: ModifiersState::empty(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            continuing: false,
+            item_active: false,
+            model_files: Vec::new(),
+            spawn_file: 0,
+            save_path: save_path.display().to_string(),
+        }
+    }
+
+    pub fn toggle(&mut self, scene: &mut EditorScene) {
+        self.enabled = !self.enabled;
+        self.looking = false;
+        self.end_drag(scene);
+        if self.enabled {
+            self.model_files = resources::list_files("obj");
+        }
+    }
+
+    // Returns true when the editor used the event
+    pub fn input(&mut self, event: &WindowEvent, scene: &mut EditorScene, camera: &camera::Camera, size: [f32; 2], ui_wants_mouse: bool, ui_wants_keyboard: bool) -> bool {
+        match event {
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+                false
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = [position.x as f32, position.y as f32];
+                self.update_drag(scene, camera, size);
+                false
+            }
+            WindowEvent::MouseInput { state, button: MouseButton::Right, .. } => {
+                self.looking = *state == ElementState::Pressed && !ui_wants_mouse;
+                self.looking
+            }
+            WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. } if !ui_wants_mouse => {
+                self.begin_drag_or_pick(scene, camera, size);
+                true
+            }
+            WindowEvent::MouseInput { state: ElementState::Released, button: MouseButton::Left, .. } => {
+                self.end_drag(scene);
+                false
+            }
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(key), .. },
+                ..
+            } if !ui_wants_keyboard => {
+                match key {
+                    VirtualKeyCode::Z if self.modifiers.ctrl() && self.modifiers.shift() => self.redo(scene),
+                    VirtualKeyCode::Z if self.modifiers.ctrl() => self.undo(scene),
+                    VirtualKeyCode::Y if self.modifiers.ctrl() => self.redo(scene),
+                    VirtualKeyCode::Delete => {
+                        if let Some(entity) = self.selected {
+                            self.apply(EditorCommand::Delete(entity), scene);
+                        }
+                    }
+                    VirtualKeyCode::Key1 => self.mode = gizmo::GizmoMode::Translate,
+                    VirtualKeyCode::Key2 => self.mode = gizmo::GizmoMode::Rotate,
+                    VirtualKeyCode::Key3 => self.mode = gizmo::GizmoMode::Scale,
+                    _ => return false,
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn apply(&mut self, command: EditorCommand, scene: &mut EditorScene) {
+        match command {
+            EditorCommand::Select(entity) => self.selected = entity,
+            EditorCommand::SetTransform(entity, transform) => {
+                let Some(before) = scene.world.local_mut(entity).map(|local| *local) else { return };
+                self.perform(Edit::Transform { entity, before, after: transform }, scene);
+            }
+            EditorCommand::SetLight(entity, light) => {
+                let Some(before) = scene.world.lights.get(entity).copied() else { return };
+                self.perform(Edit::Light { entity, before, after: light }, scene);
+            }
+            EditorCommand::SetMaterial(key, settings) => {
+                let before = scene.world.materials.get(&key).copied();
+                self.perform(Edit::Material { key, before, after: Some(settings) }, scene);
+            }
+            EditorCommand::Delete(entity) => {
+                if scene.world.is_alive(entity) {
+                    self.perform(Edit::Delete(Snapshot::take(scene.world, entity)), scene);
+                    self.selected = self.selected.filter(|selected| *selected != entity);
+                }
+            }
+            EditorCommand::Undo => self.undo(scene),
+            EditorCommand::Redo => self.redo(scene),
+            EditorCommand::SpawnModel(_) | EditorCommand::Save(_) => {}
+        }
+    }
+
+    // Adds an instance of the model and selects it
+    pub fn spawn(&mut self, world: &mut World, model: ModelId, name: &str, transform: instances::Instance) -> Entity {
+        let entity = world.spawn();
+        world.set_transform(entity, name, transform, None);
+        world.mesh_renderers.insert(entity, world::MeshRenderer { model });
+        self.push(Edit::Spawn(Snapshot::take(world, entity)), false);
+        self.selected = Some(entity);
+        entity
+    }
+
+    // Where a spawned model goes
+    pub fn spawn_position(camera: &camera::Camera) -> cgmath::Vector3<f32> {
+        (camera.eye + (camera.target - camera.eye).normalize() * SPAWN_DISTANCE).to_vec()
+    }
+
+    fn perform(&mut self, edit: Edit, scene: &mut EditorScene) {
+        edit.apply(scene, false);
+        self.push(edit, self.continuing);
+    }
+
+    fn push(&mut self, edit: Edit, merge: bool) {
+        self.redo.clear();
+        if merge && self.undo.last_mut().is_some_and(|last| last.merge(&edit)) {
+            return;
+        }
+        self.undo.push(edit);
+        if self.undo.len() > MAX_HISTORY {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn undo(&mut self, scene: &mut EditorScene) {
+        if self.drag.is_some() {
+            return;
+        }
+        let Some(edit) = self.undo.pop() else { return };
+        let respawned = edit.apply(scene, true);
+        self.redo.push(edit);
+        if let Some((old, new)) = respawned {
+            self.remap(old, new);
+        }
+    }
+
+    pub fn redo(&mut self, scene: &mut EditorScene) {
+        if self.drag.is_some() {
+            return;
+        }
+        let Some(edit) = self.redo.pop() else { return };
+        let respawned = edit.apply(scene, false);
+        self.undo.push(edit);
+        if let Some((old, new)) = respawned {
+            self.remap(old, new);
+        }
+    }
+
+    // An entity came back under a new handle, everything that refers to the old one follows
+    fn remap(&mut self, old: Entity, new: Entity) {
+        for edit in self.undo.iter_mut().chain(&mut self.redo) {
+            edit.remap(old, new);
+        }
+        if let Some(selected) = &mut self.selected {
+            remap(selected, old, new);
+        }
+    }
+
+    fn gizmo(&self, world: &World, camera: &camera::Camera, size: [f32; 2]) -> Option<gizmo::Gizmo> {
+        let entity = self.selected?;
+        let (origin, axes) = match world.world_matrix(entity) {
+            Some(matrix) => {
+                // Scaling goes along the entity's own axes, the rest along its parent's
+                let axes_of = if self.mode == gizmo::GizmoMode::Scale { matrix } else { parent_matrix(world, entity) };
+                (cgmath::Point3::from_vec(matrix.w.truncate()), axes(&axes_of))
+            }
+            None if self.mode == gizmo::GizmoMode::Translate => {
+                let light = world.lights.get(entity)?;
+                (cgmath::Point3::from_vec(light.position), axes(&cgmath::Matrix4::identity()))
+            }
+            None => return None,
+        };
+        Some(gizmo::Gizmo::new(self.mode, origin, axes, camera, size))
+    }
+
+    fn begin_drag_or_pick(&mut self, scene: &mut EditorScene, camera: &camera::Camera, size: [f32; 2]) {
+        let ray = camera.ray(self.cursor, size);
+        let grabbed = self.gizmo(scene.world, camera, size).and_then(|gizmo| {
+            let axis = gizmo.grab(camera, size, self.cursor)?;
+            gizmo.begin_drag(axis, &ray)
+        });
+        match (grabbed, self.selected) {
+            (Some(drag), Some(entity)) => {
+                let start = match scene.world.local_mut(entity) {
+                    Some(local) => DragStart::Transform(*local),
+                    None => match scene.world.lights.get(entity) {
+                        Some(light) => DragStart::Light(*light),
+                        None => return,
+                    },
+                };
+                let parent = parent_matrix(scene.world, entity);
+                let parent_scale = cgmath::vec3(parent.x.truncate().magnitude(), parent.y.truncate().magnitude(), parent.z.truncate().magnitude());
+                self.drag = Some(ActiveDrag { drag, entity, start, parent_scale });
+            }
+            _ => self.selected = pick(scene.world, scene.models, &ray),
+        }
+    }
+
+    // The selection follows the cursor, the history only gets the whole drag once it ends
+    fn update_drag(&mut self, scene: &mut EditorScene, camera: &camera::Camera, size: [f32; 2]) {
+        let Some(active) = &self.drag else { return };
+        let Some(amount) = active.drag.amount(&camera.ray(self.cursor, size)) else { return };
+        let axis = active.drag.axis;
+        match active.start {
+            DragStart::Transform(start) => {
+                let mut local = start;
+                match active.drag.gizmo.mode {
+                    gizmo::GizmoMode::Translate => {
+                        if active.parent_scale[axis] > 0.0 {
+                            local.position[axis] += amount / active.parent_scale[axis];
+                        }
+                    }
+                    gizmo::GizmoMode::Rotate => {
+                        let unit = [cgmath::Vector3::unit_x(), cgmath::Vector3::unit_y(), cgmath::Vector3::unit_z()][axis];
+                        local.rotation = cgmath::Quaternion::from_axis_angle(unit, cgmath::Rad(amount)) * start.rotation;
+                    }
+                    gizmo::GizmoMode::Scale => local.scale[axis] = start.scale[axis] * amount,
+                }
+                if let Some(current) = scene.world.local_mut(active.entity) {
+                    *current = local;
+                }
+            }
+            DragStart::Light(start) => {
+                if let Some(light) = scene.world.lights.get_mut(active.entity) {
+                    light.position = start.position + active.drag.gizmo.axes[axis] * amount;
+                }
+            }
+        }
+    }
+
+    fn end_drag(&mut self, scene: &mut EditorScene) {
+        let Some(active) = self.drag.take() else { return };
+        let entity = active.entity;
+        let edit = match active.start {
+            DragStart::Transform(before) => scene.world.local_mut(entity).map(|after| Edit::Transform { entity, before, after: *after }),
+            DragStart::Light(before) => scene.world.lights.get(entity).map(|after| Edit::Light { entity, before, after: *after }),
+        };
+        if let Some(edit) = edit {
+            self.push(edit, false);
+        }
+    }
+
+    pub fn draw(&mut self, ui: &imgui::Ui, world: &World, models: &[model::Model], camera: &camera::Camera, size: [f32; 2]) -> Vec<EditorCommand> {
+        let mut commands = Vec::new();
+        let scale = ui.io().display_framebuffer_scale[0];
+        {
+            let draw_list = ui.get_background_draw_list();
+            if let Some(bounds) = self.selected.and_then(|entity| selection_bounds(world, models, entity)) {
+                draw_bounds(&draw_list, &bounds, camera, size, scale);
+            }
+            let gizmo = self.drag.as_ref().map(|active| active.drag.gizmo).or_else(|| self.gizmo(world, camera, size));
+            if let Some(gizmo) = gizmo {
+                let active = match &self.drag {
+                    Some(active) => Some(active.drag.axis),
+                    None if !ui.io().want_capture_mouse => gizmo.grab(camera, size, self.cursor),
+                    None => None,
+                };
+                gizmo.draw(&draw_list, camera, size, scale, active);
+            }
+        }
+
+        let window = imgui::Window::new("Editor");
+        window
+            .size([300.0, 230.0], imgui::Condition::FirstUseEver)
+            .position([930.0, 0.0], imgui::Condition::FirstUseEver)
+            .build(ui, || {
+                ui.text("F1 to leave, hold the right mouse button to look around");
+                let mut mode = self.mode as u32;
+                ui.radio_button("Move (1)", &mut mode, gizmo::GizmoMode::Translate as u32);
+                ui.same_line();
+                ui.radio_button("Rotate (2)", &mut mode, gizmo::GizmoMode::Rotate as u32);
+                ui.same_line();
+                ui.radio_button("Scale (3)", &mut mode, gizmo::GizmoMode::Scale as u32);
+                self.mode = match mode {
+                    0 => gizmo::GizmoMode::Translate,
+                    1 => gizmo::GizmoMode::Rotate,
+                    _ => gizmo::GizmoMode::Scale,
+                };
+                if ui.button(format!("Undo ({})", self.undo.len())) {
+                    commands.push(EditorCommand::Undo);
+                }
+                ui.same_line();
+                if ui.button(format!("Redo ({})", self.redo.len())) {
+                    commands.push(EditorCommand::Redo);
+                }
+                ui.separator();
+                if !self.model_files.is_empty() {
+                    ui.combo("Model", &mut self.spawn_file, &self.model_files, |file| file.as_str().into());
+                    if ui.button("Spawn") {
+                        if let Some(file) = self.model_files.get(self.spawn_file) {
+                            commands.push(EditorCommand::SpawnModel(file.clone()));
+                        }
+                    }
+                    ui.same_line();
+                }
+                if ui.button("Refresh models") {
+                    self.model_files = resources::list_files("obj");
+                }
+                ui.separator();
+                ui.input_text("Map file", &mut self.save_path).build();
+                if ui.button("Save") {
+                    commands.push(EditorCommand::Save(self.save_path.clone().into()));
+                }
+            });
+
+        let window = imgui::Window::new("Outliner");
+        window
+            .size([300.0, 300.0], imgui::Condition::FirstUseEver)
+            .position([930.0, 240.0], imgui::Condition::FirstUseEver)
+            .build(ui, || {
+                let entity_of = entity_of_node(world);
+                for root in world.scene.roots() {
+                    self.outliner_node(ui, world, *root, &entity_of, &mut commands);
+                }
+                let mut lights = world.lights.iter().filter(|(entity, _)| !world.transforms.contains(*entity)).collect::<Vec<_>>();
+                lights.sort_by_key(|(_, light)| light.id);
+                for (entity, light) in lights {
+                    let selected = self.selected == Some(entity);
+                    if imgui::Selectable::new(format!("Light {}", light.id)).selected(selected).build(ui) {
+                        commands.push(EditorCommand::Select(Some(entity)));
+                    }
+                }
+            });
+
+        let window = imgui::Window::new("Inspector");
+        window
+            .size([300.0, 400.0], imgui::Condition::FirstUseEver)
+            .position([930.0, 550.0], imgui::Condition::FirstUseEver)
+            .build(ui, || match self.selected.filter(|entity| world.is_alive(*entity)) {
+                Some(entity) => inspector(ui, world, models, entity, &mut commands),
+                None => ui.text("Click on something to select it"),
+            });
+
+        // The first change of a drag starts a new edit, the ones after it go into that one
+        let was_active = self.item_active;
+        self.item_active = ui.is_any_item_active();
+        self.continuing = was_active && self.item_active;
+        commands
+    }
+
+    fn outliner_node(&self, ui: &imgui::Ui, world: &World, node: scene::NodeId, entity_of: &HashMap<scene::NodeId, Entity>, commands: &mut Vec<EditorCommand>) {
+        let Some(node_ref) = world.scene.node(node) else { return };
+        let entity = entity_of.get(&node).copied();
+        let mut flags = imgui::TreeNodeFlags::OPEN_ON_ARROW | imgui::TreeNodeFlags::DEFAULT_OPEN | imgui::TreeNodeFlags::SPAN_AVAIL_WIDTH;
+        if node_ref.children().is_empty() {
+            flags |= imgui::TreeNodeFlags::LEAF;
+        }
+        if entity.is_some() && entity == self.selected {
+            flags |= imgui::TreeNodeFlags::SELECTED;
+        }
+        let name = if node_ref.name.is_empty() { "(unnamed)" } else { &node_ref.name };
+        let token = imgui::TreeNode::new(format!("{}##{:?}", name, node)).flags(flags).push(ui);
+        if ui.is_item_clicked() {
+            commands.push(EditorCommand::Select(entity));
+        }
+        if let Some(_token) = token {
+            for child in node_ref.children() {
+                self.outliner_node(ui, world, *child, entity_of, commands);
+            }
+        }
+    }
+}
+
+fn inspector(ui: &imgui::Ui, world: &World, models: &[model::Model], entity: Entity, commands: &mut Vec<EditorCommand>) {
+    if ui.button("Delete") {
+        commands.push(EditorCommand::Delete(entity));
+    }
+    let node = world.transforms.get(entity).and_then(|transform| world.scene.node(transform.node));
+    if let Some(node) = node {
+        ui.text(format!("Name: {}", node.name));
+        let local = node.local();
+        let mut position: [f32; 3] = local.position.into();
+        let euler = cgmath::Euler::from(local.rotation);
+        let mut rotation = [cgmath::Deg::from(euler.x).0, cgmath::Deg::from(euler.y).0, cgmath::Deg::from(euler.z).0];
+        let mut scale: [f32; 3] = local.scale.into();
+        let mut changed = false;
+        changed |= imgui::Drag::new("Position").speed(0.05).build_array(ui, &mut position);
+        changed |= imgui::Drag::new("Rotation").speed(0.5).build_array(ui, &mut rotation);
+        changed |= imgui::Drag::new("Scale").speed(0.01).build_array(ui, &mut scale);
+        if changed {
+            commands.push(EditorCommand::SetTransform(entity, instances::Instance {
+                position: position.into(),
+                rotation: cgmath::Euler::new(cgmath::Deg(rotation[0]), cgmath::Deg(rotation[1]), cgmath::Deg(rotation[2])).into(),
+                scale: scale.into(),
+            }));
+        }
+    }
+
+    if let Some(light) = world.lights.get(entity) {
+        ui.separator();
+        ui.text(format!("Light {}", light.id));
+        let mut edited = *light;
+        let mut position: [f32; 3] = edited.position.into();
+        let mut color: [f32; 3] = edited.color.into();
+        let mut limitdir: [f32; 3] = edited.limitdir.into();
+        let mut changed = false;
+        // With a transform the light goes where that does
+        if node.is_none() {
+            changed |= imgui::Drag::new("Light position").speed(0.05).build_array(ui, &mut position);
+        }
+        changed |= imgui::ColorEdit::new("Color", &mut color).build(ui);
+        changed |= imgui::Drag::new("Intensity").speed(0.01).range(0.0, 100.0).build(ui, &mut edited.intensity);
+        changed |= imgui::Drag::new("Radius").speed(0.01).range(0.0, 100.0).build(ui, &mut edited.radius);
+        changed |= ui.checkbox("Spotlight", &mut edited.is_spotlight);
+        if edited.is_spotlight {
+            changed |= imgui::Drag::new("Direction").speed(0.01).range(-1.0, 1.0).build_array(ui, &mut limitdir);
+            changed |= imgui::Slider::new("Inner cos", 0.0, 1.0).build(ui, &mut edited.limitcos_inner);
+            changed |= imgui::Slider::new("Outer cos", 0.0, 1.0).build(ui, &mut edited.limitcos_outer);
+        }
+        if changed {
+            edited.position = position.into();
+            edited.color = color.into();
+            edited.limitdir = limitdir.into();
+            commands.push(EditorCommand::SetLight(entity, edited));
+        }
+    }
+
+    let renderer = world.mesh_renderers.get(entity);
+    if let Some((model_id, model)) = renderer.and_then(|renderer| Some((renderer.model, models.get(renderer.model)?))) {
+        ui.separator();
+        ui.text("Materials, shared by every instance of the model");
+        for (index, material) in model.materials.iter().enumerate() {
+            let _id = ui.push_id(index as i32);
+            if !ui.collapsing_header(format!("{}##material", material.name), imgui::TreeNodeFlags::empty()) {
+                continue;
+            }
+            let mut settings = material.uniform.settings();
+            let mut alpha_mode = settings.alpha_mode as usize;
+            let mut changed = false;
+            changed |= imgui::ColorEdit::new("Diffuse", &mut settings.diffuse_color).build(ui);
+            changed |= imgui::ColorEdit::new("Ambient", &mut settings.ambient_color).build(ui);
+            changed |= imgui::ColorEdit::new("Specular", &mut settings.specular_color).build(ui);
+            changed |= imgui::Slider::new("Dissolve", 0.0, 1.0).build(ui, &mut settings.dissolve);
+            changed |= ui.combo("Alpha mode", &mut alpha_mode, &["Opaque", "Mask", "Blend"], |mode| (*mode).into());
+            if changed {
+                settings.alpha_mode = model::AlphaMode::from_u32(alpha_mode as u32);
+                let key = MaterialKey { model: model_id, name: material.name.clone() };
+                commands.push(EditorCommand::SetMaterial(key, settings));
+            }
+        }
+    }
+}
+
+// The entity under the cursor, the closest one when there are more
+fn pick(world: &World, models: &[model::Model], ray: &culling::Ray) -> Option<Entity> {
+    let mut closest: Option<(f32, Entity)> = None;
+    let mut hit = |distance: Option<f32>, entity| {
+        if let Some(distance) = distance {
+            if closest.is_none_or(|(closest, _)| distance < closest) {
+                closest = Some((distance, entity));
+            }
+        }
+    };
+    for (entity, renderer) in world.mesh_renderers.iter() {
+        let (Some(model), Some(matrix)) = (models.get(renderer.model), world.world_matrix(entity)) else { continue };
+        let Some(inverse) = matrix.invert() else { continue };
+        // The bounds fit the model much tighter in its own space than around it in the world's
+        hit(model.bounds.ray_distance(&ray.transformed(&inverse)), entity);
+    }
+    for (entity, light) in world.lights.iter() {
+        hit(light_bounds(light).ray_distance(ray), entity);
+    }
+    closest.map(|(_, entity)| entity)
+}
+
+fn light_bounds(light: &light::Light) -> culling::Aabb {
+    let center = cgmath::Point3::from_vec(light.position);
+    let extents = cgmath::vec3(LIGHT_PICK_SIZE, LIGHT_PICK_SIZE, LIGHT_PICK_SIZE);
+    culling::Aabb { min: center - extents, max: center + extents }
+}
+
+// In the entity's own space, with the matrix that puts it in the world
+fn selection_bounds(world: &World, models: &[model::Model], entity: Entity) -> Option<(culling::Aabb, cgmath::Matrix4<f32>)> {
+    match (world.mesh_renderers.get(entity), world.world_matrix(entity)) {
+        (Some(renderer), Some(matrix)) => Some((models.get(renderer.model)?.bounds, matrix)),
+        _ => Some((light_bounds(world.lights.get(entity)?), cgmath::Matrix4::identity())),
+    }
+}
+
+fn draw_bounds(draw_list: &imgui::DrawListMut, (bounds, matrix): &(culling::Aabb, cgmath::Matrix4<f32>), camera: &camera::Camera, size: [f32; 2], scale: f32) {
+    use cgmath::Transform;
+    let corner = |i: usize| {
+        let point = cgmath::point3(
+            if i & 1 == 0 { bounds.min.x } else { bounds.max.x },
+            if i & 2 == 0 { bounds.min.y } else { bounds.max.y },
+            if i & 4 == 0 { bounds.min.z } else { bounds.max.z },
+        );
+        camera.project(matrix.transform_point(point), size).map(|[x, y]| [x / scale, y / scale])
+    };
+    // Corners one bit apart share an edge
+    for a in 0..8 {
+        for bit in [1, 2, 4] {
+            let b = a | bit;
+            if b == a {
+                continue;
+            }
+            if let (Some(a), Some(b)) = (corner(a), corner(b)) {
+                draw_list.add_line(a, b, [1.0, 0.85, 0.2, 0.8]).build();
+            }
+        }
+    }
+}
+
+fn entity_of_node(world: &World) -> HashMap<scene::NodeId, Entity> {
+    world.transforms.iter().map(|(entity, transform)| (transform.node, entity)).collect()
+}
+
+fn parent_matrix(world: &World, entity: Entity) -> cgmath::Matrix4<f32> {
+    world
+        .transforms
+        .get(entity)
+        .and_then(|transform| world.scene.node(transform.node)?.parent())
+        .and_then(|parent| world.scene.node(parent))
+        .map_or(cgmath::Matrix4::identity(), |parent| parent.world())
+}
+
+// The directions of the matrix' axes, normalized. An axis scaled to nothing falls back to
+// the world's.
+fn axes(matrix: &cgmath::Matrix4<f32>) -> [cgmath::Vector3<f32>; 3] {
+    let units = [cgmath::Vector3::unit_x(), cgmath::Vector3::unit_y(), cgmath::Vector3::unit_z()];
+    let columns = [matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate()];
+    [0, 1, 2].map(|i| if columns[i].magnitude2() > 1e-12 { columns[i].normalize() } else { units[i] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> instances::Instance {
+        instances::Instance { position: cgmath::Vector3::new(x, 0.0, 0.0), ..Default::default() }
+    }
+
+    fn local(world: &World, entity: Entity) -> Option<instances::Instance> {
+        world.scene.node(world.transforms.get(entity)?.node).map(|node| *node.local())
+    }
+
+    fn parent(world: &World, entity: Entity) -> Option<Entity> {
+        let parent = world.scene.node(world.transforms.get(entity)?.node)?.parent()?;
+        entity_of_node(world).get(&parent).copied()
+    }
+
+    #[test]
+    fn undoing_a_delete_brings_the_entity_back() {
+        let mut world = World::new();
+        let mut editor = Editor::new(std::path::Path::new("map.json"));
+        let parent_entity = editor.spawn(&mut world, 0, "parent", at(1.0));
+        let child = world.spawn();
+        world.set_transform(child, "child", at(2.0), Some(parent_entity));
+        world.scene.update_world();
+        let scene = &mut EditorScene { world: &mut world, models: &[] };
+
+        editor.apply(EditorCommand::SetTransform(parent_entity, at(3.0)), scene);
+        // The world transforms get updated every frame in the game
+        scene.world.scene.update_world();
+        editor.apply(EditorCommand::Select(Some(parent_entity)), scene);
+        editor.apply(EditorCommand::Delete(parent_entity), scene);
+        assert!(!scene.world.is_alive(parent_entity));
+        assert_eq!(editor.selected, None);
+        // The child stays where it was in the world, without its parent
+        assert_eq!(parent(scene.world, child), None);
+        assert_eq!(local(scene.world, child).unwrap().position.x, 5.0);
+
+        editor.undo(scene);
+        let restored = match editor.redo.last() {
+            Some(Edit::Delete(snapshot)) => snapshot.entity,
+            _ => panic!("The delete isn't up for redoing"),
+        };
+        assert_ne!(restored, parent_entity);
+        assert!(scene.world.is_alive(restored));
+        assert_eq!(local(scene.world, restored).unwrap(), at(3.0));
+        assert_eq!(scene.world.mesh_renderers.get(restored).unwrap().model, 0);
+        assert_eq!(scene.world.scene.node(scene.world.transforms.get(restored).unwrap().node).unwrap().name, "parent");
+        assert_eq!(parent(scene.world, child), Some(restored));
+        assert_eq!(local(scene.world, child).unwrap(), at(2.0));
+
+        // The edits from before the delete follow the new handle
+        editor.undo(scene);
+        assert_eq!(local(scene.world, restored).unwrap(), at(1.0));
+        editor.redo(scene);
+        scene.world.scene.update_world();
+        editor.redo(scene);
+        assert!(!scene.world.is_alive(restored));
+        assert_eq!(parent(scene.world, child), None);
+        assert_eq!(local(scene.world, child).unwrap().position.x, 5.0);
+    }
+
+    #[test]
+    fn undoing_a_spawn_removes_it() {
+        let mut world = World::new();
+        let mut editor = Editor::new(std::path::Path::new("map.json"));
+        let entity = editor.spawn(&mut world, 2, "spawned", at(4.0));
+        assert_eq!(editor.selected, Some(entity));
+        let scene = &mut EditorScene { world: &mut world, models: &[] };
+
+        editor.undo(scene);
+        assert!(!scene.world.is_alive(entity));
+        assert_eq!(scene.world.mesh_renderers.iter().count(), 0);
+        assert!(scene.world.scene.roots().is_empty());
+
+        editor.redo(scene);
+        let (respawned, renderer) = scene.world.mesh_renderers.iter().next().unwrap();
+        assert_ne!(respawned, entity);
+        assert_eq!(renderer.model, 2);
+        assert_eq!(local(scene.world, respawned).unwrap(), at(4.0));
+        // The selection follows it to its new handle
+        assert_eq!(editor.selected, Some(respawned));
+    }
+
+    #[test]
+    fn material_edits_go_into_the_world() {
+        let mut world = World::new();
+        let mut editor = Editor::new(std::path::Path::new("map.json"));
+        let scene = &mut EditorScene { world: &mut world, models: &[] };
+        let key = MaterialKey { model: 0, name: "wood".to_string() };
+        let settings = |dissolve| MaterialSettings {
+            diffuse_color: [1.0; 3],
+            ambient_color: [0.0; 3],
+            specular_color: [0.0; 3],
+            dissolve,
+            alpha_mode: model::AlphaMode::Opaque,
+        };
+        editor.apply(EditorCommand::SetMaterial(key.clone(), settings(0.5)), scene);
+        editor.apply(EditorCommand::SetMaterial(key.clone(), settings(0.25)), scene);
+        assert_eq!(scene.world.materials[&key], settings(0.25));
+        editor.undo(scene);
+        assert_eq!(scene.world.materials[&key], settings(0.5));
+        // Back to what the file has, so there's nothing left for the map to save
+        editor.undo(scene);
+        assert!(scene.world.materials.is_empty());
+    }
+}
@@ -0,0 +1,485 @@
+use super::{camera, hdr, render_pipeline, shaders, texture};
+use cgmath::SquareMatrix;
+use std::{mem, num::NonZeroU32};
+use wgpu::util::DeviceExt;
+
+pub use crate::world::environment::EnvironmentSettings;
+
+const ENVIRONMENT_SIZE: u32 = 512;
+const ENVIRONMENT_MIPS: u32 = 8;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+const PREFILTERED_MIPS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 256;
+const CUBE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const WORKGROUP_SIZE: u32 = 8;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyboxUniform {
+    inv_view_proj: [[f32; 4]; 4],
+    intensity: f32,
+    _padding: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct IblUniform {
+    intensity: f32,
+    prefiltered_mips: f32,
+    sky_intensity: f32, // So fog can match the skybox
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PrefilterUniform {
+    roughness: f32,
+    environment_size: f32,
+    _padding: [u32; 2],
+}
+
+// The skybox and the maps for image based lighting, all made from one hdr image at load
+pub struct Environment {
+    skybox_pipeline_layout: wgpu::PipelineLayout,
+    skybox_pipeline: wgpu::RenderPipeline,
+    skybox_bind_group: wgpu::BindGroup,
+    skybox_buffer: wgpu::Buffer,
+    ibl_buffer: wgpu::Buffer,
+    show_skybox: bool,
+
+    pub ext_bind_group: wgpu::BindGroup,
+    pub ext_bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl Environment {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        equirect: &image::Rgba32FImage,
+        sample_count: u32,
+    ) -> Self {
+        let equirect_texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("Equirectangular Environment"),
+                size: wgpu::Extent3d {
+                    width: equirect.width(),
+                    height: equirect.height(),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            },
+            bytemuck::cast_slice(equirect.as_raw()),
+        );
+        let equirect_view = equirect_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let environment = create_cube(device, "Environment Cube", ENVIRONMENT_SIZE, ENVIRONMENT_MIPS);
+        let irradiance = create_cube(device, "Irradiance Cube", IRRADIANCE_SIZE, 1);
+        let prefiltered = create_cube(device, "Prefiltered Cube", PREFILTERED_SIZE, PREFILTERED_MIPS);
+        let brdf_lut = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("BRDF LUT"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: CUBE_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+        });
+        let brdf_lut_view = brdf_lut.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self::bake(device, queue, &equirect_view, &environment, &irradiance, &prefiltered, &brdf_lut_view, &sampler);
+
+        let environment_view = environment.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Environment Cube View"),
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let irradiance_view = irradiance.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Irradiance Cube View"),
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let prefiltered_view = prefiltered.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Prefiltered Cube View"),
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+
+        let cube_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::Cube,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let uniform_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        };
+
+        let ext_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("IBL Bind Group Layout"),
+            entries: &[
+                cube_entry(0),
+                cube_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                sampler_entry(3),
+                uniform_entry(4),
+            ],
+        });
+        let ibl_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("IBL Buffer"),
+            size: mem::size_of::<IblUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let ext_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("IBL Bind Group"),
+            layout: &ext_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&irradiance_view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&prefiltered_view) },
+                wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&brdf_lut_view) },
+                wgpu::BindGroupEntry { binding: 3, resource: wgpu::BindingResource::Sampler(&sampler) },
+                wgpu::BindGroupEntry { binding: 4, resource: ibl_buffer.as_entire_binding() },
+            ],
+        });
+
+        let skybox_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Skybox Bind Group Layout"),
+            entries: &[uniform_entry(0), cube_entry(1), sampler_entry(2)],
+        });
+        let skybox_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Skybox Buffer"),
+            size: mem::size_of::<SkyboxUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let skybox_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Skybox Bind Group"),
+            layout: &skybox_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: skybox_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&environment_view) },
+                wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::Sampler(&sampler) },
+            ],
+        });
+        let skybox_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Skybox Pipeline Layout"),
+            bind_group_layouts: &[&skybox_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let skybox_pipeline = Self::create_skybox_pipeline(device, &skybox_pipeline_layout, sample_count);
+
+        Self {
+            skybox_pipeline_layout,
+            skybox_pipeline,
+            skybox_bind_group,
+            skybox_buffer,
+            ibl_buffer,
+            show_skybox: true,
+            ext_bind_group,
+            ext_bind_group_layout,
+        }
+    }
+
+    // Runs all the compute passes once, nothing here changes afterwards
+    #[allow(clippy::too_many_arguments)]
+    fn bake(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        equirect_view: &wgpu::TextureView,
+        environment: &wgpu::Texture,
+        irradiance: &wgpu::Texture,
+        prefiltered: &wgpu::Texture,
+        brdf_lut_view: &wgpu::TextureView,
+        sampler: &wgpu::Sampler,
+    ) {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("IBL Shader"),
+            source: wgpu::ShaderSource::Wgsl(shaders::source("ibl.wgsl")),
+        });
+        let pipeline = |entry_point| device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(entry_point),
+            layout: None,
+            module: &shader,
+            entry_point,
+        });
+        // Derived layouts assume float textures are filterable, which 32 bit floats aren't
+        let equirect_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("equirect_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: wgpu::TextureFormat::Rgba16Float,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let equirect_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("equirect_to_cube"),
+            layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Equirect Pipeline Layout"),
+                bind_group_layouts: &[&equirect_layout],
+                push_constant_ranges: &[],
+            })),
+            module: &shader,
+            entry_point: "equirect_to_cube",
+        });
+        let downsample_pipeline = pipeline("downsample_cube");
+        let irradiance_pipeline = pipeline("irradiance");
+        let prefilter_pipeline = pipeline("prefilter_specular");
+        let brdf_pipeline = pipeline("brdf_lut");
+
+        let environment_cube = environment.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("IBL Encoder"),
+        });
+        let dispatch = |encoder: &mut wgpu::CommandEncoder, pipeline: &wgpu::ComputePipeline, bind_group: &wgpu::BindGroup, size: u32, layers: u32| {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            let groups = size.div_ceil(WORKGROUP_SIZE);
+            pass.dispatch_workgroups(groups, groups, layers);
+        };
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &equirect_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(equirect_view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&cube_mip_view(environment, 0)) },
+            ],
+        });
+        dispatch(&mut encoder, &equirect_pipeline, &bind_group, ENVIRONMENT_SIZE, 6);
+
+        for mip in 1..ENVIRONMENT_MIPS {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &downsample_pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&cube_mip_view(environment, mip - 1)) },
+                    wgpu::BindGroupEntry { binding: 3, resource: wgpu::BindingResource::TextureView(&cube_mip_view(environment, mip)) },
+                ],
+            });
+            dispatch(&mut encoder, &downsample_pipeline, &bind_group, (ENVIRONMENT_SIZE >> mip).max(1), 6);
+        }
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &irradiance_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 4, resource: wgpu::BindingResource::TextureView(&environment_cube) },
+                wgpu::BindGroupEntry { binding: 5, resource: wgpu::BindingResource::Sampler(sampler) },
+                wgpu::BindGroupEntry { binding: 6, resource: wgpu::BindingResource::TextureView(&cube_mip_view(irradiance, 0)) },
+            ],
+        });
+        dispatch(&mut encoder, &irradiance_pipeline, &bind_group, IRRADIANCE_SIZE, 6);
+
+        // Every mip of the prefiltered cube stands for a rougher surface
+        let mut prefilter_buffers = Vec::new();
+        for mip in 0..PREFILTERED_MIPS {
+            let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Prefilter Buffer"),
+                contents: bytemuck::cast_slice(&[PrefilterUniform {
+                    roughness: mip as f32 / (PREFILTERED_MIPS - 1) as f32,
+                    environment_size: ENVIRONMENT_SIZE as f32,
+                    _padding: [0; 2],
+                }]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &prefilter_pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 4, resource: wgpu::BindingResource::TextureView(&environment_cube) },
+                    wgpu::BindGroupEntry { binding: 5, resource: wgpu::BindingResource::Sampler(sampler) },
+                    wgpu::BindGroupEntry { binding: 7, resource: wgpu::BindingResource::TextureView(&cube_mip_view(prefiltered, mip)) },
+                    wgpu::BindGroupEntry { binding: 8, resource: buffer.as_entire_binding() },
+                ],
+            });
+            dispatch(&mut encoder, &prefilter_pipeline, &bind_group, (PREFILTERED_SIZE >> mip).max(1), 6);
+            prefilter_buffers.push(buffer);
+        }
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &brdf_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 9, resource: wgpu::BindingResource::TextureView(brdf_lut_view) },
+            ],
+        });
+        dispatch(&mut encoder, &brdf_pipeline, &bind_group, BRDF_LUT_SIZE, 1);
+
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    fn create_skybox_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout, sample_count: u32) -> wgpu::RenderPipeline {
+        let shader = render_pipeline::create_fullscreen_shader(device, "Skybox Shader", &shaders::source("skybox.wgsl"));
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Skybox Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: hdr::HDR_FORMAT,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                cull_mode: None,
+                ..Default::default()
+            },
+            // Drawn first in the scene pass, so it doesn't need to test or write depth
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+            multiview: None,
+        })
+    }
+
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, sample_count: u32) {
+        self.skybox_pipeline = Self::create_skybox_pipeline(device, &self.skybox_pipeline_layout, sample_count);
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &camera::Camera, settings: &EnvironmentSettings) {
+        // Only the rotation of the view, the sky is infinitely far away
+        let mut view = cgmath::Matrix4::look_at_rh(camera.eye, camera.target, camera.up);
+        view.w = cgmath::vec4(0.0, 0.0, 0.0, 1.0);
+        let proj = cgmath::perspective(cgmath::Deg(camera.fovy), camera.aspect, camera.znear, camera.zfar);
+        let inv_view_proj = (camera::OPENGL_TO_WGPU_MATRIX * proj * view)
+            .invert()
+            .unwrap_or_else(cgmath::Matrix4::identity);
+
+        self.show_skybox = settings.skybox;
+        queue.write_buffer(&self.skybox_buffer, 0, bytemuck::cast_slice(&[SkyboxUniform {
+            inv_view_proj: inv_view_proj.into(),
+            intensity: settings.skybox_intensity,
+            _padding: [0; 3],
+        }]));
+        queue.write_buffer(&self.ibl_buffer, 0, bytemuck::cast_slice(&[IblUniform {
+            intensity: settings.ambient_intensity,
+            prefiltered_mips: PREFILTERED_MIPS as f32,
+            sky_intensity: settings.skybox_intensity,
+            _padding: 0,
+        }]));
+    }
+
+    // Has to come first in the scene pass, it draws over whatever is there
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if !self.show_skybox {
+            return;
+        }
+        render_pass.set_pipeline(&self.skybox_pipeline);
+        render_pass.set_bind_group(0, &self.skybox_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_cube(device: &wgpu::Device, label: &str, size: u32, mips: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count: mips,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: CUBE_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+    })
+}
+
+// All six faces of a single mip, for writing to from the compute passes
+fn cube_mip_view(texture: &wgpu::Texture, mip: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        label: None,
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        base_mip_level: mip,
+        mip_level_count: NonZeroU32::new(1),
+        base_array_layer: 0,
+        array_layer_count: NonZeroU32::new(6),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,40 @@
+// Fog is applied in the material shader, its settings ride along in the camera uniform
+pub use crate::world::environment::FogSettings;
+
+pub const FOG_DISTANCE: u32 = 1;
+pub const FOG_HEIGHT: u32 = 2;
+pub const FOG_SKY_COLOR: u32 = 4;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FogUniform {
+    color: [f32; 3],
+    density: f32,
+    start: f32,
+    height: f32,
+    height_falloff: f32,
+    flags: u32,
+}
+
+impl FogUniform {
+    pub fn new(settings: &FogSettings) -> Self {
+        let mut flags = 0;
+        if settings.distance_fog {
+            flags |= FOG_DISTANCE;
+        }
+        if settings.height_fog {
+            flags |= FOG_HEIGHT;
+        }
+        if settings.sky_color {
+            flags |= FOG_SKY_COLOR;
+        }
+        Self {
+            color: settings.color,
+            density: settings.density,
+            start: settings.start,
+            height: settings.height,
+            height_falloff: settings.height_falloff,
+            flags,
+        }
+    }
+}
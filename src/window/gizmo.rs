@@ -0,0 +1,160 @@
+// Handles for moving, rotating and scaling the editor's selection. They're drawn with imgui
+// on top of the scene and stay the same size on screen however far away they are.
+use cgmath::InnerSpace;
+
+use super::{camera, culling};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GizmoMode {
+    Translate = 0,
+    Rotate = 1,
+    Scale = 2,
+}
+
+// In pixels
+const HANDLE_LENGTH: f32 = 90.0;
+const GRAB_DISTANCE: f32 = 8.0;
+const CIRCLE_SEGMENTS: usize = 48;
+
+const AXIS_COLORS: [[f32; 4]; 3] = [[0.9, 0.2, 0.2, 1.0], [0.3, 0.85, 0.3, 1.0], [0.25, 0.4, 0.95, 1.0]];
+const ACTIVE_COLOR: [f32; 4] = [1.0, 0.85, 0.2, 1.0];
+
+#[derive(Debug, Copy, Clone)]
+pub struct Gizmo {
+    pub mode: GizmoMode,
+    pub origin: cgmath::Point3<f32>,
+    // Normalized, in world space
+    pub axes: [cgmath::Vector3<f32>; 3],
+    // The world size HANDLE_LENGTH pixels come out as at the origin
+    length: f32,
+}
+
+impl Gizmo {
+    pub fn new(mode: GizmoMode, origin: cgmath::Point3<f32>, axes: [cgmath::Vector3<f32>; 3], camera: &camera::Camera, size: [f32; 2]) -> Self {
+        let forward = (camera.target - camera.eye).normalize();
+        let depth = (origin - camera.eye).dot(forward).max(camera.znear);
+        let pixel = 2.0 * depth * (camera.fovy.to_radians() / 2.0).tan() / size[1];
+        Self { mode, origin, axes, length: HANDLE_LENGTH * pixel }
+    }
+
+    // What the handle of an axis runs through, a line out of the origin or a circle around it
+    fn handle(&self, axis: usize) -> Vec<cgmath::Point3<f32>> {
+        match self.mode {
+            GizmoMode::Translate | GizmoMode::Scale => vec![self.origin, self.origin + self.axes[axis] * self.length],
+            GizmoMode::Rotate => {
+                let (u, v) = (self.axes[(axis + 1) % 3], self.axes[(axis + 2) % 3]);
+                (0..=CIRCLE_SEGMENTS)
+                    .map(|i| {
+                        let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+                        self.origin + (u * angle.cos() + v * angle.sin()) * self.length
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // None when any of it is behind the camera
+    fn handle_on_screen(&self, axis: usize, camera: &camera::Camera, size: [f32; 2]) -> Option<Vec<[f32; 2]>> {
+        self.handle(axis).into_iter().map(|point| camera.project(point, size)).collect()
+    }
+
+    // The axis with its handle under the cursor, the closest one if there are more
+    pub fn grab(&self, camera: &camera::Camera, size: [f32; 2], cursor: [f32; 2]) -> Option<usize> {
+        (0..3)
+            .filter_map(|axis| {
+                let points = self.handle_on_screen(axis, camera, size)?;
+                let distance = points
+                    .windows(2)
+                    .map(|segment| segment_distance(cursor, segment[0], segment[1]))
+                    .fold(f32::MAX, f32::min);
+                (distance <= GRAB_DISTANCE).then_some((axis, distance))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(axis, _)| axis)
+    }
+
+    // Imgui works in logical pixels, the rest of the editor in physical ones. `scale` is how
+    // many physical pixels make a logical one.
+    pub fn draw(&self, draw_list: &imgui::DrawListMut, camera: &camera::Camera, size: [f32; 2], scale: f32, active: Option<usize>) {
+        let logical = |point: [f32; 2]| [point[0] / scale, point[1] / scale];
+        for (axis, axis_color) in AXIS_COLORS.iter().enumerate() {
+            let Some(points) = self.handle_on_screen(axis, camera, size) else { continue };
+            let color = if active == Some(axis) { ACTIVE_COLOR } else { *axis_color };
+            for segment in points.windows(2) {
+                draw_list.add_line(logical(segment[0]), logical(segment[1]), color).thickness(2.0).build();
+            }
+            let tip = logical(points[points.len() - 1]);
+            match self.mode {
+                GizmoMode::Translate => draw_list.add_circle(tip, 5.0, color).filled(true).build(),
+                GizmoMode::Scale => draw_list.add_rect([tip[0] - 4.0, tip[1] - 4.0], [tip[0] + 4.0, tip[1] + 4.0], color).filled(true).build(),
+                GizmoMode::Rotate => {}
+            }
+        }
+    }
+
+    // Where the cursor ray meets the handle, relative to the origin. On the axis itself for
+    // lines, in the plane of the circle for rotations.
+    fn grab_point(&self, axis: usize, ray: &culling::Ray) -> Option<cgmath::Vector3<f32>> {
+        let direction = self.axes[axis];
+        let ray_direction = ray.direction.normalize();
+        let offset = self.origin - ray.origin;
+        match self.mode {
+            GizmoMode::Translate | GizmoMode::Scale => {
+                // Closest point on the axis line to the ray
+                let b = direction.dot(ray_direction);
+                let denominator = 1.0 - b * b;
+                if denominator < 1e-4 {
+                    return None;
+                }
+                let t = (b * ray_direction.dot(offset) - direction.dot(offset)) / denominator;
+                Some(direction * t)
+            }
+            GizmoMode::Rotate => {
+                let denominator = direction.dot(ray_direction);
+                if denominator.abs() < 1e-4 {
+                    return None;
+                }
+                let t = direction.dot(offset) / denominator;
+                (t >= 0.0).then(|| ray.origin + ray_direction * t - self.origin)
+            }
+        }
+    }
+
+    // None when the cursor ray runs along the axis, or too close to the origin to scale from
+    pub fn begin_drag(&self, axis: usize, ray: &culling::Ray) -> Option<Drag> {
+        let start = self.grab_point(axis, ray)?;
+        if self.mode == GizmoMode::Scale && start.dot(self.axes[axis]).abs() < self.length * 0.05 {
+            return None;
+        }
+        Some(Drag { gizmo: *self, axis, start })
+    }
+}
+
+// A handle being dragged, the gizmo stays where it was when it started
+#[derive(Debug, Copy, Clone)]
+pub struct Drag {
+    pub gizmo: Gizmo,
+    pub axis: usize,
+    start: cgmath::Vector3<f32>,
+}
+
+impl Drag {
+    // How far the handle got dragged. A distance along the axis when translating, an angle in
+    // radians around it when rotating and a factor when scaling.
+    pub fn amount(&self, ray: &culling::Ray) -> Option<f32> {
+        let current = self.gizmo.grab_point(self.axis, ray)?;
+        let axis = self.gizmo.axes[self.axis];
+        Some(match self.gizmo.mode {
+            GizmoMode::Translate => (current - self.start).dot(axis),
+            GizmoMode::Rotate => axis.dot(self.start.cross(current)).atan2(self.start.dot(current)),
+            GizmoMode::Scale => current.dot(axis) / self.start.dot(axis),
+        })
+    }
+}
+
+fn segment_distance(point: [f32; 2], a: [f32; 2], b: [f32; 2]) -> f32 {
+    let (point, a, b) = (cgmath::Vector2::from(point), cgmath::Vector2::from(a), cgmath::Vector2::from(b));
+    let ab = b - a;
+    let t = if ab.magnitude2() > 0.0 { ((point - a).dot(ab) / ab.magnitude2()).clamp(0.0, 1.0) } else { 0.0 };
+    (a + ab * t - point).magnitude()
+}
@@ -0,0 +1,579 @@
+// Instance culling on the GPU. A compute pass tests every instance against the camera frustum
+// and the depth of the previous frame, copies the visible ones into a compacted buffer grouped
+// by level of detail and fills in the instance count of every mesh's indirect draws. Every
+// model gets its own set of buffers. Adapters without compute shaders or indirect draws keep
+// using the CPU culling in `culling`.
+use std::{
+    mem,
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use super::{culling, instances, model, shaders};
+
+const WORKGROUP_SIZE: u32 = 8;
+const CULL_WORKGROUP_SIZE: u32 = 64;
+const PYRAMID_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Float;
+// A visible count per level of detail
+const COUNTER_SIZE: wgpu::BufferAddress = (model::MAX_LODS * mem::size_of::<u32>()) as wgpu::BufferAddress;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullUniform {
+    planes: [[f32; 4]; 6],
+    previous_view_proj: [[f32; 4]; 4],
+    bounds_min: [f32; 3],
+    instance_count: u32,
+    bounds_max: [f32; 3],
+    occlusion: u32,
+    pyramid_size: [f32; 2],
+    pyramid_levels: u32,
+    mesh_count: u32,
+    capacity: u32,
+    _padding: [u32; 3],
+}
+
+// Laid out like wgpu's DrawIndexedIndirect, the cull pass fills in the instance count
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DrawCommand {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+pub struct GpuCulling {
+    // One per model, they all share the pipelines and the depth pyramid
+    targets: Vec<CullTarget>,
+    copy_depth_pipeline: wgpu::ComputePipeline,
+    downsample_pipeline: wgpu::ComputePipeline,
+    downsample_layout: wgpu::BindGroupLayout,
+    cull_pipeline: wgpu::ComputePipeline,
+    cull_layout: wgpu::BindGroupLayout,
+    commands_pipeline: wgpu::ComputePipeline,
+    commands_layout: wgpu::BindGroupLayout,
+    pyramid: Pyramid,
+    previous_view_proj: cgmath::Matrix4<f32>,
+    // Only once the pyramid was built from a frame it can be tested against
+    pyramid_valid: bool,
+}
+
+// The buffers culling the instances of one model
+struct CullTarget {
+    buffer: wgpu::Buffer,
+    // Every instance, straight from the CPU
+    instance_buffer: wgpu::Buffer,
+    // The level of detail of every instance
+    lod_buffer: wgpu::Buffer,
+    // The ones that passed, what the scene draws from. Every level gets room for all of them.
+    visible_buffer: wgpu::Buffer,
+    capacity: usize,
+    instance_count: u32,
+    counter_buffer: wgpu::Buffer,
+    commands: wgpu::Buffer,
+    mesh_count: u32,
+    cull_bind_group: wgpu::BindGroup,
+    commands_bind_group: wgpu::BindGroup,
+    readback: Readback,
+}
+
+// Max depth pyramid of the depth prepass, one level per mip
+struct Pyramid {
+    size: (u32, u32),
+    levels: u32,
+    view: wgpu::TextureView,
+    copy_bind_group: wgpu::BindGroup,
+    downsample_bind_groups: Vec<wgpu::BindGroup>,
+}
+
+// The visible count copied back for the stats. It's mapped without waiting on the GPU, so it
+// shows up a few frames late.
+struct Readback {
+    buffer: wgpu::Buffer,
+    mapped: Arc<AtomicBool>,
+    in_flight: bool,
+    copied: bool,
+    total: u32,
+    stats: culling::CullStats,
+}
+
+impl GpuCulling {
+    pub fn supported(adapter: &wgpu::Adapter) -> bool {
+        adapter.get_downlevel_capabilities().flags.contains(
+            wgpu::DownlevelFlags::COMPUTE_SHADERS | wgpu::DownlevelFlags::INDIRECT_EXECUTION,
+        )
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        depth_view: &wgpu::TextureView,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("GPU Culling Shader"),
+            source: wgpu::ShaderSource::Wgsl(shaders::source("gpu_culling.wgsl")),
+        });
+        let pipeline = |layout: Option<&wgpu::PipelineLayout>, entry_point| device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(entry_point),
+            layout,
+            module: &shader,
+            entry_point,
+        });
+        let pipeline_layout = |label, layout| device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts: &[layout],
+            push_constant_ranges: &[],
+        });
+        // Derived layouts assume float textures are filterable, which 32 bit floats aren't
+        let pyramid_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let buffer_entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let downsample_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("pyramid_downsample_bind_group_layout"),
+            entries: &[
+                pyramid_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: PYRAMID_FORMAT,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let cull_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("cull_bind_group_layout"),
+            entries: &[
+                buffer_entry(3, wgpu::BufferBindingType::Storage { read_only: true }),
+                buffer_entry(4, wgpu::BufferBindingType::Uniform),
+                buffer_entry(5, wgpu::BufferBindingType::Storage { read_only: true }),
+                buffer_entry(6, wgpu::BufferBindingType::Storage { read_only: false }),
+                buffer_entry(7, wgpu::BufferBindingType::Storage { read_only: false }),
+                pyramid_entry(8),
+            ],
+        });
+        let copy_depth_pipeline = pipeline(None, "cs_copy_depth");
+        let downsample_pipeline = pipeline(Some(&pipeline_layout("Pyramid Downsample Pipeline Layout", &downsample_layout)), "cs_downsample");
+        let cull_pipeline = pipeline(Some(&pipeline_layout("Cull Pipeline Layout", &cull_layout)), "cs_cull");
+        let commands_pipeline = pipeline(None, "cs_commands");
+
+        let pyramid = Pyramid::new(device, config, depth_view, &copy_depth_pipeline, &downsample_layout);
+
+        Self {
+            targets: Vec::new(),
+            copy_depth_pipeline,
+            downsample_pipeline,
+            downsample_layout,
+            cull_pipeline,
+            cull_layout,
+            commands_layout: commands_pipeline.get_bind_group_layout(0),
+            commands_pipeline,
+            pyramid,
+            previous_view_proj: cgmath::SquareMatrix::identity(),
+            pyramid_valid: false,
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, depth_view: &wgpu::TextureView) {
+        self.pyramid = Pyramid::new(device, config, depth_view, &self.copy_depth_pipeline, &self.downsample_layout);
+        for target in &mut self.targets {
+            target.cull_bind_group = target.create_cull_bind_group(device, &self.cull_layout, &self.pyramid.view);
+        }
+        self.pyramid_valid = false;
+    }
+
+    // Indirect draws of the compacted instances of the model at `index` in the scene that was
+    // culled last, one batch per level of detail
+    pub fn batches(&self, index: usize) -> Vec<model::LodBatch<'_>> {
+        let Some(target) = self.targets.get(index) else { return Vec::new() };
+        let level_size = (target.capacity * mem::size_of::<instances::InstanceRaw>()) as wgpu::BufferAddress;
+        (0..model::MAX_LODS)
+            .map(|lod| model::LodBatch {
+                lod,
+                instance_buffer: target.visible_buffer.slice(lod as wgpu::BufferAddress * level_size..(lod as wgpu::BufferAddress + 1) * level_size),
+                instances: model::DrawInstances::Indirect {
+                    commands: &target.commands,
+                    offset: (lod * target.mesh_count as usize) as wgpu::BufferAddress * model::DRAW_INDEXED_INDIRECT_SIZE,
+                },
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> culling::CullStats {
+        let mut stats = culling::CullStats::default();
+        for target in &self.targets {
+            stats += target.readback.stats;
+        }
+        stats
+    }
+
+    // Occlusion tests against the pyramid of the last frame, so `build_pyramid` has to run
+    // after this frame's depth prepass whenever `occlusion` is on.
+    pub fn cull(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &[model::ModelInstances],
+        view_proj: cgmath::Matrix4<f32>,
+        occlusion: bool,
+    ) {
+        self.targets.truncate(scene.len());
+        while self.targets.len() < scene.len() {
+            self.targets.push(CullTarget::new(device, &self.cull_layout, &self.commands_layout, &self.pyramid.view));
+        }
+
+        let frustum = culling::Frustum::from_matrix(view_proj);
+        for (target, instances) in self.targets.iter_mut().zip(scene) {
+            let model = instances.model;
+            let raws = instances.transforms.iter().map(instances::InstanceRaw::from_matrix).collect::<Vec<_>>();
+            if raws.len() > target.capacity {
+                target.capacity = raws.len().next_power_of_two();
+                (target.instance_buffer, target.lod_buffer, target.visible_buffer) = create_instance_buffers(device, target.capacity);
+                target.cull_bind_group = target.create_cull_bind_group(device, &self.cull_layout, &self.pyramid.view);
+            }
+            target.write_commands(device, queue, &self.commands_layout, model);
+            let lods = instances.lods.iter().map(|lod| *lod as u32).collect::<Vec<_>>();
+            queue.write_buffer(&target.instance_buffer, 0, bytemuck::cast_slice(&raws));
+            queue.write_buffer(&target.lod_buffer, 0, bytemuck::cast_slice(&lods));
+            queue.write_buffer(&target.counter_buffer, 0, bytemuck::cast_slice(&[0u32; model::MAX_LODS]));
+            queue.write_buffer(&target.buffer, 0, bytemuck::cast_slice(&[CullUniform {
+                planes: frustum.planes().map(|plane| plane.into()),
+                previous_view_proj: self.previous_view_proj.into(),
+                bounds_min: model.bounds.min.into(),
+                instance_count: raws.len() as u32,
+                bounds_max: model.bounds.max.into(),
+                occlusion: (occlusion && self.pyramid_valid) as u32,
+                pyramid_size: [self.pyramid.size.0 as f32, self.pyramid.size.1 as f32],
+                pyramid_levels: self.pyramid.levels,
+                mesh_count: target.mesh_count,
+                capacity: target.capacity as u32,
+                _padding: [0; 3],
+            }]));
+            target.instance_count = raws.len() as u32;
+        }
+        self.previous_view_proj = view_proj;
+        self.pyramid_valid = occlusion;
+
+        {
+            // Every dispatch sees the writes of the one before it
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: Some("Cull Pass") });
+            for target in &self.targets {
+                pass.set_pipeline(&self.cull_pipeline);
+                pass.set_bind_group(0, &target.cull_bind_group, &[]);
+                pass.dispatch_workgroups(target.instance_count.div_ceil(CULL_WORKGROUP_SIZE).max(1), 1, 1);
+                pass.set_pipeline(&self.commands_pipeline);
+                pass.set_bind_group(0, &target.commands_bind_group, &[]);
+                pass.dispatch_workgroups((target.mesh_count * model::MAX_LODS as u32).div_ceil(CULL_WORKGROUP_SIZE).max(1), 1, 1);
+            }
+        }
+
+        for target in &mut self.targets {
+            // The readback buffer can't be written to while it's mapped
+            if !target.readback.in_flight {
+                encoder.copy_buffer_to_buffer(&target.counter_buffer, 0, &target.readback.buffer, 0, COUNTER_SIZE);
+                target.readback.copied = true;
+                target.readback.total = target.instance_count;
+            }
+        }
+    }
+
+    // Builds the pyramid the next frame's occlusion test uses, from the depth prepass
+    pub fn build_pyramid(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: Some("Depth Pyramid") });
+        let (width, height) = self.pyramid.size;
+        pass.set_pipeline(&self.copy_depth_pipeline);
+        pass.set_bind_group(0, &self.pyramid.copy_bind_group, &[]);
+        pass.dispatch_workgroups(width.div_ceil(WORKGROUP_SIZE), height.div_ceil(WORKGROUP_SIZE), 1);
+
+        pass.set_pipeline(&self.downsample_pipeline);
+        for (level, bind_group) in (1..).zip(&self.pyramid.downsample_bind_groups) {
+            pass.set_bind_group(0, bind_group, &[]);
+            let (width, height) = ((width >> level).max(1), (height >> level).max(1));
+            pass.dispatch_workgroups(width.div_ceil(WORKGROUP_SIZE), height.div_ceil(WORKGROUP_SIZE), 1);
+        }
+    }
+
+    // Call once the frame is submitted, it picks up the visible counts when they're back
+    pub fn after_submit(&mut self, device: &wgpu::Device) {
+        for target in &mut self.targets {
+            let readback = &mut target.readback;
+            if readback.copied {
+                readback.copied = false;
+                readback.in_flight = true;
+                let mapped = readback.mapped.clone();
+                readback.buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                    if result.is_ok() {
+                        mapped.store(true, Ordering::Release);
+                    }
+                });
+            }
+        }
+        device.poll(wgpu::Maintain::Poll);
+
+        for target in &mut self.targets {
+            let readback = &mut target.readback;
+            if readback.mapped.swap(false, Ordering::Acquire) {
+                let drawn = {
+                    let data = readback.buffer.slice(..).get_mapped_range();
+                    bytemuck::cast_slice::<u8, u32>(&data).iter().sum::<u32>()
+                };
+                readback.buffer.unmap();
+                readback.in_flight = false;
+                readback.stats = culling::CullStats::default();
+                readback.stats.add(drawn as usize, readback.total.max(drawn) as usize);
+            }
+        }
+    }
+}
+
+impl CullTarget {
+    fn new(
+        device: &wgpu::Device,
+        cull_layout: &wgpu::BindGroupLayout,
+        commands_layout: &wgpu::BindGroupLayout,
+        pyramid_view: &wgpu::TextureView,
+    ) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull Buffer"),
+            size: mem::size_of::<CullUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let counter_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull Counter Buffer"),
+            size: COUNTER_SIZE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let commands = create_commands_buffer(device, 1);
+        let commands_bind_group = create_commands_bind_group(device, commands_layout, &buffer, &counter_buffer, &commands);
+        let readback = Readback {
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Cull Readback Buffer"),
+                size: COUNTER_SIZE,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            mapped: Arc::new(AtomicBool::new(false)),
+            in_flight: false,
+            copied: false,
+            total: 0,
+            stats: culling::CullStats::default(),
+        };
+
+        let capacity = 1;
+        let (instance_buffer, lod_buffer, visible_buffer) = create_instance_buffers(device, capacity);
+        let cull_bind_group = create_cull_bind_group(device, cull_layout, &buffer, &instance_buffer, &lod_buffer, &visible_buffer, &counter_buffer, pyramid_view);
+
+        Self {
+            buffer,
+            instance_buffer,
+            lod_buffer,
+            visible_buffer,
+            capacity,
+            instance_count: 0,
+            counter_buffer,
+            commands,
+            mesh_count: 1,
+            cull_bind_group,
+            commands_bind_group,
+            readback,
+        }
+    }
+
+    fn create_cull_bind_group(&self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout, pyramid_view: &wgpu::TextureView) -> wgpu::BindGroup {
+        create_cull_bind_group(
+            device,
+            layout,
+            &self.buffer,
+            &self.instance_buffer,
+            &self.lod_buffer,
+            &self.visible_buffer,
+            &self.counter_buffer,
+            pyramid_view,
+        )
+    }
+
+    // Everything but the instance count comes from the model. Ordered by level, then mesh.
+    fn write_commands(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, commands_layout: &wgpu::BindGroupLayout, model: &model::Model) {
+        let mesh_count = model.meshes.len().max(1) as u32;
+        if mesh_count > self.mesh_count {
+            self.commands = create_commands_buffer(device, mesh_count);
+            self.commands_bind_group = create_commands_bind_group(device, commands_layout, &self.buffer, &self.counter_buffer, &self.commands);
+        }
+        self.mesh_count = model.meshes.len() as u32;
+        let draw_commands = (0..model::MAX_LODS)
+            .flat_map(|level| model.meshes.iter().map(move |mesh| mesh.lod(level)))
+            .map(|lod| DrawCommand {
+                index_count: lod.num_elements,
+                instance_count: 0,
+                first_index: 0,
+                base_vertex: lod.base_vertex,
+                first_instance: 0,
+            })
+            .collect::<Vec<_>>();
+        queue.write_buffer(&self.commands, 0, bytemuck::cast_slice(&draw_commands));
+    }
+}
+
+impl Pyramid {
+    fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        depth_view: &wgpu::TextureView,
+        copy_depth_pipeline: &wgpu::ComputePipeline,
+        downsample_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let size = (config.width.max(1), config.height.max(1));
+        let levels = 32 - size.0.max(size.1).leading_zeros();
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Depth Pyramid"),
+            size: wgpu::Extent3d {
+                width: size.0,
+                height: size.1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: PYRAMID_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+        });
+        let level_view = |level| texture.create_view(&wgpu::TextureViewDescriptor {
+            label: None,
+            base_mip_level: level,
+            mip_level_count: NonZeroU32::new(1),
+            ..Default::default()
+        });
+        let level_views = (0..levels).map(level_view).collect::<Vec<_>>();
+
+        let copy_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Depth Pyramid Copy Bind Group"),
+            layout: &copy_depth_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(depth_view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&level_views[0]) },
+            ],
+        });
+        let downsample_bind_groups = level_views
+            .windows(2)
+            .map(|views| device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Depth Pyramid Downsample Bind Group"),
+                layout: downsample_layout,
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&views[0]) },
+                    wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&views[1]) },
+                ],
+            }))
+            .collect();
+
+        Self {
+            size,
+            levels,
+            view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            copy_bind_group,
+            downsample_bind_groups,
+        }
+    }
+}
+
+fn create_instance_buffers(device: &wgpu::Device, capacity: usize) -> (wgpu::Buffer, wgpu::Buffer, wgpu::Buffer) {
+    let size = (capacity * mem::size_of::<instances::InstanceRaw>()) as wgpu::BufferAddress;
+    let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Cull Instance Buffer"),
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let lod_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Cull LOD Buffer"),
+        size: (capacity * mem::size_of::<u32>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let visible_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Visible Instance Buffer"),
+        size: size * model::MAX_LODS as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::VERTEX,
+        mapped_at_creation: false,
+    });
+    (instance_buffer, lod_buffer, visible_buffer)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_cull_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffer: &wgpu::Buffer,
+    instance_buffer: &wgpu::Buffer,
+    lod_buffer: &wgpu::Buffer,
+    visible_buffer: &wgpu::Buffer,
+    counter_buffer: &wgpu::Buffer,
+    pyramid_view: &wgpu::TextureView,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Cull Bind Group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry { binding: 3, resource: lod_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 4, resource: buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 5, resource: instance_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 6, resource: visible_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 7, resource: counter_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 8, resource: wgpu::BindingResource::TextureView(pyramid_view) },
+        ],
+    })
+}
+
+fn create_commands_buffer(device: &wgpu::Device, mesh_count: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Indirect Draw Buffer"),
+        size: (mesh_count as usize * model::MAX_LODS * mem::size_of::<DrawCommand>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_commands_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffer: &wgpu::Buffer,
+    counter_buffer: &wgpu::Buffer,
+    commands: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Indirect Draw Bind Group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry { binding: 4, resource: buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 7, resource: counter_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 9, resource: commands.as_entire_binding() },
+        ],
+    })
+}
@@ -0,0 +1,380 @@
+use super::{render_pipeline, shaders};
+use std::{mem, time::Instant};
+use wgpu::util::DeviceExt;
+
+pub use crate::world::environment::{HdrSettings, Tonemapper};
+
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const HISTOGRAM_BINS: u64 = 256;
+const HISTOGRAM_WORKGROUP_SIZE: u32 = 16;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TonemapUniform {
+    tonemapper: u32,
+    auto_exposure: u32,
+    exposure: f32,
+    exposure_compensation: f32,
+    apply_srgb: u32,
+    _padding: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LuminanceUniform {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    time_coeff: f32,
+    pixel_count: u32,
+}
+
+// The scene renders into a float target first, this maps it onto the surface
+pub struct Hdr {
+    tonemap_pass: render_pipeline::FullscreenPass,
+    tonemap_bind_group: wgpu::BindGroup,
+    tonemap_buffer: wgpu::Buffer,
+
+    histogram_pipeline: wgpu::ComputePipeline,
+    average_pipeline: wgpu::ComputePipeline,
+    luminance_bind_group_layout: wgpu::BindGroupLayout,
+    luminance_bind_group: wgpu::BindGroup,
+    luminance_buffer: wgpu::Buffer,
+    histogram_buffer: wgpu::Buffer,
+    average_buffer: wgpu::Buffer,
+
+    width: u32,
+    height: u32,
+    auto_exposure: bool,
+    last_update: Instant,
+    sample_count: u32,
+    // The scene renders into this one when multisampling and gets resolved into view
+    msaa_view: Option<wgpu::TextureView>,
+
+    pub view: wgpu::TextureView,
+}
+
+impl Hdr {
+    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, sample_count: u32) -> Self {
+        let tonemap_pass = {
+            let shader = render_pipeline::create_fullscreen_shader(device, "Tonemap Shader", &shaders::source("tonemap.wgsl"));
+            render_pipeline::FullscreenPass::new(device, "Tonemap", &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ], config.format, wgpu::BlendState::REPLACE, &shader, "fs_main")
+        };
+
+        let luminance_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Luminance Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let (histogram_pipeline, average_pipeline) = {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Luminance Pipeline Layout"),
+                bind_group_layouts: &[&luminance_bind_group_layout],
+                push_constant_ranges: &[],
+            });
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Luminance Shader"),
+                source: wgpu::ShaderSource::Wgsl(shaders::source("luminance.wgsl")),
+            });
+            let histogram = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Luminance Histogram Pipeline"),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point: "build_histogram",
+            });
+            let average = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Luminance Average Pipeline"),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point: "average_histogram",
+            });
+            (histogram, average)
+        };
+
+        let tonemap_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tonemap Buffer"),
+            size: mem::size_of::<TonemapUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let luminance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Luminance Params Buffer"),
+            size: mem::size_of::<LuminanceUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let histogram_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Luminance Histogram Buffer"),
+            contents: bytemuck::cast_slice(&[0u32; HISTOGRAM_BINS as usize]),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        // Starts out at middle grey so the first frames aren't blown out
+        let average_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Average Luminance Buffer"),
+            contents: bytemuck::cast_slice(&[0.18f32]),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let view = Self::create_target(device, config.width, config.height, 1);
+        let msaa_view = (sample_count > 1).then(|| Self::create_target(device, config.width, config.height, sample_count));
+        let (tonemap_bind_group, luminance_bind_group) = Self::create_bind_groups(
+            device,
+            &view,
+            &tonemap_pass.bind_group_layout,
+            &luminance_bind_group_layout,
+            &tonemap_buffer,
+            &luminance_buffer,
+            &histogram_buffer,
+            &average_buffer,
+        );
+
+        Self {
+            tonemap_pass,
+            tonemap_bind_group,
+            tonemap_buffer,
+            histogram_pipeline,
+            average_pipeline,
+            luminance_bind_group_layout,
+            luminance_bind_group,
+            luminance_buffer,
+            histogram_buffer,
+            average_buffer,
+            width: config.width,
+            height: config.height,
+            auto_exposure: true,
+            last_update: Instant::now(),
+            sample_count,
+            msaa_view,
+            view,
+        }
+    }
+
+    fn create_target(device: &wgpu::Device, width: u32, height: u32, sample_count: u32) -> wgpu::TextureView {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("HDR Texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_bind_groups(
+        device: &wgpu::Device,
+        view: &wgpu::TextureView,
+        tonemap_layout: &wgpu::BindGroupLayout,
+        luminance_layout: &wgpu::BindGroupLayout,
+        tonemap_buffer: &wgpu::Buffer,
+        luminance_buffer: &wgpu::Buffer,
+        histogram_buffer: &wgpu::Buffer,
+        average_buffer: &wgpu::Buffer,
+    ) -> (wgpu::BindGroup, wgpu::BindGroup) {
+        let tonemap = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Tonemap Bind Group"),
+            layout: tonemap_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: tonemap_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: average_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let luminance = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Luminance Bind Group"),
+            layout: luminance_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: histogram_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: average_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: luminance_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        (tonemap, luminance)
+    }
+
+    // The hdr target always matches the surface size
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.view = Self::create_target(device, width, height, 1);
+        self.msaa_view = (self.sample_count > 1).then(|| Self::create_target(device, width, height, self.sample_count));
+        let (tonemap, luminance) = Self::create_bind_groups(
+            device,
+            &self.view,
+            &self.tonemap_pass.bind_group_layout,
+            &self.luminance_bind_group_layout,
+            &self.tonemap_buffer,
+            &self.luminance_buffer,
+            &self.histogram_buffer,
+            &self.average_buffer,
+        );
+        self.tonemap_bind_group = tonemap;
+        self.luminance_bind_group = luminance;
+    }
+
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, sample_count: u32) {
+        self.sample_count = sample_count;
+        self.msaa_view = (sample_count > 1).then(|| Self::create_target(device, self.width, self.height, sample_count));
+    }
+
+    // Where the scene pass draws to, resolving into the hdr target when multisampled
+    pub fn scene_attachment(&self) -> wgpu::RenderPassColorAttachment<'_> {
+        let (view, resolve_target) = match &self.msaa_view {
+            Some(msaa_view) => (msaa_view, Some(&self.view)),
+            None => (&self.view, None),
+        };
+        wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                // The samples themselves aren't needed after the resolve
+                store: self.msaa_view.is_none(),
+            },
+        }
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, config: &wgpu::SurfaceConfiguration, settings: &HdrSettings) {
+        let now = Instant::now();
+        let delta = (now - self.last_update).as_secs_f32();
+        self.last_update = now;
+        self.auto_exposure = settings.auto_exposure;
+
+        queue.write_buffer(&self.tonemap_buffer, 0, bytemuck::cast_slice(&[TonemapUniform {
+            tonemapper: settings.tonemapper as u32,
+            auto_exposure: settings.auto_exposure as u32,
+            exposure: settings.exposure.exp2(),
+            exposure_compensation: settings.exposure_compensation.exp2(),
+            apply_srgb: !config.format.describe().srgb as u32,
+            _padding: [0; 3],
+        }]));
+
+        let log_luminance_range = (settings.max_log_luminance - settings.min_log_luminance).max(0.01);
+        queue.write_buffer(&self.luminance_buffer, 0, bytemuck::cast_slice(&[LuminanceUniform {
+            min_log_luminance: settings.min_log_luminance,
+            log_luminance_range,
+            time_coeff: (1.0 - (-delta * settings.adaptation_speed).exp()).clamp(0.0, 1.0),
+            pixel_count: self.width * self.height,
+        }]));
+    }
+
+    // Measures the hdr target (when auto exposure is on) and tonemaps it into the target,
+    // which is the surface unless there are post effects after it
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+        if self.auto_exposure {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Luminance Pass"),
+            });
+            pass.set_bind_group(0, &self.luminance_bind_group, &[]);
+            pass.set_pipeline(&self.histogram_pipeline);
+            pass.dispatch_workgroups(
+                self.width.div_ceil(HISTOGRAM_WORKGROUP_SIZE),
+                self.height.div_ceil(HISTOGRAM_WORKGROUP_SIZE),
+                1,
+            );
+            pass.set_pipeline(&self.average_pipeline);
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        self.tonemap_pass.draw(encoder, target, &self.tonemap_bind_group, wgpu::LoadOp::Clear(wgpu::Color::BLACK), None);
+    }
+}
@@ -0,0 +1,176 @@
+// Hot reloading. The directories of the asset root get polled for files that were written to
+// or deleted, and the State rebuilds whatever was made from them. Shaders are resources under
+// `shaders::PREFIX`, so they get watched wherever they're read from.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{assets, shaders};
+
+// How often the directories get walked
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    // By the file name the shader got loaded with
+    Shader(String),
+    // By the name the resource loaders take
+    Resource(String),
+}
+
+pub struct Watcher {
+    // The asset root's directories with the prefix of their names, the mounts that aren't
+    // loose files can't change
+    asset_dirs: Vec<(PathBuf, String)>,
+    modified: HashMap<PathBuf, SystemTime>,
+    last_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new(asset_dirs: Vec<(PathBuf, String)>) -> Self {
+        let mut watcher = Self {
+            asset_dirs,
+            modified: HashMap::new(),
+            last_poll: Instant::now(),
+        };
+        // Everything that's there now is what got loaded
+        watcher.changed_files();
+        watcher
+    }
+
+    // Files that got added, written to or deleted since the last poll. Nothing until
+    // POLL_INTERVAL has passed since then.
+    pub fn poll(&mut self) -> Vec<Change> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+        self.changes()
+    }
+
+    fn changes(&mut self) -> Vec<Change> {
+        self.changed_files().into_iter().filter_map(|path| self.change(&path)).collect()
+    }
+
+    // What a file in one of the directories is to the loaders
+    fn change(&self, path: &Path) -> Option<Change> {
+        let name = self
+            .asset_dirs
+            .iter()
+            .find_map(|(root, prefix)| Some(format!("{}{}", prefix, assets::relative_name(root, path)?)))?;
+        match name.strip_prefix(shaders::PREFIX).and_then(|name| name.strip_prefix('/')) {
+            Some(shader) => Some(Change::Shader(shader.to_string())),
+            None => Some(Change::Resource(name)),
+        }
+    }
+
+    fn changed_files(&mut self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for (root, _) in &self.asset_dirs {
+            modified_times(root, &mut files);
+        }
+        let previous = std::mem::take(&mut self.modified);
+        let mut changed = Vec::new();
+        for (path, modified) in files {
+            if previous.get(&path) != Some(&modified) {
+                changed.push(path.clone());
+            }
+            self.modified.insert(path, modified);
+        }
+        // Deleted ones too, whatever is mounted after their directory gets loaded instead
+        changed.extend(previous.into_keys().filter(|path| !self.modified.contains_key(path)));
+        changed
+    }
+}
+
+fn modified_times(root: &Path, files: &mut Vec<(PathBuf, SystemTime)>) {
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let Ok(entries) = std::fs::read_dir(&directory) else { continue };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else { continue };
+            if metadata.is_dir() {
+                directories.push(path);
+            } else if let Ok(modified) = metadata.modified() {
+                files.push((path, modified));
+            }
+        }
+    }
+}
+
+// Runs `build` with wgpu's validation errors caught instead of panicking, so a broken shader
+// gives an error and whatever was built from the last good one can stay in use
+pub fn validated<T>(device: &wgpu::Device, build: impl FnOnce() -> T) -> anyhow::Result<T> {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let built = build();
+    match pollster::block_on(device.pop_error_scope()) {
+        None => Ok(built),
+        Some(error) => Err(anyhow::anyhow!("{}", error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::assets::tests::TempDir;
+
+    fn touch(path: &Path, text: &str) {
+        std::fs::write(path, text).unwrap();
+        // Written twice in the same instant can keep the modification time, so it's moved on
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+    }
+
+    fn sorted(mut changes: Vec<Change>) -> Vec<Change> {
+        changes.sort_by_key(|change| format!("{:?}", change));
+        changes
+    }
+
+    #[test]
+    fn notices_written_files_under_a_prefix() {
+        let shaders = TempDir::new("watch_prefixed", &[("post.wgsl", "old"), ("ssao.wgsl", "old")]);
+        let mut watcher = Watcher::new(vec![(shaders.0.clone(), "shaders/".to_string())]);
+        assert!(watcher.changes().is_empty());
+
+        touch(&shaders.0.join("post.wgsl"), "new");
+        assert_eq!(watcher.changes(), [Change::Shader("post.wgsl".into())]);
+        // Only once
+        assert!(watcher.changes().is_empty());
+    }
+
+    #[test]
+    fn notices_added_and_deleted_files() {
+        let res = TempDir::new("watch_added", &[("models/cube.obj", "v"), ("shaders/post.wgsl", "override")]);
+        let mut watcher = Watcher::new(vec![(res.0.clone(), String::new())]);
+
+        touch(&res.0.join("models").join("tree.obj"), "v");
+        std::fs::remove_file(res.0.join("shaders").join("post.wgsl")).unwrap();
+        assert_eq!(
+            sorted(watcher.changes()),
+            [Change::Resource("models/tree.obj".into()), Change::Shader("post.wgsl".into())]
+        );
+        assert!(watcher.changes().is_empty());
+
+        // A whole directory going counts for every file in it
+        std::fs::remove_dir_all(res.0.join("models")).unwrap();
+        assert_eq!(
+            sorted(watcher.changes()),
+            [Change::Resource("models/cube.obj".into()), Change::Resource("models/tree.obj".into())]
+        );
+    }
+
+    #[test]
+    fn names_changes_like_the_loaders() {
+        let res = std::env::temp_dir().join(format!("hot_reload_res_{}", std::process::id()));
+        let source_shaders = std::env::temp_dir().join(format!("hot_reload_shaders_{}", std::process::id()));
+        let watcher = Watcher::new(vec![(res.clone(), String::new()), (source_shaders.clone(), "shaders/".to_string())]);
+        assert_eq!(watcher.change(&res.join("models").join("cube.obj")), Some(Change::Resource("models/cube.obj".into())));
+        // Shaders overridden in a resource directory and ones from a shader directory alike
+        assert_eq!(watcher.change(&res.join("shaders").join("post.wgsl")), Some(Change::Shader("post.wgsl".into())));
+        assert_eq!(watcher.change(&source_shaders.join("ssao.wgsl")), Some(Change::Shader("ssao.wgsl".into())));
+        assert_eq!(watcher.change(Path::new("/somewhere/else.png")), None);
+    }
+}
@@ -1,12 +1,7 @@
 use cgmath;
-use wgpu::util::DeviceExt;
 
 use super::model;
-
-pub struct Instance {
-    pub position: cgmath::Vector3<f32>,
-    pub rotation: cgmath::Quaternion<f32>,
-}
+pub use crate::world::scene::Instance;
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -18,15 +13,19 @@ pub struct InstanceRaw {
 
 pub struct InstanceBuffer {
     pub buffer: wgpu::Buffer,
+    capacity: usize,
 }
 
-impl Instance {
-    pub fn to_raw(&self) -> InstanceRaw {
-        InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position)
-                * cgmath::Matrix4::from(self.rotation))
-            .into(),
-            normal: cgmath::Matrix3::from(self.rotation).into(),
+impl InstanceRaw {
+    // The normal matrix is the inverse transpose, so normals stay perpendicular to the surface
+    // under non-uniform scale
+    pub fn from_matrix(model: &cgmath::Matrix4<f32>) -> Self {
+        use cgmath::{Matrix, SquareMatrix};
+        let linear = cgmath::Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        let normal = linear.invert().unwrap_or(linear).transpose();
+        Self {
+            model: (*model).into(),
+            normal: normal.into(),
         }
     }
 }
@@ -87,18 +86,24 @@ impl model::Vertex for InstanceRaw {
 }
 
 impl InstanceBuffer {
-    pub fn new(device: &wgpu::Device, instances: &Vec<Instance>) -> Self {
-        let instance_raws = instances
-            .iter()
-            .map(|instance| instance.to_raw())
-            .collect::<Vec<_>>();
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(&instance_raws),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
+    pub fn with_capacity(device: &wgpu::Device, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
         Self {
-            buffer: instance_buffer,
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Instance Buffer"),
+                size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            capacity,
+        }
+    }
+
+    // Replaces the contents, the buffer grows when they don't fit anymore
+    pub fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, raws: &[InstanceRaw]) {
+        if raws.len() > self.capacity {
+            *self = Self::with_capacity(device, raws.len().next_power_of_two());
         }
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(raws));
     }
 }
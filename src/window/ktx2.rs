@@ -0,0 +1,179 @@
+// Reader for KTX2 containers holding a single 2D image with its mip levels. Only files
+// without supercompression are supported, Basis and zstd would need a transcoder.
+use anyhow::*;
+
+const IDENTIFIER: [u8; 12] = [0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a];
+const HEADER_SIZE: usize = 80;
+const LEVEL_INDEX_SIZE: usize = 24;
+
+pub struct Ktx2 {
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    // Largest first, like the mip levels of the texture
+    pub levels: Vec<Vec<u8>>,
+}
+
+pub fn is_ktx2(bytes: &[u8]) -> bool {
+    bytes.starts_with(&IDENTIFIER)
+}
+
+// The Vulkan formats we can upload, keyed by their VkFormat value
+fn texture_format(vk_format: u32) -> Option<wgpu::TextureFormat> {
+    use wgpu::TextureFormat::*;
+    Some(match vk_format {
+        37 => Rgba8Unorm,
+        43 => Rgba8UnormSrgb,
+        131 | 133 => Bc1RgbaUnorm,
+        132 | 134 => Bc1RgbaUnormSrgb,
+        135 => Bc2RgbaUnorm,
+        136 => Bc2RgbaUnormSrgb,
+        137 => Bc3RgbaUnorm,
+        138 => Bc3RgbaUnormSrgb,
+        139 => Bc4RUnorm,
+        140 => Bc4RSnorm,
+        141 => Bc5RgUnorm,
+        142 => Bc5RgSnorm,
+        143 => Bc6hRgbUfloat,
+        144 => Bc6hRgbSfloat,
+        145 => Bc7RgbaUnorm,
+        146 => Bc7RgbaUnormSrgb,
+        _ => return None,
+    })
+}
+
+impl Ktx2 {
+    pub fn parse(bytes: &[u8], label: &str) -> Result<Self> {
+        if !is_ktx2(bytes) || bytes.len() < HEADER_SIZE {
+            bail!("{} is not a KTX2 file", label);
+        }
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        let vk_format = u32_at(12);
+        let format = texture_format(vk_format)
+            .ok_or_else(|| anyhow!("{} uses VkFormat {}, which isn't supported", label, vk_format))?;
+        let (width, height, depth) = (u32_at(20), u32_at(24), u32_at(28));
+        let (layers, faces) = (u32_at(32), u32_at(36));
+        if width == 0 || height == 0 || depth > 1 || layers > 1 || faces != 1 {
+            bail!("{} isn't a single 2D image", label);
+        }
+        if u32_at(44) != 0 {
+            bail!("{} is supercompressed, only uncompressed KTX2 files are supported", label);
+        }
+        // Zero asks the loader to generate them, we only use what's in the file
+        let level_count = u32_at(40).max(1);
+        let max_levels = 32 - width.max(height).leading_zeros();
+        if level_count > max_levels {
+            bail!("{} has {} mip levels, a {}x{} image has at most {}", label, level_count, width, height, max_levels);
+        }
+        let level_count = level_count as usize;
+        if bytes.len() < HEADER_SIZE + level_count * LEVEL_INDEX_SIZE {
+            bail!("{} is cut off in the level index", label);
+        }
+
+        let info = format.describe();
+        let (block_width, block_height) = (info.block_dimensions.0 as usize, info.block_dimensions.1 as usize);
+        let mut levels = Vec::with_capacity(level_count);
+        for level in 0..level_count {
+            let entry = HEADER_SIZE + level * LEVEL_INDEX_SIZE;
+            let (offset, length) = (u64_at(entry), u64_at(entry + 8));
+            let data = usize::try_from(offset)
+                .ok()
+                .zip(usize::try_from(length).ok())
+                .and_then(|(offset, length)| bytes.get(offset..offset.checked_add(length)?))
+                .ok_or_else(|| anyhow!("{} mip {} points outside the file", label, level))?;
+
+            let (level_width, level_height) = ((width >> level).max(1) as usize, (height >> level).max(1) as usize);
+            let expected = level_width
+                .div_ceil(block_width)
+                .checked_mul(level_height.div_ceil(block_height))
+                .and_then(|blocks| blocks.checked_mul(info.block_size as usize))
+                .ok_or_else(|| anyhow!("{} mip {} is too large", label, level))?;
+            if data.len() < expected {
+                bail!("{} mip {} has {} bytes, expected {}", label, level, data.len(), expected);
+            }
+            levels.push(data[..expected].to_vec());
+        }
+
+        Ok(Self { format, width, height, levels })
+    }
+
+    // Normal maps hold vectors, so sRGB files get read as the linear version of their format
+    pub fn into_linear(mut self) -> Self {
+        use wgpu::TextureFormat::*;
+        self.format = match self.format {
+            Rgba8UnormSrgb => Rgba8Unorm,
+            Bc1RgbaUnormSrgb => Bc1RgbaUnorm,
+            Bc2RgbaUnormSrgb => Bc2RgbaUnorm,
+            Bc3RgbaUnormSrgb => Bc3RgbaUnorm,
+            Bc7RgbaUnormSrgb => Bc7RgbaUnorm,
+            format => format,
+        };
+        self
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.format.describe().block_dimensions != (1, 1)
+    }
+
+    pub fn level_size(&self, level: usize) -> (u32, u32) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file with the given header values and one level index entry per level, every level
+    // holding `level_bytes` bytes
+    fn file(vk_format: u32, width: u32, height: u32, level_count: u32, supercompression: u32, level_bytes: &[usize]) -> Vec<u8> {
+        let mut bytes = IDENTIFIER.to_vec();
+        for value in [vk_format, 1, width, height, 0, 0, 1, level_count, supercompression] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.resize(HEADER_SIZE, 0);
+        let mut offset = HEADER_SIZE + level_bytes.len() * LEVEL_INDEX_SIZE;
+        for length in level_bytes {
+            for value in [offset as u64, *length as u64, *length as u64] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            offset += length;
+        }
+        let data_size: usize = level_bytes.iter().sum();
+        bytes.extend((0..data_size).map(|i| i as u8));
+        bytes
+    }
+
+    #[test]
+    fn parses_levels() {
+        let ktx = Ktx2::parse(&file(43, 4, 2, 3, 0, &[32, 8, 4]), "test").unwrap();
+        assert_eq!((ktx.format, ktx.width, ktx.height), (wgpu::TextureFormat::Rgba8UnormSrgb, 4, 2));
+        assert_eq!(ktx.levels.iter().map(Vec::len).collect::<Vec<_>>(), [32, 8, 4]);
+        assert_eq!(ktx.level_size(2), (1, 1));
+        assert_eq!(ktx.levels[1][0], 32);
+        assert_eq!(ktx.into_linear().format, wgpu::TextureFormat::Rgba8Unorm);
+
+        // Blocks round up, a 2x2 BC1 level is still a whole block
+        let ktx = Ktx2::parse(&file(131, 2, 2, 1, 0, &[8]), "test").unwrap();
+        assert!(ktx.is_compressed());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let parse = |bytes: Vec<u8>| Ktx2::parse(&bytes, "test").map(|_| ()).unwrap_err().to_string();
+        assert!(parse(b"not a ktx2 file".to_vec()).contains("not a KTX2 file"));
+        assert!(parse(file(9999, 4, 4, 1, 0, &[64])).contains("isn't supported"));
+        assert!(parse(file(37, 0, 4, 1, 0, &[64])).contains("single 2D image"));
+        assert!(parse(file(37, 4, 4, 1, 2, &[64])).contains("supercompressed"));
+        assert!(parse(file(37, 4, 4, 4, 0, &[64, 16, 4, 4])).contains("at most 3"));
+        assert!(parse(file(37, 1, 1, 40, 0, &[4])).contains("at most 1"));
+        assert!(parse(file(37, 4, 4, 1, 0, &[64])[..HEADER_SIZE + 10].to_vec()).contains("cut off"));
+        assert!(parse(file(37, 4, 4, 1, 0, &[32])).contains("expected 64"));
+
+        let mut outside = file(37, 4, 4, 1, 0, &[64]);
+        outside[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse(outside).contains("outside the file"));
+    }
+}
@@ -1,28 +1,22 @@
 use std::{mem};
 
+use bytemuck::Zeroable;
 use wgpu::util::DeviceExt;
 
+use super::{camera, shadow};
+pub use crate::world::{environment::DirectionalLight, Light};
+
+// Depth range of the point and spot light shadow maps, shader.wgsl has a copy of these
+pub const SHADOW_NEAR: f32 = 0.1;
+pub const SHADOW_FAR: f32 = 100.0;
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct Light {
-    pub id: usize,
-    pub position: cgmath::Vector3<f32>,
-    pub color: cgmath::Vector3<f32>,
-    pub intensity: f32,
-    pub radius: f32,
-    pub is_spotlight: bool,
-    pub limitcos_inner: f32,
-    pub limitcos_outer: f32,
-    pub limitdir: cgmath::Vector3<f32>,
-}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct LightRaw {
     pub proj: [[f32; 4]; 4],
     pub position: [f32; 3],
-    pub _padding: u32,
+    pub shadow_index: u32, // Cube for point lights, atlas tile for spot lights
     pub color: [f32; 3],
     pub intensity: f32,
     pub radius: f32,
@@ -30,53 +24,32 @@ pub struct LightRaw {
     pub limitcos_inner: f32,
     pub limitcos_outer: f32,
     pub limitdir: [f32; 3],
-    pub _padding1: u32,
+    pub normal_offset: f32,
+    pub shadow_rect: [f32; 4], // Offset and scale of the spot light's tile in the atlas
+    pub slope_bias: f32,
+    pub _padding: [u32; 3],
 }
 
 pub struct LightBuffer {
     pub buffer: wgpu::Buffer,
     pub light_num_buffer: wgpu::Buffer,
+    capacity: usize,
+    pub bind_group: wgpu::BindGroup,
+    pub bind_group_layout: wgpu::BindGroupLayout,
 }
 
+// The GPU side of the world's lights
 impl Light {
-    pub fn new(
-        id: usize,
-        position: cgmath::Vector3<f32>,
-        color: cgmath::Vector3<f32>,
-        intensity: f32,
-        radius: f32,
-    ) -> Self {
-        Self {
-            id,
-            position,
-            color,
-            intensity,
-            radius,
-            is_spotlight: false,
-            limitcos_inner: 0.9,
-            limitcos_outer: 1.0,
-            limitdir: (0.0, 0.0, 0.0).into(),
-        }
-    }
-    pub fn to_raw(&self) -> LightRaw {
-        let pos = cgmath::point3(self.position.x, self.position.y, self.position.z);
-        let center = cgmath::point3(self.position.x,0.,0.);
-        let view = cgmath::Matrix4::look_at_rh(
-            pos,
-            center,
-            cgmath::Vector3::new(0.0, 1.0, 0.0),
-        );
-        let projection = cgmath::perspective(cgmath::Deg(90.), 1.0, 0.1, 100.0);
-        let view_proj = (projection) * view;
-        let view_proj: [[f32;4]; 4] = [
-            view_proj.x.into(),
-            view_proj.y.into(),
-            view_proj.z.into(),
-            view_proj.w.into(),
-        ];
+    pub fn to_raw(self, shadow_index: u32, shadow_rect: [f32; 4]) -> LightRaw {
+        // Point lights sample their cube by direction, only spot lights need a projection
+        let view_proj: [[f32; 4]; 4] = if self.is_spotlight {
+            self.spot_view_projection().into()
+        } else {
+            <cgmath::Matrix4<f32> as cgmath::SquareMatrix>::identity().into()
+        };
         LightRaw {
             position: self.position.into(),
-            _padding: 0,
+            shadow_index,
             color: self.color.into(),
             intensity: self.intensity,
             radius: self.radius,
@@ -84,30 +57,54 @@ impl Light {
             limitcos_inner: self.limitcos_inner,
             limitcos_outer: self.limitcos_outer,
             limitdir: self.limitdir.into(),
-            proj: view_proj.into(),
-            _padding1: 0,
+            proj: view_proj,
+            normal_offset: self.normal_offset,
+            shadow_rect,
+            slope_bias: self.slope_bias,
+            _padding: [0; 3],
         }
     }
+
+    // Perspective looking down the cone, wide enough to cover the outer edge of it
+    pub fn spot_view_projection(&self) -> cgmath::Matrix4<f32> {
+        use cgmath::InnerSpace;
+        let pos = cgmath::point3(self.position.x, self.position.y, self.position.z);
+        let dir = if self.limitdir.magnitude2() > f32::EPSILON {
+            self.limitdir.normalize()
+        } else {
+            -cgmath::Vector3::unit_y()
+        };
+        let up = if dir.y.abs() > 0.99 {
+            cgmath::Vector3::unit_z()
+        } else {
+            cgmath::Vector3::unit_y()
+        };
+        let view = cgmath::Matrix4::look_to_rh(pos, dir, up);
+        let cone_cos = self.limitcos_inner.min(self.limitcos_outer).clamp(-1.0, 1.0);
+        let fov = (2.0 * cone_cos.acos()).clamp(1.0f32.to_radians(), 170.0f32.to_radians());
+        let projection = cgmath::perspective(cgmath::Rad(fov), 1.0, SHADOW_NEAR, SHADOW_FAR);
+        camera::OPENGL_TO_WGPU_MATRIX * projection * view
+    }
     
+    // One projection per cube face, oriented the way cube map sampling expects them
     pub fn calculate_view_projections(&self) -> [[[f32; 4];4];6] {
-        let directions: [cgmath::Vector3<f32>; 6] = [
-            [1.0,0.0,0.0].into(),
-            [-1.0,0.0,0.0].into(),
-            [0.0,1.0,0.0].into(),
-            [0.0,-1.0,0.0].into(),
-            [0.0,0.0,1.0].into(),
-            [0.0,0.0,-1.0].into(),
+        let faces: [(cgmath::Vector3<f32>, cgmath::Vector3<f32>); 6] = [
+            ([1.0, 0.0, 0.0].into(), [0.0, -1.0, 0.0].into()),
+            ([-1.0, 0.0, 0.0].into(), [0.0, -1.0, 0.0].into()),
+            ([0.0, 1.0, 0.0].into(), [0.0, 0.0, 1.0].into()),
+            ([0.0, -1.0, 0.0].into(), [0.0, 0.0, -1.0].into()),
+            ([0.0, 0.0, 1.0].into(), [0.0, -1.0, 0.0].into()),
+            ([0.0, 0.0, -1.0].into(), [0.0, -1.0, 0.0].into()),
         ];
+        // Cube maps are left handed, so the faces come out mirrored on y compared to a
+        // normal camera. That also flips the winding, see the shadow pipeline.
+        let flip_y = cgmath::Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+        let projection = cgmath::perspective(cgmath::Deg(90.), 1.0, SHADOW_NEAR, SHADOW_FAR);
+        let pos = cgmath::point3(self.position.x, self.position.y, self.position.z);
         let mut view_projections: [[[f32; 4];4];6] = [[[0.0; 4]; 4]; 6];
-        for (index, i) in directions.iter().enumerate() {
-            let pos = cgmath::point3(self.position.x, self.position.y, self.position.z);
-            let view = cgmath::Matrix4::look_to_rh(
-                pos,
-                *i,
-                cgmath::Vector3::new(0.0, 1.0, 0.0),
-            );
-            let projection = cgmath::perspective(cgmath::Deg(90.), 1.0, 0.1, 100.0);
-            let view_proj = (projection) * view;
+        for (index, (dir, up)) in faces.iter().enumerate() {
+            let view = cgmath::Matrix4::look_to_rh(pos, *dir, *up);
+            let view_proj = camera::OPENGL_TO_WGPU_MATRIX * flip_y * projection * view;
             view_projections[index] = view_proj.into();
         }
         
@@ -115,36 +112,120 @@ impl Light {
     }
 }
 
+// Converts the lights in order, handing out the shadow map slots the same way `Shadow` does
+pub fn lights_to_raw(lights: &[Light]) -> Vec<LightRaw> {
+    let spot_count = lights.iter().filter(|light| light.is_spotlight).count();
+    lights
+        .iter()
+        .zip(shadow::shadow_slots(lights))
+        .map(|(light, slot)| {
+            let rect = if light.is_spotlight {
+                shadow::spot_atlas_rect(slot, spot_count)
+            } else {
+                [0.0; 4]
+            };
+            light.to_raw(slot as u32, rect)
+        })
+        .collect()
+}
+
 impl LightBuffer {
-    pub fn new(device: &wgpu::Device, lights: &Vec<Light>) -> Self {
-        let light_raws = lights
-            .iter()
-            .map(|light| light.to_raw())
-            .collect::<Vec<_>>();
-        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+    pub fn new(device: &wgpu::Device, lights: &[Light]) -> Self {
+        let bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer { 
+                        ty: wgpu::BufferBindingType::Uniform, 
+                        has_dynamic_offset: false, 
+                        min_binding_size: None 
+                    },
+                    count: None,
+                }],
+                label: Some("Light Bind group layout"),
+            });
+
+        let light_num_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Num Buffer"),
+            contents: bytemuck::cast_slice(&[lights.len() as u32]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let mut light_raws = lights_to_raw(lights);
+        // A storage binding can't be empty, so always keep room for one light
+        let capacity = light_raws.len().max(1);
+        light_raws.resize(capacity, LightRaw::zeroed());
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Light Buffer"),
             contents: bytemuck::cast_slice(&light_raws),
             usage: wgpu::BufferUsages::STORAGE
                 | wgpu::BufferUsages::COPY_DST
                 | wgpu::BufferUsages::COPY_SRC,
         });
-        let light_num_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: &[light_raws.len() as u8],
-            usage: wgpu::BufferUsages::UNIFORM,
-        });
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &buffer, &light_num_buffer);
+
         Self {
-            buffer: light_buffer,
-            light_num_buffer: light_num_buffer,
+            buffer,
+            light_num_buffer,
+            capacity,
+            bind_group,
+            bind_group_layout,
         }
     }
-    pub fn repopulate_lights(&mut self, queue: &wgpu::Queue, lights: &Vec<Light>) {
-        for (i, light) in lights.iter().enumerate() {
-            queue.write_buffer(
-                &self.buffer,
-                (i * mem::size_of::<LightRaw>()) as wgpu::BufferAddress,
-                bytemuck::bytes_of(&light.to_raw()),
-            );
+
+    fn create_light_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Light Buffer"),
+            size: (capacity * mem::size_of::<LightRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+        light_num_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: light_num_buffer.as_entire_binding(),
+            }],
+            label: Some("Light Bind Group"),
+        })
+    }
+
+    // Uploads the lights, growing the storage buffer if there are more lights than it fits.
+    // Growing replaces `bind_group`, the passes take it from here every frame.
+    pub fn repopulate_lights(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, lights: &[Light]) {
+        if lights.len() > self.capacity {
+            self.capacity = lights.len().next_power_of_two();
+            self.buffer = Self::create_light_buffer(device, self.capacity);
+            self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &self.buffer, &self.light_num_buffer);
         }
+
+        let light_raws = lights_to_raw(lights);
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&light_raws));
+        queue.write_buffer(&self.light_num_buffer, 0, bytemuck::cast_slice(&[lights.len() as u32]));
     }
 }
@@ -0,0 +1,209 @@
+// Picks a level of detail for every instance from how big it shows up on screen. An instance
+// only switches once it's a margin past the switch point, so it doesn't flicker between two
+// levels right at the edge.
+use std::{collections::HashMap, ops::Range};
+
+use cgmath::{InnerSpace, MetricSpace};
+
+use super::{camera, culling, instances, model};
+use crate::world::Entity;
+
+#[derive(Debug, Copy, Clone)]
+pub struct LodSettings {
+    pub enabled: bool,
+    // Height on screen, as a fraction of the window, below which the next level is used
+    pub screen_sizes: [f32; model::MAX_LODS - 1],
+    // How far past a switch point an instance has to be before it switches, as a fraction
+    pub hysteresis: f32,
+    // Levels coarser than the camera's that the shadow passes draw
+    pub shadow_bias: u8,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            screen_sizes: [0.3, 0.1],
+            hysteresis: 0.1,
+            shadow_bias: 1,
+        }
+    }
+}
+
+// The level every instance got this frame, indexed like the instances. What they were drawn
+// at before is kept by entity, so it stays with them when others get added or removed.
+#[derive(Default)]
+pub struct LodSelector {
+    levels: Vec<u8>,
+    shadow_levels: Vec<u8>,
+    previous: HashMap<Entity, u8>,
+}
+
+impl LodSelector {
+    pub fn update(
+        &mut self,
+        camera: &camera::Camera,
+        bounds: &culling::Aabb,
+        entities: &[Entity],
+        transforms: &[cgmath::Matrix4<f32>],
+        settings: &LodSettings,
+    ) {
+        let tan_half_fovy = (camera.fovy.to_radians() / 2.0).tan();
+        self.levels.clear();
+        for (entity, transform) in entities.iter().zip(transforms) {
+            if !settings.enabled {
+                self.levels.push(0);
+                continue;
+            }
+            let world_bounds = bounds.transformed(transform);
+            let radius = world_bounds.extents().magnitude();
+            let distance = world_bounds.center().distance(camera.eye);
+            let size = if distance > radius { radius / (distance * tan_half_fovy) } else { f32::MAX };
+
+            let mut next = self.previous.get(entity).copied().unwrap_or(0) as usize;
+            while next + 1 < model::MAX_LODS && size < settings.screen_sizes[next] * (1.0 - settings.hysteresis) {
+                next += 1;
+            }
+            while next > 0 && size > settings.screen_sizes[next - 1] * (1.0 + settings.hysteresis) {
+                next -= 1;
+            }
+            self.levels.push(next as u8);
+        }
+        // Entities that are gone this frame get dropped
+        self.previous = entities.iter().copied().zip(self.levels.iter().copied()).collect();
+
+        let shadow_bias = if settings.enabled { settings.shadow_bias } else { 0 };
+        self.shadow_levels = self
+            .levels
+            .iter()
+            .map(|level| level.saturating_add(shadow_bias).min(model::MAX_LODS as u8 - 1))
+            .collect();
+    }
+
+    pub fn levels(&self) -> &[u8] {
+        &self.levels
+    }
+
+    pub fn shadow_levels(&self) -> &[u8] {
+        &self.shadow_levels
+    }
+}
+
+// Adds the raws of the visible instances ordered by their level, and returns the range every
+// level ends up at. The visible list gets the same order, for anything that refers back to it.
+pub fn append_by_level(
+    raws: &mut Vec<instances::InstanceRaw>,
+    visible: &mut [usize],
+    transforms: &[cgmath::Matrix4<f32>],
+    levels: &[u8],
+) -> [Range<u32>; model::MAX_LODS] {
+    visible.sort_by_key(|index| levels[*index]);
+    let mut ranges: [Range<u32>; model::MAX_LODS] = Default::default();
+    for (lod, range) in ranges.iter_mut().enumerate() {
+        let start = raws.len() as u32;
+        raws.extend(
+            visible
+                .iter()
+                .filter(|index| levels[**index] as usize == lod)
+                .map(|index| instances::InstanceRaw::from_matrix(&transforms[*index])),
+        );
+        *range = start..raws.len() as u32;
+    }
+    ranges
+}
+
+// One batch per level that has any instances, all drawn from the same buffer
+pub fn direct_batches<'a>(buffer: &'a wgpu::Buffer, ranges: &[Range<u32>]) -> Vec<model::LodBatch<'a>> {
+    ranges
+        .iter()
+        .enumerate()
+        .filter(|(_, range)| !range.is_empty())
+        .map(|(lod, range)| model::LodBatch {
+            lod,
+            instance_buffer: buffer.slice(..),
+            instances: model::DrawInstances::Direct(range.clone()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::World;
+
+    // Looking down -z with a 90 degree fov, so the screen size is just radius over distance
+    fn camera() -> camera::Camera {
+        let mut camera = camera::Camera::new(cgmath::Vector3::new(0.0, 0.0, 0.0), 0.0, 1.0);
+        camera.fovy = 90.0;
+        camera
+    }
+
+    fn bounds() -> culling::Aabb {
+        culling::Aabb::from_points([[-1.0, -1.0, -1.0], [1.0, 1.0, 1.0]])
+    }
+
+    // Where the unit box shows up at the given screen size
+    fn at_size(size: f32) -> cgmath::Matrix4<f32> {
+        let distance = bounds().extents().magnitude() / size;
+        cgmath::Matrix4::from_translation(cgmath::Vector3::new(0.0, 0.0, -distance))
+    }
+
+    #[test]
+    fn switches_past_the_margin() {
+        let settings = LodSettings::default();
+        let entity = World::new().spawn();
+        let mut selector = LodSelector::default();
+        let mut level_at = |size: f32| {
+            selector.update(&camera(), &bounds(), &[entity], &[at_size(size)], &settings);
+            selector.levels()[0]
+        };
+        assert_eq!(level_at(0.5), 0);
+        // Below the switch point, but not by the margin
+        assert_eq!(level_at(0.28), 0);
+        assert_eq!(level_at(0.2), 1);
+        assert_eq!(level_at(0.32), 1);
+        assert_eq!(level_at(0.05), 2);
+        assert_eq!(level_at(1.0), 0);
+    }
+
+    #[test]
+    fn keeps_levels_by_entity() {
+        let settings = LodSettings::default();
+        let mut world = World::new();
+        let (far, near) = (world.spawn(), world.spawn());
+        let mut selector = LodSelector::default();
+        selector.update(&camera(), &bounds(), &[far, near], &[at_size(0.2), at_size(0.5)], &settings);
+        assert_eq!(selector.levels(), [1, 0]);
+
+        // The far one is gone, the near one is in the margin and has to stay where it was
+        selector.update(&camera(), &bounds(), &[near], &[at_size(0.28)], &settings);
+        assert_eq!(selector.levels(), [0]);
+
+        // New ones start from the full level
+        let new = world.spawn();
+        selector.update(&camera(), &bounds(), &[new, near], &[at_size(0.32), at_size(0.28)], &settings);
+        assert_eq!(selector.levels(), [0, 0]);
+        assert_eq!(selector.shadow_levels(), [1, 1]);
+    }
+
+    #[test]
+    fn disabled_uses_the_full_level() {
+        let settings = LodSettings { enabled: false, ..Default::default() };
+        let entity = World::new().spawn();
+        let mut selector = LodSelector::default();
+        selector.update(&camera(), &bounds(), &[entity], &[at_size(0.01)], &settings);
+        assert_eq!((selector.levels(), selector.shadow_levels()), (&[0][..], &[0][..]));
+    }
+
+    #[test]
+    fn orders_instances_by_level() {
+        let transforms = [0.0, 1.0, 2.0, 3.0].map(|x| cgmath::Matrix4::from_translation(cgmath::Vector3::new(x, 0.0, 0.0)));
+        let levels = [2, 0, 1, 0];
+        let mut visible = vec![0, 1, 2];
+        let mut raws = Vec::new();
+        let ranges = append_by_level(&mut raws, &mut visible, &transforms, &levels);
+        assert_eq!(ranges, [0..1, 1..2, 2..3]);
+        assert_eq!(visible, [1, 2, 0]);
+        assert_eq!(raws.len(), 3);
+    }
+}
@@ -1,14 +1,32 @@
 // Local Imports, had to do it this way idk why
 pub mod texture;
+pub mod ktx2;
+pub mod bcn;
 pub mod instances;
 pub mod camera;
 pub mod vertex;
 pub mod model;
+pub mod assets;
+pub mod asset_manager;
 pub mod resources;
 pub mod light;
 pub mod render_pipeline;
 pub mod ui;
 pub mod shadow;
+pub mod cascade;
+pub mod hdr;
+pub mod post;
+pub mod environment;
+pub mod ssao;
+pub mod fog;
+pub mod culling;
+pub mod gpu_culling;
+pub mod lod;
+pub mod simplify;
+pub mod gizmo;
+pub mod editor;
+pub mod shaders;
+pub mod hot_reload;
 
 use wgpu::util::DeviceExt;
 // winit Imports
@@ -18,10 +36,15 @@ use winit::{
     window::WindowBuilder,
     window::Window,
 };
+use anyhow::Context;
 
 use crate::window::model::{Vertex};
+use crate::world::{self, map, systems};
 
 
+// Drawn for every light
+const CUBE_MODEL: &str = "Models/cube.obj";
+
 // All of the states needed for running the game
 struct State {
     // Standard renderer stuff
@@ -36,50 +59,227 @@ struct State {
     
     // Render Pipelin
     render_pipeline: wgpu::RenderPipeline,
+    blend_render_pipeline: wgpu::RenderPipeline,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    sample_count: u32,
+
+    // The game objects, and what the render system gathered from them this frame
+    world: world::World,
+    schedule: world::Schedule,
+    draw_list: systems::DrawList,
+    // The entity with the camera and the controller the input goes to
+    player: world::Entity,
+    // The map as it was loaded, and where F5 saves the world back to
+    map: map::Map,
+    map_path: std::path::PathBuf,
+    // Level editor, toggled with F1
+    editor: editor::Editor,
+    // Looks for shaders and resources that changed on disk
+    watcher: hot_reload::Watcher,
 
     // Camera stuff
-    camera: camera::Camera,
     camera_uniform: camera::CameraUniform,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
-    camera_controller: camera::CameraController,
 
-    // Instancing
-    instances: Vec<instances::Instance>,
+    // Instancing, the buffer only holds the ones the camera can see
     instance_buffer: instances::InstanceBuffer,
+    // Replaces the CPU culling of the scene when the adapter can do it
+    gpu_culling: Option<gpu_culling::GpuCulling>,
+    // One per model
+    lod_selectors: Vec<lod::LodSelector>,
 
     //Depth buffer
     depth_texture: texture::Texture,
 
     // Model testing stuff
-    obj_model: model::Model,
+    // Indexed by `world::ModelId`, in the order the map lists them
+    models: Vec<model::Model>,
     cube_model: model::Model,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    texture_filtering: texture::SamplerSettings,
+    // Textures, meshes and shader modules, shared by name
+    asset_manager: asset_manager::AssetManager,
 
-    // Light stuff
-    light0: light::Light,
+    // Light stuff, the lights are the ones the GPU buffers were last filled with
+    lights: Vec<light::Light>,
+    next_light_id: usize,
     light_buffer: light::LightBuffer,
-    light_bind_group: wgpu::BindGroup,
     light_render_pipeline: wgpu::RenderPipeline,
-    lights_are_dirty: bool,
+    light_pipeline_layout: wgpu::PipelineLayout,
 
     // Shadow Stuff
     shadow_config: shadow::Shadow,
 
+    // Sun stuff
+    sun: light::DirectionalLight,
+    sun_shadow: cascade::CascadedShadow,
+
+    // HDR target and tonemapping
+    hdr: hdr::Hdr,
+    post: post::PostProcessing,
+
+    // Skybox and image based lighting
+    environment: environment::Environment,
+
+    // Ambient occlusion, from a depth prepass
+    ssao: ssao::Ssao,
+
     // Render Overlay stuff
+    render_textures_bind_layout: wgpu::BindGroupLayout,
     render_texture_bind_group: wgpu::BindGroup,
     render_target_buffer: wgpu::Buffer,
 }
 
-fn features() -> wgpu::Features {
+fn features(adapter: &wgpu::Adapter) -> wgpu::Features {
     wgpu::Features::DEPTH_CLIP_CONTROL |
-    wgpu::Features::MULTIVIEW
+    wgpu::Features::MULTIVIEW |
+    // Optional, BCn textures get decoded on the CPU without it
+    (adapter.features() & wgpu::Features::TEXTURE_COMPRESSION_BC) |
+    // Optional, lets MSAA go past the sample counts every adapter has to support
+    (adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+}
+
+// Sample counts the scene can be rendered with. Only 1 and 4 are guaranteed, 2 and 8 are
+// offered when the adapter reports its own format capabilities and those allow multisampling.
+fn supported_sample_counts(adapter: &wgpu::Adapter) -> Vec<u32> {
+    let multisampled = |format| adapter
+        .get_texture_format_features(format)
+        .flags
+        .contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE);
+    if !multisampled(hdr::HDR_FORMAT) || !multisampled(texture::Texture::DEPTH_FORMAT) {
+        return vec![1];
+    }
+    if adapter.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES) {
+        vec![1, 2, 4, 8]
+    } else {
+        vec![1, 4]
+    }
+}
+
+fn create_scene_pipelines(
+    device: &wgpu::Device,
+    asset_manager: &mut asset_manager::AssetManager,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    light_pipeline_layout: &wgpu::PipelineLayout,
+    sample_count: u32,
+) -> (wgpu::RenderPipeline, wgpu::RenderPipeline, wgpu::RenderPipeline) {
+    let shader = asset_manager.shaders.get_or_insert_with("shader.wgsl", || {
+        let source = shaders::source("shader.wgsl");
+        let bytes = source.len() as u64;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Normal Shader"),
+            source: wgpu::ShaderSource::Wgsl(source),
+        });
+        (module, bytes)
+    });
+    let scene_pipeline = |blend| {
+        render_pipeline::create_render_pipeline(
+            device,
+            render_pipeline_layout,
+            hdr::HDR_FORMAT,
+            Some(texture::Texture::DEPTH_FORMAT),
+            &[model::ModelVertex::desc(), instances::InstanceRaw::desc()],
+            &shader,
+            sample_count,
+            blend,
+        )
+    };
+    let render_pipeline = scene_pipeline(wgpu::BlendState::REPLACE);
+    // Blended materials go through the same shader, drawn back to front afterwards
+    let blend_render_pipeline = scene_pipeline(wgpu::BlendState::ALPHA_BLENDING);
+
+    let light_render_pipeline = {
+        let shader = asset_manager.shaders.get_or_insert_with("light.wgsl", || {
+            let source = shaders::source("light.wgsl");
+            let bytes = source.len() as u64;
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Light Shader"),
+                source: wgpu::ShaderSource::Wgsl(source),
+            });
+            (module, bytes)
+        });
+        render_pipeline::create_render_pipeline(
+            device,
+            light_pipeline_layout,
+            hdr::HDR_FORMAT,
+            Some(texture::Texture::DEPTH_FORMAT),
+            &[model::ModelVertex::desc()],
+            &shader,
+            sample_count,
+            wgpu::BlendState::REPLACE,
+        )
+    };
+
+    (render_pipeline, blend_render_pipeline, light_render_pipeline)
+}
+
+fn create_shadow(
+    device: &wgpu::Device,
+    asset_manager: &mut asset_manager::AssetManager,
+    lights: &[light::Light],
+    material_layout: &wgpu::BindGroupLayout,
+    settings: &shadow::ShadowSettings,
+) -> shadow::Shadow {
+    let shader = asset_manager.shaders.get_or_insert_with("shadow.wgsl", || {
+        let source = shaders::source("shadow.wgsl");
+        (render_pipeline::create_depth_shader(device, "Shadow Shader", &source), source.len() as u64)
+    });
+    let spot_shader = asset_manager.shaders.get_or_insert_with("spot_shadow.wgsl", || {
+        let source = shaders::source("spot_shadow.wgsl");
+        (render_pipeline::create_depth_shader(device, "Spot Shadow Shader", &source), source.len() as u64)
+    });
+    shadow::Shadow::new(device, &shader, &spot_shader, lights, &[model::ModelVertex::desc(), instances::InstanceRaw::desc()], material_layout, settings)
+}
+
+fn create_sun_shadow(device: &wgpu::Device, asset_manager: &mut asset_manager::AssetManager, material_layout: &wgpu::BindGroupLayout) -> cascade::CascadedShadow {
+    let shader = asset_manager.shaders.get_or_insert_with("cascade.wgsl", || {
+        let source = shaders::source("cascade.wgsl");
+        (render_pipeline::create_depth_shader(device, "Cascade Shader", &source), source.len() as u64)
+    });
+    cascade::CascadedShadow::new(device, &shader, &[model::ModelVertex::desc(), instances::InstanceRaw::desc()], material_layout, cascade::MAX_CASCADES, 2048)
+}
+
+async fn load_luts(device: &wgpu::Device, queue: &wgpu::Queue) -> anyhow::Result<Vec<texture::Texture>> {
+    let mut luts = Vec::new();
+    for file in post::LUT_FILES {
+        luts.push(resources::load_lut(file, device, queue).await.with_context(|| format!("Couldn't load LUT {}", file))?);
+    }
+    Ok(luts)
+}
+
+// Has to be made again whenever the ambient occlusion targets change size
+fn create_render_textures_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    render_target_buffer: &wgpu::Buffer,
+    ssao: &ssao::Ssao,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Render texture Bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(render_target_buffer.as_entire_buffer_binding())
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(ssao.view()),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(ssao.raw_view()),
+            },
+        ]
+    })
 }
 
 
 impl State {
 
     // After creating a window, initializing wgpu and other stuff for tha rendering
-    async fn new(window: &Window) -> Self {
+    async fn new(window: &Window, map: map::Map, map_path: std::path::PathBuf) -> anyhow::Result<Self> {
         let size = window.inner_size();
         let hidpi_factor = window.scale_factor();
 
@@ -92,11 +292,11 @@ impl State {
                 compatible_surface: Some(&surface),
                 force_fallback_adapter: false,
             },
-        ).await.unwrap();
+        ).await.context("Couldn't find a graphics adapter")?;
 
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
-                features: features(),
+                features: features(&adapter),
                 // WebGL doesn't support all of wgpu's features, so if
                 // we're building for the web we'll have to disable some.
                 limits: if cfg!(target_arch = "wasm32") {
@@ -110,7 +310,7 @@ impl State {
                 label: None,
             },
             None, // Trace path
-        ).await.unwrap();
+        ).await.context("Couldn't open the graphics device")?;
 
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -121,71 +321,41 @@ impl State {
         };
         surface.configure(&device, &config);
 
-        let ui = ui::UI::new(&window, hidpi_factor, &device, &queue, &config);
+        let mut ui = ui::UI::new(window, hidpi_factor, &device, &queue, &config);
+        ui.msaa_sample_counts = supported_sample_counts(&adapter);
+        let sample_count = ui.msaa_samples;
 
-        let camera = camera::Camera::new((10.0,5.0,10.0).into(), 45.0, config.width as f32 / config.height as f32, 45.0);
+        let spawn = map.spawn_points.first().copied().unwrap_or(map::SpawnPoint {
+            position: (10.0, 5.0, 10.0).into(),
+            yaw: 45.0,
+        });
+        let camera = camera::Camera::new(spawn.position, spawn.yaw, config.width as f32 / config.height as f32);
 
         let (camera_uniform, camera_buffer, camera_bind_group_layout, camera_bind_group) = camera.create_camera_buffers_and_uniform(&device);
 
-        let camera_controller = camera::CameraController::new(0.2, 0.1);
+        let mut world = world::World::new();
+        let player = world.spawn();
+        world.cameras.insert(player, camera);
+        world.player_controllers.insert(player, world::PlayerController::new(0.2, 0.1));
+        map.spawn(&mut world);
+        let next_light_id = map.lights.len();
+        ui.environment_settings = map.environment.settings;
+        ui.fog_settings = map.environment.fog;
+        ui.hdr_settings = map.environment.hdr;
+        ui.post_settings = map.environment.post;
+        let schedule = world::Schedule::new()
+            .with_system(systems::player_controllers)
+            .with_system(systems::propagate_transforms);
+        systems::propagate_transforms(&mut world);
+        let draw_list = systems::gather_draw_list(&world);
+        let lights_vec = draw_list.lights.clone();
 
-        let light0 = light::Light::new(0, [2.0, 2.1, 2.0].into(), [1.0, 1.0, 1.0].into(), 1.0, 1.0);
-
-        let lights_vec = vec![light0];
-        
         let light_buffer = light::LightBuffer::new(&device, &lights_vec);
 
-        let light_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    // This should match the filterable field of the
-                    // corresponding Texture entry above.
-                    ty: wgpu::BindingType::Buffer { 
-                        ty: wgpu::BufferBindingType::Uniform, 
-                        has_dynamic_offset: false, 
-                        min_binding_size: None 
-                    },
-                    count: None,
-                }],
-                label: Some("Light Bind group layout"),
-            });
-
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: light_buffer.light_num_buffer.as_entire_binding(),
-            }],
-            label: None,
-        });
+        let depth_texture = texture::Texture::create_depth_texture(&device, &config, sample_count, "depth_texture");
 
-        let depth_texture = texture::Texture::create_depth_texture(&device, &config, "depth_texture");
+        let hdr = hdr::Hdr::new(&device, &config, sample_count);
 
-        let shadow_config = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Shadow Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("../Shaders/shadow.wgsl").into()),
-            };
-            let shader = device.create_shader_module(shader);
-            shadow::Shadow::new(&device, &shader, lights_vec, &[model::ModelVertex::desc(), instances::InstanceRaw::desc()], 8192, 8192)
-        };
-        
         let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
                     wgpu::BindGroupLayoutEntry { // Standard diffuse Texture
@@ -236,6 +406,22 @@ impl State {
                 label: Some("texture_bind_group_layout"),
         });
         
+        let mut asset_manager = asset_manager::AssetManager::default();
+        let shadow_config = create_shadow(&device, &mut asset_manager, &lights_vec, &texture_bind_group_layout, &ui.shadow_settings);
+        
+        let sun = map.environment.sun;
+
+        let sun_shadow = create_sun_shadow(&device, &mut asset_manager, &texture_bind_group_layout);
+        
+        let environment = {
+            let sky = resources::load_hdr(&map.environment.sky)
+                .await
+                .with_context(|| format!("Couldn't load the sky {}", map.environment.sky))?;
+            environment::Environment::new(&device, &queue, &sky, sample_count)
+        };
+
+        let ssao = ssao::Ssao::new(&device, &config, &[model::ModelVertex::desc(), instances::InstanceRaw::desc()], &texture_bind_group_layout);
+
         let render_textures_bind_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Depth and Shadow Texture BindGroup"),
             entries: &[
@@ -249,20 +435,24 @@ impl State {
                     },
                     count: None,
                 },
-                wgpu::BindGroupLayoutEntry { // Standard Depth Texture
+                wgpu::BindGroupLayoutEntry { // Blurred ambient occlusion
                     binding: 1,
                     visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Texture {
                         multisampled: false,
                         view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Depth,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
                     },
                     count: None,
                 },
-                wgpu::BindGroupLayoutEntry { // Standard Depth Sampler
+                wgpu::BindGroupLayoutEntry { // Raw ambient occlusion, for the debug view
                     binding: 2,
                     visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
                     count: None,
                 },
             ],
@@ -273,69 +463,52 @@ impl State {
             bind_group_layouts: &[
                 &texture_bind_group_layout,
                 &camera_bind_group_layout,
-                &light_bind_group_layout,
+                &light_buffer.bind_group_layout,
                 &shadow_config.ext_bind_group_layout,
                 &render_textures_bind_layout,
+                &sun_shadow.ext_bind_group_layout,
+                &environment.ext_bind_group_layout,
             ],
             push_constant_ranges: &[],
         });
 
-        
-        let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("../Shaders/shader.wgsl").into()),
-            };
-            render_pipeline::create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc(), instances::InstanceRaw::desc()],
-                shader,
-            )
-        };
-        
-        let light_render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Light Pipeline Layout"),
-                bind_group_layouts: &[&camera_bind_group_layout, &light_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Light Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("../Shaders/light.wgsl").into()),
-            };
-            render_pipeline::create_render_pipeline(
-                &device,
-                &layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc()],
-                shader,
-            )
-        };
+        let light_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Light Pipeline Layout"),
+            bind_group_layouts: &[&camera_bind_group_layout, &light_buffer.bind_group_layout],
+            push_constant_ranges: &[],
+        });
 
-        let obj_model = resources::load_model(
-            "Models1/test.obj",
-            &device,
-            &queue,
-            &texture_bind_group_layout,
-        ).await.unwrap();       
+        let (render_pipeline, blend_render_pipeline, light_render_pipeline) = create_scene_pipelines(&device, &mut asset_manager, &render_pipeline_layout, &light_pipeline_layout, sample_count);
+
+        let texture_filtering = ui.texture_filtering;
+        let mut models = Vec::with_capacity(map.models.len());
+        for file in &map.models {
+            models.push(resources::load_model(
+                file,
+                &device,
+                &queue,
+                &texture_bind_group_layout,
+                &texture_filtering,
+                &mut asset_manager,
+            ).await.with_context(|| format!("Couldn't load model {}", file))?);
+        }
 
         let cube_model = resources::load_model(
-            "Models/cube.obj",
+            CUBE_MODEL,
             &device,
             &queue,
             &texture_bind_group_layout,
-        ).await.unwrap();    
+            &texture_filtering,
+            &mut asset_manager,
+        ).await.with_context(|| format!("Couldn't load model {}", CUBE_MODEL))?;
 
-        let instance_vec = vec![instances::Instance {
-            position: cgmath::Vector3::new(0.0,0.0,0.0),
-            rotation: cgmath::Quaternion::new(0.0,0.0,0.0,0.0),
-        }];
+        let luts = load_luts(&device, &queue).await?;
+        let post = post::PostProcessing::new(&device, &config, &hdr.view, luts, &ui.post_settings);
 
-        let instance_buffer = instances::InstanceBuffer::new(&device, &instance_vec);
+        let instance_buffer = instances::InstanceBuffer::with_capacity(&device, 1);
+        let gpu_culling = gpu_culling::GpuCulling::supported(&adapter)
+            .then(|| gpu_culling::GpuCulling::new(&device, &config, ssao.depth_view()));
+        ui.gpu_culling_supported = gpu_culling.is_some();
 
         let render_target_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Render target num"),
@@ -343,26 +516,9 @@ impl State {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let render_textures_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Render texture Bind group"),
-            layout: &render_textures_bind_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(render_target_buffer.as_entire_buffer_binding())
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&depth_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::Sampler(&depth_texture.sampler),
-                }
-            ]
-        });
+        let render_textures_bind_group = create_render_textures_bind_group(&device, &render_textures_bind_layout, &render_target_buffer, &ssao);
 
-        Self {
+        Ok(Self {
             surface,
             device,
             queue,
@@ -370,25 +526,45 @@ impl State {
             size,
             ui,
             render_pipeline,
-            camera,
+            blend_render_pipeline,
+            render_pipeline_layout,
+            sample_count,
+            world,
+            schedule,
+            draw_list,
+            player,
+            map,
+            editor: editor::Editor::new(&map_path),
+            map_path,
+            watcher: hot_reload::Watcher::new(assets::with_root(|root| root.directories())),
             camera_uniform,
             camera_buffer,
             camera_bind_group,
-            camera_controller,
-            instances: instance_vec,
-            instance_buffer: instance_buffer,
+            instance_buffer,
+            gpu_culling,
+            lod_selectors: Vec::new(),
             depth_texture,
-            obj_model,
+            models,
             cube_model,
-            light0,
+            texture_bind_group_layout,
+            texture_filtering,
+            asset_manager,
+            lights: lights_vec,
+            next_light_id,
             light_buffer,
-            light_bind_group,
             light_render_pipeline,
-            lights_are_dirty: true,
+            light_pipeline_layout,
             shadow_config,
+            sun,
+            sun_shadow,
+            hdr,
+            post,
+            environment,
+            ssao,
+            render_textures_bind_layout,
             render_texture_bind_group: render_textures_bind_group,
             render_target_buffer
-        }
+        })
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -397,59 +573,462 @@ impl State {
             self.config.width = new_size.width;
             self.config.height = new_size.height;
             self.surface.configure(&self.device, &self.config);
-            self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
-            self.camera.resize(new_size.width, new_size.height);
+            self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.config, self.sample_count, "depth_texture");
+            self.hdr.resize(&self.device, new_size.width, new_size.height);
+            self.post.resize(&self.device, &self.config, &self.hdr.view);
+            self.ssao.resize(&self.device, &self.config);
+            if let Some(gpu_culling) = &mut self.gpu_culling {
+                gpu_culling.resize(&self.device, &self.config, self.ssao.depth_view());
+            }
+            self.render_texture_bind_group = create_render_textures_bind_group(&self.device, &self.render_textures_bind_layout, &self.render_target_buffer, &self.ssao);
+            for (_, camera) in self.world.cameras.iter_mut() {
+                camera.resize(new_size.width, new_size.height);
+            }
         }
     }
 
+    // Everything the scene pass draws into and with has to agree on the sample count
+    fn set_sample_count(&mut self, sample_count: u32) {
+        self.sample_count = sample_count;
+        self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.config, sample_count, "depth_texture");
+        self.hdr.set_sample_count(&self.device, sample_count);
+        self.environment.set_sample_count(&self.device, sample_count);
+        let (render_pipeline, blend_render_pipeline, light_render_pipeline) = create_scene_pipelines(
+            &self.device,
+            &mut self.asset_manager,
+            &self.render_pipeline_layout,
+            &self.light_pipeline_layout,
+            sample_count,
+        );
+        self.render_pipeline = render_pipeline;
+        self.blend_render_pipeline = blend_render_pipeline;
+        self.light_render_pipeline = light_render_pipeline;
+    }
+
     fn input(&mut self, event: &WindowEvent, window: &Window) -> bool {
-        if self.camera_controller.process_event(event, window) {return true}
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput {
+                state: ElementState::Pressed,
+                virtual_keycode: Some(key @ (VirtualKeyCode::F1 | VirtualKeyCode::F5)),
+                ..
+            },
+            ..
+        } = event {
+            match key {
+                VirtualKeyCode::F1 => self.editor.toggle(&mut editor::EditorScene { world: &mut self.world, models: &self.models }),
+                _ => self.save_map(),
+            }
+            return true;
+        }
+        if self.editor.enabled {
+            if let Some(camera) = self.world.cameras.get(self.player).copied() {
+                let size = [self.config.width as f32, self.config.height as f32];
+                let (wants_mouse, wants_keyboard) = (self.ui.wants_mouse(), self.ui.wants_keyboard());
+                let mut scene = editor::EditorScene { world: &mut self.world, models: &self.models };
+                if self.editor.input(event, &mut scene, &camera, size, wants_mouse, wants_keyboard) {
+                    return true;
+                }
+            }
+        }
+        if let Some(controller) = self.world.player_controllers.get_mut(self.player) {
+            if camera::process_event(&mut controller.input, event, window) {return true}
+        }
         false
     }
     fn mouse_input(&mut self, event: &DeviceEvent) {
-        self.camera_controller.process_mouse_event(event);
+        // The editor needs the mouse, the camera only turns while it's looking around
+        if self.editor.enabled && !self.editor.looking {
+            return;
+        }
+        if let Some(controller) = self.world.player_controllers.get_mut(self.player) {
+            camera::process_mouse_event(&mut controller.input, event);
+        }
     }
 
     fn update(&mut self) {
-        self.camera_controller.update_camera(&mut self.camera);
-        self.camera_uniform.update_view_proj(&self.camera);
+        self.hot_reload();
+        if self.ui.msaa_samples != self.sample_count {
+            self.set_sample_count(self.ui.msaa_samples);
+        }
+        self.schedule.run(&mut self.world);
+        self.draw_list = systems::gather_draw_list(&self.world);
+        if let Some(camera) = &self.draw_list.camera {
+            self.camera_uniform.update_view_proj(camera);
+            self.sun_shadow.update(&self.queue, camera, &self.sun);
+            self.environment.update(&self.queue, camera, &self.ui.environment_settings);
+            self.ssao.update(&self.queue, camera, &self.ui.ssao_settings);
+        }
+        self.camera_uniform.update_fog(&self.ui.fog_settings);
+        if self.ui.texture_filtering != self.texture_filtering {
+            self.texture_filtering = self.ui.texture_filtering;
+            for model in &mut self.models {
+                model.set_filtering(&self.device, &self.texture_bind_group_layout, &self.texture_filtering);
+            }
+            self.cube_model.set_filtering(&self.device, &self.texture_bind_group_layout, &self.texture_filtering);
+        }
+        // Models that got loaded again get the map's changes back here too
+        for (id, model) in self.models.iter_mut().enumerate() {
+            model.apply_material_settings(&self.queue, id, &self.world.materials);
+        }
         self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
         self.queue.write_buffer(&self.render_target_buffer, 0, bytemuck::cast_slice(&[self.ui.render_target as i32]));
-        // let old_position: cgmath::Vector3<_> = self.light0.position.into();
-        // self.light0.position = (cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0)) * old_position).into();
-        // self.light_buffer.repopulate_lights(&self.queue, &vec![self.light0]);
-        // self.shadow_config.update_lights(vec![self.light0]);
+        self.shadow_config.update_settings(&self.device, &self.queue, &self.ui.shadow_settings);
+        self.hdr.update(&self.queue, &self.config, &self.ui.hdr_settings);
+        self.post.update(&self.device, &self.queue, &self.ui.post_settings);
+        // if let Some(light) = self.light_mut(0) {
+        //     let old_position: cgmath::Vector3<_> = light.position;
+        //     light.position = cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0)) * old_position;
+        // }
+    }
+
+    // Writes the world as it is now to the map file, with the models and spawn points it was loaded with
+    fn save_map(&self) {
+        let environment = map::MapEnvironment {
+            sky: self.map.environment.sky.clone(),
+            settings: self.ui.environment_settings,
+            sun: self.sun,
+            fog: self.ui.fog_settings,
+            hdr: self.ui.hdr_settings,
+            post: self.ui.post_settings,
+        };
+        let map = map::Map::from_world(&self.world, &self.map.models, &self.map.spawn_points, &environment);
+        match map.save(&self.map_path) {
+            Ok(()) => log::info!("Saved the map to {}", self.map_path.display()),
+            Err(e) => log::error!("{:#}", e),
+        }
+    }
+
+    // Loads the model when the map doesn't have it yet, and puts an instance of it in front of the camera
+    fn spawn_model(&mut self, file: &str, camera: &camera::Camera) {
+        let model = match self.map.models.iter().position(|loaded| loaded == file) {
+            Some(model) => model,
+            None => match self.load_model(file) {
+                Ok(loaded) => {
+                    self.models.push(loaded);
+                    self.map.models.push(file.to_string());
+                    self.models.len() - 1
+                }
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    return;
+                }
+            },
+        };
+        let name = std::path::Path::new(file).file_stem().map_or(file.into(), |stem| stem.to_string_lossy());
+        let transform = instances::Instance {
+            position: editor::Editor::spawn_position(camera),
+            ..Default::default()
+        };
+        self.editor.spawn(&mut self.world, model, &name, transform);
+    }
+
+    // Rebuilds whatever was made from the shaders and resources that changed on disk
+    fn hot_reload(&mut self) {
+        let mut shader_files = Vec::new();
+        for change in self.watcher.poll() {
+            match change {
+                hot_reload::Change::Shader(file) => shader_files.push(file),
+                hot_reload::Change::Resource(file) => self.reload_resource(&file),
+            }
+        }
+        if !shader_files.is_empty() {
+            self.reload_shaders(&shader_files);
+        }
+    }
+
+    // Everything built from one of the shaders gets built again, and only replaces what's
+    // there when wgpu had nothing to complain about. Until then the error shows in the UI.
+    fn reload_shaders(&mut self, files: &[String]) {
+        log::info!("Reloading shaders {:?}", files);
+        let uses = |names: &[&str]| files.iter().any(|file| names.contains(&file.as_str()));
+        // The cached modules are the old ones
+        self.asset_manager.shaders.clear();
+        let device = &self.device;
+        let mut results = Vec::new();
+
+        if uses(&["shader.wgsl", "light.wgsl"]) {
+            let built = hot_reload::validated(device, || {
+                create_scene_pipelines(device, &mut self.asset_manager, &self.render_pipeline_layout, &self.light_pipeline_layout, self.sample_count)
+            });
+            results.push(("Scene", built.map(|(render_pipeline, blend_render_pipeline, light_render_pipeline)| {
+                self.render_pipeline = render_pipeline;
+                self.blend_render_pipeline = blend_render_pipeline;
+                self.light_render_pipeline = light_render_pipeline;
+            })));
+        }
+        if uses(&["shadow.wgsl", "spot_shadow.wgsl", "alpha_mask.wgsl"]) {
+            let built = hot_reload::validated(device, || {
+                create_shadow(device, &mut self.asset_manager, &self.lights, &self.texture_bind_group_layout, &self.ui.shadow_settings)
+            });
+            results.push(("Shadows", built.map(|shadow| self.shadow_config = shadow)));
+        }
+        if uses(&["cascade.wgsl", "alpha_mask.wgsl"]) {
+            let built = hot_reload::validated(device, || create_sun_shadow(device, &mut self.asset_manager, &self.texture_bind_group_layout));
+            results.push(("Sun shadows", built.map(|sun_shadow| self.sun_shadow = sun_shadow)));
+        }
+        if uses(&["depth_prepass.wgsl", "ssao.wgsl", "alpha_mask.wgsl", "fullscreen.wgsl"]) {
+            let built = hot_reload::validated(device, || {
+                ssao::Ssao::new(device, &self.config, &[model::ModelVertex::desc(), instances::InstanceRaw::desc()], &self.texture_bind_group_layout)
+            });
+            results.push(("Ambient occlusion", built.map(|ssao| {
+                self.ssao = ssao;
+                self.render_texture_bind_group = create_render_textures_bind_group(device, &self.render_textures_bind_layout, &self.render_target_buffer, &self.ssao);
+                // The culling reads the prepass depth
+                if let Some(gpu_culling) = &mut self.gpu_culling {
+                    gpu_culling.resize(device, &self.config, self.ssao.depth_view());
+                }
+            })));
+        }
+        if uses(&["tonemap.wgsl", "luminance.wgsl", "fullscreen.wgsl"]) {
+            let built = hot_reload::validated(device, || hdr::Hdr::new(device, &self.config, self.sample_count));
+            results.push(("HDR", built.map(|hdr| {
+                self.hdr = hdr;
+                self.post.resize(device, &self.config, &self.hdr.view);
+            })));
+        }
+        if uses(&["skybox.wgsl", "ibl.wgsl", "fullscreen.wgsl"]) {
+            results.push(("Environment", self.reload_environment()));
+        }
+        if uses(&["bloom.wgsl", "post.wgsl", "fullscreen.wgsl"]) {
+            results.push(("Post processing", self.reload_post()));
+        }
+        if uses(&["gpu_culling.wgsl"]) && self.gpu_culling.is_some() {
+            let device = &self.device;
+            let built = hot_reload::validated(device, || gpu_culling::GpuCulling::new(device, &self.config, self.ssao.depth_view()));
+            results.push(("GPU culling", built.map(|gpu_culling| self.gpu_culling = Some(gpu_culling))));
+        }
+
+        // Broken modules shouldn't be built with the next time
+        if results.iter().any(|(_, result)| result.is_err()) {
+            self.asset_manager.shaders.clear();
+        }
+        for (name, result) in results {
+            self.ui.set_reload_error(name, result.err());
+        }
+    }
+
+    // The HDR of the sky is loaded again along with the shaders, so this does for both
+    fn reload_environment(&mut self) -> anyhow::Result<()> {
+        let sky = pollster::block_on(resources::load_hdr(&self.map.environment.sky))?;
+        let device = &self.device;
+        self.environment = hot_reload::validated(device, || {
+            environment::Environment::new(device, &self.queue, &sky, self.sample_count)
+        })?;
+        Ok(())
+    }
+
+    fn reload_post(&mut self) -> anyhow::Result<()> {
+        let luts = pollster::block_on(load_luts(&self.device, &self.queue))?;
+        let device = &self.device;
+        self.post = hot_reload::validated(device, || {
+            post::PostProcessing::new(device, &self.config, &self.hdr.view, luts, &self.ui.post_settings)
+        })?;
+        Ok(())
+    }
+
+    // Models get loaded again in place, and textures swapped into the materials using them
+    fn reload_resource(&mut self, file: &str) {
+        let path = std::path::Path::new(file);
+        if file == self.map.environment.sky {
+            let result = self.reload_environment();
+            self.ui.set_reload_error("Environment", result.err());
+        }
+        if post::LUT_FILES.contains(&file) {
+            let result = self.reload_post();
+            self.ui.set_reload_error("Post processing", result.err());
+        }
+
+        let is_model = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("obj"));
+        let is_material = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("mtl"));
+        if is_model || is_material {
+            let uses_file = |model_file: &str| {
+                let model_path = std::path::Path::new(model_file);
+                if is_material {
+                    // Materials are only known by the model loading them, any in its directory might be one
+                    model_path.parent() == path.parent()
+                } else {
+                    // Or one of its levels of detail
+                    let stem = model_path.with_extension("").to_string_lossy().into_owned();
+                    file == model_file || file.starts_with(&format!("{}_lod", stem))
+                }
+            };
+            let reloaded: Vec<usize> = (0..self.map.models.len()).filter(|&i| uses_file(&self.map.models[i])).collect();
+            if is_model {
+                for model_file in self.map.models.iter().map(String::as_str).chain([CUBE_MODEL]).filter(|model_file| uses_file(model_file)) {
+                    self.asset_manager.meshes.remove(model_file);
+                }
+            }
+            for model in reloaded {
+                let model_file = self.map.models[model].clone();
+                let result = self.load_model(&model_file).map(|loaded| self.models[model] = loaded);
+                self.ui.set_reload_error(&model_file, result.err());
+            }
+            if uses_file(CUBE_MODEL) {
+                let result = self.load_model(CUBE_MODEL).map(|loaded| self.cube_model = loaded);
+                self.ui.set_reload_error(CUBE_MODEL, result.err());
+            }
+            return;
+        }
+
+        // Loaded once for all the materials using it, as a colour texture and as a normal map
+        // when it's used as both
+        let mut error = None;
+        for is_normal_map in [false, true] {
+            let key = asset_manager::texture_key(file, is_normal_map);
+            let Some(old) = self.asset_manager.textures.get(&key) else { continue };
+            let loaded = pollster::block_on(resources::load_texture_asset(file, is_normal_map, &self.device, &self.queue));
+            let loaded = match loaded {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    error = Some(e);
+                    continue;
+                }
+            };
+            let bytes = loaded.texture.bytes;
+            let new = self.asset_manager.textures.insert(&key, loaded, bytes);
+            for model in self.models.iter_mut().chain(std::iter::once(&mut self.cube_model)) {
+                for material in &mut model.materials {
+                    let swap = |texture: &asset_manager::Handle<_>| asset_manager::Handle::ptr_eq(texture, &old).then(|| new.clone());
+                    let (diffuse, normal) = (swap(&material.diffuse_texture), swap(&material.normal_texture));
+                    if diffuse.is_some() || normal.is_some() {
+                        material.set_textures(&self.device, &self.texture_bind_group_layout, diffuse, normal);
+                    }
+                }
+            }
+        }
+        self.ui.set_reload_error(file, error);
+    }
+
+    fn load_model(&mut self, file: &str) -> anyhow::Result<model::Model> {
+        let loaded = pollster::block_on(resources::load_model(
+            file,
+            &self.device,
+            &self.queue,
+            &self.texture_bind_group_layout,
+            &self.texture_filtering,
+            &mut self.asset_manager,
+        ));
+        loaded.map_err(|e| e.context(format!("Couldn't load model {}", file)))
+    }
+
+    // Adds a light to the scene and returns the id to refer to it with later on.
+    // The GPU buffers and shadow maps are updated on the next render.
+    pub fn add_light(&mut self, mut light: light::Light) -> usize {
+        light.id = self.next_light_id;
+        self.next_light_id += 1;
+        let entity = self.world.spawn();
+        self.world.lights.insert(entity, light);
+        light.id
+    }
+
+    fn light_entity(&self, id: usize) -> Option<world::Entity> {
+        self.world.lights.iter().find(|(_, light)| light.id == id).map(|(entity, _)| entity)
+    }
+
+    pub fn remove_light(&mut self, id: usize) -> Option<light::Light> {
+        let entity = self.light_entity(id)?;
+        let light = self.world.lights.remove(entity);
+        self.world.despawn(entity);
+        light
+    }
+
+    pub fn light_mut(&mut self, id: usize) -> Option<&mut light::Light> {
+        let entity = self.light_entity(id)?;
+        self.world.lights.get_mut(entity)
     }
 
     fn render(&mut self, window: &Window) -> Result<(), wgpu::SurfaceError> {
-        if self.lights_are_dirty {
-            self.lights_are_dirty = false;
-            self.light_buffer.repopulate_lights(&self.queue, &vec![self.light0])
+        // Nothing to draw the scene from
+        let Some(camera) = self.draw_list.camera else { return Ok(()) };
+        if self.draw_list.lights != self.lights {
+            self.lights = self.draw_list.lights.clone();
+            self.light_buffer.repopulate_lights(&self.device, &self.queue, &self.lights);
+            self.shadow_config.update_lights(&self.device, &self.lights);
         }
-        
+
         let output = self.surface.get_current_texture()?;
         let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
-        self.shadow_config.render(&mut encoder, &self.instance_buffer, &self.instances, &self.obj_model, &self.queue);
+
+        let view_proj = camera.build_view_projection_matrix();
+        self.lod_selectors.resize_with(self.models.len(), Default::default);
+        for (id, (model, lod_selector)) in self.models.iter().zip(&mut self.lod_selectors).enumerate() {
+            lod_selector.update(
+                &camera,
+                &model.bounds,
+                self.draw_list.entities(id),
+                self.draw_list.transforms(id),
+                &self.ui.lod_settings,
+            );
+        }
+        let scene = |shadow: bool| {
+            self.models
+                .iter()
+                .zip(&self.lod_selectors)
+                .enumerate()
+                .map(|(id, (model, lod_selector))| model::ModelInstances {
+                    model,
+                    transforms: self.draw_list.transforms(id),
+                    lods: if shadow { lod_selector.shadow_levels() } else { lod_selector.levels() },
+                })
+                .collect::<Vec<_>>()
+        };
+        let (camera_scene, shadow_scene) = (scene(false), scene(true));
+
+        let use_gpu_culling = self.ui.gpu_culling;
+        let occlusion_culling = use_gpu_culling && self.ui.occlusion_culling;
+        let mut gpu_culling = self.gpu_culling.as_mut().filter(|_| use_gpu_culling);
+        let mut camera_stats = culling::CullStats::default();
+        if let Some(gpu_culling) = &mut gpu_culling {
+            gpu_culling.cull(&mut encoder, &self.device, &self.queue, &camera_scene, view_proj, occlusion_culling);
+            camera_stats = gpu_culling.stats();
+        }
+        // Blended meshes get sorted on the CPU, so they always need the CPU culled instances
+        let has_blended = self.models.iter().any(|model| {
+            model.meshes.iter().any(|mesh| model.materials[mesh.material].alpha_mode == model::AlphaMode::Blend)
+        });
+        let mut visible = vec![Vec::new(); camera_scene.len()];
+        let mut ranges: Vec<[std::ops::Range<u32>; model::MAX_LODS]> = vec![Default::default(); camera_scene.len()];
+        if gpu_culling.is_none() || has_blended {
+            let frustum = culling::Frustum::from_matrix(view_proj);
+            let mut raws = Vec::new();
+            for (instances, (visible, ranges)) in camera_scene.iter().zip(visible.iter_mut().zip(&mut ranges)) {
+                *visible = culling::visible_instances(&[frustum], &instances.model.bounds, instances.transforms);
+                *ranges = lod::append_by_level(&mut raws, visible, instances.transforms, instances.lods);
+                if gpu_culling.is_none() {
+                    camera_stats.add(visible.len(), instances.transforms.len());
+                }
+            }
+            self.instance_buffer.write(&self.device, &self.queue, &raws);
+        }
+        let gpu_culling = gpu_culling.map(|gpu_culling| &*gpu_culling);
+        let scene_batches = camera_scene
+            .iter()
+            .zip(&ranges)
+            .enumerate()
+            .map(|(index, (instances, ranges))| model::ModelBatches {
+                model: instances.model,
+                batches: match gpu_culling {
+                    Some(gpu_culling) => gpu_culling.batches(index),
+                    None => lod::direct_batches(&self.instance_buffer.buffer, ranges),
+                },
+            })
+            .collect::<Vec<_>>();
+
+        let mut shadow_stats = self.shadow_config.render(&mut encoder, &self.device, &self.queue, &shadow_scene);
+        shadow_stats += self.sun_shadow.render(&mut encoder, &self.device, &self.queue, &shadow_scene);
+        self.ui.cull_stats = [camera_stats, shadow_stats];
+        self.ssao.render(&mut encoder, &scene_batches, occlusion_culling);
+        if let (Some(gpu_culling), true) = (gpu_culling, occlusion_culling) {
+            gpu_culling.build_pyramid(&mut encoder);
+        }
         {   
              
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                })],
+                color_attachments: &[Some(self.hdr.scene_attachment())],
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: &self.depth_texture.view,
                     depth_ops: Some(wgpu::Operations {
@@ -459,22 +1038,100 @@ impl State {
                     stencil_ops: None,
                 }),
             });
+            self.environment.render(&mut render_pass);
+
             use model::DrawLight;
             render_pass.set_vertex_buffer(1, self.instance_buffer.buffer.slice(..));
-            render_pass.set_pipeline(&&self.light_render_pipeline);
-            render_pass.draw_light_model(&self.cube_model, &self.camera_bind_group, &self.light_bind_group);
+            render_pass.set_pipeline(&self.light_render_pipeline);
+            // One gizmo per light, the shader picks the light by instance index
+            render_pass.draw_light_model_instanced(&self.cube_model, 0..self.lights.len() as u32, &self.camera_bind_group, &self.light_buffer.bind_group);
             
             use model::DrawModel;
-            render_pass.set_pipeline(&&self.render_pipeline);
+            render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(3, &self.shadow_config.ext_bind_group, &[]);
             render_pass.set_bind_group(4, &self.render_texture_bind_group, &[]);
-            render_pass.draw_model_instanced(&self.obj_model, 0..self.instances.len() as u32, &self.camera_bind_group, &self.light_bind_group);
-            
+            render_pass.set_bind_group(5, &self.sun_shadow.ext_bind_group, &[]);
+            render_pass.set_bind_group(6, &self.environment.ext_bind_group, &[]);
+            for model_batches in &scene_batches {
+                let model = model_batches.model;
+                for batch in &model_batches.batches {
+                    for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+                        let material = &model.materials[mesh.material];
+                        if material.alpha_mode != model::AlphaMode::Blend {
+                            render_pass.draw_mesh_instances(mesh, mesh_index, material, batch, &self.camera_bind_group, &self.light_buffer.bind_group);
+                        }
+                    }
+                }
+            }
+
+            // Everything blended last and back to front, so it covers what's behind it
+            render_pass.set_pipeline(&self.blend_render_pipeline);
+            for (model_index, mesh_index, instance) in model::blended_draw_order(&camera_scene, &visible, camera.eye) {
+                let instances = &camera_scene[model_index];
+                let mesh = &instances.model.meshes[mesh_index];
+                let material = &instances.model.materials[mesh.material];
+                // Every model's visible instances start where its first level does
+                let first = ranges[model_index][0].start + instance;
+                let batch = model::LodBatch {
+                    lod: instances.lods[visible[model_index][instance as usize]] as usize,
+                    instance_buffer: self.instance_buffer.buffer.slice(..),
+                    instances: model::DrawInstances::Direct(first..first + 1),
+                };
+                render_pass.draw_mesh_instances(mesh, mesh_index, material, &batch, &self.camera_bind_group, &self.light_buffer.bind_group);
+            }
         }
+        self.post.render_bloom(&mut encoder, &self.hdr.view);
+        self.hdr.render(&mut encoder, self.post.ldr_input(&view));
+        self.post.render(&mut encoder, &view);
         self.queue.submit(std::iter::once(encoder.finish()));
-        self.ui.draw(window, &self.device, &self.queue, &view);
+        if let Some(gpu_culling) = &mut self.gpu_culling {
+            gpu_culling.after_submit(&self.device);
+        }
+        let mut editor_commands = Vec::new();
+        let mut unload_assets = false;
+        let (editor, world, models, asset_manager) = (&mut self.editor, &self.world, &self.models, &self.asset_manager);
+        let size = [self.config.width as f32, self.config.height as f32];
+        let light_commands = self.ui.draw(window, &self.device, &self.queue, &view, &self.lights, |ui| {
+            if editor.enabled {
+                editor_commands = editor.draw(ui, world, models, &camera, size);
+            }
+            unload_assets = ui::draw_assets(ui, asset_manager);
+        });
         output.present();
 
+        if unload_assets {
+            let unloaded = self.asset_manager.unload_unused();
+            log::info!("Unloaded {} unused assets", unloaded);
+        }
+
+        for command in light_commands {
+            match command {
+                ui::LightCommand::Add => {
+                    // New lights show up a bit in front of the camera
+                    let position = camera.eye + (camera.target - camera.eye) * 2.0;
+                    self.add_light(light::Light::new(0, cgmath::vec3(position.x, position.y, position.z), [1.0, 1.0, 1.0].into(), 1.0, 1.0));
+                }
+                ui::LightCommand::Remove(id) => {
+                    self.remove_light(id);
+                }
+                ui::LightCommand::Modify(light) => {
+                    if let Some(existing) = self.light_mut(light.id) {
+                        *existing = light;
+                    }
+                }
+            }
+        }
+        for command in editor_commands {
+            match command {
+                editor::EditorCommand::SpawnModel(file) => self.spawn_model(&file, &camera),
+                editor::EditorCommand::Save(path) => {
+                    self.map_path = path;
+                    self.save_map();
+                }
+                command => self.editor.apply(command, &mut editor::EditorScene { world: &mut self.world, models: &self.models }),
+            }
+        }
+
         Ok(())
     }
 }
@@ -482,12 +1139,46 @@ impl State {
  
 
 
-pub async fn run() {
+// Loaded when no map is given on the command line
+const DEFAULT_MAP: &str = "maps/default.json";
+// Where the default map gets saved, the one in the resources gets overwritten on every build
+const SAVED_MAP: &str = "map.json";
+
+pub async fn run(map_path: Option<std::path::PathBuf>, asset_dir: Option<std::path::PathBuf>, archives: Vec<std::path::PathBuf>) {
     env_logger::init();
+    match assets::AssetRoot::search(asset_dir, &archives) {
+        Ok(root) => assets::set_root(root),
+        Err(e) => {
+            log::error!("{:#}", e);
+            return;
+        }
+    }
+    let map = match &map_path {
+        Some(path) => map::Map::load(path),
+        None => resources::load_string(DEFAULT_MAP)
+            .await
+            .and_then(|text| map::Map::parse(&text))
+            .map_err(|e| e.context(format!("Couldn't load map {}", DEFAULT_MAP))),
+    };
+    let map = match map {
+        Ok(map) => map,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return;
+        }
+    };
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
-    let mut state = State::new(&window).await;
+    let map_path = map_path.unwrap_or_else(|| SAVED_MAP.into());
+    let mut state = match State::new(&window, map, map_path).await {
+        Ok(state) => state,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return;
+        }
+    };
 
     event_loop.run(move |event, _, control_flow| {
         match event {
@@ -511,7 +1202,7 @@ pub async fn run() {
             Event::WindowEvent {
                 ref event,
                 window_id,
-            } if window_id == window.id() => if !state.input(event, &window) {
+            } if window_id == window.id() && !state.input(event, &window) => {
                 match event {
                     WindowEvent::CloseRequested
                     | WindowEvent::KeyboardInput {
@@ -532,7 +1223,7 @@ pub async fn run() {
                     _ => {}
                 }
             },
-            Event::DeviceEvent { ref event, .. } => state.mouse_input(&event),
+            Event::DeviceEvent { ref event, .. } => state.mouse_input(event),
             _ => {}
         }
         state.ui.handle_input(&window, &event);
@@ -1,26 +1,142 @@
 use std::ops::Range;
 
-use super::texture;
+use crate::world::{material::{MaterialKey, MaterialSettings}, ModelId};
+use super::{asset_manager::{Handle, TextureAsset}, culling, render_pipeline::DepthPipelines, texture};
 
+pub use crate::world::material::AlphaMode;
+
+
+// Full detail plus up to two coarser levels
+pub const MAX_LODS: usize = 3;
 
 pub struct Model {
-    pub meshes: Vec<Mesh>,
+    // Shared with every other model loaded from the same file
+    pub meshes: Handle<Vec<Mesh>>,
     pub materials: Vec<Material>,
+    // Around all of the meshes, instances get culled with it
+    pub bounds: culling::Aabb,
 }
 
+// Alpha tested texels below this get discarded
+pub const ALPHA_CUTOFF: f32 = 0.5;
+
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: texture::Texture,
-    pub normal_texture: texture::Texture,
-    pub diffuse_color: [f32; 3],
+    // Shared with the other materials using the same files
+    pub diffuse_texture: Handle<TextureAsset>,
+    pub normal_texture: Handle<TextureAsset>,
+    pub alpha_mode: AlphaMode,
+    // What the uniform buffer holds
+    pub uniform: MaterialUniform,
+    // As the MTL file had it, before the map's changes
+    pub loaded_uniform: MaterialUniform,
+    pub sampler_settings: texture::SamplerSettings,
+    // The material's own, for both textures
+    pub sampler: wgpu::Sampler,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    pub fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        diffuse_texture: &texture::Texture,
+        normal_texture: &texture::Texture,
+        sampler: &wgpu::Sampler,
+        uniform_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: uniform_buffer.as_entire_binding(),
+                }
+            ],
+            label: None,
+        })
+    }
+
+    // New samplers with the given filtering, the address mode stays the material's own
+    pub fn set_filtering(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout, settings: &texture::SamplerSettings) {
+        self.sampler_settings = texture::SamplerSettings {
+            address_mode: self.sampler_settings.address_mode,
+            ..*settings
+        };
+        self.sampler = self.sampler_settings.create_sampler(device, Some(&self.name));
+        self.bind_group = self.create_own_bind_group(device, layout);
+    }
+
+    // Swaps in textures that got loaded again
+    pub fn set_textures(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout, diffuse: Option<Handle<TextureAsset>>, normal: Option<Handle<TextureAsset>>) {
+        if let Some(diffuse) = diffuse {
+            self.diffuse_texture = diffuse;
+        }
+        if let Some(normal) = normal {
+            self.normal_texture = normal;
+        }
+        self.bind_group = self.create_own_bind_group(device, layout);
+    }
+
+    fn create_own_bind_group(&self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout) -> wgpu::BindGroup {
+        Self::create_bind_group(device, layout, &self.diffuse_texture.texture, &self.normal_texture.texture, &self.sampler, &self.uniform_buffer)
+    }
+
+    // New colours, dissolve or alpha mode, the textures stay the same
+    pub fn set_uniform(&mut self, queue: &wgpu::Queue, uniform: MaterialUniform) {
+        self.uniform = uniform;
+        self.alpha_mode = AlphaMode::from_u32(uniform.alpha_mode);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+}
+
+impl Model {
+    // Puts the map's changes to the materials on top of what got loaded, and takes back the
+    // ones that aren't there anymore
+    pub fn apply_material_settings(&mut self, queue: &wgpu::Queue, model: ModelId, settings: &std::collections::BTreeMap<MaterialKey, MaterialSettings>) {
+        for material in &mut self.materials {
+            let key = MaterialKey { model, name: material.name.clone() };
+            let uniform = match settings.get(&key) {
+                Some(settings) => material.loaded_uniform.with_settings(settings),
+                None => material.loaded_uniform,
+            };
+            if uniform != material.uniform {
+                material.set_uniform(queue, uniform);
+            }
+        }
+    }
+
+    pub fn set_filtering(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout, settings: &texture::SamplerSettings) {
+        for material in &mut self.materials {
+            material.set_filtering(device, layout, settings);
+        }
+    }
+}
+
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct MaterialUniform {
     pub use_texture: u32,
-    pub _p1: [f32; 3],
+    pub dissolve: f32,
+    pub alpha_mode: u32,
+    pub alpha_cutoff: f32,
     pub ambient_color: [f32; 3],
     pub _p2: u32,
     pub diffuse_color: [f32; 3],
@@ -29,12 +145,96 @@ pub struct MaterialUniform {
     pub _p4: u32,
 }
 
+impl MaterialUniform {
+    pub fn settings(&self) -> MaterialSettings {
+        MaterialSettings {
+            diffuse_color: self.diffuse_color,
+            ambient_color: self.ambient_color,
+            specular_color: self.specular_color,
+            dissolve: self.dissolve,
+            alpha_mode: AlphaMode::from_u32(self.alpha_mode),
+        }
+    }
+
+    pub fn with_settings(self, settings: &MaterialSettings) -> Self {
+        Self {
+            diffuse_color: settings.diffuse_color,
+            ambient_color: settings.ambient_color,
+            specular_color: settings.specular_color,
+            dissolve: settings.dissolve,
+            alpha_mode: settings.alpha_mode as u32,
+            ..self
+        }
+    }
+}
+
 pub struct Mesh {
-    pub name: String,
     pub vertex_buffer: wgpu::Buffer,
+    // Full detail first, never empty
+    pub lods: Vec<MeshLod>,
+    pub material: usize,
+    // In model space, its center is used for sorting blended meshes
+    pub bounds: culling::Aabb,
+}
+
+// One level of detail, its vertices are somewhere in the mesh's vertex buffer
+pub struct MeshLod {
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
-    pub material: usize,
+    pub base_vertex: i32,
+}
+
+impl Mesh {
+    // Meshes with fewer levels use their coarsest one
+    pub fn lod(&self, level: usize) -> &MeshLod {
+        &self.lods[level.min(self.lods.len() - 1)]
+    }
+}
+
+// Where the instance count of a draw comes from. Indirect draws read one
+// DrawIndexedIndirect per mesh of the model, in mesh order, starting at `offset`.
+#[derive(Clone)]
+pub enum DrawInstances<'a> {
+    Direct(Range<u32>),
+    Indirect { commands: &'a wgpu::Buffer, offset: wgpu::BufferAddress },
+}
+
+impl<'b> DrawInstances<'b> {
+    fn draw<'a>(&self, pass: &mut wgpu::RenderPass<'a>, mesh_index: usize, lod: &MeshLod)
+    where
+        'b: 'a,
+    {
+        match self {
+            DrawInstances::Direct(range) => pass.draw_indexed(0..lod.num_elements, lod.base_vertex, range.clone()),
+            DrawInstances::Indirect { commands, offset } => {
+                pass.draw_indexed_indirect(commands, offset + mesh_index as wgpu::BufferAddress * DRAW_INDEXED_INDIRECT_SIZE)
+            }
+        }
+    }
+}
+
+// The instances that get drawn at one level of detail, vertex buffer slot 1 takes the slice
+#[derive(Clone)]
+pub struct LodBatch<'a> {
+    pub lod: usize,
+    pub instance_buffer: wgpu::BufferSlice<'a>,
+    pub instances: DrawInstances<'a>,
+}
+
+pub const DRAW_INDEXED_INDIRECT_SIZE: wgpu::BufferAddress = 20;
+
+// A model with the world matrices of its instances and the level every one gets drawn at
+#[derive(Clone, Copy)]
+pub struct ModelInstances<'a> {
+    pub model: &'a Model,
+    pub transforms: &'a [cgmath::Matrix4<f32>],
+    pub lods: &'a [u8],
+}
+
+// What a pass draws of a model
+pub struct ModelBatches<'a> {
+    pub model: &'a Model,
+    pub batches: Vec<LodBatch<'a>>,
 }
 
 pub trait Vertex {
@@ -47,6 +247,8 @@ pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
+    pub tangent: [f32; 3],
+    pub bitangent: [f32; 3],
 }
 
 impl Vertex for ModelVertex {
@@ -71,37 +273,82 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
 }
 
-pub trait DrawModel<'a> {
-    // With Materials
-    fn draw_mesh(&mut self,
-        mesh: &'a Mesh, 
-        material: &'a Material, 
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
+// Accumulates per-triangle tangents and bitangents from the uv layout, then
+// orthogonalizes them against the vertex normal (Gram-Schmidt) and keeps the
+// handedness in the bitangent, the same way MikkTSpace does it.
+pub fn compute_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    use cgmath::{InnerSpace, Vector2, Vector3, Zero};
 
-    fn draw_mesh_instanced(&mut self,
-        mesh: &'a Mesh, 
-        material: &'a Material, 
-        instances: Range<u32>, 
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
+    let mut tangents = vec![Vector3::<f32>::zero(); vertices.len()];
+    let mut bitangents = vec![Vector3::<f32>::zero(); vertices.len()];
 
-    // Without Materials
-    fn draw_model(&mut self, 
-        model: &'a Model, 
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
-    fn draw_model_instanced(&mut self, 
-        model: &'a Model, 
-        instances: Range<u32>, 
+    for c in indices.chunks_exact(3) {
+        let (i0, i1, i2) = (c[0] as usize, c[1] as usize, c[2] as usize);
+        let pos0: Vector3<f32> = vertices[i0].position.into();
+        let pos1: Vector3<f32> = vertices[i1].position.into();
+        let pos2: Vector3<f32> = vertices[i2].position.into();
+        let uv0: Vector2<f32> = vertices[i0].tex_coords.into();
+        let uv1: Vector2<f32> = vertices[i1].tex_coords.into();
+        let uv2: Vector2<f32> = vertices[i2].tex_coords.into();
+
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+
+        let det = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        if det.abs() < f32::EPSILON {
+            // Degenerate uv mapping, this triangle can't tell us anything
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        // Flip the bitangent so it points along +v, image rows go top to bottom
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
+
+        for i in [i0, i1, i2] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    for (i, v) in vertices.iter_mut().enumerate() {
+        let n: Vector3<f32> = v.normal.into();
+        let mut t = tangents[i] - n * n.dot(tangents[i]);
+        if t.magnitude2() < f32::EPSILON {
+            // No usable uv's, just pick any direction perpendicular to the normal
+            let axis = if n.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+            t = axis - n * n.dot(axis);
+        }
+        let t = t.normalize();
+        let handedness = if n.cross(t).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        v.tangent = t.into();
+        v.bitangent = (n.cross(t) * handedness).into();
+    }
+}
+
+pub trait DrawModel<'a> {
+    // `mesh_index` picks the indirect command
+    fn draw_mesh_instances(&mut self,
+        mesh: &'a Mesh,
+        mesh_index: usize,
+        material: &'a Material,
+        batch: &LodBatch<'a>,
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
@@ -111,56 +358,104 @@ impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
 where 
     'b: 'a, 
 {
-    fn draw_mesh(&mut self, 
-        mesh: &'a Mesh, 
-        material: &'a Material, 
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup
-    ) {
-        self.draw_mesh_instanced(mesh, material, 0..1, camera_bind_group, light_bind_group);
-    }
-    fn draw_mesh_instanced(&mut self, 
-        mesh: &'a Mesh, 
-        material: &'a Material, 
-        instances: Range<u32>, 
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup
+    fn draw_mesh_instances(&mut self,
+        mesh: &'b Mesh,
+        mesh_index: usize,
+        material: &'b Material,
+        batch: &LodBatch<'b>,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup
     ) {
+        let lod = mesh.lod(batch.lod);
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.set_vertex_buffer(1, batch.instance_buffer);
+        self.set_index_buffer(lod.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
         self.set_bind_group(2, light_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances)
+        batch.instances.draw(self, mesh_index, lod);
     }
+}
 
-    fn draw_model(&mut self, 
-        model: &'b Model, 
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'b wgpu::BindGroup,
-    ) {
-        self.draw_model_instanced(model, 0..1, camera_bind_group, light_bind_group);
+// Blended meshes of all models, one entry of (model index, mesh index, instance index) per
+// visible instance, sorted so the ones furthest away from `eye` come first. `visible` has
+// the visible instances of every model, the instance index is the position in there.
+pub fn blended_draw_order(scene: &[ModelInstances], visible: &[Vec<usize>], eye: cgmath::Point3<f32>) -> Vec<(usize, usize, u32)> {
+    use cgmath::{MetricSpace, Transform};
+
+    let mut order = Vec::new();
+    for (model_index, (instances, visible)) in scene.iter().zip(visible).enumerate() {
+        let model = instances.model;
+        for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+            if model.materials[mesh.material].alpha_mode != AlphaMode::Blend {
+                continue;
+            }
+            for (instance_index, transform) in visible.iter().map(|index| &instances.transforms[*index]).enumerate() {
+                let center = transform.transform_point(mesh.bounds.center());
+                order.push((center.distance2(eye), model_index, mesh_index, instance_index as u32));
+            }
+        }
     }
-    fn draw_model_instanced(&mut self, 
-        model: &'b Model, 
-        instances: Range<u32>, 
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'b wgpu::BindGroup
+    order.sort_by(|a, b| b.0.total_cmp(&a.0));
+    order.into_iter().map(|(_, model, mesh, instance)| (model, mesh, instance)).collect()
+}
+
+// Depth only drawing for the shadow maps and the depth prepass, group 0 has to be set already
+pub trait DrawDepth<'a> {
+    fn draw_model_depth(
+        &mut self,
+        model: &'a Model,
+        batches: &[LodBatch<'a>],
+        pipelines: &'a DepthPipelines,
+        include_blended: bool,
+    );
+}
+
+impl<'a, 'b> DrawDepth<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_model_depth(
+        &mut self,
+        model: &'b Model,
+        batches: &[LodBatch<'b>],
+        pipelines: &'b DepthPipelines,
+        include_blended: bool,
     ) {
-        for mesh in &model.meshes {
+        let draw = |pass: &mut Self, mesh_index: usize, mesh: &'b Mesh| {
+            pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            for batch in batches {
+                let lod = mesh.lod(batch.lod);
+                pass.set_vertex_buffer(1, batch.instance_buffer);
+                pass.set_index_buffer(lod.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                batch.instances.draw(pass, mesh_index, lod);
+            }
+        };
+
+        // Opaque meshes first, so the pipeline only changes once
+        self.set_pipeline(&pipelines.opaque);
+        for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+            if model.materials[mesh.material].alpha_mode != AlphaMode::Opaque {
+                continue;
+            }
+            draw(self, mesh_index, mesh);
+        }
+
+        self.set_pipeline(&pipelines.masked);
+        for (mesh_index, mesh) in model.meshes.iter().enumerate() {
             let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instances.clone(), camera_bind_group, light_bind_group);
+            match material.alpha_mode {
+                AlphaMode::Opaque => continue,
+                AlphaMode::Blend if !include_blended => continue,
+                _ => {}
+            }
+            self.set_bind_group(1, &material.bind_group, &[]);
+            draw(self, mesh_index, mesh);
         }
     }
 }
 
 pub trait DrawLight<'a> {
-    fn draw_light_mesh(
-        &mut self,
-        mesh: &'a Mesh,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
     fn draw_light_mesh_instanced(
         &mut self,
         mesh: &'a Mesh,
@@ -169,12 +464,6 @@ pub trait DrawLight<'a> {
         light_bind_group: &'a wgpu::BindGroup,
     );
 
-    fn draw_light_model(
-        &mut self,
-        model: &'a Model,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
     fn draw_light_model_instanced(
         &mut self,
         model: &'a Model,
@@ -188,15 +477,6 @@ impl<'a, 'b> DrawLight<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a,
 {
-    fn draw_light_mesh(
-        &mut self,
-        mesh: &'b Mesh,
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'b wgpu::BindGroup,
-    ) {
-        self.draw_light_mesh_instanced(mesh, 0..1, camera_bind_group, light_bind_group);
-    }
-
     fn draw_light_mesh_instanced(
         &mut self,
         mesh: &'b Mesh,
@@ -204,21 +484,14 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
+        let lod = mesh.lod(0);
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.set_index_buffer(lod.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.set_bind_group(0, camera_bind_group, &[]);
         self.set_bind_group(1, light_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_indexed(0..lod.num_elements, lod.base_vertex, instances);
     }
 
-    fn draw_light_model(
-        &mut self,
-        model: &'b Model,
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'b wgpu::BindGroup,
-    ) {
-        self.draw_light_model_instanced(model, 0..1, camera_bind_group, light_bind_group);
-    }
     fn draw_light_model_instanced(
         &mut self,
         model: &'b Model,
@@ -226,8 +499,65 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
+        for mesh in model.meshes.iter() {
             self.draw_light_mesh_instanced(mesh, instances.clone(), camera_bind_group, light_bind_group);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A quad facing +z from -1 to 1, with v = 0 along the top edge like image rows
+    fn quad(mirrored: bool) -> Vec<ModelVertex> {
+        [([-1.0, 1.0], [0.0, 0.0]), ([1.0, 1.0], [1.0, 0.0]), ([-1.0, -1.0], [0.0, 1.0]), ([1.0, -1.0], [1.0, 1.0])]
+            .into_iter()
+            .map(|([x, y], [u, v])| ModelVertex {
+                position: [x, y, 0.0],
+                tex_coords: [if mirrored { 1.0 - u } else { u }, v],
+                normal: [0.0, 0.0, 1.0],
+                tangent: [0.0; 3],
+                bitangent: [0.0; 3],
+            })
+            .collect()
+    }
+
+    fn assert_near(a: [f32; 3], b: [f32; 3]) {
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-5), "{:?} isn't {:?}", a, b);
+    }
+
+    #[test]
+    fn tangents_follow_the_uvs() {
+        let indices = [0, 2, 1, 1, 2, 3];
+        let mut vertices = quad(false);
+        compute_tangents(&mut vertices, &indices);
+        for vertex in &vertices {
+            // Along +u, and up the texture
+            assert_near(vertex.tangent, [1.0, 0.0, 0.0]);
+            assert_near(vertex.bitangent, [0.0, 1.0, 0.0]);
+        }
+
+        // Mirrored uvs flip the tangent, the handedness keeps the bitangent pointing up
+        let mut vertices = quad(true);
+        compute_tangents(&mut vertices, &indices);
+        for vertex in &vertices {
+            assert_near(vertex.tangent, [-1.0, 0.0, 0.0]);
+            assert_near(vertex.bitangent, [0.0, 1.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn tangents_without_uvs_stay_perpendicular() {
+        let mut vertices = quad(false);
+        for vertex in &mut vertices {
+            vertex.tex_coords = [0.5, 0.5];
+        }
+        compute_tangents(&mut vertices, &[0, 2, 1, 1, 2, 3]);
+        for vertex in &vertices {
+            assert_near(vertex.tangent, [1.0, 0.0, 0.0]);
+            let bitangent: cgmath::Vector3<f32> = vertex.bitangent.into();
+            assert!((cgmath::InnerSpace::magnitude(bitangent) - 1.0).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use super::{hdr, render_pipeline::{self, FullscreenPass}, shaders, texture};
+use std::mem;
+
+pub use crate::world::environment::{PostEffect, PostSettings};
+
+pub const LUT_FILES: [&str; 2] = ["assets/lut_neutral.png", "assets/lut_warm.png"];
+const MAX_BLOOM_MIPS: u32 = 6;
+
+impl PostEffect {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PostEffect::Fxaa => "FXAA",
+            PostEffect::Vignette => "Vignette",
+            PostEffect::ColorGrading => "Colour grading",
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostUniform {
+    surface_is_srgb: u32,
+    bloom_filter_radius: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    lut_strength: f32,
+    fxaa_edge_threshold: f32,
+    fxaa_edge_threshold_min: f32,
+    _padding: [u32; 4],
+}
+
+pub struct PostProcessing {
+    settings: PostSettings,
+    settings_buffer: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    luts: Vec<texture::Texture>,
+    format: wgpu::TextureFormat,
+
+    bloom_downsample_first: FullscreenPass,
+    bloom_downsample: FullscreenPass,
+    bloom_upsample: FullscreenPass,
+    bloom_composite: FullscreenPass,
+
+    fxaa: FullscreenPass,
+    vignette: FullscreenPass,
+    color_grading: FullscreenPass,
+
+    targets: Targets,
+    ldr_bind_groups: [wgpu::BindGroup; 2],
+}
+
+// Everything that depends on the size of the surface
+struct Targets {
+    bloom_mips: Vec<wgpu::TextureView>,
+    // Reads the hdr image for the first downsample
+    bloom_hdr_bind_group: wgpu::BindGroup,
+    // One per mip, reading from it
+    bloom_mip_bind_groups: Vec<wgpu::BindGroup>,
+    // Effects ping pong between these two, the last one writes to the surface
+    ldr_targets: [wgpu::TextureView; 2],
+}
+
+impl PostProcessing {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        hdr_view: &wgpu::TextureView,
+        luts: Vec<texture::Texture>,
+        settings: &PostSettings,
+    ) -> Self {
+        let source_entries = [
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+        let mut ldr_entries = source_entries.to_vec();
+        ldr_entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D3,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        });
+
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+        // Lerps between the scene and the bloom by the blend constant
+        let composite = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Constant,
+                dst_factor: wgpu::BlendFactor::OneMinusConstant,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+
+        let bloom_shader = render_pipeline::create_fullscreen_shader(device, "Bloom Shader", &shaders::source("bloom.wgsl"));
+        let bloom_downsample_first = FullscreenPass::new(device, "Bloom Downsample First", &source_entries, hdr::HDR_FORMAT, wgpu::BlendState::REPLACE, &bloom_shader, "fs_downsample_first");
+        let bloom_downsample = FullscreenPass::new(device, "Bloom Downsample", &source_entries, hdr::HDR_FORMAT, wgpu::BlendState::REPLACE, &bloom_shader, "fs_downsample");
+        let bloom_upsample = FullscreenPass::new(device, "Bloom Upsample", &source_entries, hdr::HDR_FORMAT, additive, &bloom_shader, "fs_upsample");
+        let bloom_composite = FullscreenPass::new(device, "Bloom Composite", &source_entries, hdr::HDR_FORMAT, composite, &bloom_shader, "fs_upsample");
+
+        let post_shader = render_pipeline::create_fullscreen_shader(device, "Post Shader", &shaders::source("post.wgsl"));
+        let fxaa = FullscreenPass::new(device, "FXAA", &ldr_entries, config.format, wgpu::BlendState::REPLACE, &post_shader, "fs_fxaa");
+        let vignette = FullscreenPass::new(device, "Vignette", &ldr_entries, config.format, wgpu::BlendState::REPLACE, &post_shader, "fs_vignette");
+        let color_grading = FullscreenPass::new(device, "Colour Grading", &ldr_entries, config.format, wgpu::BlendState::REPLACE, &post_shader, "fs_color_grading");
+
+        let settings_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Post Settings Buffer"),
+            size: mem::size_of::<PostUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Post Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let targets = Targets::new(device, config, hdr_view, &bloom_downsample, &sampler, &settings_buffer);
+        let lut = settings.lut.min(luts.len() - 1);
+        let ldr_bind_groups = Self::create_ldr_bind_groups(device, &fxaa, &targets, &sampler, &settings_buffer, &luts[lut]);
+
+        Self {
+            settings: *settings,
+            settings_buffer,
+            sampler,
+            luts,
+            format: config.format,
+            bloom_downsample_first,
+            bloom_downsample,
+            bloom_upsample,
+            bloom_composite,
+            fxaa,
+            vignette,
+            color_grading,
+            targets,
+            ldr_bind_groups,
+        }
+    }
+
+    // All the ldr effects share a layout, so any of their passes can make the bind groups
+    fn create_ldr_bind_groups(
+        device: &wgpu::Device,
+        pass: &FullscreenPass,
+        targets: &Targets,
+        sampler: &wgpu::Sampler,
+        settings_buffer: &wgpu::Buffer,
+        lut: &texture::Texture,
+    ) -> [wgpu::BindGroup; 2] {
+        let create = |view: &wgpu::TextureView| pass.create_bind_group(device, "Post Bind Group", &[
+            wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(view) },
+            wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(sampler) },
+            wgpu::BindGroupEntry { binding: 2, resource: settings_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 3, resource: wgpu::BindingResource::TextureView(&lut.view) },
+        ]);
+        [create(&targets.ldr_targets[0]), create(&targets.ldr_targets[1])]
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, hdr_view: &wgpu::TextureView) {
+        self.targets = Targets::new(device, config, hdr_view, &self.bloom_downsample, &self.sampler, &self.settings_buffer);
+        self.ldr_bind_groups = Self::create_ldr_bind_groups(device, &self.fxaa, &self.targets, &self.sampler, &self.settings_buffer, &self.luts[self.settings.lut]);
+    }
+
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, settings: &PostSettings) {
+        let lut = settings.lut.min(self.luts.len() - 1);
+        if lut != self.settings.lut {
+            self.ldr_bind_groups = Self::create_ldr_bind_groups(device, &self.fxaa, &self.targets, &self.sampler, &self.settings_buffer, &self.luts[lut]);
+        }
+        self.settings = PostSettings { lut, ..*settings };
+
+        queue.write_buffer(&self.settings_buffer, 0, bytemuck::cast_slice(&[PostUniform {
+            surface_is_srgb: self.format.describe().srgb as u32,
+            bloom_filter_radius: settings.bloom_filter_radius,
+            vignette_intensity: settings.vignette_intensity,
+            vignette_radius: settings.vignette_radius,
+            vignette_smoothness: settings.vignette_smoothness,
+            lut_strength: settings.lut_strength,
+            fxaa_edge_threshold: settings.fxaa_edge_threshold,
+            fxaa_edge_threshold_min: settings.fxaa_edge_threshold_min,
+            _padding: [0; 4],
+        }]));
+    }
+
+    fn enabled_effects(&self) -> impl Iterator<Item = PostEffect> + '_ {
+        self.settings.effects.iter().filter(|(_, enabled)| *enabled).map(|(effect, _)| *effect)
+    }
+
+    // Where tonemapping should write to, straight to the surface if there's nothing after it
+    pub fn ldr_input<'a>(&'a self, surface_view: &'a wgpu::TextureView) -> &'a wgpu::TextureView {
+        if self.enabled_effects().next().is_some() {
+            &self.targets.ldr_targets[0]
+        } else {
+            surface_view
+        }
+    }
+
+    // Adds bloom to the hdr image, has to run before tonemapping
+    pub fn render_bloom(&self, encoder: &mut wgpu::CommandEncoder, hdr_view: &wgpu::TextureView) {
+        if !self.settings.bloom || self.targets.bloom_mips.is_empty() {
+            return;
+        }
+        let mips = &self.targets.bloom_mips;
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::BLACK);
+
+        encoder.push_debug_group("bloom");
+        self.bloom_downsample_first.draw(encoder, &mips[0], &self.targets.bloom_hdr_bind_group, clear, None);
+        for (mip, source) in mips.iter().skip(1).zip(&self.targets.bloom_mip_bind_groups) {
+            self.bloom_downsample.draw(encoder, mip, source, clear, None);
+        }
+        for i in (1..mips.len()).rev() {
+            self.bloom_upsample.draw(encoder, &mips[i - 1], &self.targets.bloom_mip_bind_groups[i], wgpu::LoadOp::Load, None);
+        }
+        let strength = self.settings.bloom_strength as f64;
+        self.bloom_composite.draw(
+            encoder,
+            hdr_view,
+            &self.targets.bloom_mip_bind_groups[0],
+            wgpu::LoadOp::Load,
+            Some(wgpu::Color { r: strength, g: strength, b: strength, a: strength }),
+        );
+        encoder.pop_debug_group();
+    }
+
+    // Runs the enabled ldr effects in order, starting from what ldr_input handed out
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, surface_view: &wgpu::TextureView) {
+        let effects = self.enabled_effects().collect::<Vec<_>>();
+        for (i, effect) in effects.iter().enumerate() {
+            let pass = match effect {
+                PostEffect::Fxaa => &self.fxaa,
+                PostEffect::Vignette => &self.vignette,
+                PostEffect::ColorGrading => &self.color_grading,
+            };
+            let target = if i + 1 == effects.len() {
+                surface_view
+            } else {
+                &self.targets.ldr_targets[(i + 1) % 2]
+            };
+            pass.draw(encoder, target, &self.ldr_bind_groups[i % 2], wgpu::LoadOp::Clear(wgpu::Color::BLACK), None);
+        }
+    }
+}
+
+impl Targets {
+    fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        hdr_view: &wgpu::TextureView,
+        bloom_pass: &FullscreenPass,
+        sampler: &wgpu::Sampler,
+        settings_buffer: &wgpu::Buffer,
+    ) -> Self {
+        let create_bind_group = |view: &wgpu::TextureView| bloom_pass.create_bind_group(device, "Bloom Bind Group", &[
+            wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(view) },
+            wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(sampler) },
+            wgpu::BindGroupEntry { binding: 2, resource: settings_buffer.as_entire_binding() },
+        ]);
+
+        // Halve the size until it gets too small to be useful
+        let mut bloom_mips = Vec::new();
+        let (mut width, mut height) = (config.width / 2, config.height / 2);
+        while bloom_mips.len() < MAX_BLOOM_MIPS as usize && width >= 2 && height >= 2 {
+            bloom_mips.push(create_target(device, width, height, hdr::HDR_FORMAT, "Bloom Mip"));
+            width /= 2;
+            height /= 2;
+        }
+        let bloom_hdr_bind_group = create_bind_group(hdr_view);
+        let bloom_mip_bind_groups = bloom_mips.iter().map(create_bind_group).collect();
+
+        let ldr_targets = [
+            create_target(device, config.width, config.height, config.format, "Post Target"),
+            create_target(device, config.width, config.height, config.format, "Post Target"),
+        ];
+
+        Self {
+            bloom_mips,
+            bloom_hdr_bind_group,
+            bloom_mip_bind_groups,
+            ldr_targets,
+        }
+    }
+}
+
+pub fn create_target(device: &wgpu::Device, width: u32, height: u32, format: wgpu::TextureFormat, label: &str) -> wgpu::TextureView {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+    });
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
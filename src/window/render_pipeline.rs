@@ -1,31 +1,31 @@
+use super::shaders;
 
+// Anything that isn't REPLACE blended gets sorted instead, so it doesn't write depth
+#[allow(clippy::too_many_arguments)]
 pub fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
     color_format: wgpu::TextureFormat,
     depth_format: Option<wgpu::TextureFormat>,
     vertex_layouts: &[wgpu::VertexBufferLayout],
-    shader: wgpu::ShaderModuleDescriptor,
+    shader: &wgpu::ShaderModule,
+    sample_count: u32,
+    blend: wgpu::BlendState,
 ) -> wgpu::RenderPipeline {
-    let shader = device.create_shader_module(shader);
-
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some("Render Pipeline"),
         layout: Some(layout),
         vertex: wgpu::VertexState {
-            module: &shader,
+            module: shader,
             entry_point: "vs_main",
             buffers: vertex_layouts,
         },
         fragment: Some(wgpu::FragmentState {
-            module: &shader,
+            module: shader,
             entry_point: "fs_main",
             targets: &[Some(wgpu::ColorTargetState {
                 format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
+                blend: Some(blend),
                 write_mask: wgpu::ColorWrites::ALL,
             })],
         }),
@@ -43,16 +43,196 @@ pub fn create_render_pipeline(
         },
         depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
             format,
-            depth_write_enabled: true,
+            depth_write_enabled: blend == wgpu::BlendState::REPLACE,
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
         multiview: None,
     })
 }
+
+// Builds a shader module for a depth only pass, with fs_masked and MaskedOutput from
+// alpha_mask.wgsl in front of the source for the alpha tested pipeline.
+pub fn create_depth_shader(device: &wgpu::Device, label: &str, source: &str) -> wgpu::ShaderModule {
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(label),
+        source: wgpu::ShaderSource::Wgsl(format!("{}\n{}", shaders::source("alpha_mask.wgsl"), source).into()),
+    })
+}
+
+// Depth only passes draw alpha tested materials with a second pipeline, which has the
+// material at group 1 and runs fs_masked. The vertex entry of that one gets a _masked suffix.
+pub struct DepthPipelines {
+    pub opaque: wgpu::RenderPipeline,
+    pub masked: wgpu::RenderPipeline,
+}
+
+impl DepthPipelines {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        label: &str,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        material_layout: &wgpu::BindGroupLayout,
+        shader: &wgpu::ShaderModule,
+        vertex_entry: &str,
+        vertex_layouts: &[wgpu::VertexBufferLayout],
+        primitive: wgpu::PrimitiveState,
+        depth_stencil: wgpu::DepthStencilState,
+        multiview: Option<std::num::NonZeroU32>,
+    ) -> Self {
+        let opaque_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts: &[bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let masked_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts: &[bind_group_layout, material_layout],
+            push_constant_ranges: &[],
+        });
+        let masked_entry = format!("{}_masked", vertex_entry);
+
+        let opaque = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(&opaque_layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: vertex_entry,
+                buffers: vertex_layouts,
+            },
+            fragment: None,
+            primitive,
+            depth_stencil: Some(depth_stencil.clone()),
+            multisample: wgpu::MultisampleState::default(),
+            multiview,
+        });
+        let masked = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(&masked_layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: &masked_entry,
+                buffers: vertex_layouts,
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_masked",
+                targets: &[],
+            }),
+            primitive,
+            depth_stencil: Some(depth_stencil),
+            multisample: wgpu::MultisampleState::default(),
+            multiview,
+        });
+
+        Self { opaque, masked }
+    }
+}
+
+// Builds a shader module for a fullscreen pass, the shared vertex stage in fullscreen.wgsl
+// gets put in front of the source so the fragment stages can use FullscreenOutput.
+pub fn create_fullscreen_shader(device: &wgpu::Device, label: &str, source: &str) -> wgpu::ShaderModule {
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(label),
+        source: wgpu::ShaderSource::Wgsl(format!("{}\n{}", shaders::source("fullscreen.wgsl"), source).into()),
+    })
+}
+
+// A single triangle covering the whole target, for post processing and the like.
+// Everything the fragment stage reads comes in through bind group 0.
+pub struct FullscreenPass {
+    pipeline: wgpu::RenderPipeline,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl FullscreenPass {
+    pub fn new(
+        device: &wgpu::Device,
+        label: &str,
+        entries: &[wgpu::BindGroupLayoutEntry],
+        color_format: wgpu::TextureFormat,
+        blend: wgpu::BlendState,
+        shader: &wgpu::ShaderModule,
+        fragment_entry: &str,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(label),
+            entries,
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: fragment_entry,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: color_format,
+                    blend: Some(blend),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self { pipeline, bind_group_layout }
+    }
+
+    pub fn create_bind_group(&self, device: &wgpu::Device, label: &str, entries: &[wgpu::BindGroupEntry]) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(label),
+            layout: &self.bind_group_layout,
+            entries,
+        })
+    }
+
+    // The blend constant is only used by passes that blend with BlendFactor::Constant
+    pub fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        bind_group: &wgpu::BindGroup,
+        load: wgpu::LoadOp<wgpu::Color>,
+        blend_constant: Option<wgpu::Color>,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        if let Some(constant) = blend_constant {
+            pass.set_blend_constant(constant);
+        }
+        pass.draw(0..3, 0..1);
+    }
+}
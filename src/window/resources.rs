@@ -1,83 +1,240 @@
 use std::{io::{BufReader, Cursor}, path::Path};
 
+use anyhow::Context;
 use wgpu::util::DeviceExt;
 
-use super::{texture, model::{self, MaterialUniform}};
+use super::{asset_manager::{self, AssetManager, Handle, TextureAsset}, assets, bcn, culling, ktx2, simplify, texture, model::{self, MaterialUniform}};
+
+// Every generated level of detail aims for this fraction of the triangles of the one before
+const LOD_REDUCTION: f32 = 0.5;
+
+// What materials without a diffuse or normal map get
+const DEFAULT_TEXTURE: &str = "assets/default_texture.png";
+const DEFAULT_NORMAL: &str = "assets/default_normal.png";
 
 pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
-    let txt = std::fs::read_to_string(path).unwrap();
-    
-    Ok(txt)
+    let data = load_binary(file_name).await?;
+    String::from_utf8(data).with_context(|| format!("{} isn't UTF-8", file_name))
 }
 
 pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
-    let data = std::fs::read(path)?;
+    assets::with_root(|root| root.read(file_name))
+}
 
-    Ok(data)
+// Every resource with the extension, as the name the loaders take. Hand made levels of
+// detail are left out, they come with their model.
+pub fn list_files(extension: &str) -> Vec<String> {
+    assets::with_root(|root| root.files())
+        .into_iter()
+        .filter(|name| Path::new(name).extension().is_some_and(|e| e.eq_ignore_ascii_case(extension)))
+        .filter(|name| !name.contains("_lod"))
+        .collect()
 }
 
 pub async fn load_texture(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+    is_normal_map: bool,
 ) -> anyhow::Result<texture::Texture> {
     let data = load_binary(file_name).await?;
-    texture::Texture::from_bytes(device, queue, &data, file_name)
+    texture::Texture::from_bytes(device, queue, &data, file_name, is_normal_map)
+}
+
+pub async fn load_lut(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<texture::Texture> {
+    let data = load_binary(file_name).await?;
+    texture::Texture::from_lut_strip(device, queue, &data, file_name)
+}
+
+// Radiance .hdr image with the full float range, the generic image loading clamps it to 8 bits
+pub async fn load_hdr(file_name: &str) -> anyhow::Result<image::Rgba32FImage> {
+    let data = load_binary(file_name).await?;
+    let decoder = image::codecs::hdr::HdrDecoder::new(Cursor::new(data))?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?;
+    let rgba = pixels.iter().flat_map(|p| [p[0], p[1], p[2], 1.0]).collect();
+    image::Rgba32FImage::from_raw(metadata.width, metadata.height, rgba)
+        .ok_or_else(|| anyhow::anyhow!("{} has the wrong amount of pixels", file_name))
 }
 
+// Textures with only (nearly) fully transparent cut outs get alpha tested, anything with
+// more partial transparency than that gets blended
+fn texture_alpha_mode(img: &image::DynamicImage) -> model::AlphaMode {
+    if !img.color().has_alpha() {
+        return model::AlphaMode::Opaque;
+    }
+    let (mut transparent, mut partial) = (0usize, 0usize);
+    for pixel in img.to_rgba8().pixels() {
+        match pixel[3] {
+            255 => {}
+            0..=12 => transparent += 1,
+            _ => partial += 1,
+        }
+    }
+    if transparent + partial == 0 {
+        model::AlphaMode::Opaque
+    } else if partial > transparent {
+        model::AlphaMode::Blend
+    } else {
+        model::AlphaMode::Mask
+    }
+}
+
+// tobj hands over map statements with their options still in front of the file name,
+// like "-clamp on -bm 0.5 brick.png". Only -clamp is used, the rest is skipped.
+fn parse_texture_map(map: &str) -> (String, wgpu::AddressMode) {
+    let mut address_mode = wgpu::AddressMode::Repeat;
+    let mut tokens = map.split_whitespace().peekable();
+    while let Some(option) = tokens.peek().filter(|t| t.starts_with('-')).copied() {
+        tokens.next();
+        match option {
+            "-clamp" => {
+                if tokens.next() == Some("on") {
+                    address_mode = wgpu::AddressMode::ClampToEdge;
+                }
+            }
+            // Up to three numbers each
+            "-o" | "-s" | "-t" => {
+                for _ in 0..3 {
+                    if tokens.next_if(|t| t.parse::<f32>().is_ok()).is_none() {
+                        break;
+                    }
+                }
+            }
+            "-mm" => {
+                tokens.next();
+                tokens.next();
+            }
+            _ => {
+                tokens.next();
+            }
+        }
+    }
+    (tokens.collect::<Vec<_>>().join(" "), address_mode)
+}
+
+// Same as texture_alpha_mode for KTX2 files. Decoding all of a big compressed texture
+// just for this isn't worth it, so a smaller mip is checked instead.
+fn ktx2_alpha_mode(ktx: &ktx2::Ktx2) -> model::AlphaMode {
+    use wgpu::TextureFormat::*;
+    if !matches!(ktx.format,
+        Rgba8Unorm | Rgba8UnormSrgb | Bc1RgbaUnorm | Bc1RgbaUnormSrgb | Bc2RgbaUnorm | Bc2RgbaUnormSrgb
+        | Bc3RgbaUnorm | Bc3RgbaUnormSrgb | Bc7RgbaUnorm | Bc7RgbaUnormSrgb)
+    {
+        return model::AlphaMode::Opaque;
+    }
+    let level = (0..ktx.levels.len())
+        .find(|level| ktx.level_size(*level).0 <= 256)
+        .unwrap_or(ktx.levels.len() - 1);
+    let (width, height) = ktx.level_size(level);
+    let rgba = if ktx.is_compressed() {
+        match bcn::decode(ktx.format, &ktx.levels[level], width, height) {
+            Ok(rgba) => rgba,
+            Err(_) => return model::AlphaMode::Opaque,
+        }
+    } else {
+        ktx.levels[level].clone()
+    };
+    match image::RgbaImage::from_raw(width, height, rgba) {
+        Some(img) => texture_alpha_mode(&image::DynamicImage::ImageRgba8(img)),
+        None => model::AlphaMode::Opaque,
+    }
+}
+
+// KTX2 or anything the image crate reads, with the alpha mode its alpha channel asks for
+pub async fn load_diffuse_texture(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<(texture::Texture, model::AlphaMode)> {
+    let data = load_binary(file_name).await?;
+    if ktx2::is_ktx2(&data) {
+        let ktx = ktx2::Ktx2::parse(&data, file_name)?;
+        Ok((texture::Texture::from_ktx2(device, queue, &ktx, file_name)?, ktx2_alpha_mode(&ktx)))
+    } else {
+        let img = image::load_from_memory(&data)?;
+        Ok((texture::Texture::from_image(device, queue, &img, Some(file_name), false)?, texture_alpha_mode(&img)))
+    }
+}
+
+// The texture a material uses, loaded once however many materials use it
+pub async fn load_material_texture(
+    file_name: &str,
+    is_normal_map: bool,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    assets: &mut AssetManager,
+) -> anyhow::Result<Handle<TextureAsset>> {
+    let key = asset_manager::texture_key(file_name, is_normal_map);
+    if let Some(loaded) = assets.textures.get(&key) {
+        return Ok(loaded);
+    }
+    let loaded = load_texture_asset(file_name, is_normal_map, device, queue).await?;
+    let bytes = loaded.texture.bytes;
+    Ok(assets.textures.insert(&key, loaded, bytes))
+}
+
+// Without looking in the cache, for loading a texture again
+pub async fn load_texture_asset(
+    file_name: &str,
+    is_normal_map: bool,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> anyhow::Result<TextureAsset> {
+    let (texture, alpha_mode) = if is_normal_map {
+        (load_texture(file_name, device, queue, true).await?, model::AlphaMode::Opaque)
+    } else {
+        load_diffuse_texture(file_name, device, queue).await?
+    };
+    Ok(TextureAsset { texture, alpha_mode })
+}
+
+// The meshes come from the cache when another model got loaded from the file already
 pub async fn load_model(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
+    sampler: &texture::SamplerSettings,
+    assets: &mut AssetManager,
 ) -> anyhow::Result<model::Model> {
-    let path = Path::new(file_name).parent().unwrap().to_str().unwrap();
-    let obj_text = load_string(file_name).await?;
-    let obj_cursor = Cursor::new(obj_text);
-    let mut obj_reader = BufReader::new(obj_cursor);
-
-    let (models, obj_materials) = tobj::load_obj_buf_async(
-        &mut obj_reader,
-        &tobj::LoadOptions {
-            triangulate: true,
-            single_index: true,
-            ..Default::default()
-        },
-        |p| async move {
-            let mat_text = load_string(&format!("{}/{}", path, &p)).await.unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
-        },
-    )
-    .await?;
+    let (models, obj_materials) = assets::with_root(|root| read_obj(root, file_name))?;
     let mut materials = Vec::new();
-    for mat in obj_materials? {
-        let diffuse_path = mat.diffuse_texture;
-        let diffuse_texture_w = if diffuse_path != "" {
-            Some(load_texture(&format!("{}/{}", path, &diffuse_path), device, queue).await?)
-        } else {
-            None
+    for mat in obj_materials {
+        let (diffuse_path, address_mode) = parse_texture_map(&mat.diffuse_texture);
+        let sampler = texture::SamplerSettings { address_mode, ..*sampler };
+        let diffuse_file = (!diffuse_path.is_empty()).then(|| sibling_name(file_name, &diffuse_path));
+        let (normal_path, _) = parse_texture_map(&mat.normal_texture);
+        let normal_file = (!normal_path.is_empty()).then(|| sibling_name(file_name, &normal_path));
+        // Flat (0.5, 0.5, 1.0) normal map where there's none, leaves the vertex normal as is
+        let diffuse_texture = load_material_texture(diffuse_file.as_deref().unwrap_or(DEFAULT_TEXTURE), false, device, queue, assets).await?;
+        let normal_texture = load_material_texture(normal_file.as_deref().unwrap_or(DEFAULT_NORMAL), true, device, queue, assets).await?;
+
+        // Tr is the inverse of d, some exporters only write that one
+        let dissolve = match mat.unknown_param.get("Tr").and_then(|tr| tr.parse::<f32>().ok()) {
+            Some(transparency) if mat.dissolve >= 1.0 => 1.0 - transparency,
+            _ => mat.dissolve,
         };
-        let normal_path = mat.normal_texture;
-        let normal_texture_w = if normal_path != "" {
-            Some(load_texture(&format!("{}/{}", path, &normal_path), device, queue).await?)
+        let alpha_mode = if dissolve < 1.0 {
+            model::AlphaMode::Blend
         } else {
-            None
+            diffuse_texture.alpha_mode
         };
 
         let material_uniform = MaterialUniform {
-            use_texture: if diffuse_texture_w.is_some() { 1 } else { 0 },
-            _p1: [0.0; 3],
-            ambient_color: mat.ambient.into(),
+            use_texture: if diffuse_file.is_some() { 1 } else { 0 },
+            dissolve,
+            alpha_mode: alpha_mode as u32,
+            alpha_cutoff: model::ALPHA_CUTOFF,
+            ambient_color: mat.ambient,
             _p2: 0,
-            diffuse_color: mat.diffuse.into(),
+            diffuse_color: mat.diffuse,
             _p3: 0,
-            specular_color: mat.specular.into(),
+            specular_color: mat.specular,
             _p4: 0,
         };
 
@@ -85,102 +242,250 @@ pub async fn load_model(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Material Uniform Buffer"),
                 contents: bytemuck::cast_slice(&[material_uniform]),
-                usage: wgpu::BufferUsages::UNIFORM,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
         );
 
-        let diffuse_texture = if diffuse_texture_w.is_some() {
-            diffuse_texture_w.unwrap()
-        } else {
-            load_texture("assets/default_texture.png", device, queue).await?
-        };
-        let normal_texture = if normal_texture_w.is_some() {
-            normal_texture_w.unwrap()
-        } else {
-            load_texture("assets/default_texture.png", device, queue).await?
-        };
-
-        let diffuse_texture_bind_group: wgpu::BindingResource = wgpu::BindingResource::TextureView(&diffuse_texture.view);
-        let diffuse_sampler_bind_group: wgpu::BindingResource = wgpu::BindingResource::Sampler(&diffuse_texture.sampler);
-        let normal_texture_bind_group: wgpu::BindingResource = wgpu::BindingResource::TextureView(&normal_texture.view);
-        let normal_sampler_bind_group: wgpu::BindingResource = wgpu::BindingResource::Sampler(&normal_texture.sampler);
-        let mat_uniform_bind_group: wgpu::BindingResource = mat_uniform_buffer.as_entire_binding();
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: diffuse_texture_bind_group,
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: diffuse_sampler_bind_group,
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: normal_texture_bind_group,
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: normal_sampler_bind_group,
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: mat_uniform_bind_group,
-                }
-            ],
-            label: None,
-        });
-        let diffuse_color = mat.diffuse;
+        let material_sampler = sampler.create_sampler(device, Some(&mat.name));
+        let bind_group = model::Material::create_bind_group(device, layout, &diffuse_texture.texture, &normal_texture.texture, &material_sampler, &mat_uniform_buffer);
         materials.push(model::Material {
             name: mat.name,
             diffuse_texture,
             normal_texture,
-            diffuse_color,
+            alpha_mode,
+            uniform: material_uniform,
+            loaded_uniform: material_uniform,
+            sampler_settings: sampler,
+            sampler: material_sampler,
+            uniform_buffer: mat_uniform_buffer,
             bind_group,
         })
     }
 
+    let meshes = match assets.meshes.get(file_name) {
+        Some(meshes) => meshes,
+        None => {
+            let (meshes, bytes) = load_meshes(file_name, models, device).await?;
+            assets.meshes.insert(file_name, meshes, bytes)
+        }
+    };
+
+    let bounds = meshes
+        .iter()
+        .map(|mesh| mesh.bounds)
+        .reduce(|a, b| a.union(&b))
+        .unwrap_or_else(|| culling::Aabb::from_points([]));
+
+    Ok(model::Model { meshes, materials, bounds })
+}
+
+// The meshes and materials of an OBJ file, with the MTL files it names read next to it
+fn read_obj(root: &assets::AssetRoot, file_name: &str) -> anyhow::Result<(Vec<tobj::Model>, Vec<tobj::Material>)> {
+    let obj_text = root.read(file_name)?;
+    let (models, materials) = tobj::load_obj_buf(
+        &mut BufReader::new(Cursor::new(obj_text)),
+        &tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        },
+        |mtl| match root.read(&sibling_name(file_name, &mtl.to_string_lossy())) {
+            Ok(mtl_text) => tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mtl_text))),
+            Err(e) => {
+                log::error!("{:#}", e);
+                Err(tobj::LoadError::OpenFileFailed)
+            }
+        },
+    )
+    .with_context(|| format!("Couldn't parse {}", file_name))?;
+    let materials = materials.with_context(|| format!("Couldn't load the materials of {}", file_name))?;
+    Ok((models, materials))
+}
+
+// What a file refers to by `name` is next to it, or at the root for the files in there
+fn sibling_name(file_name: &str, name: &str) -> String {
+    match Path::new(file_name).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory.join(name).to_string_lossy().into_owned(),
+        _ => name.to_string(),
+    }
+}
+
+// Every mesh of the model with its levels of detail, and what their buffers take up
+async fn load_meshes(file_name: &str, models: Vec<tobj::Model>, device: &wgpu::Device) -> anyhow::Result<(Vec<model::Mesh>, u64)> {
+    // Hand made levels in `<model>_lod1.obj` and so on replace the generated ones
+    let mut lod_files = Vec::new();
+    for level in 1..model::MAX_LODS {
+        let lod_file = lod_file_name(file_name, level);
+        lod_files.push(load_lod_file(&lod_file, models.len()).await?);
+    }
+
+    let mut bytes = 0;
     let meshes = models
         .into_iter()
-        .map(|m| {
-            let vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| model::ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                })
-                .collect::<Vec<_>>();
+        .enumerate()
+        .map(|(mesh_index, m)| {
+            let mut vertices = mesh_vertices(&m.mesh);
+            let bounds = culling::Aabb::from_points(vertices.iter().map(|v| v.position));
 
+            // Indices of every level, relative to where its vertices start
+            let mut lods = vec![(m.mesh.indices.clone(), 0)];
+            for lod_models in &lod_files {
+                let (previous, previous_base) = lods.last().unwrap();
+                if let Some(lod_models) = lod_models {
+                    let base_vertex = vertices.len();
+                    vertices.extend(mesh_vertices(&lod_models[mesh_index].mesh));
+                    lods.push((lod_models[mesh_index].mesh.indices.clone(), base_vertex));
+                    continue;
+                }
+                let positions = vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+                // What splits a position into seams, the tangents follow from these
+                let attributes = vertices
+                    .iter()
+                    .map(|v| (v.tex_coords.map(f32::to_bits), v.normal.map(f32::to_bits)))
+                    .collect::<Vec<_>>();
+                let previous = previous.iter().map(|i| i + *previous_base as u32).collect::<Vec<_>>();
+                let target = (previous.len() as f32 * LOD_REDUCTION) as usize / 3 * 3;
+                let simplified = simplify::simplify(&positions, &attributes, &previous, target);
+                // Not worth a level if it barely got any simpler
+                if simplified.is_empty() || simplified.len() as f32 > previous.len() as f32 * 0.9 {
+                    break;
+                }
+                lods.push((simplified, 0));
+            }
+
+            bytes += (vertices.len() * std::mem::size_of::<model::ModelVertex>()) as u64;
+            bytes += lods.iter().map(|(indices, _)| (indices.len() * std::mem::size_of::<u32>()) as u64).sum::<u64>();
             let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some(&format!("{:?} Vertex Buffer", file_name)),
                 contents: bytemuck::cast_slice(&vertices),
                 usage: wgpu::BufferUsages::VERTEX,
             });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
+            let lods = lods
+                .into_iter()
+                .enumerate()
+                .map(|(level, (indices, base_vertex))| model::MeshLod {
+                    index_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some(&format!("{:?} LOD {} Index Buffer", file_name, level)),
+                        contents: bytemuck::cast_slice(&indices),
+                        usage: wgpu::BufferUsages::INDEX,
+                    }),
+                    num_elements: indices.len() as u32,
+                    base_vertex: base_vertex as i32,
+                })
+                .collect();
 
             model::Mesh {
-                name: file_name.to_string(),
                 vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
+                lods,
                 material: m.mesh.material_id.unwrap_or(0),
+                bounds,
             }
         })
         .collect::<Vec<_>>();
+    Ok((meshes, bytes))
+}
+
+fn mesh_vertices(mesh: &tobj::Mesh) -> Vec<model::ModelVertex> {
+    let mut vertices = (0..mesh.positions.len() / 3)
+        .map(|i| model::ModelVertex {
+            position: [
+                mesh.positions[i * 3],
+                mesh.positions[i * 3 + 1],
+                mesh.positions[i * 3 + 2],
+            ],
+            tex_coords: if mesh.texcoords.is_empty() {
+                [0.0; 2]
+            } else {
+                [mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1]]
+            },
+            normal: if mesh.normals.is_empty() {
+                [0.0, 1.0, 0.0]
+            } else {
+                [
+                    mesh.normals[i * 3],
+                    mesh.normals[i * 3 + 1],
+                    mesh.normals[i * 3 + 2],
+                ]
+            },
+            // Filled in by compute_tangents below
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
+        })
+        .collect::<Vec<_>>();
+    model::compute_tangents(&mut vertices, &mesh.indices);
+    vertices
+}
+
+// `models/tree.obj` has its level 1 in `models/tree_lod1.obj`
+fn lod_file_name(file_name: &str, level: usize) -> String {
+    let path = Path::new(file_name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let lod_path = path.with_file_name(format!("{}_lod{}", stem, level));
+    match path.extension() {
+        Some(extension) => lod_path.with_extension(extension),
+        None => lod_path,
+    }
+    .to_string_lossy()
+    .into_owned()
+}
 
-    Ok(model::Model { meshes, materials })
+// The meshes of a level of detail file, in the same order as the full model's. Missing files
+// are fine, those levels get generated, but ones that can't be read or parsed are errors.
+// The materials of the file aren't used.
+async fn load_lod_file(file_name: &str, mesh_count: usize) -> anyhow::Result<Option<Vec<tobj::Model>>> {
+    let Some(data) = assets::with_root(|root| root.find(file_name)).transpose()? else {
+        return Ok(None);
+    };
+    let obj_text = String::from_utf8(data).with_context(|| format!("{} isn't UTF-8", file_name))?;
+    let (models, _) = tobj::load_obj_buf_async(
+        &mut BufReader::new(Cursor::new(obj_text)),
+        &tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        },
+        |_| async { Err(tobj::LoadError::OpenFileFailed) },
+    )
+    .await
+    .with_context(|| format!("Couldn't parse {}", file_name))?;
+    if models.len() != mesh_count {
+        log::warn!("{} has {} meshes instead of {}, generating that level instead", file_name, models.len(), mesh_count);
+        return Ok(None);
+    }
+    Ok(Some(models))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::assets::tests::TempDir;
+
+    #[test]
+    fn models_at_the_root_find_their_materials() {
+        let obj = "mtllib cube.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvn 0 0 1\nusemtl red\nf 1/1/1 2/2/1 3/3/1\n";
+        let mtl = "newmtl red\nKd 1 0 0\nmap_Kd red.png\n";
+        let res = TempDir::new("root_model", &[("cube.obj", obj), ("cube.mtl", mtl), ("models/cube.obj", obj), ("models/cube.mtl", mtl)]);
+        let mut root = assets::AssetRoot::default();
+        root.mount(assets::Directory::new(&res.0));
+        let at_root = read_obj(&root, "cube.obj");
+        let in_directory = read_obj(&root, "models/cube.obj");
+
+        for (models, materials) in [at_root.unwrap(), in_directory.unwrap()] {
+            assert_eq!(models.len(), 1);
+            assert_eq!(models[0].mesh.material_id, Some(0));
+            assert_eq!(materials[0].name, "red");
+            assert_eq!(materials[0].diffuse, [1.0, 0.0, 0.0]);
+        }
+        assert_eq!(sibling_name("cube.obj", "red.png"), "red.png");
+        assert_eq!(sibling_name("models/cube.obj", "red.png"), "models/red.png");
+        assert_eq!(sibling_name("", "red.png"), "red.png");
+    }
+
+    #[test]
+    fn lod_files_sit_next_to_their_model() {
+        assert_eq!(lod_file_name("models/tree.obj", 1), "models/tree_lod1.obj");
+        // Only the file's own extension changes, whatever its case
+        assert_eq!(lod_file_name("maps.obj/rock.OBJ", 2), "maps.obj/rock_lod2.OBJ");
+        assert_eq!(lod_file_name("cube.obj", 1), "cube_lod1.obj");
+    }
 }
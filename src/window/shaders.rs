@@ -0,0 +1,67 @@
+// WGSL sources. They're read through the asset root like any other resource, so a resource
+// directory can override them and they can be edited with the game running. The copies
+// baked into the binary come last.
+use std::borrow::Cow;
+
+use anyhow::Context;
+
+use super::assets;
+
+const BAKED: [(&str, &str); 16] = [
+    ("alpha_mask.wgsl", include_str!("../Shaders/alpha_mask.wgsl")),
+    ("bloom.wgsl", include_str!("../Shaders/bloom.wgsl")),
+    ("cascade.wgsl", include_str!("../Shaders/cascade.wgsl")),
+    ("depth_prepass.wgsl", include_str!("../Shaders/depth_prepass.wgsl")),
+    ("fullscreen.wgsl", include_str!("../Shaders/fullscreen.wgsl")),
+    ("gpu_culling.wgsl", include_str!("../Shaders/gpu_culling.wgsl")),
+    ("ibl.wgsl", include_str!("../Shaders/ibl.wgsl")),
+    ("light.wgsl", include_str!("../Shaders/light.wgsl")),
+    ("luminance.wgsl", include_str!("../Shaders/luminance.wgsl")),
+    ("post.wgsl", include_str!("../Shaders/post.wgsl")),
+    ("shader.wgsl", include_str!("../Shaders/shader.wgsl")),
+    ("shadow.wgsl", include_str!("../Shaders/shadow.wgsl")),
+    ("skybox.wgsl", include_str!("../Shaders/skybox.wgsl")),
+    ("spot_shadow.wgsl", include_str!("../Shaders/spot_shadow.wgsl")),
+    ("ssao.wgsl", include_str!("../Shaders/ssao.wgsl")),
+    ("tonemap.wgsl", include_str!("../Shaders/tonemap.wgsl")),
+];
+
+// Shader files are read from the asset root under this
+pub const PREFIX: &str = "shaders";
+
+// The shaders as they were when the game got built, mounted after everything else
+pub struct Baked;
+
+impl assets::Mount for Baked {
+    fn read(&self, name: &str) -> Option<anyhow::Result<Vec<u8>>> {
+        let file = name.strip_prefix(PREFIX)?.strip_prefix('/')?;
+        BAKED.iter().find(|(baked, _)| *baked == file).map(|(_, text)| Ok(text.as_bytes().to_vec()))
+    }
+
+    fn files(&self) -> Vec<String> {
+        BAKED.iter().map(|(file, _)| format!("{}/{}", PREFIX, file)).collect()
+    }
+
+    fn describe(&self) -> String {
+        "the built in shaders".into()
+    }
+}
+
+// Whatever the asset root has for the file, falling back on the built in copy when that
+// can't be read
+pub fn source(file: &str) -> Cow<'static, str> {
+    let name = format!("{}/{}", PREFIX, file);
+    let read = assets::with_root(|root| root.read(&name))
+        .and_then(|data| String::from_utf8(data).with_context(|| format!("{} isn't UTF-8", name)));
+    match read {
+        Ok(text) => text.into(),
+        Err(e) => {
+            log::error!("{:#}, using the built in copy", e);
+            BAKED
+                .iter()
+                .find(|(baked, _)| *baked == file)
+                .map(|(_, text)| Cow::Borrowed(*text))
+                .unwrap_or_else(|| panic!("No shader called {}", file))
+        }
+    }
+}
@@ -1,34 +1,138 @@
-use super::{light, instances::{self, Instance}, model};
+use super::{culling, light, instances, lod, model, render_pipeline::DepthPipelines};
 use std::{mem, num::NonZeroU32};
 
 pub struct Shadow {
-    bind_group: wgpu::BindGroup,
-    render_pipeline: wgpu::RenderPipeline,
-    uniform_buf: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipelines: DepthPipelines,
+    spot_bind_group_layout: wgpu::BindGroupLayout,
+    spot_pipelines: DepthPipelines,
+    shadow_sampler: wgpu::Sampler,
+    depth_sampler: wgpu::Sampler,
+    settings_buffer: wgpu::Buffer,
+    shadow_size: u32,
+    spot_atlas_size: u32,
+    spot_atlas_view: wgpu::TextureView,
+    // What every light can see, one range per light
+    instance_buffer: instances::InstanceBuffer,
 
     lights: Vec<light::Light>,
-    light_target_views: Vec<Option<wgpu::TextureView>>,
+    light_targets: Vec<LightTarget>,
+    spot_targets: Vec<SpotTarget>,
     pub shadow_view: wgpu::TextureView,
 
     pub ext_bind_group: wgpu::BindGroup,
     pub ext_bind_group_layout: wgpu::BindGroupLayout,
 }
 
+// Everything needed to render the six cube faces of a single point light
+struct LightTarget {
+    view: wgpu::TextureView,
+    uniform_buf: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+// Spot lights all render into the same atlas, they only need their own projection
+struct SpotTarget {
+    uniform_buf: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShadowFilter {
+    Hardware2x2 = 0, // Single bilinear comparison sample
+    PoissonPcf = 1,
+    Pcss = 2,        // Blocker search first, then PCF with a penumbra sized kernel
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ShadowSettings {
+    pub filter: ShadowFilter,
+    pub pcf_samples: u32,
+    pub pcf_radius: f32,      // In texels
+    pub blocker_samples: u32,
+    pub light_size: f32,      // In texels, how wide the penumbra grows with distance
+    pub resolution: u32,      // Size of every cube face
+    pub spot_atlas_resolution: u32,
+}
+
+pub const MAX_SHADOW_SAMPLES: u32 = 16;
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            filter: ShadowFilter::PoissonPcf,
+            pcf_samples: 16,
+            pcf_radius: 1.5,
+            blocker_samples: 16,
+            light_size: 8.0,
+            resolution: 2048,
+            spot_atlas_resolution: 4096,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowSettingsUniform {
+    filter_mode: u32,
+    pcf_samples: u32,
+    pcf_radius: f32,
+    blocker_samples: u32,
+    light_size: f32,
+    cube_resolution: f32,
+    atlas_resolution: f32,
+    _padding: u32,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 struct GlobalUniforms {
     projections: [[[f32; 4]; 4]; 6],
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct SpotUniforms {
+    projection: [[f32; 4]; 4],
+}
+
+// Index of every light among the lights of its own kind. Point lights use it as their
+// cube in the cube array, spot lights as their tile in the atlas.
+pub fn shadow_slots(lights: &[light::Light]) -> Vec<usize> {
+    let mut point_count = 0;
+    let mut spot_count = 0;
+    lights
+        .iter()
+        .map(|light| {
+            let counter = if light.is_spotlight { &mut spot_count } else { &mut point_count };
+            *counter += 1;
+            *counter - 1
+        })
+        .collect()
+}
+
+// The atlas is split into an even grid, big enough to fit every spot light
+fn spot_atlas_grid(spot_count: usize) -> usize {
+    (spot_count.max(1) as f32).sqrt().ceil() as usize
+}
+
+// Offset and size of a tile in uv space, as [x, y, width, height]
+pub fn spot_atlas_rect(slot: usize, spot_count: usize) -> [f32; 4] {
+    let grid = spot_atlas_grid(spot_count);
+    let size = 1.0 / grid as f32;
+    [(slot % grid) as f32 * size, (slot / grid) as f32 * size, size, size]
+}
+
 
 impl Shadow {
     pub fn new(
-        device: &wgpu::Device, 
+        device: &wgpu::Device,
         shader: &wgpu::ShaderModule,
-        lights: Vec<light::Light>,
+        spot_shader: &wgpu::ShaderModule,
+        lights: &[light::Light],
         vertex_layouts: &[wgpu::VertexBufferLayout],
-        shadow_width: u32,
-        shadow_height: u32,
+        material_layout: &wgpu::BindGroupLayout,
+        settings: &ShadowSettings,
     ) -> Self {
         let uniform_size = mem::size_of::<GlobalUniforms>() as wgpu::BufferAddress;
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -43,42 +147,64 @@ impl Shadow {
                 },
                 count: None,
             }],
-        });        
-
-        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: Some("Shadow Pipeline Layout"),
-            bind_group_layouts: &[
-                &bind_group_layout,
-            ],
-            push_constant_ranges: &[],
         });
 
-        let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: uniform_size,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let pipelines = DepthPipelines::new(
+            device,
+            "shadow",
+            &bind_group_layout,
+            material_layout,
+            shader,
+            "vs_bake",
+            vertex_layouts,
+            wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                // The cube face projections are mirrored, which turns the winding around
+                front_face: wgpu::FrontFace::Cw,
+                cull_mode: Some(wgpu::Face::Back),
+                unclipped_depth: device
+                    .features()
+                    .contains(wgpu::Features::DEPTH_CLIP_CONTROL),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                ..Default::default()
+            },
+            wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: 2, // corresponds to bilinear filtering
+                    slope_scale: 1.0,
+                    clamp: 0.0,
+                },
+            },
+            NonZeroU32::new(6),
+        );
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
+        let spot_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Internal Spot Shadow Bind Group"),
+            entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
-                resource: uniform_buf.as_entire_binding(),
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
             }],
-            label: None,
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("shadow"),
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: shader,
-                entry_point: "vs_bake",
-                buffers: vertex_layouts,
-            },
-            fragment: None,
-            primitive: wgpu::PrimitiveState {
+        let spot_pipelines = DepthPipelines::new(
+            device,
+            "spot shadow",
+            &spot_bind_group_layout,
+            material_layout,
+            spot_shader,
+            "vs_bake",
+            vertex_layouts,
+            wgpu::PrimitiveState {
                 topology: wgpu::PrimitiveTopology::TriangleList,
                 front_face: wgpu::FrontFace::Ccw,
                 cull_mode: Some(wgpu::Face::Back),
@@ -88,20 +214,19 @@ impl Shadow {
                 polygon_mode: wgpu::PolygonMode::Fill,
                 ..Default::default()
             },
-            depth_stencil: Some(wgpu::DepthStencilState {
+            wgpu::DepthStencilState {
                 format: wgpu::TextureFormat::Depth32Float,
                 depth_write_enabled: true,
                 depth_compare: wgpu::CompareFunction::LessEqual,
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState {
-                    constant: 2, // corresponds to bilinear filtering
+                    constant: 2,
                     slope_scale: 1.0,
                     clamp: 0.0,
                 },
-            }),
-            multisample: wgpu::MultisampleState::default(),
-            multiview: NonZeroU32::new(6),
-        });
+            },
+            None,
+        );
 
         let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("Shadow Sampler"),
@@ -115,27 +240,27 @@ impl Shadow {
             ..Default::default()
         });
 
-        let shadow_size = wgpu::Extent3d {
-            width: shadow_width,
-            height: shadow_height,
-            depth_or_array_layers: ((lights.len())*6) as u32,
-        };
-
-        let shadow_texture = device.create_texture(&wgpu::TextureDescriptor {
-            size: shadow_size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Depth32Float,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-            label: Some("Base Shadow Texture"),
-        });
-        let shadow_view = shadow_texture.create_view(&wgpu::TextureViewDescriptor {
-            label: Some("Shadow Texture View"),
-            dimension: Some(wgpu::TextureViewDimension::CubeArray),
+        // The blocker search needs the raw depth, which a comparison sampler can't give
+        let depth_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Depth Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
             ..Default::default()
         });
 
+        let settings_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Settings Buffer"),
+            size: mem::size_of::<ShadowSettingsUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let spot_atlas_view = Self::create_spot_atlas(device, settings.spot_atlas_resolution);
+
         let pub_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("External Shadow Bind Group Layout"),
             entries: &[
@@ -155,69 +280,257 @@ impl Shadow {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
             ],
         });
 
-        let pub_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("External Shadow Bind Group"),
-            layout: &pub_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&shadow_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&shadow_sampler),
-                },
-            ]
+        let point_count = lights.iter().filter(|light| !light.is_spotlight).count();
+        let (shadow_view, light_targets) = Self::create_light_targets(
+            device,
+            &bind_group_layout,
+            point_count,
+            settings.resolution,
+        );
+        let pub_bind_group = Self::create_ext_bind_group(
+            device,
+            &pub_bind_group_layout,
+            &shadow_view,
+            &spot_atlas_view,
+            &shadow_sampler,
+            &depth_sampler,
+            &settings_buffer,
+        );
+
+        let mut shadow = Self {
+            bind_group_layout,
+            pipelines,
+            spot_bind_group_layout,
+            spot_pipelines,
+            shadow_sampler,
+            depth_sampler,
+            settings_buffer,
+            shadow_size: settings.resolution,
+            spot_atlas_size: settings.spot_atlas_resolution,
+            spot_atlas_view,
+            instance_buffer: instances::InstanceBuffer::with_capacity(device, 1),
+            lights: Vec::new(),
+            light_targets,
+            spot_targets: Vec::new(),
+            shadow_view,
+            ext_bind_group: pub_bind_group,
+            ext_bind_group_layout: pub_bind_group_layout,
+        };
+        shadow.update_lights(device, lights);
+        shadow
+    }
+
+    // (Re)allocates the cube array with six layers per point light, plus the per light
+    // views and projection buffers the bake pass renders with.
+    fn create_light_targets(
+        device: &wgpu::Device,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        light_count: usize,
+        shadow_size: u32,
+    ) -> (wgpu::TextureView, Vec<LightTarget>) {
+        // A cube array needs at least one cube, even without any lights
+        let shadow_size = wgpu::Extent3d {
+            width: shadow_size,
+            height: shadow_size,
+            depth_or_array_layers: (light_count.max(1) * 6) as u32,
+        };
+
+        let shadow_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: shadow_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Depth32Float,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: Some("Base Shadow Texture"),
+        });
+        let shadow_view = shadow_texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Shadow Texture View"),
+            dimension: Some(wgpu::TextureViewDimension::CubeArray),
+            ..Default::default()
         });
 
-        let shadow_target_views = (0..lights.len())
+        let light_targets = (0..light_count)
             .map(|i| {
-                Some(shadow_texture.create_view(&wgpu::TextureViewDescriptor {
+                let view = shadow_texture.create_view(&wgpu::TextureViewDescriptor {
                     label: Some("Shadow Views"),
                     dimension: Some(wgpu::TextureViewDimension::D2Array),
                     aspect: wgpu::TextureAspect::DepthOnly,
                     base_mip_level: 0,
                     mip_level_count: None,
-                    base_array_layer: i as u32,
+                    base_array_layer: (i * 6) as u32,
                     array_layer_count: NonZeroU32::new(6),
                     format: None,
-                }))
+                });
+                // Every light gets its own buffer, writes to a shared one would all
+                // land before the encoder is submitted and only the last would stick.
+                let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Shadow Projection Buffer"),
+                    size: mem::size_of::<GlobalUniforms>() as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buf.as_entire_binding(),
+                    }],
+                    label: None,
+                });
+                LightTarget { view, uniform_buf, bind_group }
             })
             .collect::<Vec<_>>();
 
-        Self { 
-            bind_group: bind_group, 
-            render_pipeline: pipeline, 
-            uniform_buf: uniform_buf, 
-            lights: lights, 
-            light_target_views: shadow_target_views,
-            shadow_view,
-            ext_bind_group: pub_bind_group,
-            ext_bind_group_layout: pub_bind_group_layout,
-        }
+        (shadow_view, light_targets)
+    }
+
+    fn create_spot_atlas(device: &wgpu::Device, size: u32) -> wgpu::TextureView {
+        let spot_atlas_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Depth32Float,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label: Some("Spot Shadow Atlas"),
+        });
+        spot_atlas_texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    fn create_spot_target(device: &wgpu::Device, layout: &wgpu::BindGroupLayout) -> SpotTarget {
+        let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Spot Shadow Projection Buffer"),
+            size: mem::size_of::<SpotUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buf.as_entire_binding(),
+            }],
+            label: None,
+        });
+        SpotTarget { uniform_buf, bind_group }
     }
+
+    fn create_ext_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        shadow_view: &wgpu::TextureView,
+        spot_atlas_view: &wgpu::TextureView,
+        shadow_sampler: &wgpu::Sampler,
+        depth_sampler: &wgpu::Sampler,
+        settings_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("External Shadow Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(shadow_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(shadow_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(spot_atlas_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(depth_sampler),
+                },
+            ]
+        })
+    }
+
+    // Culls the instances against every light first, point lights keep the ones any of
+    // their six faces can see
     pub fn render(
-        &mut self, 
+        &mut self,
         encoder: &mut wgpu::CommandEncoder,
-        instance_buf: &instances::InstanceBuffer,
-        instances: &Vec<Instance>,
-        model: &model::Model,
+        device: &wgpu::Device,
         queue: &wgpu::Queue,
-    ) -> bool {
+        scene: &[model::ModelInstances],
+    ) -> culling::CullStats {
+        let mut stats = culling::CullStats::default();
+        let mut raws = Vec::new();
+        let mut ranges = Vec::with_capacity(self.lights.len());
+        let point_lights = self.lights.iter().filter(|light| !light.is_spotlight);
+        let spot_lights = self.lights.iter().filter(|light| light.is_spotlight);
+        for light in point_lights.take(self.light_targets.len()).chain(spot_lights.take(self.spot_targets.len())) {
+            let frusta = if light.is_spotlight {
+                vec![culling::Frustum::from_matrix(light.spot_view_projection())]
+            } else {
+                light.calculate_view_projections().iter().map(|m| culling::Frustum::from_matrix((*m).into())).collect()
+            };
+            ranges.push(
+                scene
+                    .iter()
+                    .map(|instances| {
+                        let mut visible = culling::visible_instances(&frusta, &instances.model.bounds, instances.transforms);
+                        stats.add(visible.len(), instances.transforms.len());
+                        lod::append_by_level(&mut raws, &mut visible, instances.transforms, instances.lods)
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+        self.instance_buffer.write(device, queue, &raws);
+        let mut ranges = ranges.into_iter();
+
+        use model::DrawDepth;
         encoder.push_debug_group("shadow passes");
-        for (i, light) in self.lights.iter().enumerate() {
-            let light_target_view = &self.light_target_views[i].as_ref().unwrap();
+        let point_lights = self.lights.iter().filter(|light| !light.is_spotlight);
+        for (light, target) in point_lights.zip(&self.light_targets) {
+            let ranges = ranges.next().unwrap_or_default();
             encoder.push_debug_group(&format!(
                 "shadow pass {} (light at position {:?})",
-                i, light.position
+                light.id, light.position
             ));
 
-            // The light uniform buffer already has the projection,
-            // let's just copy it over to the shadow uniform buffer.
-            queue.write_buffer(&self.uniform_buf, 0, bytemuck::cast_slice(&[GlobalUniforms {
+            queue.write_buffer(&target.uniform_buf, 0, bytemuck::cast_slice(&[GlobalUniforms {
                 projections: light.calculate_view_projections(),
             }]));
 
@@ -227,7 +540,7 @@ impl Shadow {
                     label: None,
                     color_attachments: &[],
                     depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                        view: light_target_view,
+                        view: &target.view,
                         depth_ops: Some(wgpu::Operations {
                             load: wgpu::LoadOp::Clear(1.0),
                             store: true,
@@ -236,23 +549,124 @@ impl Shadow {
                     }),
                 });
 
-                pass.set_pipeline(&self.render_pipeline);
-                pass.set_vertex_buffer(1, instance_buf.buffer.slice(..));
-                pass.set_bind_group(0, &self.bind_group, &[]);
-
-                for mesh in &model.meshes {
-                    pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-                    pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-                    pass.draw_indexed(0..mesh.num_elements, 0, 0..instances.len() as u32)
+                pass.set_bind_group(0, &target.bind_group, &[]);
+                // Blended surfaces still cast a shadow, cut off like the alpha tested ones
+                for (instances, ranges) in scene.iter().zip(&ranges) {
+                    pass.draw_model_depth(instances.model, &lod::direct_batches(&self.instance_buffer.buffer, ranges), &self.pipelines, true);
                 }
             }
 
             encoder.pop_debug_group();
         }
+
+        encoder.push_debug_group("spot shadow atlas");
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Spot Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.spot_atlas_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            let spot_count = self.spot_targets.len();
+            let spot_lights = self.lights.iter().filter(|light| light.is_spotlight);
+            for (slot, (light, target)) in spot_lights.zip(&self.spot_targets).enumerate() {
+                let ranges = ranges.next().unwrap_or_default();
+                queue.write_buffer(&target.uniform_buf, 0, bytemuck::cast_slice(&[SpotUniforms {
+                    projection: light.spot_view_projection().into(),
+                }]));
+
+                let rect = spot_atlas_rect(slot, spot_count);
+                let atlas_size = self.spot_atlas_size as f32;
+                pass.set_viewport(
+                    rect[0] * atlas_size,
+                    rect[1] * atlas_size,
+                    rect[2] * atlas_size,
+                    rect[3] * atlas_size,
+                    0.0,
+                    1.0,
+                );
+                pass.set_bind_group(0, &target.bind_group, &[]);
+                for (instances, ranges) in scene.iter().zip(&ranges) {
+                    pass.draw_model_depth(instances.model, &lod::direct_batches(&self.instance_buffer.buffer, ranges), &self.spot_pipelines, true);
+                }
+            }
+        }
         encoder.pop_debug_group();
-        true
+
+        encoder.pop_debug_group();
+        stats
     }
-    pub fn _update_lights(&mut self, lights: Vec<light::Light>) {
-        self.lights = lights;
+
+    // Takes over the new light list, the cube array only gets reallocated when the
+    // amount of point lights changed, which recreates `ext_bind_group`
+    pub fn update_lights(&mut self, device: &wgpu::Device, lights: &[light::Light]) {
+        let point_count = lights.iter().filter(|light| !light.is_spotlight).count();
+        let spot_count = lights.len() - point_count;
+
+        if point_count != self.light_targets.len() {
+            self.reallocate_cubes(device, point_count);
+        }
+
+        // The atlas itself stays, spot lights just get a smaller tile when there's more of them
+        while self.spot_targets.len() < spot_count {
+            self.spot_targets.push(Self::create_spot_target(device, &self.spot_bind_group_layout));
+        }
+        self.spot_targets.truncate(spot_count);
+
+        self.lights = lights.to_vec();
     }
-}
\ No newline at end of file
+
+    fn reallocate_cubes(&mut self, device: &wgpu::Device, point_count: usize) {
+        let (shadow_view, light_targets) = Self::create_light_targets(
+            device,
+            &self.bind_group_layout,
+            point_count,
+            self.shadow_size,
+        );
+        self.shadow_view = shadow_view;
+        self.light_targets = light_targets;
+        self.ext_bind_group = Self::create_ext_bind_group(
+            device,
+            &self.ext_bind_group_layout,
+            &self.shadow_view,
+            &self.spot_atlas_view,
+            &self.shadow_sampler,
+            &self.depth_sampler,
+            &self.settings_buffer,
+        );
+    }
+
+    // Uploads the filter settings, and reallocates the shadow maps when the resolution changed
+    pub fn update_settings(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, settings: &ShadowSettings) {
+        if settings.spot_atlas_resolution != self.spot_atlas_size {
+            self.spot_atlas_size = settings.spot_atlas_resolution;
+            self.spot_atlas_view = Self::create_spot_atlas(device, self.spot_atlas_size);
+            // The cube array is untouched, this is only to get a bind group with the new atlas
+            if settings.resolution == self.shadow_size {
+                self.reallocate_cubes(device, self.light_targets.len());
+            }
+        }
+        if settings.resolution != self.shadow_size {
+            self.shadow_size = settings.resolution;
+            self.reallocate_cubes(device, self.light_targets.len());
+        }
+
+        queue.write_buffer(&self.settings_buffer, 0, bytemuck::cast_slice(&[ShadowSettingsUniform {
+            filter_mode: settings.filter as u32,
+            pcf_samples: settings.pcf_samples.clamp(1, MAX_SHADOW_SAMPLES),
+            pcf_radius: settings.pcf_radius,
+            blocker_samples: settings.blocker_samples.clamp(1, MAX_SHADOW_SAMPLES),
+            light_size: settings.light_size,
+            cube_resolution: self.shadow_size as f32,
+            atlas_resolution: self.spot_atlas_size as f32,
+            _padding: 0,
+        }]));
+    }
+}
@@ -0,0 +1,338 @@
+// Quadric error mesh simplification for the generated levels of detail. Edges collapse onto
+// one of their own vertices, so every level keeps using the vertex buffer of the full mesh.
+// Vertices split for their uv's or normals collapse together, and only along the seam they
+// lie on. Vertices on open borders never move, that keeps outlines and texturing whole.
+use std::{collections::HashMap, hash::Hash};
+
+// Symmetric 4x4 matrix of summed squared plane distances, only the upper half is stored
+#[derive(Debug, Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(normal: [f64; 3], distance: f64, weight: f64) -> Self {
+        let [a, b, c] = normal;
+        let d = distance;
+        Self([a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d].map(|q| q * weight))
+    }
+
+    fn add(&self, other: &Quadric) -> Self {
+        let mut sum = *self;
+        for (q, o) in sum.0.iter_mut().zip(other.0) {
+            *q += o;
+        }
+        sum
+    }
+
+    fn error(&self, [x, y, z]: [f64; 3]) -> f64 {
+        let q = &self.0;
+        q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+            + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+            + q[7] * z * z + 2.0 * q[8] * z
+            + q[9]
+    }
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+// Keeps collapsing the cheapest edges until at most `target_index_count` indices are left,
+// or nothing can collapse anymore. Returns the new index list, the vertices are untouched.
+// Vertices with the same position and attributes count as one vertex, ones with the same
+// position but different attributes are the two sides of a seam.
+pub fn simplify<A: Eq + Hash>(positions: &[[f32; 3]], attributes: &[A], indices: &[u32], target_index_count: usize) -> Vec<u32> {
+    let position = |vertex: u32| positions[vertex as usize].map(|p| p as f64);
+    let key = |vertex: usize| positions[vertex].map(f32::to_bits);
+
+    // Exact copies get welded, so only real seams are left
+    let mut first_with_attributes = HashMap::new();
+    let welded = (0..positions.len())
+        .map(|vertex| *first_with_attributes.entry((key(vertex), &attributes[vertex])).or_insert(vertex as u32))
+        .collect::<Vec<_>>();
+    let mut indices = indices.iter().map(|i| welded[*i as usize]).collect::<Vec<_>>();
+
+    // Every vertex at a position collapses together, this is the first vertex at it
+    let mut first_at_position = HashMap::new();
+    let canonical = (0..positions.len())
+        .map(|vertex| *first_at_position.entry(key(vertex)).or_insert(vertex))
+        .collect::<Vec<_>>();
+
+    let mut quadrics = vec![Quadric::default(); positions.len()];
+    let mut edge_uses = HashMap::new();
+    for triangle in indices.chunks_exact(3) {
+        let [p0, p1, p2] = [0, 1, 2].map(|corner| position(triangle[corner]));
+        let normal = cross(sub(p1, p0), sub(p2, p0));
+        let length = dot(normal, normal).sqrt();
+        if length > f64::EPSILON {
+            let normal = normal.map(|n| n / length);
+            // Weighted by area, so slivers don't count as much as big faces
+            let quadric = Quadric::from_plane(normal, -dot(normal, p0), length * 0.5);
+            for corner in triangle {
+                let c = canonical[*corner as usize];
+                quadrics[c] = quadrics[c].add(&quadric);
+            }
+        }
+        for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+            let (a, b) = (canonical[triangle[a] as usize], canonical[triangle[b] as usize]);
+            *edge_uses.entry((a.min(b), a.max(b))).or_insert(0u32) += 1;
+        }
+    }
+
+    // Edges only one triangle uses are on an open border, seams are used from both sides
+    let mut locked = vec![false; positions.len()];
+    for ((a, b), uses) in edge_uses {
+        if uses == 1 {
+            locked[a] = true;
+            locked[b] = true;
+        }
+    }
+
+    // Every pass collapses the cheapest edges that don't share any triangles with each other
+    while indices.len() > target_index_count {
+        let mut triangles_around = vec![Vec::new(); positions.len()];
+        let mut copies = vec![Vec::new(); positions.len()];
+        let mut collapses = Vec::new();
+        for (triangle_index, triangle) in indices.chunks_exact(3).enumerate() {
+            for corner in 0..3 {
+                let (from, to) = (triangle[corner], triangle[(corner + 1) % 3]);
+                if triangles_around[from as usize].is_empty() {
+                    copies[canonical[from as usize]].push(from);
+                }
+                triangles_around[from as usize].push(triangle_index);
+                for (from, to) in [(from, to), (to, from)] {
+                    let (from, to) = (canonical[from as usize], canonical[to as usize]);
+                    if !locked[from] {
+                        let quadric = quadrics[from].add(&quadrics[to]);
+                        collapses.push((quadric.error(position(to as u32)), from, to));
+                    }
+                }
+            }
+        }
+        collapses.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let triangle = |triangle_index: usize| &indices[triangle_index * 3..triangle_index * 3 + 3];
+        let triangles_to_remove = (indices.len() - target_index_count).div_ceil(3);
+        let mut removed = 0;
+        let mut touched = vec![false; positions.len()];
+        let mut remap = (0..positions.len() as u32).collect::<Vec<_>>();
+        for (_, from, to) in collapses {
+            if removed >= triangles_to_remove {
+                break;
+            }
+            if touched[from] || touched[to] {
+                continue;
+            }
+            // Every copy moves onto the copy of `to` it shares a triangle with. A copy without
+            // one is on the other side of a seam that the edge crosses, so it can't collapse.
+            let moves = copies[from]
+                .iter()
+                .map(|copy| {
+                    triangles_around[*copy as usize]
+                        .iter()
+                        .flat_map(|triangle_index| triangle(*triangle_index))
+                        .find(|vertex| canonical[**vertex as usize] == to)
+                        .map(|target| (*copy, *target))
+                })
+                .collect::<Option<Vec<_>>>();
+            let Some(moves) = moves else { continue };
+
+            let flips = moves.iter().any(|(copy, target)| {
+                triangles_around[*copy as usize].iter().any(|triangle_index| {
+                    let triangle = triangle(*triangle_index);
+                    if triangle.contains(target) {
+                        return false;
+                    }
+                    let corners = triangle.iter().map(|v| position(*v)).collect::<Vec<_>>();
+                    let moved = triangle
+                        .iter()
+                        .map(|v| if v == copy { position(*target) } else { position(*v) })
+                        .collect::<Vec<_>>();
+                    let before = cross(sub(corners[1], corners[0]), sub(corners[2], corners[0]));
+                    let after = cross(sub(moved[1], moved[0]), sub(moved[2], moved[0]));
+                    dot(before, after) <= 0.0
+                })
+            });
+            if flips {
+                continue;
+            }
+
+            // Nothing these triangles touch moves again this pass, so the checks above stay valid
+            for (copy, target) in moves {
+                for triangle_index in &triangles_around[copy as usize] {
+                    let triangle = triangle(*triangle_index);
+                    for vertex in triangle {
+                        touched[canonical[*vertex as usize]] = true;
+                    }
+                    if triangle.contains(&target) {
+                        removed += 1;
+                    }
+                }
+                remap[copy as usize] = target;
+            }
+            quadrics[to] = quadrics[to].add(&quadrics[from]);
+        }
+        if removed == 0 {
+            break;
+        }
+
+        indices = indices
+            .chunks_exact(3)
+            .map(|triangle| [0, 1, 2].map(|corner| remap[triangle[corner] as usize]))
+            .filter(|[a, b, c]| a != b && b != c && c != a)
+            .flatten()
+            .collect();
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A grid of `n` by `n` quads over the unit square, at height zero
+    fn grid(n: u32) -> (Vec<[f32; 3]>, Vec<u32>) {
+        let positions = (0..=n)
+            .flat_map(|y| (0..=n).map(move |x| [x as f32 / n as f32, y as f32 / n as f32, 0.0]))
+            .collect();
+        let indices = (0..n)
+            .flat_map(|y| (0..n).map(move |x| y * (n + 1) + x))
+            .flat_map(|i| [i, i + 1, i + n + 2, i, i + n + 2, i + n + 1])
+            .collect();
+        (positions, indices)
+    }
+
+    // A cube out of six `n` by `n` grids, with their own vertices and the face as attribute, like
+    // an OBJ with hard normals would load
+    fn seamed_cube(n: u32) -> (Vec<[f32; 3]>, Vec<u32>, Vec<u32>) {
+        let (square, square_indices) = grid(n);
+        let (mut positions, mut attributes, mut indices) = (Vec::new(), Vec::new(), Vec::new());
+        for face in 0..6u32 {
+            let (axis, side) = ((face / 2) as usize, if face % 2 == 0 { -1.0 } else { 1.0 });
+            let base = positions.len() as u32;
+            for [u, v, _] in &square {
+                let mut position = [0.0; 3];
+                position[axis] = side;
+                position[(axis + 1) % 3] = u * 2.0 - 1.0;
+                position[(axis + 2) % 3] = v * 2.0 - 1.0;
+                positions.push(position);
+                attributes.push(face);
+            }
+            for triangle in square_indices.chunks_exact(3) {
+                // Outwards on both sides of the axis
+                let triangle = if side > 0.0 { [triangle[0], triangle[1], triangle[2]] } else { [triangle[0], triangle[2], triangle[1]] };
+                indices.extend(triangle.map(|i| base + i));
+            }
+        }
+        (positions, attributes, indices)
+    }
+
+    fn position_key(positions: &[[f32; 3]], vertex: u32) -> [u32; 3] {
+        positions[vertex as usize].map(f32::to_bits)
+    }
+
+    #[test]
+    fn seamed_closed_mesh_reaches_target() {
+        let (positions, attributes, indices) = seamed_cube(8);
+        // Further than it could get if every seam vertex was locked in place
+        let target = indices.len() / 5 / 3 * 3;
+        let simplified = simplify(&positions, &attributes, &indices, target);
+        assert!(simplified.len() <= target, "{} indices left, wanted {}", simplified.len(), target);
+        assert!(!simplified.is_empty());
+
+        // Still closed, every edge between positions has a triangle on both sides
+        let mut edge_uses = HashMap::new();
+        for triangle in simplified.chunks_exact(3) {
+            for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+                let (a, b) = (position_key(&positions, triangle[a]), position_key(&positions, triangle[b]));
+                *edge_uses.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+        assert!(edge_uses.values().all(|uses| *uses == 2));
+
+        // No triangle spans two faces, and the corners where three seams meet stay put
+        for triangle in simplified.chunks_exact(3) {
+            assert!(triangle.iter().all(|v| attributes[*v as usize] == attributes[triangle[0] as usize]));
+        }
+        let corners = simplified
+            .iter()
+            .filter(|v| positions[**v as usize].iter().all(|p| p.abs() == 1.0))
+            .map(|v| position_key(&positions, *v))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(corners.len(), 8);
+    }
+
+    #[test]
+    fn keeps_open_borders() {
+        let (positions, indices) = grid(6);
+        let attributes = vec![(); positions.len()];
+        let simplified = simplify(&positions, &attributes, &indices, 0);
+        assert!(simplified.len() < indices.len() / 2);
+        let on_border = |[x, y, _]: [f32; 3]| x == 0.0 || y == 0.0 || x == 1.0 || y == 1.0;
+        for (vertex, position) in positions.iter().enumerate() {
+            if on_border(*position) {
+                assert!(simplified.contains(&(vertex as u32)), "border vertex {} got removed", vertex);
+            }
+        }
+    }
+
+    #[test]
+    fn welds_exact_copies() {
+        // Every triangle with its own vertices, but nothing that differs between them
+        let (shared, shared_indices) = grid(6);
+        let positions = shared_indices.iter().map(|i| shared[*i as usize]).collect::<Vec<_>>();
+        let indices = (0..positions.len() as u32).collect::<Vec<_>>();
+        let attributes = vec![0u8; positions.len()];
+        let simplified = simplify(&positions, &attributes, &indices, 0);
+        let from_shared = simplify(&shared, &vec![0u8; shared.len()], &shared_indices, 0);
+        assert_eq!(simplified.len(), from_shared.len());
+    }
+
+    #[test]
+    fn seams_only_collapse_along_themselves() {
+        // Two halves of a grid with different uv's, split down the middle
+        let (mut positions, mut indices) = grid(8);
+        let mut attributes = positions.iter().map(|[x, _, _]| *x < 0.5).collect::<Vec<_>>();
+        // The right half gets its own copies of the vertices on the seam
+        let mut copies = HashMap::new();
+        for triangle in indices.chunks_exact_mut(3) {
+            if triangle.iter().all(|v| positions[*v as usize][0] >= 0.5) {
+                for vertex in triangle {
+                    if positions[*vertex as usize][0] == 0.5 {
+                        *vertex = *copies.entry(*vertex).or_insert_with(|| {
+                            positions.push(positions[*vertex as usize]);
+                            attributes.push(false);
+                            positions.len() as u32 - 1
+                        });
+                    }
+                }
+            }
+        }
+        for vertex in copies.keys() {
+            attributes[*vertex as usize] = true;
+        }
+
+        let simplified = simplify(&positions, &attributes, &indices, 0);
+        assert!(simplified.len() < indices.len() / 2);
+        // Both sides still meet at the seam, over the full length of it
+        for triangle in simplified.chunks_exact(3) {
+            let triangle: [u32; 3] = triangle.try_into().unwrap();
+            let side = attributes[triangle[0] as usize];
+            assert!(triangle.iter().all(|v| attributes[*v as usize] == side));
+            let x = triangle.map(|v| positions[v as usize][0]);
+            assert!(if side { x.iter().all(|x| *x <= 0.5) } else { x.iter().all(|x| *x >= 0.5) });
+        }
+        for (vertex, copy) in &copies {
+            if [0.0, 1.0].contains(&positions[*vertex as usize][1]) {
+                assert!(simplified.contains(vertex) && simplified.contains(copy));
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use super::{camera, model, post, render_pipeline::{self, DepthPipelines, FullscreenPass}, shaders, texture};
+use cgmath::SquareMatrix;
+use std::mem;
+
+pub const AO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+pub const MAX_SSAO_SAMPLES: u32 = 64;
+
+#[derive(Debug, Copy, Clone)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    pub radius: f32,    // In world units
+    pub intensity: f32, // Exponent on the occlusion, higher is darker
+    pub samples: u32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 0.5,
+            intensity: 1.5,
+            samples: 16,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoUniform {
+    view_proj: [[f32; 4]; 4],
+    proj: [[f32; 4]; 4],
+    inv_proj: [[f32; 4]; 4],
+    radius: f32,
+    intensity: f32,
+    sample_count: u32,
+    bias: f32,
+}
+
+// Ambient occlusion for the scene pass. It needs depth before the scene is drawn, so the
+// models get a depth prepass of their own first.
+pub struct Ssao {
+    enabled: bool,
+    buffer: wgpu::Buffer,
+    depth_pipelines: DepthPipelines,
+    depth_bind_group: wgpu::BindGroup,
+    ssao_pass: FullscreenPass,
+    blur_pass: FullscreenPass,
+    targets: Targets,
+}
+
+// Everything that depends on the size of the surface
+struct Targets {
+    depth_texture: texture::Texture,
+    ssao_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+    // Straight out of the ssao pass, still noisy
+    raw_view: wgpu::TextureView,
+    // What the scene pass multiplies the ambient term with
+    blurred_view: wgpu::TextureView,
+}
+
+impl Ssao {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        vertex_layouts: &[wgpu::VertexBufferLayout],
+        material_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSAO Buffer"),
+            size: mem::size_of::<SsaoUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_entry = |binding, visibility| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let depth_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Depth,
+            },
+            count: None,
+        };
+        let ao_entry = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+
+        let depth_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Depth Prepass Bind Group"),
+            entries: &[uniform_entry(0, wgpu::ShaderStages::VERTEX)],
+        });
+        let depth_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Depth Prepass Bind Group"),
+            layout: &depth_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry { binding: 0, resource: buffer.as_entire_binding() }],
+        });
+        let depth_shader = render_pipeline::create_depth_shader(device, "Depth Prepass Shader", &shaders::source("depth_prepass.wgsl"));
+        let depth_pipelines = DepthPipelines::new(
+            device,
+            "depth prepass",
+            &depth_bind_group_layout,
+            material_layout,
+            &depth_shader,
+            "vs_main",
+            vertex_layouts,
+            wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                ..Default::default()
+            },
+            wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            },
+            None,
+        );
+
+        let shader = render_pipeline::create_fullscreen_shader(device, "SSAO Shader", &shaders::source("ssao.wgsl"));
+        let ssao_pass = FullscreenPass::new(
+            device,
+            "SSAO",
+            &[depth_entry, uniform_entry(1, wgpu::ShaderStages::FRAGMENT)],
+            AO_FORMAT,
+            wgpu::BlendState::REPLACE,
+            &shader,
+            "fs_ssao",
+        );
+        let blur_pass = FullscreenPass::new(
+            device,
+            "SSAO Blur",
+            &[depth_entry, uniform_entry(1, wgpu::ShaderStages::FRAGMENT), ao_entry],
+            AO_FORMAT,
+            wgpu::BlendState::REPLACE,
+            &shader,
+            "fs_blur",
+        );
+
+        let targets = Targets::new(device, config, &ssao_pass, &blur_pass, &buffer);
+
+        Self {
+            enabled: true,
+            buffer,
+            depth_pipelines,
+            depth_bind_group,
+            ssao_pass,
+            blur_pass,
+            targets,
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        self.targets = Targets::new(device, config, &self.ssao_pass, &self.blur_pass, &self.buffer);
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &camera::Camera, settings: &SsaoSettings) {
+        self.enabled = settings.enabled;
+        let view = cgmath::Matrix4::look_at_rh(camera.eye, camera.target, camera.up);
+        let proj = camera::OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(camera.fovy), camera.aspect, camera.znear, camera.zfar);
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[SsaoUniform {
+            view_proj: (proj * view).into(),
+            proj: proj.into(),
+            inv_proj: proj.invert().unwrap_or_else(cgmath::Matrix4::identity).into(),
+            radius: settings.radius.max(0.001),
+            intensity: settings.intensity,
+            sample_count: settings.samples.clamp(1, MAX_SSAO_SAMPLES),
+            // Keeps flat surfaces from occluding themselves
+            bias: 0.025 * settings.radius,
+        }]));
+    }
+
+    // The occlusion the scene pass uses, white when it's turned off
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.targets.blurred_view
+    }
+
+    pub fn raw_view(&self) -> &wgpu::TextureView {
+        &self.targets.raw_view
+    }
+
+    // The depth the prepass leaves behind, also what occlusion culling tests against
+    pub fn depth_view(&self) -> &wgpu::TextureView {
+        &self.targets.depth_texture.view
+    }
+
+    // `depth_prepass` keeps the prepass running while the occlusion itself is turned off
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        scene: &[model::ModelBatches],
+        depth_prepass: bool,
+    ) {
+        use model::DrawDepth;
+        if self.enabled || depth_prepass {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Depth Prepass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.targets.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            pass.set_bind_group(0, &self.depth_bind_group, &[]);
+            // Blended surfaces would occlude what shows through them
+            for model_batches in scene {
+                pass.draw_model_depth(model_batches.model, &model_batches.batches, &self.depth_pipelines, false);
+            }
+        }
+
+        let white = wgpu::LoadOp::Clear(wgpu::Color::WHITE);
+        if !self.enabled {
+            for view in [&self.targets.raw_view, &self.targets.blurred_view] {
+                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("SSAO Clear"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view,
+                        resolve_target: None,
+                        ops: wgpu::Operations { load: white, store: true },
+                    })],
+                    depth_stencil_attachment: None,
+                });
+            }
+            return;
+        }
+
+        encoder.push_debug_group("ssao");
+        self.ssao_pass.draw(encoder, &self.targets.raw_view, &self.targets.ssao_bind_group, white, None);
+        self.blur_pass.draw(encoder, &self.targets.blurred_view, &self.targets.blur_bind_group, white, None);
+        encoder.pop_debug_group();
+    }
+}
+
+impl Targets {
+    fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        ssao_pass: &FullscreenPass,
+        blur_pass: &FullscreenPass,
+        buffer: &wgpu::Buffer,
+    ) -> Self {
+        let depth_texture = texture::Texture::create_depth_texture(device, config, 1, "ssao_depth_texture");
+        let raw_view = post::create_target(device, config.width, config.height, AO_FORMAT, "SSAO Target");
+        let blurred_view = post::create_target(device, config.width, config.height, AO_FORMAT, "SSAO Blur Target");
+
+        let ssao_bind_group = ssao_pass.create_bind_group(device, "SSAO Bind Group", &[
+            wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&depth_texture.view) },
+            wgpu::BindGroupEntry { binding: 1, resource: buffer.as_entire_binding() },
+        ]);
+        let blur_bind_group = blur_pass.create_bind_group(device, "SSAO Blur Bind Group", &[
+            wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&depth_texture.view) },
+            wgpu::BindGroupEntry { binding: 1, resource: buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&raw_view) },
+        ]);
+
+        Self {
+            depth_texture,
+            ssao_bind_group,
+            blur_bind_group,
+            raw_view,
+            blurred_view,
+        }
+    }
+}
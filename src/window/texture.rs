@@ -1,10 +1,57 @@
 use image::GenericImageView;
 use anyhow::*;
 
+use super::{bcn, ktx2};
+
+// Views keep their texture alive, so only the view is held on to
 pub struct Texture {
-    pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
-    pub sampler: wgpu::Sampler,
+    // What its levels take up on the GPU
+    pub bytes: u64,
+}
+
+// How a material texture gets sampled. Filtering and anisotropy are the same for every
+// material, the address mode comes from the MTL map options.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SamplerSettings {
+    pub filter: wgpu::FilterMode,
+    pub anisotropy: u8, // 1 turns it off, otherwise 2, 4, 8 or 16
+    pub address_mode: wgpu::AddressMode,
+}
+
+pub const ANISOTROPY_LEVELS: [u8; 5] = [1, 2, 4, 8, 16];
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            filter: wgpu::FilterMode::Linear,
+            anisotropy: 16,
+            address_mode: wgpu::AddressMode::Repeat,
+        }
+    }
+}
+
+impl SamplerSettings {
+    pub fn create_sampler(&self, device: &wgpu::Device, label: Option<&str>) -> wgpu::Sampler {
+        // Anisotropy only makes sense on top of linear filtering
+        let anisotropy = match self.filter {
+            wgpu::FilterMode::Linear if self.anisotropy > 1 => std::num::NonZeroU8::new(self.anisotropy),
+            _ => None,
+        };
+        device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                label,
+                address_mode_u: self.address_mode,
+                address_mode_v: self.address_mode,
+                address_mode_w: self.address_mode,
+                mag_filter: self.filter,
+                min_filter: self.filter,
+                mipmap_filter: self.filter,
+                anisotropy_clamp: anisotropy,
+                ..Default::default()
+            }
+        )
+    }
 }
 
 impl Texture {
@@ -12,17 +59,26 @@ impl Texture {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8], 
-        label: &str
+        label: &str,
+        is_normal_map: bool,
     ) -> Result<Self> {
+        if ktx2::is_ktx2(bytes) {
+            let ktx = ktx2::Ktx2::parse(bytes, label)?;
+            let ktx = if is_normal_map { ktx.into_linear() } else { ktx };
+            return Self::from_ktx2(device, queue, &ktx, label);
+        }
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        Self::from_image(device, queue, &img, Some(label), is_normal_map)
     }
 
+    // Uploads the image with its full mip chain. Normal maps hold vectors rather than
+    // colours, so they stay linear and their mips get renormalized.
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
-        label: Option<&str>
+        label: Option<&str>,
+        is_normal_map: bool,
     ) -> Result<Self> {
         let rgba = img.to_rgba8();
         let dimensions = img.dimensions();
@@ -32,14 +88,158 @@ impl Texture {
             height: dimensions.1,
             depth_or_array_layers: 1,
         };
+        let mips = generate_mips(rgba, is_normal_map);
         let texture = device.create_texture(
             &wgpu::TextureDescriptor {
                 label,
                 size,
-                mip_level_count: 1,
+                mip_level_count: mips.len() as u32,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: if is_normal_map {
+                    wgpu::TextureFormat::Rgba8Unorm
+                } else {
+                    wgpu::TextureFormat::Rgba8UnormSrgb
+                },
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            }
+        );
+
+        for (level, mip) in mips.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                mip,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * mip.width()),
+                    rows_per_image: std::num::NonZeroU32::new(mip.height()),
+                },
+                wgpu::Extent3d {
+                    width: mip.width(),
+                    height: mip.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bytes = mips.iter().map(|mip| mip.as_raw().len() as u64).sum();
+
+        Ok(Self { view, bytes })
+    }
+
+    // Block compressed levels go up as they are when the adapter can sample them, otherwise
+    // they're decoded on the CPU first. The mip levels are the ones from the file.
+    pub fn from_ktx2(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        ktx: &ktx2::Ktx2,
+        label: &str,
+    ) -> Result<Self> {
+        // Compressed textures have to be a whole number of blocks
+        let sample_compressed = device.features().contains(wgpu::Features::TEXTURE_COMPRESSION_BC)
+            && ktx.width.is_multiple_of(4)
+            && ktx.height.is_multiple_of(4);
+        let (format, levels) = if !ktx.is_compressed() || sample_compressed {
+            (ktx.format, ktx.levels.clone())
+        } else {
+            let mut levels = Vec::with_capacity(ktx.levels.len());
+            for (level, data) in ktx.levels.iter().enumerate() {
+                let (width, height) = ktx.level_size(level);
+                levels.push(bcn::decode(ktx.format, data, width, height).with_context(|| label.to_string())?);
+            }
+            (bcn::decoded_format(ktx.format), levels)
+        };
+
+        let size = wgpu::Extent3d {
+            width: ktx.width,
+            height: ktx.height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: levels.len() as u32,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            }
+        );
+
+        let info = format.describe();
+        for (level, data) in levels.iter().enumerate() {
+            // Small mips still take up a whole block
+            let level_size = size.mip_level_size(level as u32, false).physical_size(format);
+            let blocks_per_row = level_size.width / info.block_dimensions.0 as u32;
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(blocks_per_row * info.block_size as u32),
+                    rows_per_image: None,
+                },
+                level_size,
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bytes = levels.iter().map(|level| level.len() as u64).sum();
+
+        Ok(Self { view, bytes })
+    }
+
+    // Colour grading lookup table, stored as a horizontal strip of `size` slices that
+    // are each `size` by `size` texels. Blue picks the slice, red and green the texel.
+    pub fn from_lut_strip(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        label: &str
+    ) -> Result<Self> {
+        let img = image::load_from_memory(bytes)?;
+        let rgba = img.to_rgba8().into_raw();
+        let (width, height) = img.dimensions();
+        if width != height * height {
+            bail!("LUT strip {} is {}x{}, expected {}x{}", label, width, height, height * height, height);
+        }
+        let lut_size = height;
+
+        // Cut the strip into slices so it can be uploaded as a single 3D texture
+        let mut data = Vec::with_capacity(rgba.len());
+        for slice in 0..lut_size {
+            for y in 0..lut_size {
+                let start = ((y * width + slice * lut_size) * 4) as usize;
+                data.extend_from_slice(&rgba[start..start + (lut_size * 4) as usize]);
+            }
+        }
+
+        let size = wgpu::Extent3d {
+            width: lut_size,
+            height: lut_size,
+            depth_or_array_layers: lut_size,
+        };
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D3,
+                // The table is authored in display space, so it's sampled as is
+                format: wgpu::TextureFormat::Rgba8Unorm,
                 usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             }
         );
@@ -51,34 +251,22 @@ impl Texture {
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            &data,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
-                rows_per_image: std::num::NonZeroU32::new(dimensions.1),
+                bytes_per_row: std::num::NonZeroU32::new(4 * lut_size),
+                rows_per_image: std::num::NonZeroU32::new(lut_size),
             },
             size,
         );
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
-            }
-        );
-        
-        Ok(Self { texture, view, sampler })
+        Ok(Self { view, bytes: data.len() as u64 })
     }
 
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
-    pub fn create_depth_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, label: &str) -> Self {
+    pub fn create_depth_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, sample_count: u32, label: &str) -> Self {
         let size = wgpu::Extent3d {
             width: config.width,
             height: config.height,
@@ -88,7 +276,7 @@ impl Texture {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT
@@ -97,22 +285,117 @@ impl Texture {
         let texture = device.create_texture(&desc);
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor { // 4.
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                compare: Some(wgpu::CompareFunction::LessEqual), // 5.
-                lod_min_clamp: -100.0,
-                lod_max_clamp: 100.0,
-                ..Default::default()
+        let bytes = config.width as u64 * config.height as u64 * 4 * sample_count as u64;
+
+        Self { view, bytes }
+    }
+
+}
+
+// Halves the image with a 2x2 box filter until it's down to a single texel. Colours are
+// averaged in linear space, odd sizes repeat their last row or column.
+fn generate_mips(base: image::RgbaImage, is_normal_map: bool) -> Vec<image::RgbaImage> {
+    let to_linear: Vec<f32> = (0..=255u8)
+        .map(|v| {
+            let c = v as f32 / 255.0;
+            if is_normal_map {
+                c
+            } else if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
             }
-        );
+        })
+        .collect();
+    let from_linear = |c: f32| {
+        let c = if is_normal_map {
+            c
+        } else if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+
+    let mut mips = vec![base];
+    loop {
+        let previous = mips.last().unwrap();
+        let (width, height) = previous.dimensions();
+        if width == 1 && height == 1 {
+            break;
+        }
+        let (mip_width, mip_height) = ((width / 2).max(1), (height / 2).max(1));
+        let mip = image::RgbaImage::from_fn(mip_width, mip_height, |x, y| {
+            let mut sum = [0.0f32; 4];
+            for (sx, sy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let pixel = previous.get_pixel((x * 2 + sx).min(width - 1), (y * 2 + sy).min(height - 1));
+                for c in 0..3 {
+                    sum[c] += to_linear[pixel[c] as usize];
+                }
+                // Alpha is always linear
+                sum[3] += pixel[3] as f32 / 255.0;
+            }
+            let mut average = sum.map(|c| c / 4.0);
+            if is_normal_map {
+                // Averaged normals get shorter, stretch them back to unit length
+                let n = [average[0] * 2.0 - 1.0, average[1] * 2.0 - 1.0, average[2] * 2.0 - 1.0];
+                let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+                if length > 1e-4 {
+                    for c in 0..3 {
+                        average[c] = n[c] / length * 0.5 + 0.5;
+                    }
+                }
+            }
+            image::Rgba([
+                from_linear(average[0]),
+                from_linear(average[1]),
+                from_linear(average[2]),
+                (average[3].clamp(0.0, 1.0) * 255.0).round() as u8,
+            ])
+        });
+        mips.push(mip);
+    }
+    mips
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Self { texture, view, sampler }
+    fn sizes(mips: &[image::RgbaImage]) -> Vec<(u32, u32)> {
+        mips.iter().map(|mip| mip.dimensions()).collect()
     }
 
+    #[test]
+    fn averages_colours_in_linear_space() {
+        let base = image::RgbaImage::from_fn(2, 1, |x, _| if x == 0 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([255; 4]) });
+        let mips = generate_mips(base, false);
+        assert_eq!(sizes(&mips), [(2, 1), (1, 1)]);
+        // Half way in linear light is 188 in sRGB, not 128. Alpha is averaged as it is.
+        assert_eq!(mips[1].get_pixel(0, 0).0, [188, 188, 188, 128]);
+    }
+
+    #[test]
+    fn odd_and_non_square_sizes_go_down_to_one_texel() {
+        let colour = image::Rgba([10, 120, 240, 200]);
+        let mips = generate_mips(image::RgbaImage::from_pixel(5, 3, colour), false);
+        assert_eq!(sizes(&mips), [(5, 3), (2, 1), (1, 1)]);
+        // The repeated edge texels don't shift a flat colour
+        assert!(mips.iter().all(|mip| mip.pixels().all(|pixel| *pixel == colour)));
+
+        let mips = generate_mips(image::RgbaImage::from_pixel(1, 7, colour), false);
+        assert_eq!(sizes(&mips), [(1, 7), (1, 3), (1, 1)]);
+        assert_eq!(generate_mips(image::RgbaImage::from_pixel(1, 1, colour), false).len(), 1);
+    }
+
+    #[test]
+    fn renormalizes_normal_maps() {
+        // Leaning 45 degrees left and right, the average points straight out again
+        let base = image::RgbaImage::from_fn(2, 1, |x, _| if x == 0 { image::Rgba([218, 128, 218, 255]) } else { image::Rgba([37, 128, 218, 255]) });
+        let mips = generate_mips(base.clone(), true);
+        assert_eq!(mips[1].get_pixel(0, 0).0, [128, 128, 255, 255]);
+        // As colours they'd just get averaged, and come out too short
+        assert_ne!(generate_mips(base, false)[1].get_pixel(0, 0).0[2], 255);
+    }
 }
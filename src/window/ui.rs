@@ -5,6 +5,8 @@ use winit::{
     window::Window,
 };
 
+use super::{asset_manager, culling, environment, fog, hdr, light, lod, model, post, shadow, ssao, texture};
+
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -13,8 +15,16 @@ pub enum RenderTarget {
     DepthTexture = 1,
     ShadowTexture = 2,
     NoShadows = 3,
+    Cascades = 4,
+    AmbientOcclusion = 5,
 }
 
+// Changes to the lights made in the UI, applied by the State after drawing
+pub enum LightCommand {
+    Add,
+    Remove(usize),
+    Modify(light::Light),
+}
 
 pub struct UI {
     imgui: imgui::Context,
@@ -24,6 +34,22 @@ pub struct UI {
     last_cursor: Option<imgui::MouseCursor>,
     pub render_target: RenderTarget,
     render_target_int: u32,
+    pub shadow_settings: shadow::ShadowSettings,
+    pub hdr_settings: hdr::HdrSettings,
+    pub post_settings: post::PostSettings,
+    pub environment_settings: environment::EnvironmentSettings,
+    pub ssao_settings: ssao::SsaoSettings,
+    pub fog_settings: fog::FogSettings,
+    pub msaa_samples: u32,
+    pub msaa_sample_counts: Vec<u32>,
+    pub texture_filtering: texture::SamplerSettings,
+    pub cull_stats: [culling::CullStats; 2], // Camera, then all the shadow passes together
+    pub gpu_culling_supported: bool,
+    pub gpu_culling: bool,
+    pub occlusion_culling: bool,
+    pub lod_settings: lod::LodSettings,
+    // What failed to hot reload and why, until it reloads fine
+    pub reload_errors: Vec<(String, String)>,
 }
 
 impl UI {
@@ -53,7 +79,7 @@ impl UI {
             ..Default::default()
         };
     
-        let renderer = Renderer::new(&mut imgui, &device, &queue, renderer_config);
+        let renderer = Renderer::new(&mut imgui, device, queue, renderer_config);
     
         let last_frame = Instant::now();
     
@@ -67,20 +93,37 @@ impl UI {
             last_cursor,
             render_target: RenderTarget::NoShadows,
             render_target_int: 3,
+            shadow_settings: shadow::ShadowSettings::default(),
+            hdr_settings: hdr::HdrSettings::default(),
+            post_settings: post::PostSettings::default(),
+            environment_settings: environment::EnvironmentSettings::default(),
+            ssao_settings: ssao::SsaoSettings::default(),
+            fog_settings: fog::FogSettings::default(),
+            msaa_samples: 1,
+            msaa_sample_counts: vec![1],
+            texture_filtering: texture::SamplerSettings::default(),
+            cull_stats: Default::default(),
+            gpu_culling_supported: false,
+            gpu_culling: true,
+            occlusion_culling: true,
+            lod_settings: lod::LodSettings::default(),
+            reload_errors: Vec::new(),
         }
     }
-    pub fn draw(&mut self, window: &Window ,device: &wgpu::Device, queue: &wgpu::Queue, surface_view: &wgpu::TextureView) {
+    // `extra` gets to add its own windows, the editor's, to the frame
+    pub fn draw(&mut self, window: &Window ,device: &wgpu::Device, queue: &wgpu::Queue, surface_view: &wgpu::TextureView, lights: &[light::Light], extra: impl FnOnce(&imgui::Ui)) -> Vec<LightCommand> {
+        let mut light_commands = Vec::new();
         let delta_s = self.last_frame.elapsed();
         let now = Instant::now();
         self.imgui.io_mut().update_delta_time(now - self.last_frame);
         self.last_frame = now;
 
-        self.imgui_platform.prepare_frame(self.imgui.io_mut(), &window).expect("Failed to prepare frame");
+        self.imgui_platform.prepare_frame(self.imgui.io_mut(), window).expect("Failed to prepare frame");
         let ui = self.imgui.frame();
         {
             let window = imgui::Window::new("Information");
             window
-                .size([300.0, 200.0], imgui::Condition::FirstUseEver)
+                .size([300.0, 340.0], imgui::Condition::FirstUseEver)
                 .position([0.0; 2], imgui::Condition::FirstUseEver)
                 .build(&ui, || {
                     let mouse_pos = ui.io().mouse_pos;
@@ -88,21 +131,255 @@ impl UI {
                     ui.text(format!("Mouse Position: ({:.1},{:.1})", mouse_pos[0], mouse_pos[1]));
                     ui.text(format!("FPS: {:?}", fps));
                     ui.text(format!("Frametime: {:?}", delta_s));
+                    let [camera_stats, shadow_stats] = self.cull_stats;
+                    ui.text(format!("Instances drawn: {}, culled: {}", camera_stats.drawn, camera_stats.culled));
+                    ui.text(format!("Shadow instances drawn: {}, culled: {}", shadow_stats.drawn, shadow_stats.culled));
+                    if self.gpu_culling_supported {
+                        ui.checkbox("GPU culling", &mut self.gpu_culling);
+                        if self.gpu_culling {
+                            ui.checkbox("Occlusion culling", &mut self.occlusion_culling);
+                        }
+                    } else {
+                        self.gpu_culling = false;
+                    }
+                    let mut msaa = self.msaa_sample_counts.iter().position(|c| *c == self.msaa_samples).unwrap_or(0);
+                    if ui.combo("MSAA", &mut msaa, &self.msaa_sample_counts, |c| format!("{}x", c).into()) {
+                        self.msaa_samples = self.msaa_sample_counts[msaa];
+                    }
+                    let filtering = &mut self.texture_filtering;
+                    let mut linear = filtering.filter == wgpu::FilterMode::Linear;
+                    if ui.checkbox("Linear texture filtering", &mut linear) {
+                        filtering.filter = if linear { wgpu::FilterMode::Linear } else { wgpu::FilterMode::Nearest };
+                    }
+                    if linear {
+                        let mut anisotropy = texture::ANISOTROPY_LEVELS.iter().position(|a| *a == filtering.anisotropy).unwrap_or(0);
+                        if ui.combo("Anisotropy", &mut anisotropy, &texture::ANISOTROPY_LEVELS, |a| format!("{}x", a).into()) {
+                            filtering.anisotropy = texture::ANISOTROPY_LEVELS[anisotropy];
+                        }
+                    }
                     let mut clicked = false;
                     clicked |= ui.radio_button("Standard View", &mut self.render_target_int, 0);
                     clicked |= ui.radio_button("Depth Texture", &mut self.render_target_int, 1);
                     clicked |= ui.radio_button("Shadow Texture", &mut self.render_target_int, 2);
                     clicked |= ui.radio_button("No Shadows", &mut self.render_target_int, 3);
+                    clicked |= ui.radio_button("Shadow Cascades", &mut self.render_target_int, 4);
+                    clicked |= ui.radio_button("Ambient Occlusion", &mut self.render_target_int, 5);
                     if clicked {
                         match self.render_target_int {
                             0 => {self.render_target = RenderTarget::Default},
                             1 => {self.render_target = RenderTarget::DepthTexture},
                             2 => {self.render_target = RenderTarget::ShadowTexture},
                             3 => {self.render_target = RenderTarget::NoShadows},
+                            4 => {self.render_target = RenderTarget::Cascades},
+                            5 => {self.render_target = RenderTarget::AmbientOcclusion},
                             _ => {},
                         }
                     }
                 });
+            let window = imgui::Window::new("Lights");
+            window
+                .size([300.0, 400.0], imgui::Condition::FirstUseEver)
+                .position([0.0, 210.0], imgui::Condition::FirstUseEver)
+                .build(&ui, || {
+                    if ui.button("Add Light") {
+                        light_commands.push(LightCommand::Add);
+                    }
+                    for light in lights {
+                        let _id = ui.push_id(light.id as i32);
+                        if !ui.collapsing_header(format!("Light {}", light.id), imgui::TreeNodeFlags::empty()) {
+                            continue;
+                        }
+                        let mut edited = *light;
+                        let mut position: [f32; 3] = edited.position.into();
+                        let mut color: [f32; 3] = edited.color.into();
+                        let mut limitdir: [f32; 3] = edited.limitdir.into();
+                        let mut changed = false;
+                        changed |= imgui::Drag::new("Position").speed(0.05).build_array(&ui, &mut position);
+                        changed |= imgui::ColorEdit::new("Color", &mut color).build(&ui);
+                        changed |= imgui::Drag::new("Intensity").speed(0.01).range(0.0, 100.0).build(&ui, &mut edited.intensity);
+                        changed |= imgui::Drag::new("Radius").speed(0.01).range(0.0, 100.0).build(&ui, &mut edited.radius);
+                        changed |= ui.checkbox("Spotlight", &mut edited.is_spotlight);
+                        changed |= imgui::Drag::new("Normal offset").speed(0.05).range(0.0, 10.0).build(&ui, &mut edited.normal_offset);
+                        changed |= imgui::Drag::new("Slope bias").speed(0.05).range(0.0, 10.0).build(&ui, &mut edited.slope_bias);
+                        if edited.is_spotlight {
+                            changed |= imgui::Drag::new("Direction").speed(0.01).range(-1.0, 1.0).build_array(&ui, &mut limitdir);
+                            changed |= imgui::Slider::new("Inner cos", 0.0, 1.0).build(&ui, &mut edited.limitcos_inner);
+                            changed |= imgui::Slider::new("Outer cos", 0.0, 1.0).build(&ui, &mut edited.limitcos_outer);
+                        }
+                        if changed {
+                            edited.position = position.into();
+                            edited.color = color.into();
+                            edited.limitdir = limitdir.into();
+                            light_commands.push(LightCommand::Modify(edited));
+                        }
+                        if ui.button("Remove") {
+                            light_commands.push(LightCommand::Remove(light.id));
+                        }
+                    }
+                });
+            let window = imgui::Window::new("Shadows");
+            let settings = &mut self.shadow_settings;
+            window
+                .size([300.0, 220.0], imgui::Condition::FirstUseEver)
+                .position([310.0, 0.0], imgui::Condition::FirstUseEver)
+                .build(&ui, || {
+                    let mut filter = settings.filter as u32;
+                    ui.radio_button("Hardware 2x2", &mut filter, shadow::ShadowFilter::Hardware2x2 as u32);
+                    ui.radio_button("Poisson PCF", &mut filter, shadow::ShadowFilter::PoissonPcf as u32);
+                    ui.radio_button("PCSS", &mut filter, shadow::ShadowFilter::Pcss as u32);
+                    settings.filter = match filter {
+                        0 => shadow::ShadowFilter::Hardware2x2,
+                        1 => shadow::ShadowFilter::PoissonPcf,
+                        _ => shadow::ShadowFilter::Pcss,
+                    };
+                    imgui::Slider::new("PCF samples", 1, shadow::MAX_SHADOW_SAMPLES).build(&ui, &mut settings.pcf_samples);
+                    imgui::Slider::new("PCF radius", 0.0, 8.0).build(&ui, &mut settings.pcf_radius);
+                    if settings.filter == shadow::ShadowFilter::Pcss {
+                        imgui::Slider::new("Blocker samples", 1, shadow::MAX_SHADOW_SAMPLES).build(&ui, &mut settings.blocker_samples);
+                        imgui::Slider::new("Light size", 0.0, 32.0).build(&ui, &mut settings.light_size);
+                    }
+                    let resolutions = [512, 1024, 2048, 4096, 8192];
+                    let mut resolution = resolutions.iter().position(|r| *r == settings.resolution).unwrap_or(2);
+                    if ui.combo("Cube resolution", &mut resolution, &resolutions, |r| format!("{}", r).into()) {
+                        settings.resolution = resolutions[resolution];
+                    }
+                    let mut atlas_resolution = resolutions.iter().position(|r| *r == settings.spot_atlas_resolution).unwrap_or(3);
+                    if ui.combo("Spot atlas resolution", &mut atlas_resolution, &resolutions, |r| format!("{}", r).into()) {
+                        settings.spot_atlas_resolution = resolutions[atlas_resolution];
+                    }
+                });
+            let window = imgui::Window::new("HDR");
+            let settings = &mut self.hdr_settings;
+            window
+                .size([300.0, 220.0], imgui::Condition::FirstUseEver)
+                .position([310.0, 230.0], imgui::Condition::FirstUseEver)
+                .build(&ui, || {
+                    let mut tonemapper = settings.tonemapper as u32;
+                    ui.radio_button("ACES", &mut tonemapper, hdr::Tonemapper::Aces as u32);
+                    ui.radio_button("Reinhard", &mut tonemapper, hdr::Tonemapper::Reinhard as u32);
+                    ui.radio_button("AgX", &mut tonemapper, hdr::Tonemapper::AgX as u32);
+                    settings.tonemapper = match tonemapper {
+                        0 => hdr::Tonemapper::Aces,
+                        1 => hdr::Tonemapper::Reinhard,
+                        _ => hdr::Tonemapper::AgX,
+                    };
+                    ui.checkbox("Auto exposure", &mut settings.auto_exposure);
+                    if settings.auto_exposure {
+                        imgui::Slider::new("Min log luminance", -16.0, 0.0).build(&ui, &mut settings.min_log_luminance);
+                        imgui::Slider::new("Max log luminance", 0.0, 16.0).build(&ui, &mut settings.max_log_luminance);
+                        imgui::Slider::new("Adaptation speed", 0.1, 10.0).build(&ui, &mut settings.adaptation_speed);
+                    } else {
+                        imgui::Slider::new("Exposure (EV)", -10.0, 10.0).build(&ui, &mut settings.exposure);
+                    }
+                    imgui::Slider::new("Compensation (EV)", -5.0, 5.0).build(&ui, &mut settings.exposure_compensation);
+                    ui.separator();
+                    let environment = &mut self.environment_settings;
+                    ui.checkbox("Skybox", &mut environment.skybox);
+                    imgui::Slider::new("Skybox intensity", 0.0, 4.0).build(&ui, &mut environment.skybox_intensity);
+                    imgui::Slider::new("Ambient intensity", 0.0, 4.0).build(&ui, &mut environment.ambient_intensity);
+                });
+            let window = imgui::Window::new("Ambient Occlusion");
+            let settings = &mut self.ssao_settings;
+            window
+                .size([300.0, 130.0], imgui::Condition::FirstUseEver)
+                .position([620.0, 0.0], imgui::Condition::FirstUseEver)
+                .build(&ui, || {
+                    ui.checkbox("SSAO", &mut settings.enabled);
+                    imgui::Slider::new("Radius", 0.05, 2.0).build(&ui, &mut settings.radius);
+                    imgui::Slider::new("Intensity", 0.0, 4.0).build(&ui, &mut settings.intensity);
+                    imgui::Slider::new("Samples", 1, ssao::MAX_SSAO_SAMPLES).build(&ui, &mut settings.samples);
+                });
+            let window = imgui::Window::new("Fog");
+            let settings = &mut self.fog_settings;
+            window
+                .size([300.0, 240.0], imgui::Condition::FirstUseEver)
+                .position([620.0, 140.0], imgui::Condition::FirstUseEver)
+                .build(&ui, || {
+                    ui.checkbox("Distance fog", &mut settings.distance_fog);
+                    ui.checkbox("Height fog", &mut settings.height_fog);
+                    ui.checkbox("Colour from skybox", &mut settings.sky_color);
+                    if !settings.sky_color {
+                        imgui::ColorEdit::new("Colour", &mut settings.color).build(&ui);
+                    }
+                    imgui::Slider::new("Density", 0.0, 0.5).build(&ui, &mut settings.density);
+                    imgui::Slider::new("Start distance", 0.0, 100.0).build(&ui, &mut settings.start);
+                    if settings.height_fog {
+                        imgui::Slider::new("Height", -20.0, 20.0).build(&ui, &mut settings.height);
+                        imgui::Slider::new("Height falloff", 0.01, 4.0).build(&ui, &mut settings.height_falloff);
+                    }
+                });
+            let window = imgui::Window::new("Level of Detail");
+            let settings = &mut self.lod_settings;
+            window
+                .size([300.0, 150.0], imgui::Condition::FirstUseEver)
+                .position([620.0, 390.0], imgui::Condition::FirstUseEver)
+                .build(&ui, || {
+                    ui.checkbox("LOD", &mut settings.enabled);
+                    imgui::Slider::new("LOD 1 below", 0.0, 1.0).build(&ui, &mut settings.screen_sizes[0]);
+                    imgui::Slider::new("LOD 2 below", 0.0, 1.0).build(&ui, &mut settings.screen_sizes[1]);
+                    imgui::Slider::new("Hysteresis", 0.0, 0.5).build(&ui, &mut settings.hysteresis);
+                    imgui::Slider::new("Shadow bias", 0, model::MAX_LODS as u8 - 1).build(&ui, &mut settings.shadow_bias);
+                });
+            let window = imgui::Window::new("Post Processing");
+            let settings = &mut self.post_settings;
+            window
+                .size([300.0, 360.0], imgui::Condition::FirstUseEver)
+                .position([310.0, 460.0], imgui::Condition::FirstUseEver)
+                .build(&ui, || {
+                    ui.checkbox("Bloom", &mut settings.bloom);
+                    if settings.bloom {
+                        imgui::Slider::new("Bloom strength", 0.0, 0.5).build(&ui, &mut settings.bloom_strength);
+                        imgui::Slider::new("Bloom radius", 0.0, 0.02).build(&ui, &mut settings.bloom_filter_radius);
+                    }
+                    ui.separator();
+                    ui.text("After tonemapping, top to bottom:");
+                    let count = settings.effects.len();
+                    let mut swap = None;
+                    for i in 0..count {
+                        let _id = ui.push_id(i as i32);
+                        let (effect, enabled) = &mut settings.effects[i];
+                        ui.checkbox(effect.name(), enabled);
+                        ui.same_line_with_pos(200.0);
+                        if ui.arrow_button("up", imgui::Direction::Up) && i > 0 {
+                            swap = Some((i, i - 1));
+                        }
+                        ui.same_line();
+                        if ui.arrow_button("down", imgui::Direction::Down) && i + 1 < count {
+                            swap = Some((i, i + 1));
+                        }
+                    }
+                    if let Some((a, b)) = swap {
+                        settings.effects.swap(a, b);
+                    }
+                    ui.separator();
+                    if ui.collapsing_header("FXAA", imgui::TreeNodeFlags::empty()) {
+                        imgui::Slider::new("Edge threshold", 0.0, 0.5).build(&ui, &mut settings.fxaa_edge_threshold);
+                        imgui::Slider::new("Edge threshold min", 0.0, 0.1).build(&ui, &mut settings.fxaa_edge_threshold_min);
+                    }
+                    if ui.collapsing_header("Vignette", imgui::TreeNodeFlags::empty()) {
+                        imgui::Slider::new("Intensity", 0.0, 1.0).build(&ui, &mut settings.vignette_intensity);
+                        imgui::Slider::new("Radius", 0.0, 1.5).build(&ui, &mut settings.vignette_radius);
+                        imgui::Slider::new("Smoothness", 0.01, 1.0).build(&ui, &mut settings.vignette_smoothness);
+                    }
+                    if ui.collapsing_header("Colour grading", imgui::TreeNodeFlags::empty()) {
+                        ui.combo("LUT", &mut settings.lut, &post::LUT_FILES, |file| (*file).into());
+                        imgui::Slider::new("Strength", 0.0, 1.0).build(&ui, &mut settings.lut_strength);
+                    }
+                });
+            if !self.reload_errors.is_empty() {
+                let window = imgui::Window::new("Reload Errors");
+                window
+                    .size([500.0, 300.0], imgui::Condition::FirstUseEver)
+                    .position([310.0, 400.0], imgui::Condition::FirstUseEver)
+                    .build(&ui, || {
+                        for (name, error) in &self.reload_errors {
+                            ui.text_colored([1.0, 0.4, 0.4, 1.0], name);
+                            ui.text_wrapped(error);
+                            ui.separator();
+                        }
+                    });
+            }
+            extra(&ui);
         }
 
         let mut encoder: wgpu::CommandEncoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -111,7 +388,7 @@ impl UI {
 
         if self.last_cursor != ui.mouse_cursor() {
             self.last_cursor = ui.mouse_cursor();
-            self.imgui_platform.prepare_render(&ui, &window);
+            self.imgui_platform.prepare_render(&ui, window);
         }
 
         {
@@ -128,15 +405,74 @@ impl UI {
                 depth_stencil_attachment: None,
             });
             self.renderer
-                    .render(ui.render(), &queue, &device, &mut render_pass)
+                    .render(ui.render(), queue, device, &mut render_pass)
                     .expect("Rendering failed");
         }
 
         queue.submit(Some(encoder.finish()));
+        light_commands
+    }
+
+    // Records or clears the error from the last time `name` got reloaded
+    pub fn set_reload_error(&mut self, name: &str, error: Option<anyhow::Error>) {
+        self.reload_errors.retain(|(failed, _)| failed != name);
+        match error {
+            Some(error) => {
+                log::error!("Couldn't reload {}: {:#}", name, error);
+                self.reload_errors.push((name.to_string(), format!("{:#}", error)));
+            }
+            None => log::info!("Reloaded {}", name),
+        }
+    }
+
+    // Whether imgui used the mouse or keyboard last frame, the scene shouldn't get it then
+    pub fn wants_mouse(&self) -> bool {
+        self.imgui.io().want_capture_mouse
+    }
+
+    pub fn wants_keyboard(&self) -> bool {
+        self.imgui.io().want_capture_keyboard
     }
 
     pub fn handle_input<T>(&mut self, window: &Window, event: &Event<T>) -> bool{
         self.imgui_platform.handle_event(self.imgui.io_mut(), window, event);
-        return true;
+        true
     }
-}
\ No newline at end of file
+}
+
+// What the asset manager has loaded and the GPU memory that takes up. Drawn by the State,
+// which does the unloading when this returns true.
+pub fn draw_assets(ui: &imgui::Ui, assets: &asset_manager::AssetManager) -> bool {
+    let mut unload = false;
+    imgui::Window::new("Assets")
+        .size([360.0, 300.0], imgui::Condition::FirstUseEver)
+        .position([620.0, 600.0], imgui::Condition::FirstUseEver)
+        .collapsed(true, imgui::Condition::FirstUseEver)
+        .build(ui, || {
+            ui.text(format!("Textures and meshes: {}", format_bytes(assets.textures.bytes() + assets.meshes.bytes())));
+            unload = ui.button("Unload unused");
+            draw_cache(ui, "Textures", &assets.textures);
+            draw_cache(ui, "Meshes", &assets.meshes);
+            draw_cache(ui, "Shader modules", &assets.shaders);
+        });
+    unload
+}
+
+fn draw_cache<T>(ui: &imgui::Ui, name: &str, cache: &asset_manager::Cache<T>) {
+    let infos = cache.infos();
+    let unused = infos.iter().filter(|info| info.references == 0).count();
+    let header = format!("{}: {} loaded, {} unused, {}###{}", name, cache.len(), unused, format_bytes(cache.bytes()), name);
+    if ui.collapsing_header(header, imgui::TreeNodeFlags::empty()) {
+        for info in infos {
+            ui.text(format!("{} ({} users, {})", info.name, info.references, format_bytes(info.bytes)));
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
@@ -0,0 +1,152 @@
+use cgmath::InnerSpace;
+
+use super::scene;
+
+// Index into the models the renderer holds
+pub type ModelId = usize;
+
+// The entity's node in the world's scene graph, which holds the actual transform
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    pub node: scene::NodeId,
+}
+
+// Draws a model at the entity's transform
+#[derive(Debug, Copy, Clone)]
+pub struct MeshRenderer {
+    pub model: ModelId,
+}
+
+// With a Transform the light's position follows it, without one the light stays put. The
+// renderer packs these into its light buffer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Light {
+    pub id: usize,
+    pub position: cgmath::Vector3<f32>,
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+    pub radius: f32,
+    pub is_spotlight: bool,
+    pub limitcos_inner: f32,
+    pub limitcos_outer: f32,
+    pub limitdir: cgmath::Vector3<f32>,
+    pub normal_offset: f32, // Shadow lookup offset along the surface normal, in texels
+    pub slope_bias: f32,    // Shadow depth bias scaled by the surface slope, in texels
+}
+
+impl Light {
+    pub fn new(
+        id: usize,
+        position: cgmath::Vector3<f32>,
+        color: cgmath::Vector3<f32>,
+        intensity: f32,
+        radius: f32,
+    ) -> Self {
+        Self {
+            id,
+            position,
+            color,
+            intensity,
+            radius,
+            is_spotlight: false,
+            limitcos_inner: 0.9,
+            limitcos_outer: 1.0,
+            limitdir: (0.0, -1.0, 0.0).into(),
+            normal_offset: 1.0,
+            slope_bias: 1.0,
+        }
+    }
+}
+
+// The first one found is the one the scene gets drawn from
+#[derive(Debug, Copy, Clone)]
+pub struct Camera {
+    pub eye: cgmath::Point3<f32>,
+    pub target: cgmath::Point3<f32>,
+    pub rotation: cgmath::Vector2<f32>, // x for horizontal rotation, y for vertical rotation
+    pub up: cgmath::Vector3<f32>,
+    pub aspect: f32,
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl Camera {
+    pub fn new(pos: cgmath::Vector3<f32>, rotation: f32, aspect: f32) -> Self {
+        let mut target = pos;
+        let rotation = rotation.to_radians();
+        target.x -= rotation.sin();
+        target.z -= rotation.cos();
+        Self {
+            aspect,
+            fovy: 45.0,
+            zfar: 100.0,
+            znear: 0.1,
+            eye: cgmath::Point3::new(pos.x,pos.y,pos.z),
+            rotation: cgmath::Vector2::new(rotation, 0.0),
+            target: cgmath::Point3::new(target.x,target.y,target.z),
+            up: cgmath::Vector3::unit_y(),
+        }
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.aspect = width as f32 / height as f32;
+    }
+
+    pub fn update_target(&mut self) {
+        // (cos(pitch)cos(yaw), cos(pitch)sin(yaw), sin(pitch))
+        let pitch = self.rotation.x.to_radians();
+        let yaw = self.rotation.y.to_radians();
+        let target: cgmath::Point3<f32> = cgmath::point3(pitch.cos()*yaw.cos(), pitch.cos()*yaw.sin(), pitch.sin());
+        self.target = cgmath::point3(self.eye.x+target.x, self.eye.y+target.y, self.eye.z+target.z);
+    }
+}
+
+// What the player is holding down, the window fills it in from its input events
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PlayerInput {
+    pub forward: bool,
+    pub backwards: bool,
+    // Mouse movement since the camera last got updated
+    pub mouse_delta: Option<(f64, f64)>,
+}
+
+// Moves the entity's camera with the player's input
+#[derive(Debug, Copy, Clone)]
+pub struct PlayerController {
+    pub speed: f32,
+    pub mouse_speed: f32,
+    pub input: PlayerInput,
+}
+
+impl PlayerController {
+    pub fn new(speed: f32, mouse_speed: f32) -> Self {
+        Self { speed, mouse_speed, input: PlayerInput::default() }
+    }
+
+    pub fn update_camera(&mut self, camera: &mut Camera) {
+        if let Some(delta) = self.input.mouse_delta.take() {
+            let mut delta: cgmath::Vector2<f64> = delta.into();
+            delta.y *= -1.0;
+            delta *= self.mouse_speed as f64;
+            let delta: cgmath::Vector2<f32> = cgmath::Vector2 { x: delta.x as f32, y: delta.y as f32 };
+            camera.rotation.x += delta.x;
+            camera.rotation.y -= delta.y;
+            camera.rotation.y = camera.rotation.y.clamp(-80., 80.);
+            camera.rotation.x %= 720.;
+            camera.update_target();
+        }
+        
+        let forward = camera.eye - camera.target;
+        let forward_norm = forward.normalize();
+
+        if self.input.forward {
+            camera.eye -= forward_norm * self.speed;
+            camera.target -= forward_norm * self.speed;
+        }
+        if self.input.backwards {
+            camera.eye += forward_norm * self.speed;
+            camera.target += forward_norm * self.speed;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// How a map's sky, sun, fog and camera look. The renderer turns these into its environment
+// maps, passes and uniforms, in here they're only the settings.
+use cgmath::InnerSpace;
+use serde::{Deserialize, Serialize};
+
+pub const ENVIRONMENT_FILE: &str = "assets/sky.hdr";
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnvironmentSettings {
+    pub skybox: bool,
+    pub skybox_intensity: f32,
+    pub ambient_intensity: f32, // Scales both the diffuse and specular image based light
+}
+
+impl Default for EnvironmentSettings {
+    fn default() -> Self {
+        Self {
+            skybox: true,
+            skybox_intensity: 1.0,
+            ambient_intensity: 1.0,
+        }
+    }
+}
+
+// The sun, lights everything from a single direction and casts cascaded shadows
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DirectionalLight {
+    pub direction: cgmath::Vector3<f32>, // Pointing from the sun towards the scene
+    pub color: cgmath::Vector3<f32>,
+    pub intensity: f32,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: cgmath::Vector3<f32>, color: cgmath::Vector3<f32>, intensity: f32) -> Self {
+        Self {
+            direction: direction.normalize(),
+            color,
+            intensity,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FogSettings {
+    pub distance_fog: bool,
+    pub height_fog: bool,
+    pub color: [f32; 3],
+    pub sky_color: bool,     // Takes the colour from the skybox behind the surface instead
+    pub density: f32,        // Per world unit, for both kinds
+    pub start: f32,          // Distance from the camera where the fog begins
+    pub height: f32,         // World height where the height fog has the full density
+    pub height_falloff: f32, // How quickly the height fog thins out above that
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            distance_fog: false,
+            height_fog: false,
+            color: [0.6, 0.65, 0.7],
+            sky_color: true,
+            density: 0.02,
+            start: 5.0,
+            height: 0.0,
+            height_falloff: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tonemapper {
+    Aces = 0,
+    Reinhard = 1,
+    AgX = 2,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HdrSettings {
+    pub tonemapper: Tonemapper,
+    pub auto_exposure: bool,
+    pub exposure: f32,              // In EV, only used for manual exposure
+    pub exposure_compensation: f32, // In EV, applied on top of both modes
+    pub min_log_luminance: f32,     // Range of the luminance histogram, in log2 units
+    pub max_log_luminance: f32,
+    pub adaptation_speed: f32,      // How fast the eye adapts to a new average, per second
+}
+
+impl Default for HdrSettings {
+    fn default() -> Self {
+        Self {
+            tonemapper: Tonemapper::Aces,
+            auto_exposure: true,
+            exposure: 0.0,
+            exposure_compensation: 0.0,
+            min_log_luminance: -10.0,
+            max_log_luminance: 4.0,
+            adaptation_speed: 1.5,
+        }
+    }
+}
+
+// Effects that run after tonemapping, in whatever order they are listed in
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PostEffect {
+    Fxaa,
+    Vignette,
+    ColorGrading,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostSettings {
+    // Bloom works on the hdr image, so it always runs before tonemapping
+    pub bloom: bool,
+    pub bloom_strength: f32,
+    pub bloom_filter_radius: f32, // In uv units
+    pub effects: [(PostEffect, bool); 3],
+    pub fxaa_edge_threshold: f32,
+    pub fxaa_edge_threshold_min: f32,
+    pub vignette_intensity: f32,
+    pub vignette_radius: f32,
+    pub vignette_smoothness: f32,
+    pub lut: usize, // Index into the colour grading LUTs the renderer has
+    pub lut_strength: f32,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            bloom: true,
+            bloom_strength: 0.04,
+            bloom_filter_radius: 0.005,
+            effects: [
+                (PostEffect::ColorGrading, false),
+                (PostEffect::Vignette, true),
+                (PostEffect::Fxaa, true),
+            ],
+            fxaa_edge_threshold: 0.125,
+            fxaa_edge_threshold_min: 0.0312,
+            vignette_intensity: 0.4,
+            vignette_radius: 0.9,
+            vignette_smoothness: 0.5,
+            lut: 0,
+            lut_strength: 1.0,
+        }
+    }
+}
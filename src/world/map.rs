@@ -0,0 +1,614 @@
+// Map files, JSON describing everything a level starts out with. Rotations are quaternions
+// written as [x, y, z, w], angles are in degrees. Any field but a light's position and an
+// instance's name can be left out, it gets its default then.
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::{environment, material, scene, Entity, Light, MeshRenderer, ModelId, World};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Map {
+    // Model files in the resource directory, instances refer to them by index
+    pub models: Vec<String>,
+    pub instances: Vec<MapInstance>,
+    pub lights: Vec<Light>,
+    pub spawn_points: Vec<SpawnPoint>,
+    pub environment: MapEnvironment,
+    // Only the materials changed in the editor, the rest stay as their files have them
+    pub materials: Vec<MapMaterial>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapInstance {
+    pub name: String,
+    // None for an empty that only groups its children
+    pub model: Option<ModelId>,
+    // Relative to the parent
+    pub transform: scene::Instance,
+    // Index of an instance earlier in the list
+    pub parent: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapMaterial {
+    pub model: ModelId,
+    // As the model's MTL file names it
+    pub name: String,
+    pub settings: material::MaterialSettings,
+}
+
+// Where players start out
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpawnPoint {
+    pub position: cgmath::Vector3<f32>,
+    pub yaw: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapEnvironment {
+    // Equirectangular .hdr image in the resource directory
+    pub sky: String,
+    pub settings: environment::EnvironmentSettings,
+    pub sun: environment::DirectionalLight,
+    pub fog: environment::FogSettings,
+    pub hdr: environment::HdrSettings,
+    pub post: environment::PostSettings,
+}
+
+impl Default for SpawnPoint {
+    fn default() -> Self {
+        Self {
+            position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            yaw: 0.0,
+        }
+    }
+}
+
+impl Default for MapEnvironment {
+    fn default() -> Self {
+        Self {
+            sky: environment::ENVIRONMENT_FILE.to_string(),
+            settings: environment::EnvironmentSettings::default(),
+            sun: environment::DirectionalLight::new([-0.4, -1.0, -0.3].into(), [1.0, 0.96, 0.9].into(), 0.5),
+            fog: environment::FogSettings::default(),
+            hdr: environment::HdrSettings::default(),
+            post: environment::PostSettings::default(),
+        }
+    }
+}
+
+impl Map {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Couldn't read map {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Couldn't load map {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let text = self.to_json_string().with_context(|| format!("Couldn't save map {}", path.display()))?;
+        std::fs::write(path, text).with_context(|| format!("Couldn't write map {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let file: MapFile = serde_json::from_str(text)?;
+        let models = file.models;
+        let instances = file
+            .instances
+            .into_iter()
+            .enumerate()
+            .map(|(i, instance)| instance.into_instance(i, models.len()).with_context(|| format!("In instances[{}]", i)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let lights = file
+            .lights
+            .into_iter()
+            .enumerate()
+            .map(|(i, light)| light.into_light(i).with_context(|| format!("In lights[{}]", i)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let spawn_points = file
+            .spawn_points
+            .into_iter()
+            .map(|spawn| SpawnPoint { position: spawn.position.into(), yaw: spawn.yaw })
+            .collect();
+        let materials = file
+            .materials
+            .into_iter()
+            .enumerate()
+            .map(|(i, material)| {
+                anyhow::ensure!(material.model < models.len(), "In materials[{}]: model {} doesn't refer to anything", i, material.model);
+                Ok(MapMaterial { model: material.model, name: material.name, settings: material.settings })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            models,
+            instances,
+            lights,
+            spawn_points,
+            environment: file.environment.into(),
+            materials,
+        })
+    }
+
+    pub fn to_json_string(&self) -> anyhow::Result<String> {
+        let file = MapFile {
+            models: self.models.clone(),
+            instances: self.instances.iter().map(InstanceFile::from).collect(),
+            lights: self.lights.iter().map(LightFile::from).collect(),
+            spawn_points: self
+                .spawn_points
+                .iter()
+                .map(|spawn| SpawnPointFile { position: spawn.position.into(), yaw: spawn.yaw })
+                .collect(),
+            environment: EnvironmentFile::from(&self.environment),
+            materials: self
+                .materials
+                .iter()
+                .map(|material| MaterialFile { model: material.model, name: material.name.clone(), settings: material.settings })
+                .collect(),
+        };
+        // JSON has no NaN or infinity, serde_json writes them as null and they wouldn't load again
+        let value = serde_json::to_value(file)?;
+        if let Some(path) = find_null(&value, "") {
+            anyhow::bail!("{} isn't a finite number", path);
+        }
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+
+    // Adds the instances, lights and changed materials to the world, the lights get their
+    // index as id. Returns the entities of the instances, in the same order.
+    pub fn spawn(&self, world: &mut World) -> Vec<Entity> {
+        let mut entities: Vec<Entity> = Vec::with_capacity(self.instances.len());
+        for instance in &self.instances {
+            let entity = world.spawn();
+            let parent = instance.parent.and_then(|parent| entities.get(parent).copied());
+            world.set_transform(entity, &instance.name, instance.transform, parent);
+            if let Some(model) = instance.model {
+                world.mesh_renderers.insert(entity, MeshRenderer { model });
+            }
+            entities.push(entity);
+        }
+        for (id, light) in self.lights.iter().enumerate() {
+            let entity = world.spawn();
+            world.lights.insert(entity, Light { id, ..*light });
+        }
+        for material in &self.materials {
+            let key = material::MaterialKey { model: material.model, name: material.name.clone() };
+            world.materials.insert(key, material.settings);
+        }
+        entities
+    }
+
+    // The other way around, every entity with a transform becomes an instance
+    pub fn from_world(world: &World, models: &[String], spawn_points: &[SpawnPoint], environment: &MapEnvironment) -> Self {
+        // Parents have to come before their children in the file
+        let mut order = Vec::new();
+        let mut stack = world.scene.roots().iter().rev().copied().collect::<Vec<_>>();
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            if let Some(node) = world.scene.node(node) {
+                stack.extend(node.children().iter().rev());
+            }
+        }
+        let entities: HashMap<scene::NodeId, Entity> = world.transforms.iter().map(|(entity, transform)| (transform.node, entity)).collect();
+        let order = order.into_iter().filter(|node| entities.contains_key(node)).collect::<Vec<_>>();
+        let index_of: HashMap<scene::NodeId, usize> = order.iter().enumerate().map(|(index, node)| (*node, index)).collect();
+
+        let instances = order
+            .iter()
+            .filter_map(|node_id| {
+                let node = world.scene.node(*node_id)?;
+                let entity = entities[node_id];
+                Some(MapInstance {
+                    name: node.name.clone(),
+                    model: world.mesh_renderers.get(entity).map(|renderer| renderer.model),
+                    transform: *node.local(),
+                    parent: node.parent().and_then(|parent| index_of.get(&parent).copied()),
+                })
+            })
+            .collect();
+        let mut lights = world.lights.iter().map(|(_, light)| *light).collect::<Vec<_>>();
+        lights.sort_by_key(|light| light.id);
+        let materials = world
+            .materials
+            .iter()
+            .map(|(key, settings)| MapMaterial { model: key.model, name: key.name.clone(), settings: *settings })
+            .collect();
+
+        Self {
+            models: models.to_vec(),
+            instances,
+            lights,
+            spawn_points: spawn_points.to_vec(),
+            environment: environment.clone(),
+            materials,
+        }
+    }
+}
+
+// Where the first null is, as a path like `lights[0].color[1]`. Nothing in a map file is
+// written as null, so that's a number JSON can't hold.
+fn find_null(value: &serde_json::Value, path: &str) -> Option<String> {
+    match value {
+        serde_json::Value::Null => Some(path.to_string()),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .find_map(|(i, value)| find_null(value, &format!("{}[{}]", path, i))),
+        serde_json::Value::Object(fields) => fields.iter().find_map(|(key, value)| {
+            let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            find_null(value, &path)
+        }),
+        _ => None,
+    }
+}
+
+// How the map looks on disk. The vectors are plain lists and everything has a default, the
+// fields that don't are options that get checked when turning it into a `Map`.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct MapFile {
+    models: Vec<String>,
+    instances: Vec<InstanceFile>,
+    lights: Vec<LightFile>,
+    spawn_points: Vec<SpawnPointFile>,
+    environment: EnvironmentFile,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    materials: Vec<MaterialFile>,
+}
+
+// All of the settings the editor has get written, not only the ones that changed
+#[derive(Serialize, Deserialize)]
+struct MaterialFile {
+    model: ModelId,
+    name: String,
+    #[serde(flatten)]
+    settings: material::MaterialSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct InstanceFile {
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<ModelId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<usize>,
+    position: [f32; 3],
+    rotation: [f32; 4],
+    scale: [f32; 3],
+}
+
+impl Default for InstanceFile {
+    fn default() -> Self {
+        Self::from(&MapInstance {
+            name: String::new(),
+            model: None,
+            transform: scene::Instance::default(),
+            parent: None,
+        })
+    }
+}
+
+impl From<&MapInstance> for InstanceFile {
+    fn from(instance: &MapInstance) -> Self {
+        let rotation = instance.transform.rotation;
+        Self {
+            name: Some(instance.name.clone()),
+            model: instance.model,
+            parent: instance.parent,
+            position: instance.transform.position.into(),
+            rotation: [rotation.v.x, rotation.v.y, rotation.v.z, rotation.s],
+            scale: instance.transform.scale.into(),
+        }
+    }
+}
+
+impl InstanceFile {
+    fn into_instance(self, index: usize, model_count: usize) -> anyhow::Result<MapInstance> {
+        let name = self.name.context("name is missing")?;
+        if let Some(model) = self.model.filter(|model| *model >= model_count) {
+            anyhow::bail!("model {} doesn't refer to anything", model);
+        }
+        if let Some(parent) = self.parent.filter(|parent| *parent >= index) {
+            anyhow::bail!("parent {} doesn't refer to anything", parent);
+        }
+        let [x, y, z, w] = self.rotation;
+        Ok(MapInstance {
+            name,
+            model: self.model,
+            transform: scene::Instance {
+                position: self.position.into(),
+                rotation: cgmath::Quaternion::new(w, x, y, z),
+                scale: self.scale.into(),
+            },
+            parent: self.parent,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct LightFile {
+    position: Option<[f32; 3]>,
+    color: [f32; 3],
+    intensity: f32,
+    radius: f32,
+    spotlight: bool,
+    limitcos_inner: f32,
+    limitcos_outer: f32,
+    limitdir: [f32; 3],
+    normal_offset: f32,
+    slope_bias: f32,
+}
+
+impl Default for LightFile {
+    fn default() -> Self {
+        let mut file = Self::from(&Light::new(0, [0.0, 0.0, 0.0].into(), [1.0, 1.0, 1.0].into(), 1.0, 1.0));
+        file.position = None;
+        file
+    }
+}
+
+impl From<&Light> for LightFile {
+    fn from(light: &Light) -> Self {
+        Self {
+            position: Some(light.position.into()),
+            color: light.color.into(),
+            intensity: light.intensity,
+            radius: light.radius,
+            spotlight: light.is_spotlight,
+            limitcos_inner: light.limitcos_inner,
+            limitcos_outer: light.limitcos_outer,
+            limitdir: light.limitdir.into(),
+            normal_offset: light.normal_offset,
+            slope_bias: light.slope_bias,
+        }
+    }
+}
+
+impl LightFile {
+    fn into_light(self, id: usize) -> anyhow::Result<Light> {
+        let position = self.position.context("position is missing")?;
+        Ok(Light {
+            id,
+            position: position.into(),
+            color: self.color.into(),
+            intensity: self.intensity,
+            radius: self.radius,
+            is_spotlight: self.spotlight,
+            limitcos_inner: self.limitcos_inner,
+            limitcos_outer: self.limitcos_outer,
+            limitdir: self.limitdir.into(),
+            normal_offset: self.normal_offset,
+            slope_bias: self.slope_bias,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct SpawnPointFile {
+    position: [f32; 3],
+    yaw: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct EnvironmentFile {
+    sky: String,
+    #[serde(flatten)]
+    settings: environment::EnvironmentSettings,
+    sun: SunFile,
+    fog: environment::FogSettings,
+    hdr: environment::HdrSettings,
+    post: environment::PostSettings,
+}
+
+// Kept the way it's written, `DirectionalLight::new` would normalize the direction
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct SunFile {
+    direction: [f32; 3],
+    color: [f32; 3],
+    intensity: f32,
+}
+
+impl Default for EnvironmentFile {
+    fn default() -> Self {
+        Self::from(&MapEnvironment::default())
+    }
+}
+
+impl Default for SunFile {
+    fn default() -> Self {
+        EnvironmentFile::default().sun
+    }
+}
+
+impl From<&MapEnvironment> for EnvironmentFile {
+    fn from(environment: &MapEnvironment) -> Self {
+        Self {
+            sky: environment.sky.clone(),
+            settings: environment.settings,
+            sun: SunFile {
+                direction: environment.sun.direction.into(),
+                color: environment.sun.color.into(),
+                intensity: environment.sun.intensity,
+            },
+            fog: environment.fog,
+            hdr: environment.hdr,
+            post: environment.post,
+        }
+    }
+}
+
+impl From<EnvironmentFile> for MapEnvironment {
+    fn from(file: EnvironmentFile) -> Self {
+        Self {
+            sky: file.sky,
+            settings: file.settings,
+            sun: environment::DirectionalLight {
+                direction: file.sun.direction.into(),
+                color: file.sun.color.into(),
+                intensity: file.sun.intensity,
+            },
+            fog: file.fog,
+            hdr: file.hdr,
+            post: file.post,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_map() -> Map {
+        let mut spot = Light::new(1, [0.5, 3.0, -2.25].into(), [1.0, 0.2, 0.1].into(), 2.5, 7.0);
+        spot.is_spotlight = true;
+        spot.limitcos_inner = 0.95;
+        spot.limitdir = [0.1, -0.9, 0.3].into();
+        Map {
+            models: vec!["Models1/test.obj".to_string(), "Models/cube.obj".to_string()],
+            instances: vec![
+                MapInstance {
+                    name: "root \"quoted\"".to_string(),
+                    model: None,
+                    transform: scene::Instance::default(),
+                    parent: None,
+                },
+                MapInstance {
+                    name: "child".to_string(),
+                    model: Some(1),
+                    transform: scene::Instance {
+                        position: [1.1, -2.0, 0.3].into(),
+                        rotation: cgmath::Quaternion::new(0.8660254, 0.0, 0.5, 0.0),
+                        scale: [2.0, 0.5, 1.0].into(),
+                    },
+                    parent: Some(0),
+                },
+                MapInstance {
+                    name: "other".to_string(),
+                    model: Some(0),
+                    transform: scene::Instance {
+                        position: [-4.0, 0.0, 1e-3].into(),
+                        ..Default::default()
+                    },
+                    parent: None,
+                },
+            ],
+            lights: vec![Light::new(0, [2.0, 2.1, 2.0].into(), [1.0, 1.0, 1.0].into(), 1.0, 1.0), spot],
+            spawn_points: vec![SpawnPoint { position: [10.0, 5.0, 10.0].into(), yaw: 45.0 }],
+            environment: MapEnvironment {
+                sky: "assets/sky.hdr".to_string(),
+                settings: environment::EnvironmentSettings {
+                    skybox: false,
+                    skybox_intensity: 0.3,
+                    ambient_intensity: 0.7,
+                },
+                sun: environment::DirectionalLight::new([0.2, -1.0, 0.1].into(), [1.0, 0.9, 0.8].into(), 0.75),
+                fog: environment::FogSettings {
+                    distance_fog: true,
+                    color: [0.1, 0.2, 0.3],
+                    density: 0.125,
+                    ..Default::default()
+                },
+                hdr: environment::HdrSettings {
+                    tonemapper: environment::Tonemapper::AgX,
+                    auto_exposure: false,
+                    exposure: -1.5,
+                    ..Default::default()
+                },
+                post: environment::PostSettings {
+                    bloom: false,
+                    effects: [
+                        (environment::PostEffect::Fxaa, true),
+                        (environment::PostEffect::ColorGrading, true),
+                        (environment::PostEffect::Vignette, false),
+                    ],
+                    lut: 1,
+                    ..Default::default()
+                },
+            },
+            materials: vec![MapMaterial {
+                model: 1,
+                name: "Material.001".to_string(),
+                settings: material::MaterialSettings {
+                    diffuse_color: [0.8, 0.1, 0.1],
+                    ambient_color: [0.0; 3],
+                    specular_color: [0.5; 3],
+                    dissolve: 0.5,
+                    alpha_mode: material::AlphaMode::Blend,
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let map = test_map();
+        let text = map.to_json_string().unwrap();
+        assert_eq!(Map::parse(&text).unwrap(), map);
+        // Saving what got loaded doesn't change anything either
+        assert_eq!(Map::parse(&text).unwrap().to_json_string().unwrap(), text);
+    }
+
+    #[test]
+    fn world_round_trip() {
+        let map = test_map();
+        let mut world = World::new();
+        map.spawn(&mut world);
+        let saved = Map::from_world(&world, &map.models, &map.spawn_points, &map.environment);
+        assert_eq!(saved, map);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let map = test_map();
+        let path = std::env::temp_dir().join(format!("map_round_trip_{}.json", std::process::id()));
+        map.save(&path).unwrap();
+        let loaded = Map::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), map);
+    }
+
+    #[test]
+    fn default_map_parses() {
+        let map = Map::parse(include_str!("../../res/maps/default.json")).unwrap();
+        assert!(map.instances.iter().all(|instance| instance.model.is_some()));
+        assert_eq!(map.spawn_points.len(), 1);
+    }
+
+    #[test]
+    fn defaults_for_left_out_fields() {
+        let map = Map::parse(r#"{ "models": ["a.obj"], "instances": [{ "name": "a", "model": 0 }], "lights": [{ "position": [1, 2, 3] }] }"#).unwrap();
+        assert_eq!(map.instances[0].transform, scene::Instance::default());
+        assert_eq!(map.lights[0], Light::new(0, [1.0, 2.0, 3.0].into(), [1.0, 1.0, 1.0].into(), 1.0, 1.0));
+        assert_eq!(map.environment, MapEnvironment::default());
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let error = Map::parse(r#"{ "models": [], "instances": [{ "name": "a", "model": 3 }] }"#).unwrap_err();
+        assert!(format!("{:#}", error).contains("instances[0]"));
+        let error = Map::parse(r#"{ "lights": [{ "color": [1, 2, 3] }] }"#).unwrap_err();
+        assert!(format!("{:#}", error).contains("position is missing"));
+        let error = Map::parse(r#"{ "models": [], "materials": [{ "model": 0, "name": "a", "diffuse_color": [1, 1, 1], "ambient_color": [0, 0, 0], "specular_color": [0, 0, 0], "dissolve": 1, "alpha_mode": "opaque" }] }"#).unwrap_err();
+        assert!(format!("{:#}", error).contains("materials[0]"));
+        let error = Map::parse(r#"{ "lights": [{ "position": [1, 2] }] }"#).unwrap_err();
+        assert!(format!("{:#}", error).contains("line 1"));
+        assert!(Map::parse("{ \"models\": [").is_err());
+    }
+
+    #[test]
+    fn refuses_to_save_non_finite_numbers() {
+        let mut map = test_map();
+        map.lights[1].color.y = f32::NAN;
+        let error = map.to_json_string().unwrap_err();
+        assert!(error.to_string().contains("lights[1].color[1]"), "{}", error);
+        map.lights[1].color.y = 1.0;
+        map.environment.fog.density = f32::INFINITY;
+        let error = map.to_json_string().unwrap_err();
+        assert!(error.to_string().contains("environment.fog.density"), "{}", error);
+    }
+}
@@ -0,0 +1,40 @@
+// What the editor can change about a model's materials. The renderer loads the materials
+// from the MTL files and puts these on top, so the map only has to keep what got changed.
+use serde::{Deserialize, Serialize};
+
+use super::ModelId;
+
+// How a material's alpha is used, picked from the MTL dissolve and the diffuse texture
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlphaMode {
+    Opaque = 0,
+    Mask = 1,  // Cut out below the cutoff, drawn and shadowed like opaque geometry
+    Blend = 2, // Drawn after everything else, back to front
+}
+
+impl AlphaMode {
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            0 => AlphaMode::Opaque,
+            1 => AlphaMode::Mask,
+            _ => AlphaMode::Blend,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaterialSettings {
+    pub diffuse_color: [f32; 3],
+    pub ambient_color: [f32; 3],
+    pub specular_color: [f32; 3],
+    pub dissolve: f32,
+    pub alpha_mode: AlphaMode,
+}
+
+// A material of one of the map's models, by the name its MTL file gives it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MaterialKey {
+    pub model: ModelId,
+    pub name: String,
+}
@@ -0,0 +1,335 @@
+// The game objects, as entities with components. Nothing in here touches the GPU, so the
+// headless server can run the same world and systems the client does.
+pub mod components;
+pub mod environment;
+pub mod map;
+pub mod material;
+pub mod scene;
+pub mod systems;
+
+pub use components::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Entity {
+    index: u32,
+    // Bumped every time the index gets reused, so old handles stop matching
+    generation: u32,
+}
+
+// Every component of one type, indexed by entity index
+pub struct Storage<T> {
+    // The generation the slot was last used by, which stays when the component goes, so a
+    // stale handle can be told apart from a live one even when there's nothing in the slot
+    slots: Vec<(u32, Option<T>)>,
+}
+
+impl<T> Default for Storage<T> {
+    fn default() -> Self {
+        Self { slots: Vec::new() }
+    }
+}
+
+impl<T> Storage<T> {
+    // Replaces the component the entity already had, if any. A stale handle, whose index a
+    // newer entity got since, doesn't insert anything.
+    pub fn insert(&mut self, entity: Entity, component: T) -> Option<T> {
+        let index = entity.index as usize;
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, || (0, None));
+        }
+        let (generation, slot) = &mut self.slots[index];
+        if generation.wrapping_sub(entity.generation) as i32 > 0 {
+            return None;
+        }
+        // Older than the handle would be what a dead entity left behind, that can go
+        let old = slot.replace(component).filter(|_| *generation == entity.generation);
+        *generation = entity.generation;
+        old
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        match self.slots.get_mut(entity.index as usize)? {
+            (generation, slot) if *generation == entity.generation => slot.take(),
+            _ => None,
+        }
+    }
+
+    // Removes the component of an entity that's being despawned, and turns away its handles
+    // from then on
+    fn despawn(&mut self, entity: Entity) -> Option<T> {
+        let component = self.remove(entity);
+        let index = entity.index as usize;
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, || (0, None));
+        }
+        self.slots[index] = (entity.generation.wrapping_add(1), None);
+        component
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        match self.slots.get(entity.index as usize)? {
+            (generation, Some(component)) if *generation == entity.generation => Some(component),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        match self.slots.get_mut(entity.index as usize)? {
+            (generation, Some(component)) if *generation == entity.generation => Some(component),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.get(entity).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, (generation, slot))| {
+            Some((Entity { index: index as u32, generation: *generation }, slot.as_ref()?))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, (generation, slot))| {
+            Some((Entity { index: index as u32, generation: *generation }, slot.as_mut()?))
+        })
+    }
+}
+
+// One storage per component type. They're separate fields so a system can borrow a few of
+// them mutably at the same time.
+#[derive(Default)]
+pub struct World {
+    generations: Vec<u32>,
+    alive: Vec<bool>,
+    free: Vec<u32>,
+
+    // Where the transforms live, every Transform component is a node in here
+    pub scene: scene::SceneGraph,
+    pub transforms: Storage<Transform>,
+    pub mesh_renderers: Storage<MeshRenderer>,
+    pub lights: Storage<Light>,
+    pub cameras: Storage<Camera>,
+    pub player_controllers: Storage<PlayerController>,
+
+    // Materials the map changes from what their files say, the renderer applies them
+    pub materials: std::collections::BTreeMap<material::MaterialKey, material::MaterialSettings>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn(&mut self) -> Entity {
+        match self.free.pop() {
+            Some(index) => {
+                self.alive[index as usize] = true;
+                Entity { index, generation: self.generations[index as usize] }
+            }
+            None => {
+                self.generations.push(0);
+                self.alive.push(true);
+                Entity { index: self.generations.len() as u32 - 1, generation: 0 }
+            }
+        }
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        let index = entity.index as usize;
+        index < self.alive.len() && self.alive[index] && self.generations[index] == entity.generation
+    }
+
+    // Takes away all of the entity's components. Entities parented to it stay where they are
+    // in the world, they just lose their parent.
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        if let Some(transform) = self.transforms.despawn(entity) {
+            let children = self.scene.node(transform.node).map(|node| node.children().to_vec()).unwrap_or_default();
+            for child in children {
+                let world = self.scene.node(child).map(|node| node.world());
+                self.scene.set_parent(child, None);
+                if let (Some(world), Some(local)) = (world, self.scene.local_mut(child)) {
+                    *local = scene::Instance::from_matrix(&world);
+                }
+            }
+            self.scene.remove(transform.node);
+        }
+        self.mesh_renderers.despawn(entity);
+        self.lights.despawn(entity);
+        self.cameras.despawn(entity);
+        self.player_controllers.despawn(entity);
+
+        let index = entity.index as usize;
+        self.alive[index] = false;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        self.free.push(entity.index);
+        true
+    }
+
+    // Gives the entity a node in the scene graph, under the parent's node if it has one
+    pub fn set_transform(&mut self, entity: Entity, name: &str, local: scene::Instance, parent: Option<Entity>) {
+        let parent_node = parent.and_then(|parent| self.transforms.get(parent)).map(|transform| transform.node);
+        match self.transforms.get(entity) {
+            Some(transform) => {
+                let node = transform.node;
+                self.scene.set_local(node, local);
+                self.scene.set_parent(node, parent_node);
+            }
+            None => {
+                let node = self.scene.add_node(name, local, parent_node);
+                self.transforms.insert(entity, Transform { node });
+            }
+        }
+    }
+
+    pub fn local_mut(&mut self, entity: Entity) -> Option<&mut scene::Instance> {
+        let node = self.transforms.get(entity)?.node;
+        self.scene.local_mut(node)
+    }
+
+    // As of the last time the transforms were propagated
+    pub fn world_matrix(&self, entity: Entity) -> Option<cgmath::Matrix4<f32>> {
+        let node = self.transforms.get(entity)?.node;
+        Some(self.scene.node(node)?.world())
+    }
+}
+
+pub type System = fn(&mut World);
+
+// The systems that run every tick, in the order they were added
+#[derive(Default)]
+pub struct Schedule {
+    systems: Vec<System>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_system(mut self, system: System) -> Self {
+        self.systems.push(system);
+        self
+    }
+
+    pub fn run(&self, world: &mut World) {
+        for system in &self.systems {
+            system(world);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> scene::Instance {
+        scene::Instance { position: cgmath::Vector3::new(x, 0.0, 0.0), ..Default::default() }
+    }
+
+    #[test]
+    fn spawns_and_despawns() {
+        let mut world = World::new();
+        let (a, b) = (world.spawn(), world.spawn());
+        assert_ne!(a, b);
+        assert!(world.is_alive(a) && world.is_alive(b));
+        world.mesh_renderers.insert(a, MeshRenderer { model: 3 });
+
+        assert!(world.despawn(a));
+        assert!(!world.is_alive(a));
+        assert!(!world.despawn(a));
+        assert!(world.mesh_renderers.get(a).is_none());
+    }
+
+    #[test]
+    fn reused_indices_get_a_new_generation() {
+        let mut world = World::new();
+        let old = world.spawn();
+        world.despawn(old);
+        let new = world.spawn();
+        assert_eq!(new.index, old.index);
+        assert_ne!(new, old);
+        assert!(world.is_alive(new) && !world.is_alive(old));
+    }
+
+    #[test]
+    fn stale_handles_miss() {
+        let mut world = World::new();
+        let old = world.spawn();
+        world.mesh_renderers.insert(old, MeshRenderer { model: 1 });
+        world.despawn(old);
+        let new = world.spawn();
+        world.mesh_renderers.insert(new, MeshRenderer { model: 2 });
+
+        // Neither overwrites nor sees the component of the entity that took the index
+        assert!(world.mesh_renderers.insert(old, MeshRenderer { model: 9 }).is_none());
+        assert_eq!(world.mesh_renderers.get(new).unwrap().model, 2);
+        assert!(world.mesh_renderers.get(old).is_none());
+        assert!(world.mesh_renderers.get_mut(old).is_none());
+        assert!(world.mesh_renderers.remove(old).is_none());
+        assert!(world.mesh_renderers.contains(new));
+
+        // The live handle still replaces its own
+        assert_eq!(world.mesh_renderers.insert(new, MeshRenderer { model: 4 }).unwrap().model, 2);
+        assert_eq!(world.mesh_renderers.get(new).unwrap().model, 4);
+    }
+
+    #[test]
+    fn stale_handles_miss_empty_slots() {
+        let mut world = World::new();
+        let old = world.spawn();
+        world.despawn(old);
+        let new = world.spawn();
+
+        // Nothing of either was ever stored, the old handle still can't put anything there
+        assert!(world.mesh_renderers.insert(old, MeshRenderer { model: 1 }).is_none());
+        assert!(world.mesh_renderers.get(new).is_none());
+        assert_eq!(world.mesh_renderers.iter().count(), 0);
+
+        // Same once the new entity's component is gone again
+        world.mesh_renderers.insert(new, MeshRenderer { model: 2 });
+        world.mesh_renderers.remove(new);
+        assert!(world.mesh_renderers.insert(old, MeshRenderer { model: 3 }).is_none());
+        assert!(world.mesh_renderers.get(new).is_none());
+        assert!(world.mesh_renderers.insert(new, MeshRenderer { model: 4 }).is_none());
+        assert_eq!(world.mesh_renderers.get(new).unwrap().model, 4);
+    }
+
+    #[test]
+    fn queries_walk_every_component() {
+        let mut world = World::new();
+        let entities = (0..4).map(|_| world.spawn()).collect::<Vec<_>>();
+        for (model, entity) in entities.iter().enumerate().filter(|(model, _)| model % 2 == 0) {
+            world.mesh_renderers.insert(*entity, MeshRenderer { model });
+        }
+        world.despawn(entities[2]);
+        let found = world.mesh_renderers.iter().map(|(entity, renderer)| (entity, renderer.model)).collect::<Vec<_>>();
+        assert_eq!(found, [(entities[0], 0)]);
+
+        for (_, renderer) in world.mesh_renderers.iter_mut() {
+            renderer.model = 7;
+        }
+        assert_eq!(world.mesh_renderers.get(entities[0]).unwrap().model, 7);
+    }
+
+    #[test]
+    fn despawning_a_parent_keeps_its_children_in_place() {
+        let mut world = World::new();
+        let (parent, child) = (world.spawn(), world.spawn());
+        world.set_transform(parent, "parent", at(1.0), None);
+        world.set_transform(child, "child", at(2.0), Some(parent));
+        world.scene.update_world();
+        assert_eq!(world.world_matrix(child).unwrap().w.x, 3.0);
+
+        world.despawn(parent);
+        world.scene.update_world();
+        assert!(world.world_matrix(parent).is_none());
+        assert_eq!(world.world_matrix(child).unwrap().w.x, 3.0);
+        let node = world.transforms.get(child).unwrap().node;
+        assert_eq!(world.scene.node(node).unwrap().parent(), None);
+    }
+}
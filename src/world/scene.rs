@@ -0,0 +1,298 @@
+// Scene graph of named nodes with local transforms. A node's world matrix is its parent's
+// times its own, and only gets worked out again when it or something above it changed.
+use cgmath::SquareMatrix;
+
+// Position, rotation and scale of a node, relative to its parent
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Instance {
+    pub position: cgmath::Vector3<f32>,
+    pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            rotation: cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Instance {
+    pub fn model_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    // Splits a matrix back up into translation, rotation and scale. Any shear a parent's
+    // non-uniform scale added gets lost.
+    pub fn from_matrix(matrix: &cgmath::Matrix4<f32>) -> Self {
+        use cgmath::InnerSpace;
+        let columns = [matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate()];
+        let scale = cgmath::Vector3::new(columns[0].magnitude(), columns[1].magnitude(), columns[2].magnitude());
+        let unscaled = |column: cgmath::Vector3<f32>, scale: f32| if scale > f32::EPSILON { column / scale } else { column };
+        let rotation = cgmath::Matrix3::from_cols(
+            unscaled(columns[0], scale.x),
+            unscaled(columns[1], scale.y),
+            unscaled(columns[2], scale.z),
+        );
+        Self {
+            position: matrix.w.truncate(),
+            rotation: cgmath::Quaternion::from(rotation).normalize(),
+            scale,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+pub struct Node {
+    pub name: String,
+    // Relative to the parent, or the world for root nodes
+    local: Instance,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    world: cgmath::Matrix4<f32>,
+    dirty: bool,
+}
+
+impl Node {
+    pub fn local(&self) -> &Instance {
+        &self.local
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    // As of the last `update_world`
+    pub fn world(&self) -> cgmath::Matrix4<f32> {
+        self.world
+    }
+}
+
+#[derive(Default)]
+pub struct SceneGraph {
+    // Removed nodes leave a hole that the next new node fills
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>,
+}
+
+impl SceneGraph {
+    pub fn add_node(&mut self, name: &str, local: Instance, parent: Option<NodeId>) -> NodeId {
+        let node = Node {
+            name: name.to_string(),
+            local,
+            parent: None,
+            children: Vec::new(),
+            world: cgmath::Matrix4::identity(),
+            dirty: true,
+        };
+        let id = match self.nodes.iter().position(Option::is_none) {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                NodeId(slot)
+            }
+            None => {
+                self.nodes.push(Some(node));
+                NodeId(self.nodes.len() - 1)
+            }
+        };
+        self.roots.push(id);
+        self.set_parent(id, parent);
+        id
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id.0)?.as_ref()
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        self.nodes.get_mut(id.0)?.as_mut()
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn set_local(&mut self, id: NodeId, local: Instance) {
+        if let Some(node) = self.node_mut(id) {
+            node.local = local;
+            node.dirty = true;
+        }
+    }
+
+    // Hands out the local transform for modification, so it's flagged dirty right away
+    pub fn local_mut(&mut self, id: NodeId) -> Option<&mut Instance> {
+        let node = self.node_mut(id)?;
+        node.dirty = true;
+        Some(&mut node.local)
+    }
+
+    // Moves the node and everything below it. Parenting a node to one of its own descendants
+    // would make a loop, so that's ignored.
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) {
+        if self.node(id).is_none() || parent.is_some_and(|parent| self.node(parent).is_none() || self.is_descendant(parent, id)) {
+            return;
+        }
+        match self.node(id).and_then(|node| node.parent) {
+            Some(old) => self.node_mut(old).unwrap().children.retain(|child| *child != id),
+            None => self.roots.retain(|root| *root != id),
+        }
+        match parent {
+            Some(new) => self.node_mut(new).unwrap().children.push(id),
+            None => self.roots.push(id),
+        }
+        let node = self.node_mut(id).unwrap();
+        node.parent = parent;
+        node.dirty = true;
+    }
+
+    // True for the node itself too
+    fn is_descendant(&self, id: NodeId, ancestor: NodeId) -> bool {
+        let mut current = Some(id);
+        while let Some(node) = current {
+            if node == ancestor {
+                return true;
+            }
+            current = self.node(node).and_then(|node| node.parent);
+        }
+        false
+    }
+
+    // Takes the node out together with everything below it
+    pub fn remove(&mut self, id: NodeId) -> Option<Node> {
+        self.node(id)?;
+        self.set_parent(id, None);
+        self.roots.retain(|root| *root != id);
+        let node = self.nodes[id.0].take()?;
+        for child in &node.children {
+            self.remove_subtree(*child);
+        }
+        Some(node)
+    }
+
+    fn remove_subtree(&mut self, id: NodeId) {
+        if let Some(node) = self.nodes[id.0].take() {
+            for child in node.children {
+                self.remove_subtree(child);
+            }
+        }
+    }
+
+    // Works out the world matrices of everything that changed since the last call, and returns
+    // the nodes that got a new one
+    pub fn update_world(&mut self) -> Vec<NodeId> {
+        let mut changed = Vec::new();
+        let mut stack = self
+            .roots
+            .iter()
+            .rev()
+            .map(|root| (*root, cgmath::Matrix4::identity(), false))
+            .collect::<Vec<_>>();
+        while let Some((id, parent_world, parent_changed)) = stack.pop() {
+            let Some(node) = self.nodes[id.0].as_mut() else { continue };
+            let recompute = node.dirty || parent_changed;
+            if recompute {
+                node.world = parent_world * node.local.model_matrix();
+                node.dirty = false;
+                changed.push(id);
+            }
+            stack.extend(node.children.iter().rev().map(|child| (*child, node.world, recompute)));
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, y: f32, z: f32) -> Instance {
+        Instance { position: cgmath::Vector3::new(x, y, z), ..Default::default() }
+    }
+
+    fn world_position(scene: &SceneGraph, id: NodeId) -> cgmath::Vector3<f32> {
+        scene.node(id).unwrap().world().w.truncate()
+    }
+
+    #[test]
+    fn children_follow_their_parent() {
+        let mut scene = SceneGraph::default();
+        let parent = scene.add_node("parent", at(1.0, 0.0, 0.0), None);
+        let child = scene.add_node("child", at(0.0, 2.0, 0.0), Some(parent));
+        let grandchild = scene.add_node("grandchild", at(0.0, 0.0, 3.0), Some(child));
+        assert_eq!(scene.update_world().len(), 3);
+        assert_eq!(world_position(&scene, grandchild), cgmath::Vector3::new(1.0, 2.0, 3.0));
+
+        // Nothing changed, nothing gets worked out again
+        assert!(scene.update_world().is_empty());
+
+        scene.local_mut(parent).unwrap().position.x = 5.0;
+        assert_eq!(scene.update_world(), [parent, child, grandchild]);
+        assert_eq!(world_position(&scene, child), cgmath::Vector3::new(5.0, 2.0, 0.0));
+        assert_eq!(world_position(&scene, grandchild), cgmath::Vector3::new(5.0, 2.0, 3.0));
+
+        // Only what's below a changed node moves
+        scene.set_local(child, at(0.0, 4.0, 0.0));
+        assert_eq!(scene.update_world(), [child, grandchild]);
+        assert_eq!(world_position(&scene, grandchild), cgmath::Vector3::new(5.0, 4.0, 3.0));
+    }
+
+    #[test]
+    fn reparenting_moves_the_subtree() {
+        let mut scene = SceneGraph::default();
+        let a = scene.add_node("a", at(1.0, 0.0, 0.0), None);
+        let b = scene.add_node("b", at(10.0, 0.0, 0.0), None);
+        let child = scene.add_node("child", at(0.0, 1.0, 0.0), Some(a));
+        scene.update_world();
+
+        scene.set_parent(child, Some(b));
+        assert_eq!(scene.node(a).unwrap().children(), []);
+        assert_eq!(scene.node(child).unwrap().parent(), Some(b));
+        scene.update_world();
+        assert_eq!(world_position(&scene, child), cgmath::Vector3::new(10.0, 1.0, 0.0));
+
+        // A loop gets ignored
+        scene.set_parent(b, Some(child));
+        assert_eq!(scene.node(b).unwrap().parent(), None);
+        assert_eq!(scene.roots(), [a, b]);
+
+        scene.set_parent(child, None);
+        scene.update_world();
+        assert_eq!(scene.roots(), [a, b, child]);
+        assert_eq!(world_position(&scene, child), cgmath::Vector3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn removing_a_node_removes_its_children() {
+        let mut scene = SceneGraph::default();
+        let root = scene.add_node("root", at(0.0, 0.0, 0.0), None);
+        let parent = scene.add_node("parent", at(0.0, 0.0, 0.0), Some(root));
+        let child = scene.add_node("child", at(0.0, 0.0, 0.0), Some(parent));
+        let grandchild = scene.add_node("grandchild", at(0.0, 0.0, 0.0), Some(child));
+        let sibling = scene.add_node("sibling", at(0.0, 0.0, 0.0), Some(root));
+
+        assert_eq!(scene.remove(parent).unwrap().name, "parent");
+        for id in [parent, child, grandchild] {
+            assert!(scene.node(id).is_none());
+        }
+        assert_eq!(scene.node(root).unwrap().children(), [sibling]);
+        assert!(scene.remove(parent).is_none());
+
+        // The holes get filled by new nodes, which start out clean
+        let new = scene.add_node("new", at(0.0, 0.0, 0.0), Some(sibling));
+        assert!([parent, child, grandchild].contains(&new));
+        assert_eq!(scene.node(new).unwrap().children(), []);
+        assert_eq!(scene.nodes.iter().flatten().count(), 3);
+        assert!(scene.update_world().contains(&new));
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use super::{Camera, Entity, Light, ModelId, World};
+
+// Applies the player input to their cameras
+pub fn player_controllers(world: &mut World) {
+    for (entity, controller) in world.player_controllers.iter_mut() {
+        if let Some(camera) = world.cameras.get_mut(entity) {
+            controller.update_camera(camera);
+        }
+    }
+}
+
+// Works out the world matrices of the transforms that changed, and moves the lights on them
+pub fn propagate_transforms(world: &mut World) {
+    let changed = world.scene.update_world();
+    if changed.is_empty() {
+        return;
+    }
+    for (entity, light) in world.lights.iter_mut() {
+        let Some(transform) = world.transforms.get(entity) else { continue };
+        if changed.contains(&transform.node) {
+            if let Some(node) = world.scene.node(transform.node) {
+                light.position = node.world().w.truncate();
+            }
+        }
+    }
+}
+
+// Everything the renderer needs from the world for a frame
+#[derive(Default)]
+pub struct DrawList {
+    pub camera: Option<Camera>,
+    // Ordered by their id, the order the GPU buffers want them in
+    pub lights: Vec<Light>,
+    instances: HashMap<ModelId, (Vec<Entity>, Vec<cgmath::Matrix4<f32>>)>,
+}
+
+impl DrawList {
+    // The world matrix of every instance of the model
+    pub fn transforms(&self, model: ModelId) -> &[cgmath::Matrix4<f32>] {
+        self.instances.get(&model).map_or(&[], |(_, transforms)| transforms.as_slice())
+    }
+
+    // The entity of every instance of the model, in the same order as the transforms
+    pub fn entities(&self, model: ModelId) -> &[Entity] {
+        self.instances.get(&model).map_or(&[], |(entities, _)| entities.as_slice())
+    }
+}
+
+// The render system, run after the transforms got propagated
+pub fn gather_draw_list(world: &World) -> DrawList {
+    let mut draw_list = DrawList {
+        camera: world.cameras.iter().next().map(|(_, camera)| *camera),
+        lights: world.lights.iter().map(|(_, light)| *light).collect(),
+        ..Default::default()
+    };
+    draw_list.lights.sort_by_key(|light| light.id);
+    for (entity, renderer) in world.mesh_renderers.iter() {
+        if let Some(matrix) = world.world_matrix(entity) {
+            let (entities, transforms) = draw_list.instances.entry(renderer.model).or_default();
+            entities.push(entity);
+            transforms.push(matrix);
+        }
+    }
+    draw_list
+}